@@ -2,23 +2,30 @@ use axum::{
     routing::post,
     Router,
     Json,
-    http::StatusCode,
     extract::State,
 };
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
-use tower_http::cors::CorsLayer;
-use common::AuthContext;
+use common::bootstrap;
+use common::{ApiError, AuthContext, AuthState, JwtVerifier, Valid, Validate, Validator};
 use std::sync::Arc;
+use sqlx::{Pool, Postgres};
+
+type DbPool = Pool<Postgres>;
 
 struct AppState {
-    jwt_secret: Arc<String>,
+    jwt_verifier: JwtVerifier,
     openai_key: String,
+    /// Only used to verify API keys, so the service still runs without a database.
+    db: Option<DbPool>,
 }
 
-impl axum::extract::FromRef<Arc<AppState>> for Arc<String> {
-    fn from_ref(state: &Arc<AppState>) -> Self {
-        state.jwt_secret.clone()
+impl AuthState for AppState {
+    fn jwt_verifier(&self) -> &JwtVerifier {
+        &self.jwt_verifier
+    }
+
+    fn db(&self) -> Option<&DbPool> {
+        self.db.as_ref()
     }
 }
 
@@ -27,6 +34,13 @@ struct AiDescribeRequest {
     notes: String,
 }
 
+impl Validate for AiDescribeRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("notes", &self.notes);
+        v.max_len("notes", self.notes.as_str(), 5000);
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct AiItem {
     description: String,
@@ -41,34 +55,29 @@ struct AiDescribeResponse {
 
 #[tokio::main]
 async fn main() {
-    dotenvy::dotenv().ok();
-    tracing_subscriber::fmt::init();
-
-    let jwt_secret = Arc::new(std::env::var("JWT_SECRET").unwrap_or_else(|_| "supersecret".to_string()));
+    let config = bootstrap::init("ai-service", 5006);
+    let jwt_verifier = config.jwt_verifier();
     let openai_key = std::env::var("OPENAI_API_KEY").unwrap_or_default();
+    let db = config.lazy_database();
     
     let state = Arc::new(AppState {
-        jwt_secret,
+        jwt_verifier,
         openai_key,
+        db: db.clone(),
     });
 
     let app = Router::new()
         .route("/api/ai/describe-line-items", post(describe_line_items))
-        .layer(CorsLayer::permissive())
         .with_state(state);
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 5006));
-    tracing::info!("AI service listening on {}", addr);
-    
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    bootstrap::serve(config, app, db).await;
 }
 
 async fn describe_line_items(
     _auth: AuthContext,
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<AiDescribeRequest>,
-) -> Result<Json<AiDescribeResponse>, (StatusCode, String)> {
+    Valid(payload): Valid<AiDescribeRequest>,
+) -> Result<Json<AiDescribeResponse>, ApiError> {
     if state.openai_key.is_empty() {
         // Return dummy data if no API key
         return Ok(Json(AiDescribeResponse {
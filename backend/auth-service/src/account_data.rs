@@ -0,0 +1,225 @@
+//! GDPR export and erasure, orchestrated across every service that stores
+//! account data. Each service contributes its own part over
+//! `/internal/account-data/<part>` (see `common::account_data`), called with
+//! the user's own token.
+
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header, HeaderMap, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use common::account_data::{AccountDataPart, ArchiveFile, ErasureReport};
+use common::bootstrap::ServiceUrls;
+use common::{current_request_id, telemetry, ApiError, AuthContext, Valid, Validate, Validator, REQUEST_ID_HEADER};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::io::Write;
+use std::sync::Arc;
+use zip::write::SimpleFileOptions;
+
+use crate::api_keys::require_session;
+use crate::models::ApiKey;
+use crate::{throttle, AppState};
+
+pub struct DataService {
+    part: &'static str,
+    url: String,
+}
+
+/// Services holding account data, in erasure order. Invoices go first
+/// because anonymizing them snapshots client and company details.
+pub fn services(urls: &ServiceUrls) -> Vec<DataService> {
+    [("invoices", &urls.invoice), ("clients", &urls.client), ("products", &urls.product), ("company", &urls.company)]
+        .into_iter()
+        .map(|(part, base)| DataService {
+            part,
+            url: format!("{}/internal/account-data/{}", base, part),
+        })
+        .collect()
+}
+
+#[derive(Deserialize)]
+pub struct DeleteAccountRequest {
+    /// Must repeat the account's email address.
+    confirm_email: String,
+}
+
+impl Validate for DeleteAccountRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("confirm_email", &self.confirm_email);
+    }
+}
+
+#[derive(Serialize)]
+pub struct DeleteAccountResponse {
+    parts: Vec<(String, ErasureReport)>,
+}
+
+#[derive(Serialize, FromRow)]
+struct AccountRecord {
+    id: i32,
+    email: String,
+    created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, FromRow)]
+struct IdentityRecord {
+    connection_id: i32,
+    subject: String,
+    email: String,
+    created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, FromRow)]
+struct LoginAttemptRecord {
+    ip_address: String,
+    user_agent: Option<String>,
+    success: bool,
+    created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct AccountExport {
+    user: AccountRecord,
+    api_keys: Vec<ApiKey>,
+    sso_identities: Vec<IdentityRecord>,
+    login_attempts: Vec<LoginAttemptRecord>,
+}
+
+fn bearer(headers: &HeaderMap) -> Result<String, ApiError> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .map(|s| s.to_string())
+        .ok_or(ApiError::Unauthorized("Missing authorization header".to_string()))
+}
+
+fn service_error(part: &str, e: impl std::fmt::Display) -> ApiError {
+    tracing::error!("Account data request to {} failed: {}", part, e);
+    ApiError::BadGateway(format!("The {} service could not process the request", part))
+}
+
+async fn own_part(state: &AppState, user_id: i32) -> Result<ArchiveFile, ApiError> {
+    let user = sqlx::query_as::<_, AccountRecord>("SELECT id, email, created_at FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_one(&state.db)
+        .await?;
+    let api_keys = sqlx::query_as::<_, ApiKey>(
+        "SELECT id, user_id, name, key_prefix, scopes, expires_at, last_used_at, created_at FROM api_keys WHERE user_id = $1"
+    )
+    .bind(user_id)
+    .fetch_all(&state.db)
+    .await?;
+    let sso_identities = sqlx::query_as::<_, IdentityRecord>(
+        "SELECT connection_id, subject, email, created_at FROM user_identities WHERE user_id = $1"
+    )
+    .bind(user_id)
+    .fetch_all(&state.db)
+    .await?;
+    let login_attempts = sqlx::query_as::<_, LoginAttemptRecord>(
+        "SELECT ip_address, user_agent, success, created_at FROM login_attempts WHERE user_id = $1 OR email = $2 ORDER BY created_at"
+    )
+    .bind(user_id)
+    .bind(&user.email)
+    .fetch_all(&state.db)
+    .await?;
+
+    ArchiveFile::json("account.json", &AccountExport { user, api_keys, sso_identities, login_attempts }).map_err(ApiError::internal)
+}
+
+/// Builds a ZIP of the account's data from every service.
+pub async fn export_account(
+    auth: AuthContext,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> Result<Response<Body>, ApiError> {
+    require_session(&auth)?;
+    let token = bearer(&headers)?;
+
+    let mut files = vec![own_part(&state, auth.user_id).await?];
+    for service in &state.data_services {
+        let part: AccountDataPart = telemetry::propagate(state.http.get(&service.url))
+            .header("Authorization", &token)
+            .header(REQUEST_ID_HEADER, current_request_id().unwrap_or_default())
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| service_error(service.part, e))?
+            .json()
+            .await
+            .map_err(|e| service_error(service.part, e))?;
+        files.extend(part.files);
+    }
+
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    for file in files {
+        let bytes = file.decode().map_err(ApiError::internal)?;
+        zip.start_file(file.path, SimpleFileOptions::default()).map_err(ApiError::internal)?;
+        zip.write_all(&bytes).map_err(ApiError::internal)?;
+    }
+    let archive = zip.finish().map_err(ApiError::internal)?.into_inner();
+
+    tracing::info!("Exported account data for user {}", auth.user_id);
+
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "application/zip")
+        .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"billio_account_{}.zip\"", auth.user_id))
+        .body(Body::from(archive))
+        .unwrap())
+}
+
+/// Erases the account service by service, then the user itself. Every step
+/// is idempotent, so a failed erasure can simply be retried.
+pub async fn delete_account(
+    auth: AuthContext,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+    Valid(payload): Valid<DeleteAccountRequest>,
+) -> Result<Json<DeleteAccountResponse>, ApiError> {
+    require_session(&auth)?;
+    let token = bearer(&headers)?;
+
+    let email: String = sqlx::query_scalar("SELECT email FROM users WHERE id = $1")
+        .bind(auth.user_id)
+        .fetch_one(&state.db)
+        .await?;
+    if !email.eq_ignore_ascii_case(payload.confirm_email.trim()) {
+        return Err(ApiError::BadRequest("confirm_email does not match the account".to_string()));
+    }
+
+    let mut parts = Vec::new();
+    for service in &state.data_services {
+        let report: ErasureReport = telemetry::propagate(state.http.delete(&service.url))
+            .header("Authorization", &token)
+            .header(REQUEST_ID_HEADER, current_request_id().unwrap_or_default())
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| service_error(service.part, e))?
+            .json()
+            .await
+            .map_err(|e| service_error(service.part, e))?;
+        parts.push((service.part.to_string(), report));
+    }
+
+    let mut tx = state.db.begin().await?;
+    sqlx::query("DELETE FROM login_attempts WHERE user_id = $1 OR email = $2")
+        .bind(auth.user_id)
+        .bind(&email)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM login_throttles WHERE throttle_key = $1")
+        .bind(throttle::account_key(&email))
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM users WHERE id = $1")
+        .bind(auth.user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    tracing::info!("Erased account {}", auth.user_id);
+    Ok(Json(DeleteAccountResponse { parts }))
+}
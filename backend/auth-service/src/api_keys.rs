@@ -0,0 +1,125 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chrono::{Duration, Utc};
+use common::{hash_api_key, is_known_scope, ApiError, AuthContext, Valid, Validate, Validator, API_KEY_PREFIX};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::models::ApiKey;
+use crate::AppState;
+
+const DEFAULT_EXPIRY_DAYS: i64 = 90;
+const MAX_EXPIRY_DAYS: i64 = 365;
+
+#[derive(Deserialize)]
+pub struct CreateApiKeyRequest {
+    name: String,
+    scopes: Vec<String>,
+    expires_in_days: Option<i64>,
+}
+
+impl Validate for CreateApiKeyRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("name", &self.name);
+        v.max_len("name", self.name.as_str(), 100);
+        if self.scopes.is_empty() {
+            v.error("scopes", "at least one scope is required");
+        }
+        for (i, scope) in self.scopes.iter().enumerate() {
+            if !is_known_scope(scope) {
+                v.error(&format!("scopes[{}]", i), format!("unknown scope '{}'", scope));
+            }
+        }
+        v.range("expires_in_days", self.expires_in_days, 1, MAX_EXPIRY_DAYS);
+    }
+}
+
+#[derive(Serialize)]
+pub struct CreateApiKeyResponse {
+    /// The plaintext key. It is only ever returned here.
+    key: String,
+    #[serde(flatten)]
+    api_key: ApiKey,
+}
+
+/// Account management (keys, export, deletion) needs a signed-in user, not an API key.
+pub(crate) fn require_session(auth: &AuthContext) -> Result<(), ApiError> {
+    if auth.scopes.is_some() {
+        return Err(ApiError::Forbidden("API keys cannot access this endpoint".to_string()));
+    }
+    Ok(())
+}
+
+fn generate_key() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    format!("{}{}", API_KEY_PREFIX, hex::encode(bytes))
+}
+
+pub async fn list_api_keys(
+    auth: AuthContext,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<ApiKey>>, ApiError> {
+    require_session(&auth)?;
+
+    let keys = sqlx::query_as::<_, ApiKey>(
+        "SELECT id, user_id, name, key_prefix, scopes, expires_at, last_used_at, created_at FROM api_keys WHERE user_id = $1 ORDER BY created_at DESC"
+    )
+    .bind(auth.user_id)
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(keys))
+}
+
+pub async fn create_api_key(
+    auth: AuthContext,
+    State(state): State<Arc<AppState>>,
+    Valid(payload): Valid<CreateApiKeyRequest>,
+) -> Result<Json<CreateApiKeyResponse>, ApiError> {
+    require_session(&auth)?;
+
+    let days = payload.expires_in_days.unwrap_or(DEFAULT_EXPIRY_DAYS);
+
+    let key = generate_key();
+    let key_prefix = key[..API_KEY_PREFIX.len() + 8].to_string();
+
+    let api_key = sqlx::query_as::<_, ApiKey>(
+        "INSERT INTO api_keys (user_id, name, key_prefix, key_hash, scopes, expires_at) VALUES ($1, $2, $3, $4, $5, $6) \
+         RETURNING id, user_id, name, key_prefix, scopes, expires_at, last_used_at, created_at"
+    )
+    .bind(auth.user_id)
+    .bind(payload.name)
+    .bind(key_prefix)
+    .bind(hash_api_key(&key))
+    .bind(payload.scopes)
+    .bind(Utc::now() + Duration::days(days))
+    .fetch_one(&state.db)
+    .await?;
+
+    Ok(Json(CreateApiKeyResponse { key, api_key }))
+}
+
+pub async fn delete_api_key(
+    auth: AuthContext,
+    Path(id): Path<i32>,
+    State(state): State<Arc<AppState>>,
+) -> Result<StatusCode, ApiError> {
+    require_session(&auth)?;
+
+    let result = sqlx::query("DELETE FROM api_keys WHERE id = $1 AND user_id = $2")
+        .bind(id)
+        .bind(auth.user_id)
+        .execute(&state.db)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound("API key not found".to_string()));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
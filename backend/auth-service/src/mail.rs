@@ -0,0 +1,58 @@
+//! Outgoing email over SMTP.
+//!
+//! Without `SMTP_URL` nothing is sent and only the template and recipient
+//! are logged: bodies carry tokens that must not end up in the logs.
+
+use common::bootstrap::{fatal, MailConfig};
+use lettre::message::Mailbox;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use std::time::Duration;
+
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) struct Mailer {
+    transport: Option<AsyncSmtpTransport<Tokio1Executor>>,
+    from: Mailbox,
+}
+
+impl Mailer {
+    /// Exits if `SMTP_URL` or `MAIL_FROM` cannot be used.
+    pub fn from_config(config: &MailConfig) -> Self {
+        let transport = config.smtp_url.as_ref().map(|url| {
+            AsyncSmtpTransport::<Tokio1Executor>::from_url(url)
+                .unwrap_or_else(|e| fatal(format!("Invalid SMTP_URL: {}", e)))
+                .timeout(Some(SEND_TIMEOUT))
+                .build()
+        });
+        if transport.is_none() {
+            tracing::warn!("SMTP_URL is not set, emails will not be sent");
+        }
+        let from = config.from.parse().unwrap_or_else(|e| fatal(format!("Invalid MAIL_FROM: {}", e)));
+        Mailer { transport, from }
+    }
+
+    /// Sends a plain-text email and counts it under `template`. Failures are
+    /// logged rather than returned, as the request that caused the email has
+    /// already done its work.
+    pub async fn send(&self, template: &str, to: &str, subject: &str, body: String) {
+        let Some(transport) = &self.transport else {
+            tracing::warn!("Not sending {} email to {}: SMTP_URL is not set", template, to);
+            return;
+        };
+        let message = match to.parse::<Mailbox>() {
+            Ok(to) => Message::builder().from(self.from.clone()).to(to).subject(subject).body(body),
+            Err(e) => {
+                tracing::error!("Not sending {} email to {}: {}", template, to, e);
+                return;
+            }
+        };
+        let result = match message {
+            Ok(message) => transport.send(message).await.map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        match result {
+            Ok(_) => crate::EMAILS_SENT.inc(&[template]),
+            Err(e) => tracing::error!("Failed to send {} email to {}: {}", template, to, e),
+        }
+    }
+}
@@ -1,63 +1,100 @@
+mod account_data;
+mod api_keys;
 mod db;
+mod keys;
+mod mail;
 mod models;
+mod oidc;
+mod sso;
+mod sso_domains;
+mod throttle;
+
+#[cfg(test)]
+#[path = "../tests/support/mock_idp.rs"]
+mod mock_idp;
 
 use axum::{
-    routing::{post, get},
+    routing::{post, get, delete},
     Router,
     Json,
-    http::StatusCode,
-    extract::State,
+    http::{HeaderMap, StatusCode},
+    extract::{ConnectInfo, State},
 };
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
-use tower_http::cors::CorsLayer;
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
     Argon2,
 };
-use common::{create_jwt, AuthContext};
+use common::bootstrap;
+use common::{ApiError, AuthContext, AuthState, JwtVerifier, Valid, Validate, Validator};
+use common::metrics::Counter;
+use jsonwebtoken::jwk::JwkSet;
 use std::sync::Arc;
 use crate::db::DbPool;
 
-struct AppState {
+pub(crate) struct AppState {
     db: DbPool,
-    jwt_secret: Arc<String>,
+    signing_keys: keys::SigningKeys,
+    jwt_verifier: JwtVerifier,
+    http: reqwest::Client,
+    mailer: mail::Mailer,
+    trusted_proxies: Vec<bootstrap::IpNet>,
+    data_services: Vec<account_data::DataService>,
 }
 
-impl axum::extract::FromRef<Arc<AppState>> for Arc<String> {
-    fn from_ref(state: &Arc<AppState>) -> Self {
-        state.jwt_secret.clone()
+impl AuthState for AppState {
+    fn jwt_verifier(&self) -> &JwtVerifier {
+        &self.jwt_verifier
+    }
+
+    fn db(&self) -> Option<&DbPool> {
+        Some(&self.db)
     }
 }
 
+static USERS_REGISTERED: Counter = Counter::new("users_registered_total", "Accounts created, by sign-up method", &["method"]);
+static LOGINS: Counter = Counter::new("logins_total", "Password logins, by result", &["result"]);
+static EMAILS_SENT: Counter = Counter::new("emails_sent_total", "Emails sent, by template", &["template"]);
+
 #[tokio::main]
 async fn main() {
-    dotenvy::dotenv().ok();
-    tracing_subscriber::fmt::init();
-
-    let pool = db::init_pool().await;
-    let jwt_secret = Arc::new(std::env::var("JWT_SECRET").unwrap_or_else(|_| "supersecret".to_string()));
+    let config = bootstrap::init("auth-service", 5001);
+    let pool = config.connect_database().await;
+    let signing_keys = keys::SigningKeys::from_config(&config.jwt);
+    let jwt_verifier = JwtVerifier::from_jwks(&signing_keys.jwks()).unwrap_or_else(|e| bootstrap::fatal(format!("Invalid JWT signing keys: {}", e)));
     
     let state = Arc::new(AppState {
-        db: pool,
-        jwt_secret,
+        db: pool.clone(),
+        signing_keys,
+        jwt_verifier,
+        http: reqwest::Client::new(),
+        mailer: mail::Mailer::from_config(&config.mail),
+        trusted_proxies: config.trusted_proxies.clone(),
+        data_services: account_data::services(&config.service_urls),
     });
 
     let app = Router::new()
+        .route("/.well-known/jwks.json", get(jwks))
         .route("/api/auth/register", post(register))
         .route("/api/auth/login", post(login))
         .route("/api/auth/status", get(status))
         .route("/api/auth/change-password", post(change_password))
         .route("/api/auth/forgot-password", post(forgot_password))
         .route("/api/auth/reset-password", post(reset_password))
-        .layer(CorsLayer::permissive())
+        .route("/api/auth/unlock", post(unlock_account))
+        .route("/api/auth/account", delete(account_data::delete_account))
+        .route("/api/auth/account/export", get(account_data::export_account))
+        .route("/api/auth/sso/:organization/start", post(sso::start))
+        .route("/api/auth/sso/callback", post(sso::callback))
+        .route("/api/auth/sso/:organization/domains", get(sso_domains::list_domains).post(sso_domains::add_domain))
+        .route("/api/auth/sso/:organization/domains/:domain", delete(sso_domains::delete_domain))
+        .route("/api/auth/sso/:organization/domains/:domain/verify", post(sso_domains::verify_domain))
+        .route("/api/auth/api-keys", get(api_keys::list_api_keys).post(api_keys::create_api_key))
+        .route("/api/auth/api-keys/:id", delete(api_keys::delete_api_key))
         .with_state(state);
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 5001));
-    tracing::info!("Auth service listening on {}", addr);
-    
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    bootstrap::serve(config, app, Some(pool)).await;
 }
 
 #[derive(Deserialize)]
@@ -89,6 +126,53 @@ struct ResetPasswordRequest {
     new_password: String,
 }
 
+#[derive(Deserialize)]
+struct UnlockAccountRequest {
+    token: String,
+}
+
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+impl Validate for RegisterRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.email("email", self.email.as_str());
+        v.min_len("password", &self.password, MIN_PASSWORD_LENGTH);
+    }
+}
+
+impl Validate for LoginRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("email", &self.email);
+        v.required("password", &self.password);
+    }
+}
+
+impl Validate for ChangePasswordRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("current_password", &self.current_password);
+        v.min_len("new_password", &self.new_password, MIN_PASSWORD_LENGTH);
+    }
+}
+
+impl Validate for ForgotPasswordRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.email("email", self.email.as_str());
+    }
+}
+
+impl Validate for ResetPasswordRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("token", &self.token);
+        v.min_len("new_password", &self.new_password, MIN_PASSWORD_LENGTH);
+    }
+}
+
+impl Validate for UnlockAccountRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("token", &self.token);
+    }
+}
+
 #[derive(Serialize)]
 struct AuthResponse {
     token: String,
@@ -103,13 +187,13 @@ struct UserInfo {
 
 async fn register(
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<RegisterRequest>,
-) -> Result<Json<AuthResponse>, (StatusCode, String)> {
+    Valid(payload): Valid<RegisterRequest>,
+) -> Result<Json<AuthResponse>, ApiError> {
     let salt = SaltString::generate(&mut OsRng);
     let argon2 = Argon2::default();
     let password_hash = argon2
         .hash_password(payload.password.as_bytes(), &salt)
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(ApiError::internal)?
         .to_string();
 
     let user = sqlx::query_as::<_, models::User>(
@@ -119,10 +203,10 @@ async fn register(
     .bind(password_hash)
     .fetch_one(&state.db)
     .await
-    .map_err(|e| (StatusCode::CONFLICT, e.to_string()))?;
+    .map_err(|e| ApiError::from(e).on_conflict("An account with this email already exists"))?;
+    USERS_REGISTERED.inc(&["password"]);
 
-    let token = create_jwt(user.id, &state.jwt_secret)
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let token = state.signing_keys.sign(user.id).map_err(ApiError::internal)?;
 
     Ok(Json(AuthResponse {
         token,
@@ -135,26 +219,38 @@ async fn register(
 
 async fn login(
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<LoginRequest>,
-) -> Result<Json<AuthResponse>, (StatusCode, String)> {
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Valid(payload): Valid<LoginRequest>,
+) -> Result<Json<AuthResponse>, ApiError> {
+    let client = throttle::ClientInfo::from_request(peer, &headers, &state.trusted_proxies);
+    throttle::check(&state.db, &client, &payload.email).await?;
+
     let user = sqlx::query_as::<_, models::User>(
         "SELECT id, email, password_hash FROM users WHERE email = $1"
     )
     .bind(&payload.email)
     .fetch_optional(&state.db)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
-    .ok_or((StatusCode::UNAUTHORIZED, "Invalid credentials".to_string()))?;
+    .await?;
+
+    let user = match user {
+        Some(user) => user,
+        None => {
+            throttle::record_failure(&state.db, &state.mailer, &client, &payload.email, None).await?;
+            return Err(ApiError::Unauthorized("Invalid credentials".to_string()));
+        }
+    };
 
-    let parsed_hash = PasswordHash::new(&user.password_hash)
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let parsed_hash = PasswordHash::new(&user.password_hash).map_err(ApiError::internal)?;
     
-    Argon2::default()
-        .verify_password(payload.password.as_bytes(), &parsed_hash)
-        .map_err(|_| (StatusCode::UNAUTHORIZED, "Invalid credentials".to_string()))?;
+    if Argon2::default().verify_password(payload.password.as_bytes(), &parsed_hash).is_err() {
+        throttle::record_failure(&state.db, &state.mailer, &client, &payload.email, Some(user.id)).await?;
+        return Err(ApiError::Unauthorized("Invalid credentials".to_string()));
+    }
+
+    throttle::record_success(&state.db, &client, &payload.email, user.id).await?;
 
-    let token = create_jwt(user.id, &state.jwt_secret)
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let token = state.signing_keys.sign(user.id).map_err(ApiError::internal)?;
 
     Ok(Json(AuthResponse {
         token,
@@ -165,59 +261,69 @@ async fn login(
     }))
 }
 
+async fn unlock_account(
+    State(state): State<Arc<AppState>>,
+    Valid(payload): Valid<UnlockAccountRequest>,
+) -> Result<StatusCode, ApiError> {
+    throttle::unlock(&state.db, &payload.token).await?;
+    Ok(StatusCode::OK)
+}
+
 async fn change_password(
     auth: AuthContext,
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<ChangePasswordRequest>,
-) -> Result<StatusCode, (StatusCode, String)> {
+    Valid(payload): Valid<ChangePasswordRequest>,
+) -> Result<StatusCode, ApiError> {
     let user = sqlx::query_as::<_, models::User>(
         "SELECT id, email, password_hash FROM users WHERE id = $1"
     )
     .bind(auth.user_id)
     .fetch_one(&state.db)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    .await?;
 
-    let parsed_hash = PasswordHash::new(&user.password_hash)
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let parsed_hash = PasswordHash::new(&user.password_hash).map_err(ApiError::internal)?;
     
     Argon2::default()
         .verify_password(payload.current_password.as_bytes(), &parsed_hash)
-        .map_err(|_| (StatusCode::UNAUTHORIZED, "Current password incorrect".to_string()))?;
+        .map_err(|_| ApiError::Unauthorized("Current password incorrect".to_string()))?;
 
     let salt = SaltString::generate(&mut OsRng);
     let argon2 = Argon2::default();
     let new_password_hash = argon2
         .hash_password(payload.new_password.as_bytes(), &salt)
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(ApiError::internal)?
         .to_string();
 
     sqlx::query("UPDATE users SET password_hash = $1 WHERE id = $2")
         .bind(new_password_hash)
         .bind(auth.user_id)
         .execute(&state.db)
-        .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        .await?;
 
     Ok(StatusCode::OK)
 }
 
 async fn forgot_password(
-    Json(payload): Json<ForgotPasswordRequest>,
-) -> Result<StatusCode, (StatusCode, String)> {
+    Valid(payload): Valid<ForgotPasswordRequest>,
+) -> Result<StatusCode, ApiError> {
     // Simulate sending reset email
     tracing::info!("Simulating forgot-password email for {}", payload.email);
+    EMAILS_SENT.inc(&["password_reset"]);
     Ok(StatusCode::OK)
 }
 
 async fn reset_password(
-    Json(payload): Json<ResetPasswordRequest>,
-) -> Result<StatusCode, (StatusCode, String)> {
+    Valid(payload): Valid<ResetPasswordRequest>,
+) -> Result<StatusCode, ApiError> {
     // In a real app, verify the token. For now, simulate success.
     tracing::info!("Simulating password reset with token {}", payload.token);
     Ok(StatusCode::OK)
 }
 
+async fn jwks(State(state): State<Arc<AppState>>) -> Json<JwkSet> {
+    Json(state.signing_keys.jwks())
+}
+
 async fn status() -> &'static str {
     "Auth service is running"
 }
@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
+use chrono::{DateTime, Utc};
 
 #[derive(Debug, FromRow, Serialize, Deserialize)]
 pub struct User {
@@ -7,3 +8,15 @@ pub struct User {
     pub email: String,
     pub password_hash: String,
 }
+
+#[derive(Debug, FromRow, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    pub key_prefix: String,
+    pub scopes: Vec<String>,
+    pub expires_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: Option<DateTime<Utc>>,
+}
@@ -0,0 +1,178 @@
+//! Minimal OpenID Connect relying party: discovery, authorization-code + PKCE
+//! and ID token verification against the provider's JWKS.
+//!
+//! Kept free of service state so it can be exercised against a mock provider.
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+/// Signature algorithms accepted on ID tokens. Symmetric and `none` are never allowed.
+const ALLOWED_ALGORITHMS: &[Algorithm] = &[
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum OidcError {
+    #[error("provider request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("invalid provider metadata: {0}")]
+    InvalidMetadata(String),
+    #[error("token endpoint rejected the code: {0}")]
+    TokenExchange(String),
+    #[error("invalid ID token: {0}")]
+    InvalidIdToken(String),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenResponse {
+    pub id_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IdTokenClaims {
+    pub sub: String,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    pub nonce: Option<String>,
+}
+
+pub struct Pkce {
+    pub verifier: String,
+    pub challenge: String,
+}
+
+/// URL-safe random string with 256 bits of entropy, used for state, nonce
+/// and PKCE verifiers.
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+pub fn pkce_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+pub fn generate_pkce() -> Pkce {
+    let verifier = random_token();
+    let challenge = pkce_challenge(&verifier);
+    Pkce { verifier, challenge }
+}
+
+pub async fn discover(http: &reqwest::Client, issuer_url: &str) -> Result<ProviderMetadata, OidcError> {
+    let url = format!("{}/.well-known/openid-configuration", issuer_url.trim_end_matches('/'));
+    let metadata = http.get(url).send().await?.error_for_status()?.json().await?;
+    Ok(metadata)
+}
+
+pub fn authorization_url(
+    metadata: &ProviderMetadata,
+    client_id: &str,
+    redirect_uri: &str,
+    state: &str,
+    nonce: &str,
+    code_challenge: &str,
+) -> Result<String, OidcError> {
+    let url = reqwest::Url::parse_with_params(
+        &metadata.authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("scope", "openid email profile"),
+            ("client_id", client_id),
+            ("redirect_uri", redirect_uri),
+            ("state", state),
+            ("nonce", nonce),
+            ("code_challenge", code_challenge),
+            ("code_challenge_method", "S256"),
+        ],
+    )
+    .map_err(|e| OidcError::InvalidMetadata(format!("authorization endpoint: {}", e)))?;
+    Ok(url.to_string())
+}
+
+pub async fn exchange_code(
+    http: &reqwest::Client,
+    metadata: &ProviderMetadata,
+    client_id: &str,
+    client_secret: Option<&str>,
+    redirect_uri: &str,
+    code: &str,
+    code_verifier: &str,
+) -> Result<TokenResponse, OidcError> {
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", redirect_uri),
+        ("client_id", client_id),
+        ("code_verifier", code_verifier),
+    ];
+    if let Some(secret) = client_secret {
+        form.push(("client_secret", secret));
+    }
+
+    let response = http.post(&metadata.token_endpoint).form(&form).send().await?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        return Err(OidcError::TokenExchange(format!("{} {}", status, body)));
+    }
+    Ok(response.json().await?)
+}
+
+/// Checks signature (by `kid` in the provider's JWKS), issuer, audience,
+/// expiry and nonce.
+pub async fn verify_id_token(
+    http: &reqwest::Client,
+    metadata: &ProviderMetadata,
+    client_id: &str,
+    id_token: &str,
+    expected_nonce: &str,
+) -> Result<IdTokenClaims, OidcError> {
+    let header = decode_header(id_token).map_err(|e| OidcError::InvalidIdToken(e.to_string()))?;
+    if !ALLOWED_ALGORITHMS.contains(&header.alg) {
+        return Err(OidcError::InvalidIdToken(format!("algorithm {:?} is not allowed", header.alg)));
+    }
+
+    let jwks: JwkSet = http.get(&metadata.jwks_uri).send().await?.error_for_status()?.json().await?;
+    let jwk = match &header.kid {
+        Some(kid) => jwks.find(kid),
+        None if jwks.keys.len() == 1 => jwks.keys.first(),
+        None => None,
+    }
+    .ok_or_else(|| OidcError::InvalidIdToken("signing key not found in JWKS".to_string()))?;
+    let key = DecodingKey::from_jwk(jwk).map_err(|e| OidcError::InvalidIdToken(e.to_string()))?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[&metadata.issuer]);
+    validation.set_audience(&[client_id]);
+
+    let claims = decode::<IdTokenClaims>(id_token, &key, &validation)
+        .map_err(|e| OidcError::InvalidIdToken(e.to_string()))?
+        .claims;
+
+    if claims.nonce.as_deref() != Some(expected_nonce) {
+        return Err(OidcError::InvalidIdToken("nonce mismatch".to_string()));
+    }
+
+    Ok(claims)
+}
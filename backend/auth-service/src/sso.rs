@@ -0,0 +1,373 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHasher, SaltString},
+    Argon2,
+};
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use chrono::{Duration, Utc};
+use common::{ApiError, Valid, Validate, Validator};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::sync::Arc;
+
+use crate::db::DbPool;
+use crate::oidc::{self, IdTokenClaims, OidcError};
+use crate::sso_domains;
+use crate::{AppState, AuthResponse, UserInfo};
+
+/// How long a user has to finish signing in at the provider.
+const LOGIN_STATE_TTL_MINUTES: i64 = 10;
+
+#[derive(FromRow)]
+struct SsoConnection {
+    id: i32,
+    issuer_url: String,
+    client_id: String,
+    client_secret: Option<String>,
+    redirect_uri: String,
+    /// Without an owner no domain can be verified, so no one signs in.
+    owner_id: Option<i32>,
+    jit_provisioning: bool,
+}
+
+#[derive(FromRow)]
+struct LoginState {
+    connection_id: i32,
+    nonce: String,
+    code_verifier: String,
+}
+
+#[derive(Serialize)]
+pub struct SsoStartResponse {
+    authorization_url: String,
+}
+
+#[derive(Deserialize)]
+pub struct SsoCallbackRequest {
+    state: String,
+    code: String,
+}
+
+impl Validate for SsoCallbackRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("state", &self.state);
+        v.required("code", &self.code);
+    }
+}
+
+fn provider_error(e: OidcError) -> ApiError {
+    tracing::warn!("OIDC provider error: {}", e);
+    ApiError::BadGateway(e.to_string())
+}
+
+/// Starts an authorization-code + PKCE login for an organization's provider.
+/// The state, nonce and verifier live in Postgres so any replica can finish it.
+pub async fn start(
+    Path(organization): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<SsoStartResponse>, ApiError> {
+    let connection = sqlx::query_as::<_, SsoConnection>(
+        "SELECT id, issuer_url, client_id, client_secret, redirect_uri, owner_id, jit_provisioning FROM sso_connections WHERE organization = $1"
+    )
+    .bind(&organization)
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(ApiError::NotFound("SSO is not configured for this organization".to_string()))?;
+
+    let metadata = oidc::discover(&state.http, &connection.issuer_url).await.map_err(provider_error)?;
+
+    let login_state = oidc::random_token();
+    let nonce = oidc::random_token();
+    let pkce = oidc::generate_pkce();
+
+    sqlx::query("INSERT INTO sso_login_states (state, connection_id, nonce, code_verifier, expires_at) VALUES ($1, $2, $3, $4, $5)")
+        .bind(&login_state)
+        .bind(connection.id)
+        .bind(&nonce)
+        .bind(&pkce.verifier)
+        .bind(Utc::now() + Duration::minutes(LOGIN_STATE_TTL_MINUTES))
+        .execute(&state.db)
+        .await?;
+
+    let authorization_url = oidc::authorization_url(
+        &metadata,
+        &connection.client_id,
+        &connection.redirect_uri,
+        &login_state,
+        &nonce,
+        &pkce.challenge,
+    )
+    .map_err(provider_error)?;
+
+    Ok(Json(SsoStartResponse { authorization_url }))
+}
+
+/// Completes the login: exchanges the code, verifies the ID token, then
+/// resolves the Billio user (see [`resolve_user`]).
+pub async fn callback(
+    State(state): State<Arc<AppState>>,
+    Valid(payload): Valid<SsoCallbackRequest>,
+) -> Result<Json<AuthResponse>, ApiError> {
+    let login_state = sqlx::query_as::<_, LoginState>(
+        "DELETE FROM sso_login_states WHERE state = $1 AND expires_at > NOW() RETURNING connection_id, nonce, code_verifier"
+    )
+    .bind(&payload.state)
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(ApiError::BadRequest("Unknown or expired SSO state".to_string()))?;
+
+    let connection = sqlx::query_as::<_, SsoConnection>(
+        "SELECT id, issuer_url, client_id, client_secret, redirect_uri, owner_id, jit_provisioning FROM sso_connections WHERE id = $1"
+    )
+    .bind(login_state.connection_id)
+    .fetch_one(&state.db)
+    .await?;
+
+    let metadata = oidc::discover(&state.http, &connection.issuer_url).await.map_err(provider_error)?;
+    let tokens = oidc::exchange_code(
+        &state.http,
+        &metadata,
+        &connection.client_id,
+        connection.client_secret.as_deref(),
+        &connection.redirect_uri,
+        &payload.code,
+        &login_state.code_verifier,
+    )
+    .await
+    .map_err(provider_error)?;
+    let claims = oidc::verify_id_token(&state.http, &metadata, &connection.client_id, &tokens.id_token, &login_state.nonce)
+        .await
+        .map_err(|e| ApiError::Unauthorized(e.to_string()))?;
+
+    let (user_id, email) = resolve_user(&state.db, &connection, &claims).await?;
+
+    let token = state.signing_keys.sign(user_id).map_err(ApiError::internal)?;
+
+    Ok(Json(AuthResponse {
+        token,
+        user: UserInfo { id: user_id, email },
+    }))
+}
+
+/// The Billio user behind a verified ID token: the one linked to its subject,
+/// or else the account with its verified email, linked now or, with JIT
+/// provisioning, created. Either way the user's email must be at a domain the
+/// connection's owner has verified.
+async fn resolve_user(db: &DbPool, connection: &SsoConnection, claims: &IdTokenClaims) -> Result<(i32, String), ApiError> {
+    let linked: Option<(i32, String)> = sqlx::query_as(
+        "SELECT u.id, u.email FROM user_identities i JOIN users u ON u.id = i.user_id WHERE i.connection_id = $1 AND i.subject = $2"
+    )
+    .bind(connection.id)
+    .bind(&claims.sub)
+    .fetch_optional(db)
+    .await?;
+
+    if let Some((user_id, email)) = linked {
+        if !sso_domains::is_verified(db, connection.id, &email).await? {
+            return Err(ApiError::Forbidden("Email domain is not verified for this organization".to_string()));
+        }
+        return Ok((user_id, email));
+    }
+
+    let email = match (&claims.email, claims.email_verified) {
+        (Some(email), true) => email.clone(),
+        _ => return Err(ApiError::Forbidden("Identity provider did not return a verified email".to_string())),
+    };
+    if !sso_domains::is_verified(db, connection.id, &email).await? {
+        return Err(ApiError::Forbidden("Email domain is not verified for this organization".to_string()));
+    }
+    let user_id = find_or_provision_user(db, connection, &email).await?;
+
+    sqlx::query("INSERT INTO user_identities (user_id, connection_id, subject, email) VALUES ($1, $2, $3, $4)")
+        .bind(user_id)
+        .bind(connection.id)
+        .bind(&claims.sub)
+        .bind(&email)
+        .execute(db)
+        .await?;
+
+    Ok((user_id, email))
+}
+
+async fn find_or_provision_user(db: &DbPool, connection: &SsoConnection, email: &str) -> Result<i32, ApiError> {
+    // An account belongs to another owner if it owns a connection itself or
+    // signs in through one of another owner's connections.
+    let existing: Option<(i32, bool)> = sqlx::query_as(
+        "SELECT u.id, \
+         EXISTS (SELECT 1 FROM sso_connections c WHERE c.owner_id = u.id AND u.id IS DISTINCT FROM $2) \
+         OR EXISTS (SELECT 1 FROM user_identities i JOIN sso_connections c ON c.id = i.connection_id \
+                    WHERE i.user_id = u.id AND c.owner_id IS DISTINCT FROM $2) \
+         FROM users u WHERE lower(u.email) = lower($1)"
+    )
+    .bind(email)
+    .bind(connection.owner_id)
+    .fetch_optional(db)
+    .await?;
+
+    match existing {
+        Some((_, true)) => return Err(ApiError::Forbidden("This email's Billio account belongs to another organization".to_string())),
+        Some((id, false)) => return Ok(id),
+        None => {}
+    }
+    if !connection.jit_provisioning {
+        return Err(ApiError::Forbidden("No Billio account exists for this email".to_string()));
+    }
+
+    // SSO users get a random password; they can set one later through password reset.
+    let salt = SaltString::generate(&mut OsRng);
+    let password_hash = Argon2::default()
+        .hash_password(oidc::random_token().as_bytes(), &salt)
+        .map_err(ApiError::internal)?
+        .to_string();
+
+    let id: i32 = sqlx::query_scalar("INSERT INTO users (email, password_hash) VALUES ($1, $2) RETURNING id")
+        .bind(email)
+        .bind(password_hash)
+        .fetch_one(db)
+        .await?;
+
+    tracing::info!("Provisioned user {} via SSO connection {}", email, connection.id);
+    crate::USERS_REGISTERED.inc(&["sso"]);
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_idp;
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+
+    async fn create_user(db: &PgPool, email: &str) -> i32 {
+        sqlx::query_scalar("INSERT INTO users (email, password_hash) VALUES ($1, 'x') RETURNING id")
+            .bind(email)
+            .fetch_one(db)
+            .await
+            .unwrap()
+    }
+
+    /// A connection owned by a new user, with `example.com` verified.
+    async fn create_connection(db: &PgPool, organization: &str, issuer: &str, jit_provisioning: bool) -> SsoConnection {
+        let owner_id = create_user(db, &format!("owner@{}.test", organization)).await;
+        let connection = sqlx::query_as::<_, SsoConnection>(
+            "INSERT INTO sso_connections (organization, issuer_url, client_id, redirect_uri, owner_id, jit_provisioning) \
+             VALUES ($1, $2, $3, $4, $5, $6) RETURNING id, issuer_url, client_id, client_secret, redirect_uri, owner_id, jit_provisioning"
+        )
+        .bind(organization)
+        .bind(issuer)
+        .bind(mock_idp::CLIENT_ID)
+        .bind(mock_idp::REDIRECT_URI)
+        .bind(owner_id)
+        .bind(jit_provisioning)
+        .fetch_one(db)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO sso_domains (connection_id, domain, verification_token, verified_at) VALUES ($1, 'example.com', 't', NOW())")
+            .bind(connection.id)
+            .execute(db)
+            .await
+            .unwrap();
+        connection
+    }
+
+    /// Signs in at the mock provider, whose ID token is for `ada@example.com`
+    /// unless `overrides` says otherwise, and resolves the Billio user.
+    async fn sign_in(db: &PgPool, jit_provisioning: bool, overrides: Value) -> Result<(i32, String), ApiError> {
+        let provider = mock_idp::spawn_provider(overrides, "mock-key-1").await;
+        let connection = create_connection(db, "acme", &provider.issuer, jit_provisioning).await;
+        let claims = mock_idp::login(&provider, None).await.unwrap();
+        resolve_user(db, &connection, &claims).await
+    }
+
+    async fn users_with_email(db: &PgPool, email: &str) -> i64 {
+        sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE lower(email) = lower($1)")
+            .bind(email)
+            .fetch_one(db)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test(migrator = "common::MIGRATOR")]
+    async fn provisions_a_new_account_at_a_verified_domain(db: PgPool) {
+        let (user_id, email) = sign_in(&db, true, json!({})).await.unwrap();
+        assert_eq!(email, "ada@example.com");
+
+        let subject: String = sqlx::query_scalar("SELECT subject FROM user_identities WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(subject, "mock-user-1");
+    }
+
+    #[sqlx::test(migrator = "common::MIGRATOR")]
+    async fn refuses_a_new_account_without_jit_provisioning(db: PgPool) {
+        let result = sign_in(&db, false, json!({})).await;
+        assert!(matches!(result, Err(ApiError::Forbidden(_))));
+        assert_eq!(users_with_email(&db, "ada@example.com").await, 0);
+    }
+
+    #[sqlx::test(migrator = "common::MIGRATOR")]
+    async fn refuses_emails_at_other_domains(db: PgPool) {
+        let result = sign_in(&db, true, json!({ "email": "mallory@elsewhere.com" })).await;
+        assert!(matches!(result, Err(ApiError::Forbidden(_))));
+        assert_eq!(users_with_email(&db, "mallory@elsewhere.com").await, 0);
+    }
+
+    #[sqlx::test(migrator = "common::MIGRATOR")]
+    async fn refuses_domains_not_yet_verified(db: PgPool) {
+        let provider = mock_idp::spawn_provider(json!({}), "mock-key-1").await;
+        let connection = create_connection(&db, "acme", &provider.issuer, true).await;
+        sqlx::query("UPDATE sso_domains SET verified_at = NULL").execute(&db).await.unwrap();
+
+        let claims = mock_idp::login(&provider, None).await.unwrap();
+        assert!(matches!(resolve_user(&db, &connection, &claims).await, Err(ApiError::Forbidden(_))));
+    }
+
+    #[sqlx::test(migrator = "common::MIGRATOR")]
+    async fn links_an_existing_account_at_a_verified_domain(db: PgPool) {
+        let existing = create_user(&db, "Ada@Example.com").await;
+
+        let (user_id, _) = sign_in(&db, false, json!({})).await.unwrap();
+        assert_eq!(user_id, existing);
+        assert_eq!(users_with_email(&db, "ada@example.com").await, 1);
+    }
+
+    #[sqlx::test(migrator = "common::MIGRATOR")]
+    async fn refuses_to_link_an_account_of_another_owner(db: PgPool) {
+        let provider = mock_idp::spawn_provider(json!({}), "mock-key-1").await;
+        let other = create_connection(&db, "globex", &provider.issuer, false).await;
+        let existing = create_user(&db, "ada@example.com").await;
+        sqlx::query("INSERT INTO user_identities (user_id, connection_id, subject, email) VALUES ($1, $2, 'globex-ada', 'ada@example.com')")
+            .bind(existing)
+            .bind(other.id)
+            .execute(&db)
+            .await
+            .unwrap();
+        // example.com moves to acme's connection
+        sqlx::query("DELETE FROM sso_domains").execute(&db).await.unwrap();
+
+        let result = sign_in(&db, true, json!({})).await;
+        assert!(matches!(result, Err(ApiError::Forbidden(_))));
+        let links: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM user_identities WHERE user_id = $1")
+            .bind(existing)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(links, 1);
+    }
+
+    #[sqlx::test(migrator = "common::MIGRATOR")]
+    async fn signs_a_linked_identity_back_in(db: PgPool) {
+        let provider = mock_idp::spawn_provider(json!({}), "mock-key-1").await;
+        let connection = create_connection(&db, "acme", &provider.issuer, true).await;
+
+        let claims = mock_idp::login(&provider, None).await.unwrap();
+        let (first, _) = resolve_user(&db, &connection, &claims).await.unwrap();
+        let claims = mock_idp::login(&provider, None).await.unwrap();
+        let (again, _) = resolve_user(&db, &connection, &claims).await.unwrap();
+        assert_eq!(first, again);
+    }
+}
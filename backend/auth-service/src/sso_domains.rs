@@ -0,0 +1,254 @@
+//! Email domains an SSO connection signs users in for.
+//!
+//! A connection only signs in emails at domains its owner has proven to
+//! control, by publishing the domain's token as a DNS TXT record at
+//! `_billio-verification.<domain>`. A domain is verified for one connection
+//! at a time, and only the owner manages a connection's domains.
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use common::{ApiError, AuthContext, Valid, Validate, Validator};
+use hickory_resolver::error::ResolveErrorKind;
+use hickory_resolver::TokioAsyncResolver;
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::sync::Arc;
+
+use crate::api_keys::require_session;
+use crate::db::DbPool;
+use crate::AppState;
+
+const RECORD_PREFIX: &str = "_billio-verification";
+const TOKEN_PREFIX: &str = "billio-verification=";
+
+#[derive(FromRow)]
+struct DomainRow {
+    domain: String,
+    verification_token: String,
+    verified_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct DnsRecord {
+    name: String,
+    value: String,
+}
+
+#[derive(Serialize)]
+pub struct SsoDomain {
+    domain: String,
+    verified_at: Option<DateTime<Utc>>,
+    /// The TXT record that proves the domain.
+    dns_record: DnsRecord,
+}
+
+impl From<DomainRow> for SsoDomain {
+    fn from(row: DomainRow) -> Self {
+        SsoDomain {
+            dns_record: DnsRecord {
+                name: format!("{}.{}", RECORD_PREFIX, row.domain),
+                value: format!("{}{}", TOKEN_PREFIX, row.verification_token),
+            },
+            domain: row.domain,
+            verified_at: row.verified_at,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AddDomainRequest {
+    domain: String,
+}
+
+impl Validate for AddDomainRequest {
+    fn validate(&self, v: &mut Validator) {
+        if !is_domain(&self.domain) {
+            v.error("domain", "must be a domain name like example.com");
+        }
+    }
+}
+
+fn is_domain(domain: &str) -> bool {
+    let labels: Vec<&str> = domain.split('.').collect();
+    domain.len() <= 253
+        && labels.len() >= 2
+        && labels.iter().all(|label| {
+            (1..=63).contains(&label.len())
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+pub(crate) fn email_domain(email: &str) -> String {
+    email.rsplit_once('@').map(|(_, domain)| domain.trim().to_lowercase()).unwrap_or_default()
+}
+
+/// Whether `email` is at a domain verified for the connection.
+pub(crate) async fn is_verified(db: &DbPool, connection_id: i32, email: &str) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM sso_domains WHERE connection_id = $1 AND domain = $2 AND verified_at IS NOT NULL)")
+        .bind(connection_id)
+        .bind(email_domain(email))
+        .fetch_one(db)
+        .await
+}
+
+/// The organization's connection, if `auth` is its owner.
+async fn owned_connection(db: &DbPool, auth: &AuthContext, organization: &str) -> Result<i32, ApiError> {
+    require_session(auth)?;
+    sqlx::query_scalar("SELECT id FROM sso_connections WHERE organization = $1 AND owner_id = $2")
+        .bind(organization)
+        .bind(auth.user_id)
+        .fetch_optional(db)
+        .await?
+        .ok_or(ApiError::NotFound("SSO is not configured for this organization".to_string()))
+}
+
+async fn find_domain(db: &DbPool, connection_id: i32, domain: &str) -> Result<DomainRow, ApiError> {
+    sqlx::query_as::<_, DomainRow>(
+        "SELECT domain, verification_token, verified_at FROM sso_domains WHERE connection_id = $1 AND domain = $2"
+    )
+    .bind(connection_id)
+    .bind(domain.to_lowercase())
+    .fetch_optional(db)
+    .await?
+    .ok_or(ApiError::NotFound("Domain not found".to_string()))
+}
+
+pub async fn list_domains(
+    auth: AuthContext,
+    Path(organization): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<SsoDomain>>, ApiError> {
+    let connection_id = owned_connection(&state.db, &auth, &organization).await?;
+
+    let domains = sqlx::query_as::<_, DomainRow>(
+        "SELECT domain, verification_token, verified_at FROM sso_domains WHERE connection_id = $1 ORDER BY domain"
+    )
+    .bind(connection_id)
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(domains.into_iter().map(SsoDomain::from).collect()))
+}
+
+/// Adds an unverified domain and returns the TXT record that verifies it.
+pub async fn add_domain(
+    auth: AuthContext,
+    Path(organization): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Valid(payload): Valid<AddDomainRequest>,
+) -> Result<(StatusCode, Json<SsoDomain>), ApiError> {
+    let connection_id = owned_connection(&state.db, &auth, &organization).await?;
+
+    let domain = sqlx::query_as::<_, DomainRow>(
+        "INSERT INTO sso_domains (connection_id, domain, verification_token) VALUES ($1, $2, $3) \
+         RETURNING domain, verification_token, verified_at"
+    )
+    .bind(connection_id)
+    .bind(payload.domain.to_lowercase())
+    .bind(crate::oidc::random_token())
+    .fetch_one(&state.db)
+    .await?;
+
+    Ok((StatusCode::CREATED, Json(domain.into())))
+}
+
+/// Looks up the domain's TXT record and marks the domain verified if it
+/// carries the token.
+pub async fn verify_domain(
+    auth: AuthContext,
+    Path((organization, domain)): Path<(String, String)>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<SsoDomain>, ApiError> {
+    let connection_id = owned_connection(&state.db, &auth, &organization).await?;
+    let row = find_domain(&state.db, connection_id, &domain).await?;
+    let record: SsoDomain = row.into();
+
+    let values = txt_records(&record.dns_record.name).await?;
+    if !values.iter().any(|value| value.trim() == record.dns_record.value) {
+        return Err(ApiError::Unprocessable(format!(
+            "No TXT record {} with value {} was found",
+            record.dns_record.name, record.dns_record.value
+        )));
+    }
+
+    let taken: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM sso_domains WHERE domain = $1 AND connection_id <> $2 AND verified_at IS NOT NULL)"
+    )
+    .bind(&record.domain)
+    .bind(connection_id)
+    .fetch_one(&state.db)
+    .await?;
+    if taken {
+        return Err(ApiError::Conflict(format!("{} is already verified for another organization", record.domain)));
+    }
+
+    let row = sqlx::query_as::<_, DomainRow>(
+        "UPDATE sso_domains SET verified_at = COALESCE(verified_at, NOW()) WHERE connection_id = $1 AND domain = $2 \
+         RETURNING domain, verification_token, verified_at"
+    )
+    .bind(connection_id)
+    .bind(&record.domain)
+    .fetch_one(&state.db)
+    .await?;
+
+    Ok(Json(row.into()))
+}
+
+pub async fn delete_domain(
+    auth: AuthContext,
+    Path((organization, domain)): Path<(String, String)>,
+    State(state): State<Arc<AppState>>,
+) -> Result<StatusCode, ApiError> {
+    let connection_id = owned_connection(&state.db, &auth, &organization).await?;
+
+    let result = sqlx::query("DELETE FROM sso_domains WHERE connection_id = $1 AND domain = $2")
+        .bind(connection_id)
+        .bind(domain.to_lowercase())
+        .execute(&state.db)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound("Domain not found".to_string()));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn txt_records(name: &str) -> Result<Vec<String>, ApiError> {
+    let resolver = TokioAsyncResolver::tokio_from_system_conf().map_err(ApiError::internal)?;
+    match resolver.txt_lookup(name).await {
+        Ok(lookup) => Ok(lookup.iter().map(|txt| txt.to_string()).collect()),
+        Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => Ok(Vec::new()),
+        Err(e) => {
+            tracing::warn!("TXT lookup for {} failed: {}", name, e);
+            Err(ApiError::BadGateway(format!("Could not look up {}", name)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_domain_names_only() {
+        assert!(is_domain("example.com"));
+        assert!(is_domain("mail.example-corp.co.uk"));
+        assert!(!is_domain("localhost"));
+        assert!(!is_domain("example..com"));
+        assert!(!is_domain("-example.com"));
+        assert!(!is_domain("ex ample.com"));
+        assert!(!is_domain("example.com/path"));
+    }
+
+    #[test]
+    fn email_domains_ignore_case() {
+        assert_eq!(email_domain("Ada@Example.COM"), "example.com");
+        assert_eq!(email_domain("no-domain"), "");
+    }
+}
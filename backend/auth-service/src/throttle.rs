@@ -0,0 +1,307 @@
+//! Login throttling shared by every auth-service replica through Postgres.
+//!
+//! Failures are counted per IP address and per account. Past a free allowance
+//! each further failure doubles the wait before the next attempt; an account
+//! that keeps failing is locked outright and its owner gets an unlock email.
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use axum::http::HeaderMap;
+use chrono::{DateTime, Duration, Utc};
+use common::bootstrap::IpNet;
+use common::ApiError;
+use sha2::{Digest, Sha256};
+use std::net::{IpAddr, SocketAddr};
+
+use crate::db::DbPool;
+use crate::mail::Mailer;
+
+/// Failures older than this no longer count towards backoff.
+const FAILURE_WINDOW_MINUTES: i64 = 60;
+const UNLOCK_TOKEN_TTL_HOURS: i64 = 24;
+
+struct Policy {
+    free_attempts: i32,
+    max_backoff_secs: i64,
+    /// Failure count at which the key is locked for `lockout_secs`.
+    lockout_after: Option<i32>,
+    lockout_secs: i64,
+}
+
+const ACCOUNT_POLICY: Policy = Policy {
+    free_attempts: 3,
+    max_backoff_secs: 15 * 60,
+    lockout_after: Some(10),
+    lockout_secs: 60 * 60,
+};
+
+const IP_POLICY: Policy = Policy {
+    free_attempts: 10,
+    max_backoff_secs: 15 * 60,
+    lockout_after: None,
+    lockout_secs: 0,
+};
+
+impl Policy {
+    fn delay_secs(&self, failures: i32) -> i64 {
+        if let Some(limit) = self.lockout_after {
+            if failures >= limit {
+                return self.lockout_secs;
+            }
+        }
+        if failures < self.free_attempts {
+            return 0;
+        }
+        let exponent = (failures - self.free_attempts).min(30) as u32;
+        2i64.saturating_pow(exponent).min(self.max_backoff_secs)
+    }
+}
+
+pub struct ClientInfo {
+    pub ip: String,
+    pub user_agent: Option<String>,
+}
+
+impl ClientInfo {
+    /// The client is the nearest address in `X-Forwarded-For` that is not one
+    /// of the `trusted` proxies, read from the right since each proxy appends
+    /// its peer. The header is ignored unless the peer itself is trusted, so
+    /// callers can't pick their own IP.
+    pub fn from_request(peer: SocketAddr, headers: &HeaderMap, trusted: &[IpNet]) -> Self {
+        let is_trusted = |ip: IpAddr| trusted.iter().any(|net| net.contains(ip));
+        let mut ip = peer.ip().to_canonical();
+        if is_trusted(ip) {
+            let forwarded = headers
+                .get_all("X-Forwarded-For")
+                .iter()
+                .filter_map(|h| h.to_str().ok())
+                .flat_map(|h| h.split(','))
+                .collect::<Vec<_>>();
+            for hop in forwarded.iter().rev() {
+                let Ok(hop) = hop.trim().parse::<IpAddr>() else { break };
+                ip = hop.to_canonical();
+                if !is_trusted(ip) {
+                    break;
+                }
+            }
+        }
+
+        ClientInfo {
+            ip: ip.to_string(),
+            user_agent: headers
+                .get("User-Agent")
+                .and_then(|h| h.to_str().ok())
+                .map(|s| s.to_string()),
+        }
+    }
+}
+
+fn ip_key(ip: &str) -> String {
+    format!("ip:{}", ip)
+}
+
+pub(crate) fn account_key(email: &str) -> String {
+    format!("account:{}", email.trim().to_lowercase())
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Rejects the attempt with 429 while either the IP or the account is backing off.
+pub async fn check(db: &DbPool, client: &ClientInfo, email: &str) -> Result<(), ApiError> {
+    let locked_until: Option<DateTime<Utc>> = sqlx::query_scalar(
+        "SELECT MAX(locked_until) FROM login_throttles WHERE throttle_key = ANY($1) AND locked_until > NOW()"
+    )
+    .bind(vec![ip_key(&client.ip), account_key(email)])
+    .fetch_one(db)
+    .await?;
+
+    match locked_until {
+        Some(until) => {
+            let wait = (until - Utc::now()).num_seconds().max(1);
+            Err(ApiError::TooManyRequests(format!("Too many failed login attempts, try again in {} seconds", wait)))
+        }
+        None => Ok(()),
+    }
+}
+
+async fn bump(db: &DbPool, key: &str, policy: &Policy) -> Result<i32, sqlx::Error> {
+    let failures: i32 = sqlx::query_scalar(
+        "INSERT INTO login_throttles (throttle_key, failures, last_failure_at) VALUES ($1, 1, NOW()) \
+         ON CONFLICT (throttle_key) DO UPDATE SET \
+         failures = CASE WHEN login_throttles.last_failure_at < NOW() - make_interval(mins => $2) THEN 1 ELSE login_throttles.failures + 1 END, \
+         last_failure_at = NOW() \
+         RETURNING failures"
+    )
+    .bind(key)
+    .bind(FAILURE_WINDOW_MINUTES as i32)
+    .fetch_one(db)
+    .await?;
+
+    let delay = policy.delay_secs(failures);
+    if delay > 0 {
+        sqlx::query("UPDATE login_throttles SET locked_until = NOW() + make_interval(secs => $2) WHERE throttle_key = $1")
+            .bind(key)
+            .bind(delay as f64)
+            .execute(db)
+            .await?;
+    }
+
+    Ok(failures)
+}
+
+async fn log_attempt(db: &DbPool, client: &ClientInfo, email: &str, user_id: Option<i32>, success: bool) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO login_attempts (email, user_id, ip_address, user_agent, success) VALUES ($1, $2, $3, $4, $5)")
+        .bind(email)
+        .bind(user_id)
+        .bind(&client.ip)
+        .bind(&client.user_agent)
+        .bind(success)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+pub async fn record_failure(db: &DbPool, mailer: &Mailer, client: &ClientInfo, email: &str, user_id: Option<i32>) -> Result<(), ApiError> {
+    log_attempt(db, client, email, user_id, false).await?;
+    tracing::warn!("Failed login for {} from {} ({:?})", email, client.ip, client.user_agent);
+    crate::LOGINS.inc(&["failure"]);
+
+    bump(db, &ip_key(&client.ip), &IP_POLICY).await?;
+    let failures = bump(db, &account_key(email), &ACCOUNT_POLICY).await?;
+
+    if let (Some(user_id), Some(limit)) = (user_id, ACCOUNT_POLICY.lockout_after) {
+        if failures == limit {
+            send_unlock_email(db, mailer, user_id, email).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A successful login clears the account's counter. The IP counter is left
+/// alone so one valid account can't be used to reset guessing against others.
+pub async fn record_success(db: &DbPool, client: &ClientInfo, email: &str, user_id: i32) -> Result<(), ApiError> {
+    log_attempt(db, client, email, Some(user_id), true).await?;
+    tracing::info!("Successful login for {} from {}", email, client.ip);
+    crate::LOGINS.inc(&["success"]);
+
+    sqlx::query("DELETE FROM login_throttles WHERE throttle_key = $1")
+        .bind(account_key(email))
+        .execute(db)
+        .await?;
+
+    Ok(())
+}
+
+async fn send_unlock_email(db: &DbPool, mailer: &Mailer, user_id: i32, email: &str) -> Result<(), sqlx::Error> {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let token = hex::encode(bytes);
+
+    sqlx::query("INSERT INTO account_unlock_tokens (user_id, token_hash, expires_at) VALUES ($1, $2, $3)")
+        .bind(user_id)
+        .bind(hash_token(&token))
+        .bind(Utc::now() + Duration::hours(UNLOCK_TOKEN_TTL_HOURS))
+        .execute(db)
+        .await?;
+
+    let body = format!(
+        "Your Billio account was locked after {} failed sign-in attempts.\n\n\
+         To unlock it, send this token to POST /api/auth/unlock within {} hours:\n\n{}\n\n\
+         If these attempts were not yours, change your password once you are back in.\n",
+        ACCOUNT_POLICY.lockout_after.unwrap_or_default(),
+        UNLOCK_TOKEN_TTL_HOURS,
+        token
+    );
+    mailer.send("account_unlock", email, "Your Billio account is locked", body).await;
+    Ok(())
+}
+
+/// Consumes an unlock token and lifts the lockout on its account.
+pub async fn unlock(db: &DbPool, token: &str) -> Result<(), ApiError> {
+    let email: String = sqlx::query_scalar(
+        "UPDATE account_unlock_tokens t SET used_at = NOW() FROM users u \
+         WHERE t.user_id = u.id AND t.token_hash = $1 AND t.used_at IS NULL AND t.expires_at > NOW() \
+         RETURNING u.email"
+    )
+    .bind(hash_token(token))
+    .fetch_optional(db)
+    .await?
+    .ok_or(ApiError::BadRequest("Invalid or expired unlock token".to_string()))?;
+
+    sqlx::query("DELETE FROM login_throttles WHERE throttle_key = $1")
+        .bind(account_key(&email))
+        .execute(db)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn free_attempts_have_no_delay() {
+        for failures in 0..ACCOUNT_POLICY.free_attempts {
+            assert_eq!(ACCOUNT_POLICY.delay_secs(failures), 0);
+        }
+        for failures in 0..IP_POLICY.free_attempts {
+            assert_eq!(IP_POLICY.delay_secs(failures), 0);
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_with_each_further_failure_up_to_the_cap() {
+        let delays: Vec<i64> = (3..10).map(|failures| ACCOUNT_POLICY.delay_secs(failures)).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 64]);
+        assert_eq!(IP_POLICY.delay_secs(10), 1);
+        assert_eq!(IP_POLICY.delay_secs(20), 15 * 60);
+        assert_eq!(IP_POLICY.delay_secs(i32::MAX), 15 * 60);
+    }
+
+    #[test]
+    fn locks_the_account_from_the_threshold_on() {
+        assert_eq!(ACCOUNT_POLICY.delay_secs(9), 64);
+        assert_eq!(ACCOUNT_POLICY.delay_secs(10), 60 * 60);
+        assert_eq!(ACCOUNT_POLICY.delay_secs(25), 60 * 60);
+    }
+
+    #[test]
+    fn ip_addresses_are_never_locked_outright() {
+        assert!((0..1000).all(|failures| IP_POLICY.delay_secs(failures) <= IP_POLICY.max_backoff_secs));
+    }
+
+    #[test]
+    fn account_keys_ignore_case_and_surrounding_space() {
+        assert_eq!(account_key(" Ann@Example.com "), account_key("ann@example.com"));
+    }
+
+    fn client_ip(peer: &str, forwarded: Option<&str>, trusted: &[&str]) -> String {
+        let mut headers = HeaderMap::new();
+        if let Some(forwarded) = forwarded {
+            headers.insert("X-Forwarded-For", forwarded.parse().unwrap());
+        }
+        let trusted: Vec<IpNet> = trusted.iter().map(|net| net.parse().unwrap()).collect();
+        ClientInfo::from_request(peer.parse().unwrap(), &headers, &trusted).ip
+    }
+
+    #[test]
+    fn ignores_forwarded_for_from_untrusted_peers() {
+        assert_eq!(client_ip("10.1.2.3:4000", Some("203.0.113.9"), &[]), "10.1.2.3");
+        assert_eq!(client_ip("10.1.2.3:4000", Some("203.0.113.9"), &["10.0.0.5"]), "10.1.2.3");
+        assert_eq!(client_ip("127.0.0.1:4000", Some("203.0.113.9"), &["10.0.0.0/8"]), "127.0.0.1");
+    }
+
+    #[test]
+    fn takes_the_nearest_untrusted_hop_behind_the_gateway() {
+        let gateway = ["10.0.0.0/8"];
+        assert_eq!(client_ip("10.0.0.5:4000", Some("203.0.113.9"), &gateway), "203.0.113.9");
+        // The client sent its own header, which the gateway appended to
+        assert_eq!(client_ip("10.0.0.5:4000", Some("1.2.3.4, 203.0.113.9"), &gateway), "203.0.113.9");
+        assert_eq!(client_ip("10.0.0.5:4000", Some("203.0.113.9, 10.0.0.8"), &gateway), "203.0.113.9");
+        assert_eq!(client_ip("10.0.0.5:4000", None, &gateway), "10.0.0.5");
+        assert_eq!(client_ip("[::ffff:10.0.0.5]:4000", Some("2001:db8::1"), &gateway), "2001:db8::1");
+    }
+}
@@ -0,0 +1,64 @@
+//! Runs the OIDC relying-party flow against a mock provider served on localhost.
+
+#[path = "../src/oidc.rs"]
+mod oidc;
+
+#[path = "support/mock_idp.rs"]
+mod mock_idp;
+
+use mock_idp::{authorize, login, spawn_provider, CLIENT_ID, REDIRECT_URI};
+use serde_json::json;
+
+#[tokio::test]
+async fn completes_code_flow_with_pkce() {
+    let provider = spawn_provider(json!({}), "mock-key-1").await;
+
+    let claims = login(&provider, None).await.unwrap();
+
+    assert_eq!(claims.sub, "mock-user-1");
+    assert_eq!(claims.email.as_deref(), Some("ada@example.com"));
+    assert!(claims.email_verified);
+}
+
+#[tokio::test]
+async fn rejects_wrong_pkce_verifier() {
+    let provider = spawn_provider(json!({}), "mock-key-1").await;
+    let http = reqwest::Client::new();
+    let metadata = oidc::discover(&http, &provider.issuer).await.unwrap();
+
+    let pkce = oidc::generate_pkce();
+    let url = oidc::authorization_url(&metadata, CLIENT_ID, REDIRECT_URI, "state", "nonce", &pkce.challenge).unwrap();
+    let code = authorize(&provider, &url);
+
+    let result = oidc::exchange_code(&http, &metadata, CLIENT_ID, None, REDIRECT_URI, &code, "not-the-verifier").await;
+    assert!(matches!(result, Err(oidc::OidcError::TokenExchange(_))));
+}
+
+#[tokio::test]
+async fn rejects_nonce_mismatch() {
+    let provider = spawn_provider(json!({}), "mock-key-1").await;
+
+    let result = login(&provider, Some("replayed-nonce")).await;
+    assert!(matches!(result, Err(oidc::OidcError::InvalidIdToken(_))));
+}
+
+#[tokio::test]
+async fn rejects_wrong_audience_and_issuer() {
+    let provider = spawn_provider(json!({ "aud": "someone-else" }), "mock-key-1").await;
+    assert!(matches!(login(&provider, None).await, Err(oidc::OidcError::InvalidIdToken(_))));
+
+    let provider = spawn_provider(json!({ "iss": "https://evil.example.com" }), "mock-key-1").await;
+    assert!(matches!(login(&provider, None).await, Err(oidc::OidcError::InvalidIdToken(_))));
+}
+
+#[tokio::test]
+async fn rejects_expired_token() {
+    let provider = spawn_provider(json!({ "exp": chrono::Utc::now().timestamp() - 3600 }), "mock-key-1").await;
+    assert!(matches!(login(&provider, None).await, Err(oidc::OidcError::InvalidIdToken(_))));
+}
+
+#[tokio::test]
+async fn rejects_unknown_signing_key() {
+    let provider = spawn_provider(json!({}), "rotated-away").await;
+    assert!(matches!(login(&provider, None).await, Err(oidc::OidcError::InvalidIdToken(_))));
+}
@@ -0,0 +1,124 @@
+//! A mock OpenID Connect provider served on localhost, shared by the OIDC
+//! tests and sso.rs's tests. Expects the includer to have `crate::oidc`.
+
+#![allow(dead_code)]
+
+use axum::{extract::State, http::StatusCode, routing::{get, post}, Form, Json, Router};
+use jsonwebtoken::{encode, EncodingKey, Header};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+pub const CLIENT_ID: &str = "billio-test";
+pub const REDIRECT_URI: &str = "http://localhost:5173/sso/callback";
+const SIGNING_KEY: &[u8] = include_bytes!("../fixtures/mock_idp_key.pem");
+const JWKS: &str = include_str!("../fixtures/mock_idp_jwks.json");
+
+/// What the mock provider remembers about one authorization request.
+pub struct PendingCode {
+    pub challenge: String,
+    pub nonce: String,
+}
+
+pub struct MockProvider {
+    pub issuer: String,
+    pub codes: Mutex<HashMap<String, PendingCode>>,
+    /// Claims merged over the defaults of every issued ID token.
+    overrides: Value,
+    kid: &'static str,
+}
+
+#[derive(Deserialize)]
+struct TokenForm {
+    code: String,
+    code_verifier: String,
+    client_id: String,
+}
+
+async fn discovery(State(p): State<Arc<MockProvider>>) -> Json<Value> {
+    Json(json!({
+        "issuer": p.issuer,
+        "authorization_endpoint": format!("{}/authorize", p.issuer),
+        "token_endpoint": format!("{}/token", p.issuer),
+        "jwks_uri": format!("{}/jwks", p.issuer),
+    }))
+}
+
+async fn jwks() -> Json<Value> {
+    Json(serde_json::from_str(JWKS).unwrap())
+}
+
+async fn token(State(p): State<Arc<MockProvider>>, Form(form): Form<TokenForm>) -> Result<Json<Value>, StatusCode> {
+    let pending = p.codes.lock().unwrap().remove(&form.code).ok_or(StatusCode::BAD_REQUEST)?;
+    if crate::oidc::pkce_challenge(&form.code_verifier) != pending.challenge || form.client_id != CLIENT_ID {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let mut claims = json!({
+        "iss": p.issuer,
+        "aud": CLIENT_ID,
+        "sub": "mock-user-1",
+        "email": "ada@example.com",
+        "email_verified": true,
+        "nonce": pending.nonce,
+        "iat": now,
+        "exp": now + 300,
+    });
+    for (k, v) in p.overrides.as_object().unwrap() {
+        claims[k] = v.clone();
+    }
+
+    let mut header = Header::new(jsonwebtoken::Algorithm::RS256);
+    header.kid = Some(p.kid.to_string());
+    let id_token = encode(&header, &claims, &EncodingKey::from_rsa_pem(SIGNING_KEY).unwrap()).unwrap();
+    Ok(Json(json!({ "access_token": "mock", "token_type": "Bearer", "id_token": id_token })))
+}
+
+pub async fn spawn_provider(overrides: Value, kid: &'static str) -> Arc<MockProvider> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let provider = Arc::new(MockProvider {
+        issuer: format!("http://{}", listener.local_addr().unwrap()),
+        codes: Mutex::new(HashMap::new()),
+        overrides,
+        kid,
+    });
+
+    let app = Router::new()
+        .route("/.well-known/openid-configuration", get(discovery))
+        .route("/jwks", get(jwks))
+        .route("/token", post(token))
+        .with_state(provider.clone());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    provider
+}
+
+/// Plays the browser: "authorizes" at the provider and returns the code.
+pub fn authorize(provider: &MockProvider, authorization_url: &str) -> String {
+    let url = reqwest::Url::parse(authorization_url).unwrap();
+    let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+    assert_eq!(params["code_challenge_method"], "S256");
+    assert_eq!(params["redirect_uri"], REDIRECT_URI);
+
+    let code = crate::oidc::random_token();
+    provider.codes.lock().unwrap().insert(
+        code.clone(),
+        PendingCode { challenge: params["code_challenge"].clone(), nonce: params["nonce"].clone() },
+    );
+    code
+}
+
+pub async fn login(provider: &MockProvider, nonce_seen_by_client: Option<&str>) -> Result<crate::oidc::IdTokenClaims, crate::oidc::OidcError> {
+    let http = reqwest::Client::new();
+    let metadata = crate::oidc::discover(&http, &provider.issuer).await?;
+
+    let nonce = crate::oidc::random_token();
+    let pkce = crate::oidc::generate_pkce();
+    let url = crate::oidc::authorization_url(&metadata, CLIENT_ID, REDIRECT_URI, "state", &nonce, &pkce.challenge)?;
+    let code = authorize(provider, &url);
+
+    let tokens = crate::oidc::exchange_code(&http, &metadata, CLIENT_ID, None, REDIRECT_URI, &code, &pkce.verifier).await?;
+    crate::oidc::verify_id_token(&http, &metadata, CLIENT_ID, &tokens.id_token, nonce_seen_by_client.unwrap_or(&nonce)).await
+}
@@ -0,0 +1,511 @@
+//! Bulk client import from CSV or vCard.
+//!
+//! `POST /api/clients/import` with `dry_run: true` parses the file and
+//! reports, for every row, the client read from it, the existing client it
+//! looks like a duplicate of (same email or tax ID, or a close name) and what
+//! would happen to it. The real run applies each row's action in a single
+//! transaction and writes nothing if any row to be imported is invalid.
+//!
+//! Rows are numbered from 1: the first data line of a CSV file, or the first
+//! card of a vCard file. `actions` and error fields (`rows[3].email`) use the
+//! same numbers.
+
+use axum::{extract::State, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use common::{ApiError, AuthContext, FieldError, Valid, Validate, Validator};
+
+use crate::{insert_client, normalized_tags, upsert_address, Address, AppState, CreateClientRequest, CLIENTS_CREATED};
+
+const MAX_ROWS: usize = 5000;
+
+/// Client fields a CSV column can be mapped to.
+const FIELDS: &[&str] = &[
+    "name", "email", "phone", "tax_id", "payment_terms", "notes", "tags",
+    "street", "city", "postal_code", "region", "country",
+];
+
+/// Names scoring at least this (Jaro-Winkler, after normalization) are likely duplicates.
+const NAME_SIMILARITY: f64 = 0.92;
+
+/// Company-form suffixes ignored when comparing names.
+const LEGAL_SUFFIXES: &[&str] = &[
+    "ag", "bv", "co", "corp", "corporation", "gmbh", "inc", "kg", "limited", "llc", "llp", "ltd", "plc", "sa", "sarl", "sas", "srl",
+];
+
+#[derive(Deserialize)]
+pub(crate) struct ImportRequest {
+    /// `csv` or `vcard`.
+    format: String,
+    /// The file's contents.
+    data: String,
+    /// CSV header → client field; unmapped headers that name a field are used as is.
+    #[serde(default)]
+    mapping: HashMap<String, String>,
+    #[serde(default)]
+    dry_run: bool,
+    /// Action per row; without one a row is created, or skipped if it looks like a duplicate.
+    #[serde(default)]
+    actions: HashMap<usize, ImportAction>,
+}
+
+impl Validate for ImportRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.one_of("format", self.format.as_str(), &["csv", "vcard"]);
+        v.required("data", &self.data);
+        for (header, field) in &self.mapping {
+            v.one_of(&format!("mapping.{}", header), field.as_str(), FIELDS);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ImportAction {
+    Create,
+    /// Fill the duplicate in with the row's non-empty fields.
+    Merge,
+    Skip,
+}
+
+#[derive(Debug, FromRow)]
+struct ExistingClient {
+    id: i32,
+    name: String,
+    email: Option<String>,
+    tax_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Duplicate {
+    client_id: i32,
+    name: String,
+    /// `email`, `tax_id` or `name`.
+    matched_on: &'static str,
+}
+
+#[derive(Serialize)]
+struct RowReport {
+    row: usize,
+    action: ImportAction,
+    client: CreateClientRequest,
+    duplicate: Option<Duplicate>,
+    /// The client created or merged into; absent on a dry run.
+    client_id: Option<i32>,
+    errors: Vec<FieldError>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ImportReport {
+    dry_run: bool,
+    created: usize,
+    merged: usize,
+    skipped: usize,
+    invalid: usize,
+    rows: Vec<RowReport>,
+}
+
+pub(crate) async fn import_clients(
+    auth: AuthContext,
+    State(state): State<Arc<AppState>>,
+    Valid(payload): Valid<ImportRequest>,
+) -> Result<Json<ImportReport>, ApiError> {
+    let parsed = match payload.format.as_str() {
+        "csv" => parse_csv(&payload.data, &payload.mapping)?,
+        _ => parse_vcards(&payload.data),
+    };
+    if parsed.len() > MAX_ROWS {
+        return Err(ApiError::Validation(vec![FieldError {
+            field: "data".to_string(),
+            message: format!("must have at most {} rows", MAX_ROWS),
+        }]));
+    }
+
+    let existing = sqlx::query_as::<_, ExistingClient>("SELECT id, name, email, tax_id FROM clients WHERE user_id = $1")
+        .bind(auth.user_id)
+        .fetch_all(&state.db)
+        .await?;
+
+    let mut rows = Vec::with_capacity(parsed.len());
+    for (i, (client, mut errors)) in parsed.into_iter().enumerate() {
+        let row = i + 1;
+        let duplicate = find_duplicate(&existing, &client);
+        let action = payload.actions.get(&row).copied().unwrap_or(if duplicate.is_some() { ImportAction::Skip } else { ImportAction::Create });
+        // A skipped row is never written, so whatever is wrong with it doesn't matter
+        if action == ImportAction::Skip {
+            errors.clear();
+        } else {
+            errors.extend(Validator::collect(&client));
+        }
+        if action == ImportAction::Merge && duplicate.is_none() {
+            errors.push(FieldError { field: "action".to_string(), message: "no duplicate was found to merge into".to_string() });
+        }
+        rows.push(RowReport { row, action, client, duplicate, client_id: None, errors });
+    }
+
+    let invalid = rows.iter().filter(|r| !r.errors.is_empty()).count();
+    if !payload.dry_run && invalid > 0 {
+        let errors = rows
+            .iter()
+            .flat_map(|r| r.errors.iter().map(move |e| FieldError { field: format!("rows[{}].{}", r.row, e.field), message: e.message.clone() }))
+            .collect();
+        return Err(ApiError::Validation(errors));
+    }
+
+    if !payload.dry_run {
+        let mut tx = state.db.begin().await?;
+        for row in &mut rows {
+            row.client_id = match (row.action, &row.duplicate) {
+                (ImportAction::Create, _) => Some(insert_client(&mut tx, auth.user_id, &row.client).await?),
+                (ImportAction::Merge, Some(duplicate)) => Some(merge_client(&mut tx, duplicate.client_id, &row.client).await?),
+                _ => None,
+            };
+        }
+        tx.commit().await?;
+    }
+
+    let count = |action| rows.iter().filter(|r| r.action == action && r.errors.is_empty()).count();
+    let (created, merged, skipped) = (count(ImportAction::Create), count(ImportAction::Merge), count(ImportAction::Skip));
+    if !payload.dry_run {
+        CLIENTS_CREATED.inc_by(&[], created as u64);
+        tracing::info!("Imported clients for user {}: {} created, {} merged, {} skipped", auth.user_id, created, merged, skipped);
+    }
+
+    Ok(Json(ImportReport { dry_run: payload.dry_run, created, merged, skipped, invalid, rows }))
+}
+
+/// Overwrites the client's fields with the row's non-empty ones, keeps its
+/// name, adds the row's tags and replaces the billing address if the row has one.
+async fn merge_client(tx: &mut sqlx::PgConnection, client_id: i32, row: &CreateClientRequest) -> Result<i32, ApiError> {
+    sqlx::query(
+        "UPDATE clients SET email = COALESCE($1, email), phone = COALESCE($2, phone), tax_id = COALESCE($3, tax_id), \
+         payment_terms = COALESCE($4, payment_terms), notes = COALESCE($5, notes), \
+         tags = tags || ARRAY(SELECT t FROM unnest($6::text[]) AS t WHERE t <> ALL(tags)) \
+         WHERE id = $7"
+    )
+    .bind(&row.email)
+    .bind(&row.phone)
+    .bind(row.canonical_tax_id())
+    .bind(row.payment_terms)
+    .bind(&row.notes)
+    .bind(normalized_tags(&row.tags))
+    .bind(client_id)
+    .execute(&mut *tx)
+    .await?;
+
+    if let Some(address) = &row.billing_address {
+        upsert_address(tx, client_id, "billing", address).await?;
+    }
+    Ok(client_id)
+}
+
+fn find_duplicate(existing: &[ExistingClient], client: &CreateClientRequest) -> Option<Duplicate> {
+    let duplicate = |c: &ExistingClient, matched_on| Some(Duplicate { client_id: c.id, name: c.name.clone(), matched_on });
+
+    if let Some(email) = client.email.as_deref() {
+        if let Some(c) = existing.iter().find(|c| c.email.as_deref().is_some_and(|e| e.eq_ignore_ascii_case(email))) {
+            return duplicate(c, "email");
+        }
+    }
+    if let Some(tax_id) = client.tax_id.as_deref().map(normalized_tax_id).filter(|t| !t.is_empty()) {
+        if let Some(c) = existing.iter().find(|c| c.tax_id.as_deref().map(normalized_tax_id) == Some(tax_id.clone())) {
+            return duplicate(c, "tax_id");
+        }
+    }
+
+    let name = normalized_name(&client.name);
+    if name.is_empty() {
+        return None;
+    }
+    existing
+        .iter()
+        .map(|c| (c, strsim::jaro_winkler(&name, &normalized_name(&c.name))))
+        .filter(|(_, score)| *score >= NAME_SIMILARITY)
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .and_then(|(c, _)| duplicate(c, "name"))
+}
+
+fn normalized_tax_id(tax_id: &str) -> String {
+    tax_id.chars().filter(char::is_ascii_alphanumeric).map(|c| c.to_ascii_uppercase()).collect()
+}
+
+/// Lower case, punctuation dropped and legal suffixes removed: "ACME, Inc." → "acme".
+fn normalized_name(name: &str) -> String {
+    let cleaned: String = name.chars().map(|c| if c.is_alphanumeric() { c.to_ascii_lowercase() } else { ' ' }).collect();
+    cleaned.split_whitespace().filter(|word| !LEGAL_SUFFIXES.contains(word)).collect::<Vec<_>>().join(" ")
+}
+
+type ParsedRow = (CreateClientRequest, Vec<FieldError>);
+
+fn parse_csv(data: &str, mapping: &HashMap<String, String>) -> Result<Vec<ParsedRow>, ApiError> {
+    let invalid = |message: String| ApiError::Validation(vec![FieldError { field: "data".to_string(), message }]);
+
+    let mut reader = csv::ReaderBuilder::new().flexible(true).trim(csv::Trim::All).from_reader(data.as_bytes());
+    let headers = reader.headers().map_err(|e| invalid(format!("is not valid CSV: {}", e)))?.clone();
+    let columns: Vec<Option<&str>> = headers
+        .iter()
+        .map(|header| match mapping.get(header) {
+            Some(field) => Some(field.as_str()),
+            // "E-mail", "Postal code" and the like match without a mapping
+            None => FIELDS.iter().find(|f| squashed(f) == squashed(header)).copied(),
+        })
+        .collect();
+    if !columns.contains(&Some("name")) {
+        return Err(ApiError::Validation(vec![FieldError {
+            field: "mapping".to_string(),
+            message: "no column is mapped to name".to_string(),
+        }]));
+    }
+
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(|e| invalid(format!("is not valid CSV: {}", e)))?;
+        let fields: HashMap<&str, String> = columns
+            .iter()
+            .zip(record.iter())
+            .filter_map(|(field, value)| Some((field.as_ref().copied()?, value.to_string())))
+            .filter(|(_, value)| !value.is_empty())
+            .collect();
+        rows.push(client_from_fields(fields));
+    }
+    Ok(rows)
+}
+
+fn squashed(name: &str) -> String {
+    name.chars().filter(char::is_ascii_alphanumeric).map(|c| c.to_ascii_lowercase()).collect()
+}
+
+/// Builds a client from field → value pairs, reporting values that don't parse.
+fn client_from_fields(mut fields: HashMap<&str, String>) -> ParsedRow {
+    let mut errors = Vec::new();
+    let payment_terms = fields.remove("payment_terms").and_then(|raw| match raw.parse::<i32>() {
+        Ok(days) => Some(days),
+        Err(_) => {
+            errors.push(FieldError { field: "payment_terms".to_string(), message: "must be a whole number of days".to_string() });
+            None
+        }
+    });
+    let tags = fields
+        .remove("tags")
+        .map(|raw| raw.split([',', ';']).map(str::trim).filter(|t| !t.is_empty()).map(str::to_string).collect())
+        .unwrap_or_default();
+
+    let has_address = ["street", "city", "postal_code", "region", "country"].iter().any(|f| fields.contains_key(f));
+    let billing_address = has_address.then(|| Address {
+        street: fields.remove("street").unwrap_or_default(),
+        city: fields.remove("city").unwrap_or_default(),
+        postal_code: fields.remove("postal_code"),
+        region: fields.remove("region"),
+        country: fields.remove("country").unwrap_or_default().to_uppercase(),
+    });
+
+    let client = CreateClientRequest {
+        name: fields.remove("name").unwrap_or_default(),
+        email: fields.remove("email"),
+        phone: fields.remove("phone"),
+        address: None,
+        tax_id: fields.remove("tax_id"),
+        payment_terms,
+        notes: fields.remove("notes"),
+        status: None,
+        tags,
+        currency: None,
+        language: None,
+        tax_treatment: None,
+        tax_exemption_reason: None,
+        discount_percent: None,
+        credit_limit: None,
+        risk_status: None,
+        billing_address,
+        shipping_address: None,
+    };
+    (client, errors)
+}
+
+/// Reads vCard 2.1–4.0: the organization (or full name) becomes the client,
+/// with the first email and phone, the work (or first) address, the note and
+/// the categories as tags.
+fn parse_vcards(data: &str) -> Vec<ParsedRow> {
+    // Unfold continuation lines, which start with a space or tab
+    let mut lines: Vec<String> = Vec::new();
+    for line in data.lines() {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(rest), Some(last)) => last.push_str(rest),
+            _ => lines.push(line.to_string()),
+        }
+    }
+
+    let mut rows = Vec::new();
+    let mut card: Option<Vec<(String, String, String)>> = None;
+    for line in &lines {
+        let Some((key, value)) = line.split_once(':') else { continue };
+        let mut params = key.split(';');
+        let name = params.next().unwrap_or_default();
+        let name = name.rsplit('.').next().unwrap_or(name).to_ascii_uppercase();
+        let params = params.collect::<Vec<_>>().join(";").to_ascii_uppercase();
+
+        match name.as_str() {
+            "BEGIN" if value.eq_ignore_ascii_case("VCARD") => card = Some(Vec::new()),
+            "END" if value.eq_ignore_ascii_case("VCARD") => {
+                if let Some(properties) = card.take() {
+                    rows.push(client_from_vcard(&properties));
+                }
+            }
+            _ => {
+                if let Some(properties) = card.as_mut() {
+                    properties.push((name, params, value.to_string()));
+                }
+            }
+        }
+    }
+    rows
+}
+
+fn client_from_vcard(properties: &[(String, String, String)]) -> ParsedRow {
+    let first = |name: &str| properties.iter().find(|(n, _, _)| n == name).map(|(_, _, v)| v.as_str());
+
+    let mut fields: HashMap<&str, String> = HashMap::new();
+    let organization = first("ORG").and_then(|org| split_escaped(org, ';').into_iter().next()).filter(|org| !org.is_empty());
+    let full_name = first("FN").map(unescape).filter(|name| !name.is_empty());
+    if let Some(name) = organization.or(full_name) {
+        fields.insert("name", name);
+    }
+    for (field, property) in [("email", "EMAIL"), ("phone", "TEL"), ("notes", "NOTE"), ("tags", "CATEGORIES")] {
+        // vCard 4 may give the phone as a `tel:` URI
+        if let Some(value) = first(property).map(|v| unescape(v.strip_prefix("tel:").unwrap_or(v))).filter(|v| !v.is_empty()) {
+            fields.insert(field, value);
+        }
+    }
+
+    // ADR: post office box; extended address; street; locality; region; postal code; country
+    let address = properties
+        .iter()
+        .filter(|(n, _, _)| n == "ADR")
+        .max_by_key(|(_, params, _)| params.contains("WORK"))
+        .map(|(_, _, value)| split_escaped(value, ';'));
+    if let Some(parts) = address {
+        let part = |i: usize| parts.get(i).map(|p| p.trim().to_string()).filter(|p| !p.is_empty());
+        let street = [part(1), part(2)].into_iter().flatten().collect::<Vec<_>>().join("\n");
+        for (field, value) in [("street", Some(street)), ("city", part(3)), ("region", part(4)), ("postal_code", part(5)), ("country", part(6))] {
+            if let Some(value) = value.filter(|v| !v.is_empty()) {
+                fields.insert(field, value);
+            }
+        }
+    }
+
+    client_from_fields(fields)
+}
+
+/// Splits a structured value on unescaped separators, unescaping each part.
+fn split_escaped(value: &str, separator: char) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        let part = parts.last_mut().expect("parts is never empty");
+        match c {
+            '\\' => match chars.next() {
+                Some('n' | 'N') => part.push('\n'),
+                Some(escaped) => part.push(escaped),
+                None => {}
+            },
+            c if c == separator => parts.push(String::new()),
+            c => part.push(c),
+        }
+    }
+    parts.iter().map(|p| p.trim().to_string()).collect()
+}
+
+fn unescape(value: &str) -> String {
+    // No property value contains a NUL, so this never splits
+    split_escaped(value, '\0').remove(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn existing(id: i32, name: &str, email: Option<&str>, tax_id: Option<&str>) -> ExistingClient {
+        ExistingClient { id, name: name.to_string(), email: email.map(str::to_string), tax_id: tax_id.map(str::to_string) }
+    }
+
+    fn request(name: &str, email: Option<&str>, tax_id: Option<&str>) -> CreateClientRequest {
+        let fields = [("name", Some(name)), ("email", email), ("tax_id", tax_id)];
+        client_from_fields(fields.into_iter().filter_map(|(f, v)| Some((f, v?.to_string()))).collect()).0
+    }
+
+    #[test]
+    fn reads_quoted_csv_with_headers_matched_loosely_or_mapped() {
+        let data = "Company,E-mail,Postal code,Payment terms,Labels\n\
+                    \"Acme, Inc.\",billing@acme.test,10115,30,\"vip; wholesale\"\n";
+        let mapping = HashMap::from([("Company".to_string(), "name".to_string()), ("Labels".to_string(), "tags".to_string())]);
+        let rows = parse_csv(data, &mapping).unwrap();
+        assert_eq!(rows.len(), 1);
+        let (client, errors) = &rows[0];
+        assert!(errors.is_empty());
+        assert_eq!(client.name, "Acme, Inc.");
+        assert_eq!(client.email.as_deref(), Some("billing@acme.test"));
+        assert_eq!(client.payment_terms, Some(30));
+        assert_eq!(client.tags, ["vip", "wholesale"]);
+        assert_eq!(client.billing_address.as_ref().and_then(|a| a.postal_code.as_deref()), Some("10115"));
+    }
+
+    #[test]
+    fn csv_needs_a_name_column() {
+        let result = parse_csv("Email\na@example.test\n", &HashMap::new());
+        assert!(matches!(result, Err(ApiError::Validation(errors)) if errors[0].field == "mapping"));
+    }
+
+    #[test]
+    fn reports_values_that_do_not_parse() {
+        let rows = parse_csv("name,payment_terms\nAcme,net 30\n", &HashMap::new()).unwrap();
+        let (client, errors) = &rows[0];
+        assert_eq!(client.payment_terms, None);
+        assert_eq!(errors.iter().map(|e| e.field.as_str()).collect::<Vec<_>>(), ["payment_terms"]);
+    }
+
+    #[test]
+    fn reads_folded_and_escaped_vcards() {
+        let data = "BEGIN:VCARD\r\nVERSION:3.0\r\nFN:Jane Doe\r\nORG:Acme\\, Inc.;Sales\r\n\
+                    item1.EMAIL;TYPE=work:jane@acme.test\r\nTEL;VALUE=uri:tel:+49 30 1234\r\n\
+                    ADR;TYPE=home:;;Home St 1;Berlin;;10115;de\r\n\
+                    ADR;TYPE=work:;Floor 2;Main St 5;Hamburg;;20095;de\r\n\
+                    NOTE:Pays late\\nbut pays\r\nCATEGORIES:vip,\r\n  wholesale\r\nEND:VCARD\r\n\
+                    BEGIN:VCARD\r\nFN:John Roe\r\nEND:VCARD\r\n";
+        let rows = parse_vcards(data);
+        assert_eq!(rows.len(), 2);
+        let (acme, errors) = &rows[0];
+        assert!(errors.is_empty());
+        assert_eq!(acme.name, "Acme, Inc.");
+        assert_eq!(acme.email.as_deref(), Some("jane@acme.test"));
+        assert_eq!(acme.phone.as_deref(), Some("+49 30 1234"));
+        assert_eq!(acme.notes.as_deref(), Some("Pays late\nbut pays"));
+        assert_eq!(acme.tags, ["vip", "wholesale"]);
+        let address = acme.billing_address.as_ref().unwrap();
+        assert_eq!((address.street.as_str(), address.city.as_str(), address.country.as_str()), ("Floor 2\nMain St 5", "Hamburg", "DE"));
+        assert_eq!(rows[1].0.name, "John Roe");
+    }
+
+    #[test]
+    fn finds_duplicates_by_email_then_tax_id_then_a_close_name() {
+        let clients = [
+            existing(1, "Globex Corporation", Some("ap@globex.test"), None),
+            existing(2, "Initech GmbH", None, Some("DE 123 456 789")),
+            existing(3, "Acme Ltd", None, None),
+        ];
+        let matched = |client: &CreateClientRequest| find_duplicate(&clients, client).map(|d| (d.client_id, d.matched_on));
+        assert_eq!(matched(&request("Someone", Some("AP@Globex.test"), None)), Some((1, "email")));
+        assert_eq!(matched(&request("Someone", None, Some("de123456789"))), Some((2, "tax_id")));
+        assert_eq!(matched(&request("ACME, Inc.", None, None)), Some((3, "name")));
+        assert_eq!(matched(&request("Umbrella", Some("x@umbrella.test"), Some("FR1"))), None);
+    }
+
+    #[test]
+    fn normalizes_names_for_comparison() {
+        assert_eq!(normalized_name("ACME, Inc."), "acme");
+        assert_eq!(normalized_name("Müller & Söhne GmbH & Co. KG"), "müller söhne");
+        assert_eq!(normalized_name("Ltd."), "");
+    }
+}
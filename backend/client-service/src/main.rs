@@ -1,28 +1,42 @@
 use axum::{
-    routing::{get, post, put, delete},
+    routing::{get, post},
     Router,
     Json,
     http::StatusCode,
-    extract::{State, Path},
+    extract::{DefaultBodyLimit, State, Path},
 };
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
-use tower_http::cors::CorsLayer;
-use common::AuthContext;
+use common::billing::{DOCUMENT_LANGUAGES, TAX_TREATMENTS};
+use common::bootstrap;
+use common::tax_id::{self, Vies};
+use common::{ApiError, AuthContext, AuthState, JwtVerifier, ListQuery, ListSpec, Page, Valid, Validate, Validator};
+use common::{FieldKind, Filter, FilterOp, SortKey};
+use common::account_data::{AccountDataPart, ArchiveFile, ErasureReport};
+use common::metrics::Counter;
+use std::collections::HashMap;
 use std::sync::Arc;
-use sqlx::postgres::PgPoolOptions;
 use sqlx::{Pool, Postgres, FromRow};
 
+mod import;
+mod statement;
+
 type DbPool = Pool<Postgres>;
 
 struct AppState {
     db: DbPool,
-    jwt_secret: Arc<String>,
+    jwt_verifier: JwtVerifier,
+    http: reqwest::Client,
+    invoice_service_url: String,
+    vies: Option<Vies>,
 }
 
-impl axum::extract::FromRef<Arc<AppState>> for Arc<String> {
-    fn from_ref(state: &Arc<AppState>) -> Self {
-        state.jwt_secret.clone()
+impl AuthState for AppState {
+    fn jwt_verifier(&self) -> &JwtVerifier {
+        &self.jwt_verifier
+    }
+
+    fn db(&self) -> Option<&DbPool> {
+        Some(&self.db)
     }
 }
 
@@ -38,9 +52,45 @@ struct Client {
     payment_terms: Option<i32>,
     notes: Option<String>,
     status: Option<String>,
+    tags: Vec<String>,
+    currency: Option<String>,
+    language: Option<String>,
+    tax_treatment: String,
+    tax_exemption_reason: Option<String>,
+    discount_percent: f64,
+    credit_limit: Option<f64>,
+    risk_status: String,
+    outstanding_balance: f64,
+    avg_days_to_pay: Option<f64>,
+    /// Between 0 and 1.
+    late_payment_ratio: Option<f64>,
+    billing_address: Option<sqlx::types::Json<Address>>,
+    shipping_address: Option<sqlx::types::Json<Address>>,
 }
 
-#[derive(Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
+struct Address {
+    street: String,
+    city: String,
+    postal_code: Option<String>,
+    region: Option<String>,
+    /// ISO 3166-1 alpha-2.
+    country: String,
+}
+
+impl Validate for Address {
+    fn validate(&self, v: &mut Validator) {
+        v.required("street", &self.street);
+        v.max_len("street", self.street.as_str(), 500);
+        v.required("city", &self.city);
+        v.max_len("city", self.city.as_str(), 100);
+        v.max_len("postal_code", self.postal_code.as_deref(), 20);
+        v.max_len("region", self.region.as_deref(), 100);
+        v.country("country", self.country.as_str());
+    }
+}
+
+#[derive(Deserialize, Serialize)]
 struct CreateClientRequest {
     name: String,
     email: Option<String>,
@@ -50,76 +100,286 @@ struct CreateClientRequest {
     payment_terms: Option<i32>,
     notes: Option<String>,
     status: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    /// Invoice defaults; currency and language fall back to the company's.
+    currency: Option<String>,
+    language: Option<String>,
+    tax_treatment: Option<String>,
+    tax_exemption_reason: Option<String>,
+    discount_percent: Option<f64>,
+    /// No limit when absent.
+    credit_limit: Option<f64>,
+    risk_status: Option<String>,
+    billing_address: Option<Address>,
+    shipping_address: Option<Address>,
+}
+
+impl Validate for CreateClientRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("name", &self.name);
+        v.max_len("name", self.name.as_str(), 255);
+        v.email("email", self.email.as_deref());
+        v.max_len("phone", self.phone.as_deref(), 50);
+        v.max_len("tax_id", self.tax_id.as_deref(), 50);
+        v.tax_id("tax_id", self.tax_id.as_deref(), self.country());
+        v.range("payment_terms", self.payment_terms, 0, 365);
+        v.one_of("status", self.status.as_deref(), CLIENT_STATUSES);
+        if self.tags.len() > MAX_TAGS {
+            v.error("tags", format!("must have at most {} tags", MAX_TAGS));
+        }
+        for (i, tag) in self.tags.iter().enumerate() {
+            v.required(&format!("tags[{}]", i), tag);
+            v.max_len(&format!("tags[{}]", i), tag.as_str(), 50);
+        }
+        v.currency("currency", self.currency.as_deref());
+        v.one_of("language", self.language.as_deref(), DOCUMENT_LANGUAGES);
+        v.one_of("tax_treatment", self.tax_treatment.as_deref(), TAX_TREATMENTS);
+        match self.tax_treatment.as_deref() {
+            Some("exempt") if self.tax_exemption_reason.is_none() => v.error("tax_exemption_reason", "is required for tax-exempt clients"),
+            Some("reverse_charge") if self.tax_id.is_none() => v.error("tax_id", "is required for reverse-charge clients"),
+            _ => {}
+        }
+        v.max_len("tax_exemption_reason", self.tax_exemption_reason.as_deref(), 500);
+        v.range("discount_percent", self.discount_percent, 0.0, 100.0);
+        v.min("credit_limit", self.credit_limit, 0.0);
+        v.one_of("risk_status", self.risk_status.as_deref(), RISK_STATUSES);
+        v.object("billing_address", self.billing_address.as_ref());
+        v.object("shipping_address", self.shipping_address.as_ref());
+    }
+}
+
+impl CreateClientRequest {
+    /// Where the client's tax ID was issued, as far as we know.
+    fn country(&self) -> Option<&str> {
+        self.billing_address.as_ref().map(|a| a.country.as_str())
+    }
+
+    /// The tax ID in the form it is stored in.
+    fn canonical_tax_id(&self) -> Option<String> {
+        self.tax_id.as_deref().map(|t| tax_id::canonical(t, self.country()))
+    }
+}
+
+/// With VIES configured, rejects EU VAT numbers it doesn't know.
+async fn check_vies(state: &AppState, payload: &CreateClientRequest) -> Result<(), ApiError> {
+    match (&state.vies, &payload.tax_id) {
+        (Some(vies), Some(tax_id)) => vies.ensure_registered("tax_id", tax_id, payload.country()).await,
+        _ => Ok(()),
+    }
 }
 
+/// Trimmed, without duplicates, in the order given.
+fn normalized_tags(tags: &[String]) -> Vec<String> {
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags.iter().map(|t| t.trim()) {
+        if !normalized.iter().any(|t| t == tag) {
+            normalized.push(tag.to_string());
+        }
+    }
+    normalized
+}
+
+#[derive(Debug, FromRow, Serialize)]
+struct TagCount {
+    tag: String,
+    clients: i64,
+}
+
+#[derive(Debug, FromRow, Serialize, Deserialize)]
+struct Contact {
+    id: i32,
+    client_id: i32,
+    name: String,
+    email: Option<String>,
+    phone: Option<String>,
+    role: Option<String>,
+    invoice_recipient: bool,
+}
+
+#[derive(Deserialize)]
+struct ContactRequest {
+    name: String,
+    email: Option<String>,
+    phone: Option<String>,
+    role: Option<String>,
+    #[serde(default)]
+    invoice_recipient: bool,
+}
+
+impl Validate for ContactRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("name", &self.name);
+        v.max_len("name", self.name.as_str(), 255);
+        v.email("email", self.email.as_deref());
+        v.max_len("phone", self.phone.as_deref(), 50);
+        v.max_len("role", self.role.as_deref(), 100);
+        if self.invoice_recipient && self.email.is_none() {
+            v.error("email", "is required for an invoice recipient");
+        }
+    }
+}
+
+const CONTACT_COLUMNS: &str = "ct.id, ct.client_id, ct.name, ct.email, ct.phone, ct.role, ct.invoice_recipient";
+
+/// Clients with figures from their invoice history: the outstanding balance
+/// (issued invoices less payments and credit notes, as on the statement), the
+/// average days from issue to final payment of paid invoices, and the share of
+/// invoices that are due or paid which were paid late or are still overdue.
+const CLIENTS_WITH_STATS: &str = "FROM clients LEFT JOIN LATERAL ( \
+    SELECT (COALESCE(SUM(i.total) FILTER (WHERE i.status NOT IN ('draft', 'void')), 0) \
+              - COALESCE(SUM(p.paid) FILTER (WHERE i.status NOT IN ('draft', 'void')), 0) \
+              - COALESCE((SELECT SUM(cn.amount) FROM credit_notes cn WHERE cn.client_id = clients.id), 0))::float8 AS outstanding_balance, \
+           AVG(p.last_paid_on - i.created_at::date) FILTER (WHERE i.status = 'paid')::float8 AS avg_days_to_pay, \
+           (COUNT(*) FILTER (WHERE (i.status = 'paid' AND p.last_paid_on > i.due_date) OR (i.status IN ('sent', 'overdue') AND i.due_date < CURRENT_DATE))::float8 \
+              / NULLIF(COUNT(*) FILTER (WHERE i.status = 'paid' OR (i.status IN ('sent', 'overdue') AND i.due_date < CURRENT_DATE)), 0)) AS late_payment_ratio \
+    FROM invoices i \
+    LEFT JOIN LATERAL (SELECT SUM(amount) AS paid, MAX(paid_on) AS last_paid_on FROM payments WHERE invoice_id = i.id) p ON TRUE \
+    WHERE i.client_id = clients.id \
+) stats ON TRUE";
+
+/// Stands in for the stats when a list neither sorts nor filters by them;
+/// they are then computed for the returned page only (see [`fill_stats`]).
+const CLIENTS_WITHOUT_STATS: &str = "FROM clients CROSS JOIN ( \
+    SELECT 0::float8 AS outstanding_balance, NULL::float8 AS avg_days_to_pay, NULL::float8 AS late_payment_ratio \
+) stats";
+
+const CLIENT_LIST: ListSpec = ListSpec {
+    columns: "id, user_id, name, email, phone, address, tax_id, payment_terms, notes, status, tags, \
+              currency, language, tax_treatment, tax_exemption_reason, discount_percent::float8 AS discount_percent, \
+              credit_limit::float8 AS credit_limit, risk_status, stats.outstanding_balance, stats.avg_days_to_pay, stats.late_payment_ratio, \
+              (SELECT to_jsonb(a) - 'client_id' - 'kind' FROM client_addresses a WHERE a.client_id = clients.id AND a.kind = 'billing') AS billing_address, \
+              (SELECT to_jsonb(a) - 'client_id' - 'kind' FROM client_addresses a WHERE a.client_id = clients.id AND a.kind = 'shipping') AS shipping_address",
+    from: CLIENTS_WITH_STATS,
+    owner_column: "user_id",
+    id_column: "id",
+    sort_keys: &[
+        SortKey::new("name", "lower(name)", FieldKind::Text),
+        SortKey::new("created_at", "COALESCE(created_at, 'epoch'::timestamptz)", FieldKind::Timestamp),
+        SortKey::new("outstanding_balance", "stats.outstanding_balance", FieldKind::Number),
+    ],
+    default_sort: "name",
+    filters: &[
+        Filter::new("status", "COALESCE(status, 'active')", FieldKind::Text, FilterOp::In),
+        Filter::new("q", "search_vector", FieldKind::Text, FilterOp::Search),
+        Filter::new("tag", "tags", FieldKind::Text, FilterOp::Overlaps),
+        Filter::new("currency", "currency", FieldKind::Text, FilterOp::In),
+        Filter::new("tax_treatment", "tax_treatment", FieldKind::Text, FilterOp::In),
+        Filter::new("risk_status", "risk_status", FieldKind::Text, FilterOp::In),
+        Filter::new("min_outstanding", "stats.outstanding_balance", FieldKind::Number, FilterOp::Min),
+        Filter::new("created_from", "created_at::date", FieldKind::Date, FilterOp::Min),
+        Filter::new("created_to", "created_at::date", FieldKind::Date, FilterOp::Max),
+    ],
+    // Archived clients only show up when asked for with `status=archived`
+    default_filters: &[("status", "active,inactive")],
+};
+
+const CLIENT_PAGE: ListSpec = ListSpec { from: CLIENTS_WITHOUT_STATS, ..CLIENT_LIST };
+
+const CLIENT_STATUSES: &[&str] = &["active", "inactive", "archived"];
+/// Invoicing a client on `hold` is treated like exceeding its credit limit.
+const RISK_STATUSES: &[&str] = &["normal", "watch", "hold"];
+const MAX_TAGS: usize = 20;
+/// vCard exports can embed photos, so imports may be larger than other requests.
+const IMPORT_BODY_LIMIT: usize = 20 * 1024 * 1024;
+
+static CLIENTS_CREATED: Counter = Counter::new("clients_created_total", "Clients created", &[]);
+
 #[tokio::main]
 async fn main() {
-    dotenvy::dotenv().ok();
-    tracing_subscriber::fmt::init();
+    let config = bootstrap::init("client-service", 5003);
+    let pool = config.connect_database().await;
 
-    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&database_url)
-        .await
-        .expect("Failed to create pool");
-
-    let jwt_secret = Arc::new(std::env::var("JWT_SECRET").unwrap_or_else(|_| "supersecret".to_string()));
+    let jwt_verifier = config.jwt_verifier();
     
     let state = Arc::new(AppState {
-        db: pool,
-        jwt_secret,
+        db: pool.clone(),
+        jwt_verifier,
+        http: reqwest::Client::new(),
+        invoice_service_url: config.service_urls.invoice.clone(),
+        vies: Vies::from_env(),
     });
 
     let app = Router::new()
         .route("/api/clients", get(list_clients).post(create_client))
+        .route("/api/clients/tags", get(list_tags))
+        .route("/api/clients/import", post(import::import_clients).layer(DefaultBodyLimit::max(IMPORT_BODY_LIMIT)))
         .route("/api/clients/:id", get(get_client).put(update_client).delete(delete_client))
-        .layer(CorsLayer::permissive())
+        .route("/api/clients/:id/statement", get(statement::get_statement))
+        .route("/api/clients/:id/statement/pdf", get(statement::get_statement_pdf))
+        .route("/api/clients/:id/contacts", get(list_contacts).post(create_contact))
+        .route("/api/clients/:id/contacts/:contact_id", get(get_contact).put(update_contact).delete(delete_contact))
+        .route("/internal/account-data/clients", get(export_account_data).delete(erase_account_data))
         .with_state(state);
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 5003));
-    tracing::info!("Client service listening on {}", addr);
-    
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    bootstrap::serve(config, app, Some(pool)).await;
 }
 
 async fn list_clients(
     auth: AuthContext,
     State(state): State<Arc<AppState>>,
-) -> Result<Json<Vec<Client>>, (StatusCode, String)> {
-    let clients = sqlx::query_as::<_, Client>(
-        "SELECT id, user_id, name, email, phone, address, tax_id, payment_terms, notes, status FROM clients WHERE user_id = $1"
+    query: ListQuery,
+) -> Result<Json<Page<Client>>, ApiError> {
+    // The stats aggregate every invoice, so only join them for all of the
+    // caller's clients when the order or filters depend on them
+    if query.sorts_by("outstanding_balance") || query.filters_by("min_outstanding") {
+        return Ok(Json(query.fetch(&state.db, &CLIENT_LIST, auth.user_id).await?));
+    }
+    let mut page = query.fetch(&state.db, &CLIENT_PAGE, auth.user_id).await?;
+    fill_stats(&state.db, &mut page.items).await?;
+    Ok(Json(page))
+}
+
+/// Computes the outstanding balance and payment stats of the given clients.
+async fn fill_stats(db: &Pool<Postgres>, clients: &mut [Client]) -> Result<(), ApiError> {
+    let ids: Vec<i32> = clients.iter().map(|c| c.id).collect();
+    let stats: HashMap<i32, (f64, Option<f64>, Option<f64>)> = sqlx::query_as::<_, (i32, f64, Option<f64>, Option<f64>)>(&format!(
+        "SELECT id, stats.outstanding_balance, stats.avg_days_to_pay, stats.late_payment_ratio {} WHERE id = ANY($1)",
+        CLIENTS_WITH_STATS
+    ))
+    .bind(&ids)
+    .fetch_all(db)
+    .await?
+    .into_iter()
+    .map(|(id, outstanding, avg_days, late_ratio)| (id, (outstanding, avg_days, late_ratio)))
+    .collect();
+    for client in clients {
+        if let Some(&(outstanding, avg_days, late_ratio)) = stats.get(&client.id) {
+            client.outstanding_balance = outstanding;
+            client.avg_days_to_pay = avg_days;
+            client.late_payment_ratio = late_ratio;
+        }
+    }
+    Ok(())
+}
+
+/// Tags in use on the caller's unarchived clients, with how many clients carry each.
+async fn list_tags(
+    auth: AuthContext,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<TagCount>>, ApiError> {
+    let tags = sqlx::query_as::<_, TagCount>(
+        "SELECT tag, COUNT(*) AS clients FROM clients, unnest(tags) AS tag WHERE user_id = $1 AND COALESCE(status, 'active') <> 'archived' GROUP BY tag ORDER BY tag"
     )
     .bind(auth.user_id)
     .fetch_all(&state.db)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    .await?;
 
-    Ok(Json(clients))
+    Ok(Json(tags))
 }
 
 async fn create_client(
     auth: AuthContext,
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<CreateClientRequest>,
-) -> Result<Json<Client>, (StatusCode, String)> {
-    let client = sqlx::query_as::<_, Client>(
-        "INSERT INTO clients (user_id, name, email, phone, address, tax_id, payment_terms, notes, status) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING id, user_id, name, email, phone, address, tax_id, payment_terms, notes, status"
-    )
-    .bind(auth.user_id)
-    .bind(payload.name)
-    .bind(payload.email)
-    .bind(payload.phone)
-    .bind(payload.address)
-    .bind(payload.tax_id)
-    .bind(payload.payment_terms)
-    .bind(payload.notes)
-    .bind(payload.status)
-    .fetch_one(&state.db)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Valid(payload): Valid<CreateClientRequest>,
+) -> Result<Json<Client>, ApiError> {
+    check_vies(&state, &payload).await?;
+    let mut tx = state.db.begin().await?;
+    let id = insert_client(&mut tx, auth.user_id, &payload).await?;
+    let client = fetch_client(&mut *tx, id, auth.user_id).await?;
+    tx.commit().await?;
 
+    CLIENTS_CREATED.inc(&[]);
     Ok(Json(client))
 }
 
@@ -127,17 +387,8 @@ async fn get_client(
     auth: AuthContext,
     Path(id): Path<i32>,
     State(state): State<Arc<AppState>>,
-) -> Result<Json<Client>, (StatusCode, String)> {
-    let client = sqlx::query_as::<_, Client>(
-        "SELECT id, user_id, name, email, phone, address, tax_id, payment_terms, notes, status FROM clients WHERE id = $1 AND user_id = $2"
-    )
-    .bind(id)
-    .bind(auth.user_id)
-    .fetch_optional(&state.db)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
-    .ok_or((StatusCode::NOT_FOUND, "Client not found".to_string()))?;
-
+) -> Result<Json<Client>, ApiError> {
+    let client = fetch_client(&state.db, id, auth.user_id).await?;
     Ok(Json(client))
 }
 
@@ -145,43 +396,300 @@ async fn update_client(
     auth: AuthContext,
     Path(id): Path<i32>,
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<CreateClientRequest>,
-) -> Result<Json<Client>, (StatusCode, String)> {
-    let client = sqlx::query_as::<_, Client>(
-        "UPDATE clients SET name = $1, email = $2, phone = $3, address = $4, tax_id = $5, payment_terms = $6, notes = $7, status = $8 WHERE id = $9 AND user_id = $10 RETURNING id, user_id, name, email, phone, address, tax_id, payment_terms, notes, status"
+    Valid(payload): Valid<CreateClientRequest>,
+) -> Result<Json<Client>, ApiError> {
+    check_vies(&state, &payload).await?;
+    let mut tx = state.db.begin().await?;
+    sqlx::query_scalar::<_, i32>(
+        "UPDATE clients SET name = $1, email = $2, phone = $3, address = $4, tax_id = $5, payment_terms = $6, notes = $7, status = $8, tags = $9, \
+         currency = $10, language = $11, tax_treatment = COALESCE($12, 'standard'), tax_exemption_reason = $13, discount_percent = COALESCE($14, 0), \
+         credit_limit = $15, risk_status = COALESCE($16, 'normal') \
+         WHERE id = $17 AND user_id = $18 RETURNING id"
     )
-    .bind(payload.name)
-    .bind(payload.email)
-    .bind(payload.phone)
-    .bind(payload.address)
-    .bind(payload.tax_id)
+    .bind(&payload.name)
+    .bind(&payload.email)
+    .bind(&payload.phone)
+    .bind(&payload.address)
+    .bind(payload.canonical_tax_id())
     .bind(payload.payment_terms)
-    .bind(payload.notes)
-    .bind(payload.status)
+    .bind(&payload.notes)
+    .bind(&payload.status)
+    .bind(normalized_tags(&payload.tags))
+    .bind(&payload.currency)
+    .bind(&payload.language)
+    .bind(&payload.tax_treatment)
+    .bind(&payload.tax_exemption_reason)
+    .bind(payload.discount_percent)
+    .bind(payload.credit_limit)
+    .bind(&payload.risk_status)
     .bind(id)
     .bind(auth.user_id)
-    .fetch_one(&state.db)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(ApiError::NotFound("Client not found".to_string()))?;
+
+    save_addresses(&mut tx, id, &payload).await?;
+    let client = fetch_client(&mut *tx, id, auth.user_id).await?;
+    tx.commit().await?;
 
     Ok(Json(client))
 }
 
+async fn fetch_client<'e>(db: impl sqlx::PgExecutor<'e>, id: i32, user_id: i32) -> Result<Client, ApiError> {
+    sqlx::query_as::<_, Client>(&format!("SELECT {} {} WHERE id = $1 AND user_id = $2", CLIENT_LIST.columns, CLIENT_LIST.from))
+        .bind(id)
+        .bind(user_id)
+        .fetch_optional(db)
+        .await?
+        .ok_or(ApiError::NotFound("Client not found".to_string()))
+}
+
+async fn insert_client(tx: &mut sqlx::PgConnection, user_id: i32, payload: &CreateClientRequest) -> Result<i32, ApiError> {
+    let id: i32 = sqlx::query_scalar(
+        "INSERT INTO clients (user_id, name, email, phone, address, tax_id, payment_terms, notes, status, tags, currency, language, tax_treatment, tax_exemption_reason, discount_percent, credit_limit, risk_status) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, COALESCE($13, 'standard'), $14, COALESCE($15, 0), $16, COALESCE($17, 'normal')) RETURNING id"
+    )
+    .bind(user_id)
+    .bind(&payload.name)
+    .bind(&payload.email)
+    .bind(&payload.phone)
+    .bind(&payload.address)
+    .bind(payload.canonical_tax_id())
+    .bind(payload.payment_terms)
+    .bind(&payload.notes)
+    .bind(&payload.status)
+    .bind(normalized_tags(&payload.tags))
+    .bind(&payload.currency)
+    .bind(&payload.language)
+    .bind(&payload.tax_treatment)
+    .bind(&payload.tax_exemption_reason)
+    .bind(payload.discount_percent)
+    .bind(payload.credit_limit)
+    .bind(&payload.risk_status)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    save_addresses(tx, id, payload).await?;
+    Ok(id)
+}
+
+/// Replaces the client's billing and shipping addresses; an omitted one is removed.
+async fn save_addresses(tx: &mut sqlx::PgConnection, client_id: i32, payload: &CreateClientRequest) -> Result<(), ApiError> {
+    for (kind, address) in [("billing", &payload.billing_address), ("shipping", &payload.shipping_address)] {
+        match address {
+            Some(a) => upsert_address(tx, client_id, kind, a).await?,
+            None => {
+                sqlx::query("DELETE FROM client_addresses WHERE client_id = $1 AND kind = $2")
+                    .bind(client_id)
+                    .bind(kind)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn upsert_address(tx: &mut sqlx::PgConnection, client_id: i32, kind: &str, a: &Address) -> Result<(), ApiError> {
+    sqlx::query(
+        "INSERT INTO client_addresses (client_id, kind, street, city, postal_code, region, country) VALUES ($1, $2, $3, $4, $5, $6, $7) \
+         ON CONFLICT (client_id, kind) DO UPDATE SET street = EXCLUDED.street, city = EXCLUDED.city, postal_code = EXCLUDED.postal_code, region = EXCLUDED.region, country = EXCLUDED.country"
+    )
+    .bind(client_id)
+    .bind(kind)
+    .bind(&a.street)
+    .bind(&a.city)
+    .bind(&a.postal_code)
+    .bind(&a.region)
+    .bind(&a.country)
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+/// Only clients without invoices can be deleted, since deleting one would
+/// detach its invoices; the others are archived with `status: "archived"`.
 async fn delete_client(
     auth: AuthContext,
     Path(id): Path<i32>,
     State(state): State<Arc<AppState>>,
-) -> Result<StatusCode, (StatusCode, String)> {
-    let result = sqlx::query("DELETE FROM clients WHERE id = $1 AND user_id = $2")
+) -> Result<StatusCode, ApiError> {
+    let mut tx = state.db.begin().await?;
+    // Locking the row keeps an invoice from being created for it in the meantime
+    sqlx::query("SELECT id FROM clients WHERE id = $1 AND user_id = $2 FOR UPDATE")
         .bind(id)
         .bind(auth.user_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(ApiError::NotFound("Client not found".to_string()))?;
+
+    let invoices: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM invoices WHERE client_id = $1")
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await?;
+    if invoices > 0 {
+        return Err(ApiError::Conflict(format!(
+            "Client has {} invoice(s) and cannot be deleted; archive it instead",
+            invoices
+        )));
+    }
+
+    sqlx::query("DELETE FROM clients WHERE id = $1")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn ensure_client(db: &DbPool, id: i32, user_id: i32) -> Result<(), ApiError> {
+    let exists: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM clients WHERE id = $1 AND user_id = $2)")
+        .bind(id)
+        .bind(user_id)
+        .fetch_one(db)
+        .await?;
+
+    if !exists {
+        return Err(ApiError::NotFound("Client not found".to_string()));
+    }
+    Ok(())
+}
+
+async fn list_contacts(
+    auth: AuthContext,
+    Path(client_id): Path<i32>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<Contact>>, ApiError> {
+    ensure_client(&state.db, client_id, auth.user_id).await?;
+    let contacts = sqlx::query_as::<_, Contact>(&format!("SELECT {} FROM client_contacts ct WHERE ct.client_id = $1 ORDER BY ct.id", CONTACT_COLUMNS))
+        .bind(client_id)
+        .fetch_all(&state.db)
+        .await?;
+
+    Ok(Json(contacts))
+}
+
+async fn create_contact(
+    auth: AuthContext,
+    Path(client_id): Path<i32>,
+    State(state): State<Arc<AppState>>,
+    Valid(payload): Valid<ContactRequest>,
+) -> Result<Json<Contact>, ApiError> {
+    ensure_client(&state.db, client_id, auth.user_id).await?;
+    let contact = sqlx::query_as::<_, Contact>(&format!(
+        "INSERT INTO client_contacts AS ct (client_id, name, email, phone, role, invoice_recipient) VALUES ($1, $2, $3, $4, $5, $6) RETURNING {}",
+        CONTACT_COLUMNS
+    ))
+    .bind(client_id)
+    .bind(payload.name)
+    .bind(payload.email)
+    .bind(payload.phone)
+    .bind(payload.role)
+    .bind(payload.invoice_recipient)
+    .fetch_one(&state.db)
+    .await?;
+
+    Ok(Json(contact))
+}
+
+async fn get_contact(
+    auth: AuthContext,
+    Path((client_id, contact_id)): Path<(i32, i32)>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Contact>, ApiError> {
+    let contact = sqlx::query_as::<_, Contact>(&format!(
+        "SELECT {} FROM client_contacts ct JOIN clients c ON c.id = ct.client_id WHERE ct.id = $1 AND ct.client_id = $2 AND c.user_id = $3",
+        CONTACT_COLUMNS
+    ))
+    .bind(contact_id)
+    .bind(client_id)
+    .bind(auth.user_id)
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(ApiError::NotFound("Contact not found".to_string()))?;
+
+    Ok(Json(contact))
+}
+
+async fn update_contact(
+    auth: AuthContext,
+    Path((client_id, contact_id)): Path<(i32, i32)>,
+    State(state): State<Arc<AppState>>,
+    Valid(payload): Valid<ContactRequest>,
+) -> Result<Json<Contact>, ApiError> {
+    let contact = sqlx::query_as::<_, Contact>(&format!(
+        "UPDATE client_contacts ct SET name = $1, email = $2, phone = $3, role = $4, invoice_recipient = $5 \
+         FROM clients c WHERE c.id = ct.client_id AND ct.id = $6 AND ct.client_id = $7 AND c.user_id = $8 RETURNING {}",
+        CONTACT_COLUMNS
+    ))
+    .bind(payload.name)
+    .bind(payload.email)
+    .bind(payload.phone)
+    .bind(payload.role)
+    .bind(payload.invoice_recipient)
+    .bind(contact_id)
+    .bind(client_id)
+    .bind(auth.user_id)
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(ApiError::NotFound("Contact not found".to_string()))?;
+
+    Ok(Json(contact))
+}
+
+async fn delete_contact(
+    auth: AuthContext,
+    Path((client_id, contact_id)): Path<(i32, i32)>,
+    State(state): State<Arc<AppState>>,
+) -> Result<StatusCode, ApiError> {
+    let result = sqlx::query("DELETE FROM client_contacts ct USING clients c WHERE c.id = ct.client_id AND ct.id = $1 AND ct.client_id = $2 AND c.user_id = $3")
+        .bind(contact_id)
+        .bind(client_id)
+        .bind(auth.user_id)
         .execute(&state.db)
-        .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        .await?;
 
     if result.rows_affected() == 0 {
-        return Err((StatusCode::NOT_FOUND, "Client not found".to_string()));
+        return Err(ApiError::NotFound("Contact not found".to_string()));
     }
 
     Ok(StatusCode::NO_CONTENT)
 }
+
+async fn export_account_data(
+    auth: AuthContext,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<AccountDataPart>, ApiError> {
+    let clients = sqlx::query_as::<_, Client>(&format!("SELECT {} {} WHERE user_id = $1", CLIENT_LIST.columns, CLIENT_LIST.from))
+        .bind(auth.user_id)
+        .fetch_all(&state.db)
+        .await?;
+    let contacts = sqlx::query_as::<_, Contact>(&format!(
+        "SELECT {} FROM client_contacts ct JOIN clients c ON c.id = ct.client_id WHERE c.user_id = $1 ORDER BY ct.id",
+        CONTACT_COLUMNS
+    ))
+    .bind(auth.user_id)
+    .fetch_all(&state.db)
+    .await?;
+
+    let files = vec![
+        ArchiveFile::json("clients.json", &clients).map_err(ApiError::internal)?,
+        ArchiveFile::json("client_contacts.json", &contacts).map_err(ApiError::internal)?,
+    ];
+
+    Ok(Json(AccountDataPart { files }))
+}
+
+async fn erase_account_data(
+    auth: AuthContext,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<ErasureReport>, ApiError> {
+    let result = sqlx::query("DELETE FROM clients WHERE user_id = $1")
+        .bind(auth.user_id)
+        .execute(&state.db)
+        .await?;
+
+    tracing::info!("Erased {} clients for user {}", result.rows_affected(), auth.user_id);
+    Ok(Json(ErasureReport { deleted: result.rows_affected(), anonymized: 0 }))
+}
@@ -0,0 +1,45 @@
+//! Contract for GDPR export and erasure across services.
+//!
+//! Each service that stores account data serves `GET /internal/account-data/<part>`
+//! returning an [`AccountDataPart`], and `DELETE` on the same path to erase or
+//! anonymize it. auth-service calls every part with the user's own token and
+//! assembles the archive. These routes are not exposed through the gateway.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+/// One file in the export archive, e.g. `clients.json` or `invoices/INV-1001.pdf`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchiveFile {
+    pub path: String,
+    /// Base64 so binary files survive the JSON transport.
+    pub content: String,
+}
+
+impl ArchiveFile {
+    pub fn json<T: Serialize>(path: impl Into<String>, value: &T) -> Result<Self, serde_json::Error> {
+        Ok(Self::bytes(path, &serde_json::to_vec_pretty(value)?))
+    }
+
+    pub fn bytes(path: impl Into<String>, bytes: &[u8]) -> Self {
+        ArchiveFile { path: path.into(), content: STANDARD.encode(bytes) }
+    }
+
+    pub fn decode(&self) -> Result<Vec<u8>, base64::DecodeError> {
+        STANDARD.decode(&self.content)
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct AccountDataPart {
+    pub files: Vec<ArchiveFile>,
+}
+
+/// Summary returned by a part's erase endpoint.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ErasureReport {
+    pub deleted: u64,
+    /// Records kept for legal retention with personal data stripped.
+    pub anonymized: u64,
+}
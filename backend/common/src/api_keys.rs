@@ -0,0 +1,163 @@
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+
+/// Prefix that marks a bearer credential as an API key rather than a JWT.
+pub const API_KEY_PREFIX: &str = "bk_";
+
+/// Every scope an API key can be granted, as `<resource>:<action>`.
+pub const API_KEY_SCOPES: &[&str] = &[
+    "invoices:read",
+    "invoices:write",
+    "estimates:read",
+    "estimates:write",
+    "recurring:read",
+    "recurring:write",
+    "plans:read",
+    "plans:write",
+    "subscriptions:read",
+    "subscriptions:write",
+    "usage:read",
+    "usage:write",
+    "reports:read",
+    "credit-notes:read",
+    "credit-notes:write",
+    "clients:read",
+    "clients:write",
+    "products:read",
+    "products:write",
+    "price-lists:read",
+    "price-lists:write",
+    "stock-movements:read",
+    "stock-movements:write",
+    "company:read",
+    "company:write",
+    "ai:write",
+];
+
+pub fn is_api_key(token: &str) -> bool {
+    token.starts_with(API_KEY_PREFIX)
+}
+
+/// API keys are long random strings, so a plain SHA-256 digest is enough to
+/// store them safely; only this hash ever reaches the database.
+pub fn hash_api_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+pub fn is_known_scope(scope: &str) -> bool {
+    API_KEY_SCOPES.contains(&scope)
+}
+
+/// Scope required to call `method path`, derived from the first path segment
+/// after `/api/`, or after `/internal/` for calls a service makes on the
+/// caller's behalf. Read-only methods need `:read`, everything else `:write`.
+/// Returns `None` for routes API keys may never call (e.g. `/api/auth/*`).
+pub fn required_scope(method: &str, path: &str) -> Option<String> {
+    let resource = path.strip_prefix("/api/").or_else(|| path.strip_prefix("/internal/"))?.split('/').next()?;
+    if resource.is_empty() || resource == "auth" {
+        return None;
+    }
+    let action = match method {
+        "GET" | "HEAD" | "OPTIONS" => "read",
+        _ => "write",
+    };
+    Some(format!("{}:{}", resource, action))
+}
+
+/// A `:write` grant also covers reads of the same resource.
+pub fn scope_allows(granted: &[String], required: &str) -> bool {
+    granted.iter().any(|s| {
+        s == required
+            || (required.ends_with(":read")
+                && s.strip_suffix(":write") == required.strip_suffix(":read"))
+    })
+}
+
+pub struct VerifiedApiKey {
+    pub user_id: i32,
+    pub scopes: Vec<String>,
+}
+
+/// Looks up an unexpired key by hash and stamps its `last_used_at`.
+pub async fn verify_api_key(db: &PgPool, key: &str) -> Result<Option<VerifiedApiKey>, sqlx::Error> {
+    let row: Option<(i32, Vec<String>)> = sqlx::query_as(
+        "UPDATE api_keys SET last_used_at = NOW() \
+         WHERE key_hash = $1 AND expires_at > NOW() \
+         RETURNING user_id, scopes"
+    )
+    .bind(hash_api_key(key))
+    .fetch_optional(db)
+    .await?;
+
+    Ok(row.map(|(user_id, scopes)| VerifiedApiKey { user_id, scopes }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scopes(granted: &[&str]) -> Vec<String> {
+        granted.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn maps_routes_to_their_resource_and_action() {
+        assert_eq!(required_scope("GET", "/api/invoices").as_deref(), Some("invoices:read"));
+        assert_eq!(required_scope("HEAD", "/api/invoices/5/pdf").as_deref(), Some("invoices:read"));
+        assert_eq!(required_scope("POST", "/api/invoices/5/payments").as_deref(), Some("invoices:write"));
+        assert_eq!(required_scope("DELETE", "/api/credit-notes/3").as_deref(), Some("credit-notes:write"));
+        assert_eq!(required_scope("PUT", "/api/company").as_deref(), Some("company:write"));
+    }
+
+    #[test]
+    fn internal_calls_need_the_scope_of_their_resource() {
+        assert_eq!(required_scope("GET", "/internal/clients/7/ledger").as_deref(), Some("clients:read"));
+        // Not a grantable scope, so no API key reaches account data
+        let scope = required_scope("GET", "/internal/account-data/invoices").unwrap();
+        assert!(!is_known_scope(&scope));
+    }
+
+    #[test]
+    fn api_keys_never_reach_auth_or_unknown_paths() {
+        assert_eq!(required_scope("GET", "/api/auth/api-keys"), None);
+        assert_eq!(required_scope("POST", "/api/auth/login"), None);
+        assert_eq!(required_scope("GET", "/api/"), None);
+        assert_eq!(required_scope("GET", "/healthz"), None);
+        assert_eq!(required_scope("GET", "/apiinvoices"), None);
+    }
+
+    #[test]
+    fn every_routed_resource_has_read_and_write_scopes() {
+        for scope in API_KEY_SCOPES {
+            let (resource, action) = scope.split_once(':').unwrap();
+            assert!(matches!(action, "read" | "write"), "{}", scope);
+            assert_eq!(required_scope("POST", &format!("/api/{}", resource)).as_deref(), Some(format!("{}:write", resource).as_str()));
+        }
+    }
+
+    #[test]
+    fn write_grants_cover_reads_of_the_same_resource() {
+        let granted = scopes(&["invoices:write", "clients:read"]);
+        assert!(scope_allows(&granted, "invoices:write"));
+        assert!(scope_allows(&granted, "invoices:read"));
+        assert!(scope_allows(&granted, "clients:read"));
+        assert!(!scope_allows(&granted, "clients:write"));
+        assert!(!scope_allows(&granted, "products:read"));
+        assert!(!scope_allows(&scopes(&[]), "invoices:read"));
+    }
+
+    #[test]
+    fn grants_match_whole_resources_and_actions() {
+        assert!(!scope_allows(&scopes(&["credit-notes:write"]), "credit:read"));
+        assert!(!scope_allows(&scopes(&["invoices:read"]), "invoices:write"));
+    }
+
+    #[test]
+    fn recognises_and_hashes_keys() {
+        assert!(is_api_key("bk_abc"));
+        assert!(!is_api_key("eyJhbGciOi"));
+        assert_eq!(hash_api_key("bk_abc"), hash_api_key("bk_abc"));
+        assert_ne!(hash_api_key("bk_abc"), hash_api_key("bk_abd"));
+        assert_eq!(hash_api_key("bk_abc").len(), 64);
+    }
+}
@@ -0,0 +1,129 @@
+//! Billing settings shared by clients (as defaults) and invoices (as applied),
+//! and the wording invoices are printed with.
+
+pub const TAX_TREATMENTS: &[&str] = &["standard", "reverse_charge", "exempt"];
+
+/// Languages invoices can be printed in.
+pub const DOCUMENT_LANGUAGES: &[&str] = &["en", "de", "fr", "es", "it", "nl"];
+
+pub const DEFAULT_LANGUAGE: &str = "en";
+pub const DEFAULT_CURRENCY: &str = "USD";
+pub const DEFAULT_PAYMENT_TERMS: i32 = 30;
+
+/// The product tax category charged at the company's `default_tax_rate`;
+/// other categories take their rate from the company's `tax_rates`.
+pub const STANDARD_TAX_CATEGORY: &str = "standard";
+
+/// Fixed wording of an invoice document in one language.
+pub struct DocumentText {
+    pub invoice: &'static str,
+    pub date: &'static str,
+    pub due_date: &'static str,
+    pub bill_to: &'static str,
+    pub subtotal: &'static str,
+    pub discount: &'static str,
+    pub total: &'static str,
+    /// Required on invoices where the buyer accounts for VAT (Art. 226(11a) of
+    /// Directive 2006/112/EC).
+    pub reverse_charge: &'static str,
+    /// Followed by the exemption reason.
+    pub exempt: &'static str,
+}
+
+impl DocumentText {
+    /// The wording for `language`, English for unknown ones.
+    pub fn for_language(language: &str) -> &'static DocumentText {
+        match language {
+            "de" => &DE,
+            "fr" => &FR,
+            "es" => &ES,
+            "it" => &IT,
+            "nl" => &NL,
+            _ => &EN,
+        }
+    }
+
+    /// The tax notice an invoice with `treatment` must carry, if any.
+    pub fn tax_notice(&self, treatment: &str, exemption_reason: Option<&str>) -> Option<String> {
+        match treatment {
+            "reverse_charge" => Some(self.reverse_charge.to_string()),
+            "exempt" => Some(match exemption_reason {
+                Some(reason) => format!("{}: {}", self.exempt, reason),
+                None => self.exempt.to_string(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+const EN: DocumentText = DocumentText {
+    invoice: "INVOICE",
+    date: "Date",
+    due_date: "Due date",
+    bill_to: "Bill to",
+    subtotal: "Subtotal",
+    discount: "Discount",
+    total: "TOTAL",
+    reverse_charge: "Reverse charge",
+    exempt: "VAT exempt",
+};
+
+const DE: DocumentText = DocumentText {
+    invoice: "RECHNUNG",
+    date: "Datum",
+    due_date: "Fällig am",
+    bill_to: "Rechnung an",
+    subtotal: "Zwischensumme",
+    discount: "Rabatt",
+    total: "GESAMT",
+    reverse_charge: "Steuerschuldnerschaft des Leistungsempfängers",
+    exempt: "Steuerfrei",
+};
+
+const FR: DocumentText = DocumentText {
+    invoice: "FACTURE",
+    date: "Date",
+    due_date: "Échéance",
+    bill_to: "Facturé à",
+    subtotal: "Sous-total",
+    discount: "Remise",
+    total: "TOTAL",
+    reverse_charge: "Autoliquidation",
+    exempt: "Exonération de TVA",
+};
+
+const ES: DocumentText = DocumentText {
+    invoice: "FACTURA",
+    date: "Fecha",
+    due_date: "Vencimiento",
+    bill_to: "Facturar a",
+    subtotal: "Subtotal",
+    discount: "Descuento",
+    total: "TOTAL",
+    reverse_charge: "Inversión del sujeto pasivo",
+    exempt: "Exento de IVA",
+};
+
+const IT: DocumentText = DocumentText {
+    invoice: "FATTURA",
+    date: "Data",
+    due_date: "Scadenza",
+    bill_to: "Intestata a",
+    subtotal: "Imponibile",
+    discount: "Sconto",
+    total: "TOTALE",
+    reverse_charge: "Inversione contabile",
+    exempt: "Esente IVA",
+};
+
+const NL: DocumentText = DocumentText {
+    invoice: "FACTUUR",
+    date: "Datum",
+    due_date: "Vervaldatum",
+    bill_to: "Factuur aan",
+    subtotal: "Subtotaal",
+    discount: "Korting",
+    total: "TOTAAL",
+    reverse_charge: "Btw verlegd",
+    exempt: "Vrijgesteld van btw",
+};
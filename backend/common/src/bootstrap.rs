@@ -0,0 +1,637 @@
+//! Startup and shutdown shared by every service.
+//!
+//! `main` calls [`init`] for logging and a validated [`ServiceConfig`],
+//! connects to the database through the config, builds its router and hands
+//! it to [`serve`]. `serve` adds `/healthz`, `/readyz` and `/metrics`,
+//! request tracing, CORS and request IDs, and on SIGTERM or Ctrl-C stops
+//! accepting connections and drains in-flight requests for up to
+//! `SHUTDOWN_GRACE_SECS`.
+//!
+//! Settings are read from the environment after loading `.env` and, if set,
+//! `CONFIG_FILE` (same `KEY=value` format). Variables already in the
+//! environment take precedence over either file.
+//!
+//! - `HOST`, `PORT`: listen address; the port defaults per service.
+//! - `DATABASE_URL`, `DATABASE_MAX_CONNECTIONS` (5), `DATABASE_ACQUIRE_TIMEOUT_SECS` (10).
+//! - `RUN_MIGRATIONS` (true): apply pending migrations when connecting.
+//! - `CORS_ALLOWED_ORIGINS`: comma-separated origins, or `*`. Unset allows
+//!   none, which is right behind the gateway.
+//! - `SHUTDOWN_GRACE_SECS` (30).
+//! - `AUTH_JWKS_URL`: auth-service's `/.well-known/jwks.json`, required by
+//!   every service verifying session tokens.
+//! - `JWT_KEYS_DIR`, `JWT_ACTIVE_KID`: auth-service's signing keys.
+//! - `TRUSTED_PROXIES`: comma-separated addresses or CIDR blocks of the
+//!   gateway, whose `X-Forwarded-For` auth-service believes. Unset believes
+//!   no one.
+//! - `SMTP_URL` (`smtp://`, or `smtps://` for TLS), `MAIL_FROM`: auth-service's
+//!   outgoing mail. Unset, nothing is sent.
+//! - `INVOICE_SERVICE_URL`, `CLIENT_SERVICE_URL`, `PRODUCT_SERVICE_URL`,
+//!   `COMPANY_SERVICE_URL`: other services' base URLs, defaulting to their
+//!   local ports.
+//!
+//! Startup problems are fatal: they are logged and the process exits with
+//! status 1, so the orchestrator's restart policy takes over.
+
+use axum::{
+    extract::State,
+    http::{header, HeaderName, HeaderValue, Method, StatusCode},
+    routing::get,
+    Json, Router,
+};
+use serde_json::{json, Value};
+use sqlx::postgres::{PgPool, PgPoolOptions};
+use std::fmt::Display;
+use std::future::IntoFuture;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::migrations::MIGRATOR;
+use crate::{metrics, telemetry, JwtVerifier};
+
+const READY_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct ServiceConfig {
+    /// Name used in logs, e.g. `client-service`.
+    pub service: &'static str,
+    pub listen_addr: SocketAddr,
+    /// Absent when `DATABASE_URL` is not set.
+    pub database: Option<DatabaseConfig>,
+    pub cors_origins: CorsOrigins,
+    pub shutdown_grace: Duration,
+    pub jwt: JwtConfig,
+    pub trusted_proxies: Vec<IpNet>,
+    pub mail: MailConfig,
+    pub service_urls: ServiceUrls,
+}
+
+pub struct DatabaseConfig {
+    pub url: String,
+    pub max_connections: u32,
+    pub acquire_timeout: Duration,
+    pub run_migrations: bool,
+}
+
+pub struct JwtConfig {
+    /// Where session tokens' keys are published; absent when `AUTH_JWKS_URL` is not set.
+    pub jwks_url: Option<String>,
+    /// Signing keys, read by auth-service only.
+    pub keys_dir: Option<PathBuf>,
+    pub active_kid: Option<String>,
+}
+
+/// Outgoing mail, sent by auth-service only.
+pub struct MailConfig {
+    /// The SMTP server; absent when `SMTP_URL` is not set.
+    pub smtp_url: Option<String>,
+    pub from: String,
+}
+
+/// Base URLs of the services called over `/internal/*`, without a trailing slash.
+pub struct ServiceUrls {
+    pub invoice: String,
+    pub client: String,
+    pub product: String,
+    pub company: String,
+}
+
+/// An address or a CIDR block, e.g. `10.0.0.7` or `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| ())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|&p| p <= max).ok_or(())?,
+            None => max,
+        };
+        Ok(IpNet { addr, prefix })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum CorsOrigins {
+    None,
+    Any,
+    List(Vec<HeaderValue>),
+}
+
+/// Every invalid setting, so a bad deploy is fixed in one pass.
+#[derive(Debug, thiserror::Error)]
+#[error("invalid configuration: {}", .0.join("; "))]
+pub struct ConfigError(pub Vec<String>);
+
+/// Loads `.env` and `CONFIG_FILE` into the environment without overriding it.
+pub fn load_env_files() -> Result<(), ConfigError> {
+    dotenvy::dotenv().ok();
+    if let Ok(path) = std::env::var("CONFIG_FILE") {
+        dotenvy::from_path(&path).map_err(|e| ConfigError(vec![format!("CONFIG_FILE {}: {}", path, e)]))?;
+    }
+    Ok(())
+}
+
+/// Sets up logging and tracing (see [`crate::telemetry`]) and loads the
+/// configuration, exiting if it is invalid.
+pub fn init(service: &'static str, default_port: u16) -> ServiceConfig {
+    let env_files = load_env_files();
+    telemetry::init(service);
+
+    env_files
+        .and_then(|()| ServiceConfig::from_env(service, default_port))
+        .unwrap_or_else(|e| fatal(e))
+}
+
+impl ServiceConfig {
+    pub fn from_env(service: &'static str, default_port: u16) -> Result<Self, ConfigError> {
+        let mut env = Env::default();
+        let host = env.parse("HOST", IpAddr::V4(Ipv4Addr::UNSPECIFIED), |_| true, "an IP address");
+        let port = env.parse("PORT", default_port, |&p| p > 0, "a port number");
+        let database = DatabaseConfig::read(&mut env);
+        let cors_origins = env.cors_origins("CORS_ALLOWED_ORIGINS");
+        let shutdown_grace = env.parse("SHUTDOWN_GRACE_SECS", 30, |&s| s <= 600, "seconds between 0 and 600");
+        let jwt = JwtConfig {
+            jwks_url: env.url("AUTH_JWKS_URL"),
+            keys_dir: env.get("JWT_KEYS_DIR").map(PathBuf::from),
+            active_kid: env.get("JWT_ACTIVE_KID"),
+        };
+        let trusted_proxies = env.ip_nets("TRUSTED_PROXIES");
+        let mail = MailConfig {
+            smtp_url: env.smtp_url("SMTP_URL"),
+            from: env.get("MAIL_FROM").unwrap_or_else(|| "Billio <no-reply@billio.local>".to_string()),
+        };
+        let service_urls = ServiceUrls {
+            invoice: env.url("INVOICE_SERVICE_URL").unwrap_or_else(|| "http://localhost:5002".to_string()),
+            client: env.url("CLIENT_SERVICE_URL").unwrap_or_else(|| "http://localhost:5003".to_string()),
+            product: env.url("PRODUCT_SERVICE_URL").unwrap_or_else(|| "http://localhost:5004".to_string()),
+            company: env.url("COMPANY_SERVICE_URL").unwrap_or_else(|| "http://localhost:5005".to_string()),
+        };
+        env.finish()?;
+
+        Ok(ServiceConfig {
+            service,
+            listen_addr: SocketAddr::new(host, port),
+            database,
+            cors_origins,
+            shutdown_grace: Duration::from_secs(shutdown_grace),
+            jwt,
+            trusted_proxies,
+            mail,
+            service_urls,
+        })
+    }
+
+    /// Verifies session tokens against `AUTH_JWKS_URL`. Exits if it is unset.
+    pub fn jwt_verifier(&self) -> JwtVerifier {
+        let Some(url) = &self.jwt.jwks_url else { fatal("AUTH_JWKS_URL must be set to auth-service's /.well-known/jwks.json") };
+        JwtVerifier::from_jwks_url(url.clone())
+    }
+
+    /// Connects to the database and applies pending migrations. Exits if
+    /// `DATABASE_URL` is unset or either step fails.
+    pub async fn connect_database(&self) -> PgPool {
+        let Some(db) = &self.database else { fatal("DATABASE_URL must be set") };
+        let pool = db
+            .pool_options()
+            .connect(&db.url)
+            .await
+            .unwrap_or_else(|e| fatal(format!("Failed to connect to the database: {}", e)));
+
+        if db.run_migrations {
+            MIGRATOR.run(&pool).await.unwrap_or_else(|e| fatal(format!("Failed to run database migrations: {}", e)));
+        } else {
+            tracing::info!("RUN_MIGRATIONS is off, not applying database migrations");
+        }
+        pool
+    }
+
+    /// A pool that connects on first use, for services where the database is
+    /// optional. `None` when `DATABASE_URL` is unset.
+    pub fn lazy_database(&self) -> Option<PgPool> {
+        self.database.as_ref().map(|db| {
+            db.pool_options()
+                .connect_lazy(&db.url)
+                .unwrap_or_else(|e| fatal(format!("Invalid DATABASE_URL: {}", e)))
+        })
+    }
+
+    fn cors_layer(&self) -> CorsLayer {
+        let origins = match &self.cors_origins {
+            CorsOrigins::None => return CorsLayer::new(),
+            CorsOrigins::Any => return CorsLayer::permissive(),
+            CorsOrigins::List(origins) => origins.clone(),
+        };
+        CorsLayer::new()
+            .allow_origin(AllowOrigin::list(origins))
+            .allow_methods([Method::GET, Method::POST, Method::PUT, Method::PATCH, Method::DELETE])
+            .allow_headers([
+                header::AUTHORIZATION,
+                header::CONTENT_TYPE,
+                HeaderName::from_static("x-api-key"),
+                HeaderName::from_static("x-request-id"),
+            ])
+            .expose_headers([header::CONTENT_DISPOSITION, HeaderName::from_static("x-request-id")])
+            .max_age(Duration::from_secs(600))
+    }
+}
+
+impl DatabaseConfig {
+    /// Database settings alone, for tools that do not serve HTTP.
+    pub fn from_env() -> Result<Option<Self>, ConfigError> {
+        let mut env = Env::default();
+        let database = DatabaseConfig::read(&mut env);
+        env.finish()?;
+        Ok(database)
+    }
+
+    fn read(env: &mut Env) -> Option<Self> {
+        let url = env.get("DATABASE_URL");
+        if let Some(url) = &url {
+            if !url.starts_with("postgres://") && !url.starts_with("postgresql://") {
+                env.invalid("DATABASE_URL", "a postgres:// URL");
+            }
+        }
+        let max_connections = env.parse("DATABASE_MAX_CONNECTIONS", 5, |&n| (1..=100).contains(&n), "between 1 and 100");
+        let acquire_timeout = env.parse("DATABASE_ACQUIRE_TIMEOUT_SECS", 10, |&s| (1..=300).contains(&s), "seconds between 1 and 300");
+        let run_migrations = env.parse("RUN_MIGRATIONS", true, |_| true, "true or false");
+
+        url.map(|url| DatabaseConfig {
+            url,
+            max_connections,
+            acquire_timeout: Duration::from_secs(acquire_timeout),
+            run_migrations,
+        })
+    }
+
+    pub fn pool_options(&self) -> PgPoolOptions {
+        PgPoolOptions::new()
+            .max_connections(self.max_connections)
+            .acquire_timeout(self.acquire_timeout)
+    }
+}
+
+#[derive(Default)]
+struct Env {
+    errors: Vec<String>,
+}
+
+impl Env {
+    fn get(&self, key: &str) -> Option<String> {
+        std::env::var(key).ok().map(|v| v.trim().to_string()).filter(|v| !v.is_empty())
+    }
+
+    fn invalid(&mut self, key: &str, expected: &str) {
+        self.errors.push(format!("{} must be {}", key, expected));
+    }
+
+    fn parse<T: FromStr>(&mut self, key: &str, default: T, valid: impl Fn(&T) -> bool, expected: &str) -> T {
+        let Some(raw) = self.get(key) else { return default };
+        let parsed = match raw.as_str() {
+            // Accept the usual spellings of booleans; FromStr only takes true/false.
+            "1" | "yes" | "on" => T::from_str("true").ok(),
+            "0" | "no" | "off" => T::from_str("false").ok(),
+            _ => None,
+        };
+        match parsed.or_else(|| raw.parse().ok()) {
+            Some(value) if valid(&value) => value,
+            _ => {
+                self.invalid(key, expected);
+                default
+            }
+        }
+    }
+
+    fn url(&mut self, key: &str) -> Option<String> {
+        let raw = self.get(key)?;
+        let rest = raw.strip_prefix("https://").or_else(|| raw.strip_prefix("http://"));
+        if rest.map_or(true, |host| host.is_empty() || host.starts_with('/')) {
+            self.invalid(key, "an http:// or https:// URL");
+            return None;
+        }
+        Some(raw.trim_end_matches('/').to_string())
+    }
+
+    fn ip_nets(&mut self, key: &str) -> Vec<IpNet> {
+        let Some(raw) = self.get(key) else { return Vec::new() };
+        let mut nets = Vec::new();
+        for net in raw.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            match net.parse() {
+                Ok(net) => nets.push(net),
+                Err(()) => self.invalid(key, "comma-separated IP addresses or CIDR blocks like 10.0.0.0/8"),
+            }
+        }
+        nets
+    }
+
+    fn smtp_url(&mut self, key: &str) -> Option<String> {
+        let raw = self.get(key)?;
+        let rest = raw.strip_prefix("smtp://").or_else(|| raw.strip_prefix("smtps://"));
+        if rest.map_or(true, |host| host.is_empty() || host.starts_with('/')) {
+            self.invalid(key, "an smtp:// or smtps:// URL");
+            return None;
+        }
+        Some(raw)
+    }
+
+    fn cors_origins(&mut self, key: &str) -> CorsOrigins {
+        let Some(raw) = self.get(key) else { return CorsOrigins::None };
+        if raw == "*" {
+            return CorsOrigins::Any;
+        }
+        let mut origins = Vec::new();
+        for origin in raw.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+            let rest = origin.strip_prefix("https://").or_else(|| origin.strip_prefix("http://"));
+            match (rest, HeaderValue::from_str(origin)) {
+                (Some(host), Ok(value)) if !host.is_empty() && !host.contains('/') => origins.push(value),
+                _ => self.invalid(key, "`*` or comma-separated origins like https://app.example.com"),
+            }
+        }
+        CorsOrigins::List(origins)
+    }
+
+    fn finish(self) -> Result<(), ConfigError> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError(self.errors))
+        }
+    }
+}
+
+/// Logs a startup problem and exits with status 1.
+pub fn fatal(message: impl Display) -> ! {
+    tracing::error!("{}", message);
+    std::process::exit(1)
+}
+
+/// Serves `app` with health checks, metrics and the shared layers until
+/// SIGTERM or Ctrl-C, then drains. `db` is what `/readyz` checks.
+pub async fn serve(config: ServiceConfig, app: Router, db: Option<PgPool>) {
+    // Probes and scrapes stay out of the request metrics and traces.
+    let operational = Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(scrape))
+        .with_state(db.clone());
+
+    let app = app
+        .layer(axum::middleware::from_fn(telemetry::observe))
+        .merge(operational)
+        .layer(config.cors_layer())
+        .layer(axum::middleware::from_fn(crate::request_id));
+
+    let listener = tokio::net::TcpListener::bind(config.listen_addr)
+        .await
+        .unwrap_or_else(|e| fatal(format!("Failed to listen on {}: {}", config.listen_addr, e)));
+    tracing::info!("{} listening on {}", config.service, config.listen_addr);
+
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let mut server = tokio::spawn(
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(async {
+                stopped.await.ok();
+            })
+            .into_future(),
+    );
+
+    tokio::select! {
+        result = &mut server => {
+            if let Err(e) = result.map_err(|e| e.to_string()).and_then(|r| r.map_err(|e| e.to_string())) {
+                fatal(format!("{} stopped: {}", config.service, e));
+            }
+            return;
+        }
+        _ = shutdown_signal() => {}
+    }
+
+    tracing::info!("{} shutting down, draining requests for up to {:?}", config.service, config.shutdown_grace);
+    stop.send(()).ok();
+    match tokio::time::timeout(config.shutdown_grace, server).await {
+        Ok(_) => tracing::info!("{} stopped", config.service),
+        Err(_) => tracing::warn!("{} stopped with requests still in flight", config.service),
+    }
+    if let Some(db) = db {
+        db.close().await;
+    }
+    telemetry::shutdown().await;
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.ok();
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+/// Prometheus scrape target.
+async fn scrape() -> ([(header::HeaderName, &'static str); 1], String) {
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], metrics::render())
+}
+
+/// Liveness: the process is up and serving.
+async fn healthz() -> Json<Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+/// Readiness: the database answers, if the service has one.
+async fn readyz(State(db): State<Option<PgPool>>) -> (StatusCode, Json<Value>) {
+    let Some(db) = db else {
+        return (StatusCode::OK, Json(json!({ "status": "ok" })));
+    };
+    match tokio::time::timeout(READY_CHECK_TIMEOUT, sqlx::query("SELECT 1").execute(&db)).await {
+        Ok(Ok(_)) => (StatusCode::OK, Json(json!({ "status": "ok", "database": "ok" }))),
+        Ok(Err(e)) => {
+            tracing::warn!("Readiness check failed: {}", e);
+            (StatusCode::SERVICE_UNAVAILABLE, Json(json!({ "status": "unavailable", "database": "error" })))
+        }
+        Err(_) => {
+            tracing::warn!("Readiness check timed out");
+            (StatusCode::SERVICE_UNAVAILABLE, Json(json!({ "status": "unavailable", "database": "timeout" })))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// `from_env` reads the process environment, so its tests take turns.
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    /// Runs `read` with `key` set to `value`. Keys are unique per test so
+    /// the helpers' tests can run in parallel.
+    fn with_var<T>(key: &str, value: &str, read: impl FnOnce(&mut Env) -> T) -> (T, Vec<String>) {
+        std::env::set_var(key, value);
+        let mut env = Env::default();
+        let result = read(&mut env);
+        std::env::remove_var(key);
+        (result, env.errors)
+    }
+
+    #[test]
+    fn booleans_take_the_usual_spellings() {
+        for (raw, expected) in [("1", true), ("yes", true), ("on", true), ("true", true), ("0", false), ("no", false), ("off", false), ("false", false)] {
+            let (value, errors) = with_var("BOOTSTRAP_TEST_BOOL", raw, |env| env.parse("BOOTSTRAP_TEST_BOOL", !expected, |_| true, "true or false"));
+            assert_eq!(value, expected, "{}", raw);
+            assert!(errors.is_empty());
+        }
+
+        let (value, errors) = with_var("BOOTSTRAP_TEST_BAD_BOOL", "maybe", |env| env.parse("BOOTSTRAP_TEST_BAD_BOOL", true, |_| true, "true or false"));
+        assert!(value);
+        assert_eq!(errors, ["BOOTSTRAP_TEST_BAD_BOOL must be true or false"]);
+    }
+
+    #[test]
+    fn numbers_outside_their_range_fall_back_and_are_reported() {
+        let (value, errors) = with_var("BOOTSTRAP_TEST_NUMBER", " 42 ", |env| env.parse("BOOTSTRAP_TEST_NUMBER", 5u32, |&n| n <= 100, "at most 100"));
+        assert_eq!((value, errors.len()), (42, 0));
+
+        let (value, errors) = with_var("BOOTSTRAP_TEST_BIG_NUMBER", "101", |env| env.parse("BOOTSTRAP_TEST_BIG_NUMBER", 5u32, |&n| n <= 100, "at most 100"));
+        assert_eq!((value, errors.len()), (5, 1));
+
+        // Only booleans get the extra spellings.
+        let (value, errors) = with_var("BOOTSTRAP_TEST_YES_NUMBER", "yes", |env| env.parse("BOOTSTRAP_TEST_YES_NUMBER", 5u32, |_| true, "a number"));
+        assert_eq!((value, errors.len()), (5, 1));
+    }
+
+    #[test]
+    fn urls_need_an_http_scheme_and_a_host() {
+        let (url, errors) = with_var("BOOTSTRAP_TEST_URL", "http://auth-service:5001/", |env| env.url("BOOTSTRAP_TEST_URL"));
+        assert_eq!(url.as_deref(), Some("http://auth-service:5001"));
+        assert!(errors.is_empty());
+
+        for raw in ["auth-service:5001", "ftp://auth-service", "https://", "http:///jwks.json"] {
+            let (url, errors) = with_var("BOOTSTRAP_TEST_BAD_URL", raw, |env| env.url("BOOTSTRAP_TEST_BAD_URL"));
+            assert_eq!(url, None, "{}", raw);
+            assert_eq!(errors, ["BOOTSTRAP_TEST_BAD_URL must be an http:// or https:// URL"]);
+        }
+
+        let (url, errors) = with_var("BOOTSTRAP_TEST_SMTP_URL", "https://mail.example.com", |env| env.smtp_url("BOOTSTRAP_TEST_SMTP_URL"));
+        assert_eq!((url, errors.len()), (None, 1));
+    }
+
+    #[test]
+    fn cors_origins_are_bare_origins_or_any() {
+        let (origins, errors) = with_var("BOOTSTRAP_TEST_CORS_ANY", "*", |env| env.cors_origins("BOOTSTRAP_TEST_CORS_ANY"));
+        assert!(matches!(origins, CorsOrigins::Any));
+        assert!(errors.is_empty());
+
+        let (origins, errors) = with_var("BOOTSTRAP_TEST_CORS", "https://app.example.com, http://localhost:3000,", |env| env.cors_origins("BOOTSTRAP_TEST_CORS"));
+        assert!(matches!(origins, CorsOrigins::List(ref list) if list == &["https://app.example.com", "http://localhost:3000"]));
+        assert!(errors.is_empty());
+
+        let (origins, errors) = with_var("BOOTSTRAP_TEST_CORS_PATH", "https://app.example.com/, app.example.com", |env| env.cors_origins("BOOTSTRAP_TEST_CORS_PATH"));
+        assert!(matches!(origins, CorsOrigins::List(ref list) if list.is_empty()));
+        assert_eq!(errors.len(), 2);
+
+        let mut env = Env::default();
+        assert!(matches!(env.cors_origins("BOOTSTRAP_TEST_CORS_UNSET"), CorsOrigins::None));
+    }
+
+    #[test]
+    fn ip_nets_match_addresses_inside_the_block() {
+        let net: IpNet = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains("10.1.200.3".parse().unwrap()));
+        assert!(!net.contains("10.2.0.1".parse().unwrap()));
+        assert!(net.contains("::ffff:10.1.0.9".parse().unwrap()));
+
+        let host: IpNet = "fd00::1".parse().unwrap();
+        assert!(host.contains("fd00::1".parse().unwrap()));
+        assert!(!host.contains("fd00::2".parse().unwrap()));
+        assert!("0.0.0.0/0".parse::<IpNet>().unwrap().contains("203.0.113.9".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("gateway".parse::<IpNet>().is_err());
+
+        let (nets, errors) = with_var("BOOTSTRAP_TEST_PROXIES", "10.0.0.7, 172.16.0.0/12, nonsense", |env| env.ip_nets("BOOTSTRAP_TEST_PROXIES"));
+        assert_eq!(nets.len(), 2);
+        assert_eq!(errors.len(), 1);
+    }
+
+    /// Variables `from_env` reads that no other test in this crate depends on.
+    const SERVICE_VARS: [&str; 5] = ["PORT", "SHUTDOWN_GRACE_SECS", "CORS_ALLOWED_ORIGINS", "AUTH_JWKS_URL", "TRUSTED_PROXIES"];
+
+    fn from_env_with(vars: &[(&str, &str)]) -> Result<ServiceConfig, ConfigError> {
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        for key in SERVICE_VARS {
+            std::env::remove_var(key);
+        }
+        for (key, value) in vars {
+            std::env::set_var(key, value);
+        }
+        let config = ServiceConfig::from_env("test-service", 5999);
+        for key in SERVICE_VARS {
+            std::env::remove_var(key);
+        }
+        config
+    }
+
+    #[test]
+    fn from_env_collects_every_invalid_setting() {
+        let Err(ConfigError(errors)) = from_env_with(&[
+            ("PORT", "0"),
+            ("SHUTDOWN_GRACE_SECS", "forever"),
+            ("CORS_ALLOWED_ORIGINS", "https://app.example.com/login"),
+            ("AUTH_JWKS_URL", "auth-service/.well-known/jwks.json"),
+        ]) else {
+            panic!("an invalid configuration was accepted");
+        };
+        let keys: Vec<&str> = errors.iter().map(|e| e.split(' ').next().unwrap()).collect();
+        assert_eq!(keys, ["PORT", "CORS_ALLOWED_ORIGINS", "SHUTDOWN_GRACE_SECS", "AUTH_JWKS_URL"]);
+    }
+
+    #[test]
+    fn from_env_defaults_what_is_unset() {
+        let config = from_env_with(&[("TRUSTED_PROXIES", "10.0.0.0/8")]).unwrap();
+        assert_eq!(config.listen_addr.port(), 5999);
+        assert_eq!(config.shutdown_grace, Duration::from_secs(30));
+        assert!(matches!(config.cors_origins, CorsOrigins::None));
+        assert_eq!(config.jwt.jwks_url, None);
+        assert_eq!(config.trusted_proxies, ["10.0.0.0/8".parse().unwrap()]);
+    }
+}
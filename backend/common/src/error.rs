@@ -0,0 +1,296 @@
+//! The error type every handler returns.
+//!
+//! Errors render as `application/problem+json` with a stable `code` and the
+//! request ID. Clients only ever see a short message; the underlying error,
+//! including any Postgres text, goes to the logs under the same request ID.
+
+use axum::{
+    extract::rejection::{JsonRejection, QueryRejection},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+use crate::request_id::current_request_id;
+use crate::validation::FieldError;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    Unauthorized(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("{0}")]
+    Unprocessable(String),
+    /// Per-field failures from [`crate::Validator`].
+    #[error("Validation failed: {0:?}")]
+    Validation(Vec<FieldError>),
+    #[error("{0}")]
+    TooManyRequests(String),
+    /// A service this one depends on failed; the message names it.
+    #[error("{0}")]
+    BadGateway(String),
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+    #[error("{0}")]
+    Internal(String),
+}
+
+/// Attached to error responses so [`crate::telemetry::observe`] can count
+/// them by code.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ErrorCode(pub &'static str);
+
+/// Body of every error response.
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    pub status: u16,
+    pub code: &'static str,
+    pub title: &'static str,
+    pub detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errors: Option<Vec<FieldError>>,
+}
+
+impl ApiError {
+    pub fn internal(e: impl std::fmt::Display) -> Self {
+        ApiError::Internal(e.to_string())
+    }
+
+    /// Replaces the generic message of a unique violation, e.g. to say which
+    /// field is taken. Any other error is returned unchanged.
+    pub fn on_conflict(self, message: impl Into<String>) -> Self {
+        match self {
+            ApiError::Database(sqlx::Error::Database(ref db)) if db.is_unique_violation() => {
+                ApiError::Conflict(message.into())
+            }
+            other => other,
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.classify().0
+    }
+
+    /// The message that is safe to show the client, e.g. in a batch report.
+    pub fn detail(&self) -> String {
+        self.classify().2
+    }
+
+    /// Status, error code and the message that is safe to show the client.
+    fn classify(&self) -> (StatusCode, &'static str, String) {
+        match self {
+            ApiError::BadRequest(m) => (StatusCode::BAD_REQUEST, "bad_request", m.clone()),
+            ApiError::Unauthorized(m) => (StatusCode::UNAUTHORIZED, "unauthorized", m.clone()),
+            ApiError::Forbidden(m) => (StatusCode::FORBIDDEN, "forbidden", m.clone()),
+            ApiError::NotFound(m) => (StatusCode::NOT_FOUND, "not_found", m.clone()),
+            ApiError::Conflict(m) => (StatusCode::CONFLICT, "conflict", m.clone()),
+            ApiError::Unprocessable(m) => (StatusCode::UNPROCESSABLE_ENTITY, "unprocessable", m.clone()),
+            ApiError::Validation(_) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "validation_failed",
+                "One or more fields are invalid".to_string(),
+            ),
+            ApiError::TooManyRequests(m) => (StatusCode::TOO_MANY_REQUESTS, "too_many_requests", m.clone()),
+            ApiError::BadGateway(m) => (StatusCode::BAD_GATEWAY, "upstream_error", m.clone()),
+            ApiError::Database(sqlx::Error::RowNotFound) => {
+                (StatusCode::NOT_FOUND, "not_found", "Resource not found".to_string())
+            }
+            ApiError::Database(sqlx::Error::Database(db)) if db.is_unique_violation() => {
+                (StatusCode::CONFLICT, "conflict", "A record with the same unique value already exists".to_string())
+            }
+            ApiError::Database(sqlx::Error::Database(db)) if db.is_foreign_key_violation() => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_reference",
+                "A referenced record does not exist or is still in use".to_string(),
+            ),
+            ApiError::Database(sqlx::Error::Database(db)) if db.is_check_violation() => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "constraint_violation",
+                "The request violates a data constraint".to_string(),
+            ),
+            ApiError::Database(_) | ApiError::Internal(_) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "internal_error", "Internal server error".to_string())
+            }
+        }
+    }
+}
+
+/// Malformed JSON is a 400; JSON with missing or mistyped fields is a 422.
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        match rejection {
+            JsonRejection::JsonDataError(e) => ApiError::Unprocessable(e.body_text()),
+            other => ApiError::BadRequest(other.body_text()),
+        }
+    }
+}
+
+/// For handlers taking `Result<Query<T>, QueryRejection>`, so a bad query
+/// string gets the same problem response as everything else.
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        ApiError::BadRequest(rejection.body_text())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, code, detail) = self.classify();
+        let request_id = current_request_id();
+
+        if status.is_server_error() {
+            tracing::error!(request_id = request_id.as_deref(), code, "{}", self);
+        } else if let ApiError::Database(e) = &self {
+            tracing::warn!(request_id = request_id.as_deref(), code, "{}", e);
+        } else {
+            tracing::debug!(request_id = request_id.as_deref(), code, "{}", self);
+        }
+
+        let problem = Problem {
+            status: status.as_u16(),
+            code,
+            title: status.canonical_reason().unwrap_or("Error"),
+            detail,
+            request_id,
+            errors: match self {
+                ApiError::Validation(errors) => Some(errors),
+                _ => None,
+            },
+        };
+
+        let mut response = (status, Json(problem)).into_response();
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_static("application/problem+json"),
+        );
+        response.extensions_mut().insert(ErrorCode(code));
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::{FromRequest, Query};
+    use axum::http::{Request, Uri};
+    use serde::Deserialize;
+    use serde_json::Value;
+    use sqlx::PgPool;
+
+    async fn body(response: Response) -> Value {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    fn summary(error: &ApiError) -> (u16, &'static str) {
+        let (status, code, _) = error.classify();
+        (status.as_u16(), code)
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn maps_database_errors_to_client_errors(db: PgPool) {
+        sqlx::query(
+            "CREATE TABLE things (id INTEGER PRIMARY KEY, quantity INTEGER CHECK (quantity > 0), parent_id INTEGER REFERENCES things(id))"
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO things (id, quantity) VALUES (1, 1)").execute(&db).await.unwrap();
+        let insert = |sql: &'static str| {
+            let db = db.clone();
+            async move { ApiError::from(sqlx::query(sql).execute(&db).await.unwrap_err()) }
+        };
+
+        assert_eq!(summary(&insert("INSERT INTO things (id, quantity) VALUES (1, 2)").await), (409, "conflict"));
+        assert_eq!(summary(&insert("INSERT INTO things (id, quantity, parent_id) VALUES (2, 1, 99)").await), (422, "invalid_reference"));
+        assert_eq!(summary(&insert("INSERT INTO things (id, quantity) VALUES (3, 0)").await), (422, "constraint_violation"));
+
+        let missing = sqlx::query_scalar::<_, i32>("SELECT id FROM things WHERE id = 42").fetch_one(&db).await.unwrap_err();
+        assert_eq!(summary(&ApiError::from(missing)), (404, "not_found"));
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn hides_other_database_errors_behind_a_500(db: PgPool) {
+        let error = ApiError::from(sqlx::query("SELECT secret FROM no_such_table").execute(&db).await.unwrap_err());
+        assert_eq!(summary(&error), (500, "internal_error"));
+        assert_eq!(error.detail(), "Internal server error");
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn names_the_taken_field_only_for_unique_violations(db: PgPool) {
+        sqlx::query("CREATE TABLE skus (sku TEXT UNIQUE)").execute(&db).await.unwrap();
+        sqlx::query("INSERT INTO skus VALUES ('A-1')").execute(&db).await.unwrap();
+
+        let taken = ApiError::from(sqlx::query("INSERT INTO skus VALUES ('A-1')").execute(&db).await.unwrap_err());
+        assert!(matches!(taken.on_conflict("SKU is already in use"), ApiError::Conflict(m) if m == "SKU is already in use"));
+        assert!(matches!(ApiError::NotFound("gone".to_string()).on_conflict("taken"), ApiError::NotFound(_)));
+    }
+
+    #[test]
+    fn internal_errors_do_not_leak_their_message() {
+        let error = ApiError::internal("connection refused to 10.0.0.3");
+        assert_eq!(summary(&error), (500, "internal_error"));
+        assert_eq!(error.detail(), "Internal server error");
+    }
+
+    #[tokio::test]
+    async fn renders_a_problem() {
+        let response = ApiError::NotFound("Client not found".to_string()).into_response();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/problem+json");
+        assert_eq!(response.extensions().get::<ErrorCode>().map(|c| c.0), Some("not_found"));
+        let problem = body(response).await;
+        assert_eq!(problem["status"], 404);
+        assert_eq!(problem["title"], "Not Found");
+        assert_eq!(problem["detail"], "Client not found");
+        assert!(problem.get("request_id").is_none());
+        assert!(problem.get("errors").is_none());
+    }
+
+    #[tokio::test]
+    async fn lists_field_errors_on_a_validation_problem() {
+        let errors = vec![FieldError { field: "email".to_string(), message: "must be a valid email".to_string() }];
+        let response = ApiError::Validation(errors).into_response();
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let problem = body(response).await;
+        assert_eq!(problem["code"], "validation_failed");
+        assert_eq!(problem["errors"][0]["field"], "email");
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[allow(dead_code)]
+    struct Payload {
+        quantity: u32,
+    }
+
+    async fn json_error(body: &'static str) -> ApiError {
+        let request = Request::post("/").header(header::CONTENT_TYPE, "application/json").body(axum::body::Body::from(body)).unwrap();
+        ApiError::from(Json::<Payload>::from_request(request, &()).await.unwrap_err())
+    }
+
+    #[tokio::test]
+    async fn malformed_json_is_a_400_and_mistyped_json_a_422() {
+        assert_eq!(summary(&json_error("{\"quantity\":").await), (400, "bad_request"));
+        assert_eq!(summary(&json_error("{\"quantity\":\"two\"}").await), (422, "unprocessable"));
+        assert_eq!(summary(&json_error("{}").await), (422, "unprocessable"));
+    }
+
+    #[test]
+    fn a_bad_query_string_is_a_400() {
+        let uri: Uri = "/?quantity=-1".parse().unwrap();
+        let error = ApiError::from(Query::<Payload>::try_from_uri(&uri).unwrap_err());
+        assert_eq!(summary(&error), (400, "bad_request"));
+    }
+}
@@ -0,0 +1,304 @@
+//! Stock levels of products that track inventory.
+//!
+//! A product's `stock_on_hand` only changes through a stock movement recorded
+//! in the same transaction as whatever caused it: invoice-service moves stock
+//! when an invoice is issued, edited, voided or deleted and when a credit note
+//! returns goods, and product-service records manual adjustments. Products
+//! that don't track inventory have no movements.
+
+use sqlx::{FromRow, PgConnection};
+
+pub struct Movement<'a> {
+    pub product_id: i32,
+    /// Positive into stock, negative out of it.
+    pub quantity: f64,
+    /// `issue`, `void`, `credit_note` or `adjustment`.
+    pub kind: &'a str,
+    pub invoice_id: Option<i32>,
+    pub credit_note_id: Option<i32>,
+    pub reason: Option<&'a str>,
+}
+
+/// A product's stock after a movement.
+#[derive(Debug, FromRow)]
+pub struct StockLevel {
+    pub product_id: i32,
+    pub name: String,
+    pub stock_on_hand: f64,
+    pub low_stock_threshold: Option<f64>,
+}
+
+impl StockLevel {
+    /// What to warn about once stock has gone down to this level.
+    pub fn warning(&self) -> Option<String> {
+        if self.stock_on_hand < 0.0 {
+            Some(format!("{} is oversold: {:.2} in stock", self.name, self.stock_on_hand))
+        } else if self.low_stock_threshold.is_some_and(|t| self.stock_on_hand <= t) {
+            Some(format!("{} is low on stock: {:.2} left", self.name, self.stock_on_hand))
+        } else {
+            None
+        }
+    }
+}
+
+/// Records a movement of the user's product and updates its stock, or returns
+/// `None` (recording nothing) when the product doesn't track inventory.
+pub async fn record(conn: &mut PgConnection, user_id: i32, movement: &Movement<'_>) -> Result<Option<StockLevel>, sqlx::Error> {
+    // The update locks the product row, so concurrent movements apply in turn
+    let level = sqlx::query_as::<_, StockLevel>(
+        "UPDATE products SET stock_on_hand = stock_on_hand + $1 \
+         WHERE id = $2 AND user_id = $3 AND track_inventory \
+         RETURNING id AS product_id, name, stock_on_hand::float8 AS stock_on_hand, low_stock_threshold::float8 AS low_stock_threshold"
+    )
+    .bind(movement.quantity)
+    .bind(movement.product_id)
+    .bind(user_id)
+    .fetch_optional(&mut *conn)
+    .await?;
+    if level.is_none() {
+        return Ok(None);
+    }
+
+    sqlx::query(
+        "INSERT INTO stock_movements (user_id, product_id, quantity, kind, invoice_id, credit_note_id, reason) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7)"
+    )
+    .bind(user_id)
+    .bind(movement.product_id)
+    .bind(movement.quantity)
+    .bind(movement.kind)
+    .bind(movement.invoice_id)
+    .bind(movement.credit_note_id)
+    .bind(movement.reason)
+    .execute(&mut *conn)
+    .await?;
+    Ok(level)
+}
+
+/// Brings the stock taken by an invoice in line with its lines: an `issued`
+/// invoice holds its lines' quantities out of stock, any other holds none.
+/// Issuing, editing, voiding and deleting an invoice all come down to moving
+/// the difference. A bundle line takes its components' stock. Returns the
+/// products whose stock went down.
+pub async fn sync_invoice(conn: &mut PgConnection, user_id: i32, invoice_id: i32, issued: bool) -> Result<Vec<StockLevel>, sqlx::Error> {
+    let changes: Vec<(i32, f64)> = sqlx::query_as(
+        "WITH wanted AS ( \
+           SELECT COALESCE(bc.component_id, ii.product_id) AS product_id, -SUM(ii.quantity * COALESCE(bc.quantity, 1)) AS quantity \
+           FROM invoice_items ii LEFT JOIN bundle_components bc ON bc.bundle_id = ii.product_id \
+           WHERE ii.invoice_id = $1 AND ii.product_id IS NOT NULL AND $3 GROUP BY 1 \
+         ), moved AS ( \
+           SELECT product_id, SUM(quantity) AS quantity FROM stock_movements \
+           WHERE invoice_id = $1 AND kind IN ('issue', 'void') GROUP BY product_id \
+         ) \
+         SELECT p.id, (COALESCE(w.quantity, 0) - COALESCE(m.quantity, 0))::float8 \
+         FROM products p LEFT JOIN wanted w ON w.product_id = p.id LEFT JOIN moved m ON m.product_id = p.id \
+         WHERE p.user_id = $2 AND p.track_inventory AND (w.product_id IS NOT NULL OR m.product_id IS NOT NULL) \
+           AND COALESCE(w.quantity, 0) <> COALESCE(m.quantity, 0) \
+         ORDER BY p.id"
+    )
+    .bind(invoice_id)
+    .bind(user_id)
+    .bind(issued)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let mut reduced = Vec::new();
+    for (product_id, quantity) in changes {
+        let kind = if issued { "issue" } else { "void" };
+        let movement = Movement { product_id, quantity, kind, invoice_id: Some(invoice_id), credit_note_id: None, reason: None };
+        if let Some(level) = record(conn, user_id, &movement).await? {
+            if quantity < 0.0 {
+                reduced.push(level);
+            }
+        }
+    }
+    Ok(reduced)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::PgPool;
+
+    async fn user(db: &PgPool) -> i32 {
+        sqlx::query_scalar("INSERT INTO users (email, password_hash) VALUES ('stock@example.com', 'x') RETURNING id")
+            .fetch_one(db)
+            .await
+            .unwrap()
+    }
+
+    async fn product(db: &PgPool, user_id: i32, name: &str, tracked: bool, stock: f64) -> i32 {
+        sqlx::query_scalar(
+            "INSERT INTO products (user_id, name, track_inventory, stock_on_hand, low_stock_threshold) VALUES ($1, $2, $3, $4, 2) RETURNING id"
+        )
+        .bind(user_id)
+        .bind(name)
+        .bind(tracked)
+        .bind(stock)
+        .fetch_one(db)
+        .await
+        .unwrap()
+    }
+
+    async fn invoice(db: &PgPool, user_id: i32, lines: &[(i32, f64)]) -> i32 {
+        let id: i32 = sqlx::query_scalar("INSERT INTO invoices (user_id, invoice_number) VALUES ($1, 'INV-1') RETURNING id")
+            .bind(user_id)
+            .fetch_one(db)
+            .await
+            .unwrap();
+        for &(product_id, quantity) in lines {
+            sqlx::query("INSERT INTO invoice_items (invoice_id, product_id, description, quantity) VALUES ($1, $2, 'Line', $3)")
+                .bind(id)
+                .bind(product_id)
+                .bind(quantity)
+                .execute(db)
+                .await
+                .unwrap();
+        }
+        id
+    }
+
+    async fn set_quantity(db: &PgPool, invoice_id: i32, quantity: f64) {
+        sqlx::query("UPDATE invoice_items SET quantity = $2 WHERE invoice_id = $1")
+            .bind(invoice_id)
+            .bind(quantity)
+            .execute(db)
+            .await
+            .unwrap();
+    }
+
+    async fn sync(db: &PgPool, user_id: i32, invoice_id: i32, issued: bool) -> Vec<i32> {
+        let mut conn = db.acquire().await.unwrap();
+        let reduced = sync_invoice(&mut conn, user_id, invoice_id, issued).await.unwrap();
+        reduced.into_iter().map(|level| level.product_id).collect()
+    }
+
+    async fn stock(db: &PgPool, product_id: i32) -> f64 {
+        sqlx::query_scalar("SELECT stock_on_hand::float8 FROM products WHERE id = $1")
+            .bind(product_id)
+            .fetch_one(db)
+            .await
+            .unwrap()
+    }
+
+    async fn movements(db: &PgPool, product_id: i32) -> Vec<(String, f64)> {
+        sqlx::query_as("SELECT kind, quantity::float8 FROM stock_movements WHERE product_id = $1 ORDER BY id")
+            .bind(product_id)
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test(migrator = "crate::MIGRATOR")]
+    async fn issuing_takes_tracked_stock_only(db: PgPool) {
+        let user_id = user(&db).await;
+        let widget = product(&db, user_id, "Widget", true, 10.0).await;
+        let service = product(&db, user_id, "Setup", false, 0.0).await;
+        let invoice_id = invoice(&db, user_id, &[(widget, 3.0), (service, 1.0)]).await;
+
+        assert_eq!(sync(&db, user_id, invoice_id, true).await, [widget]);
+        assert_eq!(stock(&db, widget).await, 7.0);
+        assert_eq!(movements(&db, widget).await, [("issue".to_string(), -3.0)]);
+        assert_eq!(stock(&db, service).await, 0.0);
+        assert!(movements(&db, service).await.is_empty());
+
+        // Syncing an unchanged invoice moves nothing
+        assert!(sync(&db, user_id, invoice_id, true).await.is_empty());
+        assert_eq!(movements(&db, widget).await.len(), 1);
+    }
+
+    #[sqlx::test(migrator = "crate::MIGRATOR")]
+    async fn editing_moves_the_difference(db: PgPool) {
+        let user_id = user(&db).await;
+        let widget = product(&db, user_id, "Widget", true, 10.0).await;
+        let invoice_id = invoice(&db, user_id, &[(widget, 3.0)]).await;
+        sync(&db, user_id, invoice_id, true).await;
+
+        set_quantity(&db, invoice_id, 5.0).await;
+        assert_eq!(sync(&db, user_id, invoice_id, true).await, [widget]);
+        assert_eq!(stock(&db, widget).await, 5.0);
+
+        set_quantity(&db, invoice_id, 1.0).await;
+        assert!(sync(&db, user_id, invoice_id, true).await.is_empty());
+        assert_eq!(stock(&db, widget).await, 9.0);
+
+        let quantities: Vec<f64> = movements(&db, widget).await.into_iter().map(|(_, q)| q).collect();
+        assert_eq!(quantities, [-3.0, -2.0, 4.0]);
+    }
+
+    #[sqlx::test(migrator = "crate::MIGRATOR")]
+    async fn voiding_and_deleting_put_stock_back(db: PgPool) {
+        let user_id = user(&db).await;
+        let widget = product(&db, user_id, "Widget", true, 10.0).await;
+        let voided = invoice(&db, user_id, &[(widget, 3.0)]).await;
+        let deleted = invoice(&db, user_id, &[(widget, 4.0)]).await;
+        sync(&db, user_id, voided, true).await;
+        sync(&db, user_id, deleted, true).await;
+        assert_eq!(stock(&db, widget).await, 3.0);
+
+        assert!(sync(&db, user_id, voided, false).await.is_empty());
+        assert_eq!(stock(&db, widget).await, 6.0);
+        assert!(sync(&db, user_id, voided, false).await.is_empty());
+        assert_eq!(stock(&db, widget).await, 6.0);
+
+        // A deleted invoice has no lines left by the time it is synced
+        sqlx::query("DELETE FROM invoice_items WHERE invoice_id = $1").bind(deleted).execute(&db).await.unwrap();
+        sync(&db, user_id, deleted, false).await;
+        assert_eq!(stock(&db, widget).await, 10.0);
+    }
+
+    #[sqlx::test(migrator = "crate::MIGRATOR")]
+    async fn bundles_take_their_tracked_components(db: PgPool) {
+        let user_id = user(&db).await;
+        let camera = product(&db, user_id, "Camera", true, 10.0).await;
+        let strap = product(&db, user_id, "Strap", true, 10.0).await;
+        let guide = product(&db, user_id, "Guide", false, 0.0).await;
+        let kit = product(&db, user_id, "Kit", false, 0.0).await;
+        for (component, quantity) in [(camera, 1.0), (strap, 2.0), (guide, 1.0)] {
+            sqlx::query("INSERT INTO bundle_components (bundle_id, component_id, quantity) VALUES ($1, $2, $3)")
+                .bind(kit)
+                .bind(component)
+                .bind(quantity)
+                .execute(&db)
+                .await
+                .unwrap();
+        }
+        // A kit and a loose strap on the same invoice
+        let invoice_id = invoice(&db, user_id, &[(kit, 2.0), (strap, 1.0)]).await;
+
+        assert_eq!(sync(&db, user_id, invoice_id, true).await, [camera, strap]);
+        assert_eq!(stock(&db, camera).await, 8.0);
+        assert_eq!(stock(&db, strap).await, 5.0);
+        assert!(movements(&db, kit).await.is_empty());
+        assert!(movements(&db, guide).await.is_empty());
+
+        sync(&db, user_id, invoice_id, false).await;
+        assert_eq!((stock(&db, camera).await, stock(&db, strap).await), (10.0, 10.0));
+    }
+
+    #[sqlx::test(migrator = "crate::MIGRATOR")]
+    async fn records_nothing_for_untracked_or_foreign_products(db: PgPool) {
+        let user_id = user(&db).await;
+        let service = product(&db, user_id, "Setup", false, 0.0).await;
+        let widget = product(&db, user_id, "Widget", true, 10.0).await;
+        let mut conn = db.acquire().await.unwrap();
+        let movement = |product_id| Movement { product_id, quantity: 5.0, kind: "adjustment", invoice_id: None, credit_note_id: None, reason: Some("Count") };
+
+        assert!(record(&mut conn, user_id, &movement(service)).await.unwrap().is_none());
+        assert!(record(&mut conn, user_id + 1, &movement(widget)).await.unwrap().is_none());
+        assert!(movements(&db, service).await.is_empty());
+        assert!(movements(&db, widget).await.is_empty());
+
+        let level = record(&mut conn, user_id, &movement(widget)).await.unwrap().unwrap();
+        assert_eq!(level.stock_on_hand, 15.0);
+        assert_eq!(movements(&db, widget).await, [("adjustment".to_string(), 5.0)]);
+    }
+
+    #[test]
+    fn warns_about_low_and_oversold_stock() {
+        let level = |stock_on_hand| StockLevel { product_id: 1, name: "Widget".to_string(), stock_on_hand, low_stock_threshold: Some(2.0) };
+        assert_eq!(level(3.0).warning(), None);
+        assert_eq!(level(2.0).warning().as_deref(), Some("Widget is low on stock: 2.00 left"));
+        assert_eq!(level(-1.0).warning().as_deref(), Some("Widget is oversold: -1.00 in stock"));
+    }
+}
@@ -0,0 +1,181 @@
+//! Contract for a client's ledger, owned by invoice-service.
+//!
+//! invoice-service serves `GET /internal/clients/:id/ledger?from=&to=`
+//! returning a [`ClientLedger`]: every issued invoice, payment and credit
+//! note of the client up to `to`, with those before `from` summed into the
+//! opening balance. client-service calls it with the user's own token to
+//! build account statements. Like the account-data routes, it is not exposed
+//! through the gateway.
+
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LedgerQuery {
+    /// First day of the period; without it the ledger starts at the first entry.
+    pub from: Option<NaiveDate>,
+    /// Last day of the period, and the date balances and aging are taken at.
+    pub to: NaiveDate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    Invoice,
+    Payment,
+    CreditNote,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LedgerEntry {
+    pub date: NaiveDate,
+    pub kind: EntryKind,
+    /// Invoice or credit note number, or the payment's reference.
+    pub reference: Option<String>,
+    /// The invoice a payment or credit note applies to.
+    pub invoice_number: Option<String>,
+    /// Positive for invoices, negative for payments and credit notes.
+    pub amount: f64,
+}
+
+/// An invoice with an outstanding amount at the ledger's `to` date.
+#[derive(Debug, Clone, Deserialize, Serialize, sqlx::FromRow)]
+pub struct OpenInvoice {
+    pub invoice_id: i32,
+    pub invoice_number: String,
+    pub issue_date: NaiveDate,
+    pub due_date: Option<NaiveDate>,
+    pub outstanding: f64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ClientLedger {
+    pub opening_balance: f64,
+    /// Entries within the period, oldest first.
+    pub entries: Vec<LedgerEntry>,
+    pub open_invoices: Vec<OpenInvoice>,
+}
+
+impl ClientLedger {
+    pub fn closing_balance(&self) -> f64 {
+        self.opening_balance + self.entries.iter().map(|e| e.amount).sum::<f64>()
+    }
+}
+
+/// The balance split by how long it has been overdue.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Aging {
+    /// Not yet due.
+    pub current: f64,
+    pub days_1_30: f64,
+    pub days_31_60: f64,
+    pub days_61_90: f64,
+    pub days_over_90: f64,
+    /// Balance not owed on any open invoice, e.g. a credit note on account (negative).
+    pub unapplied: f64,
+}
+
+impl Aging {
+    /// Buckets each open invoice by days past its due date (its issue date if
+    /// it has none) at `as_of`; whatever of `balance` is left over is unapplied.
+    pub fn of(open_invoices: &[OpenInvoice], as_of: NaiveDate, balance: f64) -> Self {
+        let mut aging = Aging::default();
+        for invoice in open_invoices {
+            let overdue = (as_of - invoice.due_date.unwrap_or(invoice.issue_date)).num_days();
+            let bucket = match overdue {
+                i64::MIN..=0 => &mut aging.current,
+                1..=30 => &mut aging.days_1_30,
+                31..=60 => &mut aging.days_31_60,
+                61..=90 => &mut aging.days_61_90,
+                _ => &mut aging.days_over_90,
+            };
+            *bucket += invoice.outstanding;
+        }
+        let aged = aging.current + aging.days_1_30 + aging.days_31_60 + aging.days_61_90 + aging.days_over_90;
+        aging.unapplied = round_cents(balance - aged);
+        aging
+    }
+}
+
+/// Rounds sums of `float8` amounts back to whole cents (and `-0.0`, the sum
+/// of nothing, to `0.0`).
+pub fn round_cents(amount: f64) -> f64 {
+    (amount * 100.0).round() / 100.0 + 0.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(value: &str) -> NaiveDate {
+        value.parse().unwrap()
+    }
+
+    fn open(number: &str, issue_date: &str, due_date: Option<&str>, outstanding: f64) -> OpenInvoice {
+        OpenInvoice {
+            invoice_id: 0,
+            invoice_number: number.to_string(),
+            issue_date: date(issue_date),
+            due_date: due_date.map(date),
+            outstanding,
+        }
+    }
+
+    fn entry(kind: EntryKind, amount: f64) -> LedgerEntry {
+        LedgerEntry { date: date("2026-10-01"), kind, reference: None, invoice_number: None, amount }
+    }
+
+    #[test]
+    fn closing_balance_nets_payments_and_credit_notes_against_invoices() {
+        let ledger = ClientLedger {
+            opening_balance: 50.0,
+            entries: vec![entry(EntryKind::Invoice, 120.0), entry(EntryKind::Payment, -100.0), entry(EntryKind::CreditNote, -20.0)],
+            open_invoices: Vec::new(),
+        };
+        assert_eq!(ledger.closing_balance(), 50.0);
+        assert_eq!(ClientLedger::default().closing_balance(), 0.0);
+    }
+
+    #[test]
+    fn ages_open_invoices_by_days_past_due() {
+        let as_of = date("2026-10-19");
+        let invoices = [
+            open("A", "2026-10-01", Some("2026-10-19"), 1.0),
+            open("B", "2026-09-01", Some("2026-10-18"), 2.0),
+            open("C", "2026-09-01", Some("2026-09-19"), 4.0),
+            open("D", "2026-08-01", Some("2026-09-18"), 8.0),
+            open("E", "2026-07-01", Some("2026-08-20"), 16.0),
+            open("F", "2026-07-01", Some("2026-07-21"), 32.0),
+            open("G", "2026-06-01", Some("2026-07-20"), 64.0),
+        ];
+        let aging = Aging::of(&invoices, as_of, 127.0);
+        assert_eq!(aging.current, 1.0);
+        assert_eq!(aging.days_1_30, 2.0 + 4.0);
+        assert_eq!(aging.days_31_60, 8.0 + 16.0);
+        assert_eq!(aging.days_61_90, 32.0);
+        assert_eq!(aging.days_over_90, 64.0);
+        assert_eq!(aging.unapplied, 0.0);
+    }
+
+    #[test]
+    fn ages_an_invoice_without_due_date_from_its_issue_date() {
+        let aging = Aging::of(&[open("A", "2026-09-01", None, 10.0)], date("2026-10-19"), 10.0);
+        assert_eq!(aging.days_31_60, 10.0);
+    }
+
+    #[test]
+    fn reports_credit_on_account_as_unapplied() {
+        let aging = Aging::of(&[open("A", "2026-10-01", Some("2026-10-31"), 30.0)], date("2026-10-19"), 20.0);
+        assert_eq!(aging.current, 30.0);
+        assert_eq!(aging.unapplied, -10.0);
+        assert_eq!(Aging::of(&[], date("2026-10-19"), -15.5).unapplied, -15.5);
+    }
+
+    #[test]
+    fn rounds_to_cents_without_negative_zero() {
+        assert_eq!(round_cents(0.1 + 0.2), 0.3);
+        assert_eq!(round_cents(10.005_000_1), 10.01);
+        assert!(round_cents(-0.0).is_sign_positive());
+        assert!(round_cents(-0.001).is_sign_positive());
+    }
+}
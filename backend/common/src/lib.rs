@@ -1,75 +1,102 @@
-use serde::{Deserialize, Serialize};
-use jsonwebtoken::{encode, decode, Header, Algorithm, Validation, EncodingKey, DecodingKey};
-use chrono::{Utc, Duration};
+pub mod account_data;
+pub mod billing;
+pub mod bootstrap;
+pub mod inventory;
+pub mod ledger;
+mod api_keys;
+mod error;
+mod jwt;
+mod list_query;
+pub mod metrics;
+mod migrations;
+pub mod pricing;
+mod request_id;
+pub mod tax_id;
+pub mod telemetry;
+mod validation;
+
+pub use api_keys::{
+    hash_api_key, is_api_key, is_known_scope, required_scope, scope_allows, verify_api_key,
+    VerifiedApiKey, API_KEY_PREFIX, API_KEY_SCOPES,
+};
+pub use error::{ApiError, Problem};
+pub use jwt::{create_jwt, Claims, JwtError, JwtVerifier, JWT_ALGORITHM};
+pub use list_query::{FieldKind, Filter, FilterOp, ListQuery, ListSpec, Page, SortKey, DEFAULT_LIMIT, MAX_LIMIT};
+pub use migrations::MIGRATOR;
+pub use request_id::{current_request_id, request_id, REQUEST_ID_HEADER};
+pub use validation::{FieldError, Valid, Validate, Validator};
+
 use std::sync::Arc;
 use axum::{
     async_trait,
     extract::FromRequestParts,
-    http::{request::Parts, StatusCode},
+    http::request::Parts,
 };
+use sqlx::PgPool;
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Claims {
-    pub sub: i32,
-    pub exp: usize,
-    pub iat: usize,
+/// Implemented by each service's state so `AuthContext` can reach the JWT
+/// verifier and, where the service has a database, the API key table.
+pub trait AuthState {
+    fn jwt_verifier(&self) -> &JwtVerifier;
+    fn db(&self) -> Option<&PgPool>;
 }
 
-pub fn create_jwt(user_id: i32, secret: &str) -> Result<String, jsonwebtoken::errors::Error> {
-    let expiration = Utc::now()
-        .checked_add_signed(Duration::days(7))
-        .expect("valid timestamp")
-        .timestamp();
-
-    let claims = Claims {
-        sub: user_id,
-        exp: expiration as usize,
-        iat: Utc::now().timestamp() as usize,
-    };
-
-    encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(secret.as_ref()),
-    )
-}
+impl<T: AuthState> AuthState for Arc<T> {
+    fn jwt_verifier(&self) -> &JwtVerifier {
+        (**self).jwt_verifier()
+    }
 
-pub fn verify_jwt(token: &str, secret: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
-    decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(secret.as_ref()),
-        &Validation::new(Algorithm::HS256),
-    )
-    .map(|data| data.claims)
+    fn db(&self) -> Option<&PgPool> {
+        (**self).db()
+    }
 }
 
 pub struct AuthContext {
     pub user_id: i32,
+    /// `None` for user sessions (full access), the granted scopes for API keys.
+    pub scopes: Option<Vec<String>>,
 }
 
 #[async_trait]
 impl<S> FromRequestParts<S> for AuthContext
 where
-    S: Send + Sync,
-    Arc<String>: axum::extract::FromRef<S>,
+    S: AuthState + Send + Sync,
 {
-    type Rejection = (StatusCode, String);
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let api_key_header = parts.headers.get("X-Api-Key").and_then(|h| h.to_str().ok());
+        let token = match api_key_header {
+            Some(key) => key,
+            None => {
+                let auth_header = parts.headers.get("Authorization")
+                    .and_then(|h| h.to_str().ok())
+                    .ok_or_else(|| ApiError::Unauthorized("Missing authorization header".to_string()))?;
 
-    async fn from_request_parts(parts: &Parts, state: &S) -> Result<Self, Self::Rejection> {
-        let secret = axum::extract::FromRef::<S>::from_ref(state);
-        
-        let auth_header = parts.headers.get("Authorization")
-            .and_then(|h| h.to_str().ok())
-            .ok_or((StatusCode::UNAUTHORIZED, "Missing authorization header".to_string()))?;
+                auth_header.strip_prefix("Bearer ")
+                    .ok_or_else(|| ApiError::Unauthorized("Invalid authorization header".to_string()))?
+            }
+        };
 
-        if !auth_header.starts_with("Bearer ") {
-            return Err((StatusCode::UNAUTHORIZED, "Invalid authorization header".to_string()));
+        if !is_api_key(token) {
+            let claims = state.jwt_verifier().verify(token)
+                .await
+                .map_err(|_| ApiError::Unauthorized("Invalid token".to_string()))?;
+            return Ok(AuthContext { user_id: claims.sub, scopes: None });
         }
 
-        let token = &auth_header[7..];
-        let claims = verify_jwt(token, &secret)
-            .map_err(|_| (StatusCode::UNAUTHORIZED, "Invalid token".to_string()))?;
+        let db = state.db()
+            .ok_or_else(|| ApiError::Unauthorized("API keys are not accepted by this service".to_string()))?;
+        let key = verify_api_key(db, token)
+            .await?
+            .ok_or_else(|| ApiError::Unauthorized("Invalid or expired API key".to_string()))?;
+
+        let required = required_scope(parts.method.as_str(), parts.uri.path())
+            .ok_or_else(|| ApiError::Forbidden("API keys cannot access this endpoint".to_string()))?;
+        if !scope_allows(&key.scopes, &required) {
+            return Err(ApiError::Forbidden(format!("API key is missing the '{}' scope", required)));
+        }
 
-        Ok(AuthContext { user_id: claims.sub })
+        Ok(AuthContext { user_id: key.user_id, scopes: Some(key.scopes) })
     }
 }
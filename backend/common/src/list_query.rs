@@ -0,0 +1,484 @@
+//! Pagination, filtering and sorting for list endpoints.
+//!
+//! Each list route declares a [`ListSpec`] naming the sort keys and filters it
+//! supports, and takes a [`ListQuery`] extracted from the query string:
+//!
+//! `GET /api/invoices?status=sent,overdue&min_total=100&sort=-due_date&limit=50&cursor=…`
+//!
+//! Pages are keyset-paginated on `(sort key, id)`, so deep pages cost the same
+//! as the first one. The cursor is opaque to clients and only valid for the
+//! sort it was issued with.
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Query},
+    http::request::Parts,
+};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, NaiveDate};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::{PgPool, PgRow};
+use sqlx::{FromRow, Postgres, QueryBuilder, Row};
+use std::collections::HashMap;
+
+use crate::{ApiError, FieldError};
+
+pub const DEFAULT_LIMIT: i64 = 50;
+pub const MAX_LIMIT: i64 = 200;
+
+/// Parameters that are not filters.
+const RESERVED_PARAMS: &[&str] = &["limit", "cursor", "sort"];
+
+#[derive(Debug, Clone, Copy)]
+pub enum FieldKind {
+    Text,
+    Int,
+    Number,
+    Date,
+    Timestamp,
+}
+
+impl FieldKind {
+    fn sql_type(self) -> &'static str {
+        match self {
+            FieldKind::Text => "text",
+            FieldKind::Int => "int8",
+            FieldKind::Number => "numeric",
+            FieldKind::Date => "date",
+            FieldKind::Timestamp => "timestamptz",
+        }
+    }
+
+    fn parses(self, value: &str) -> bool {
+        match self {
+            FieldKind::Text => true,
+            FieldKind::Int => value.parse::<i64>().is_ok(),
+            FieldKind::Number => value.parse::<f64>().map(f64::is_finite).unwrap_or(false),
+            FieldKind::Date => NaiveDate::parse_from_str(value, "%Y-%m-%d").is_ok(),
+            FieldKind::Timestamp => DateTime::parse_from_rfc3339(value).is_ok(),
+        }
+    }
+
+    fn expected(self) -> &'static str {
+        match self {
+            FieldKind::Text => "text",
+            FieldKind::Int => "an integer",
+            FieldKind::Number => "a number",
+            FieldKind::Date => "a date (YYYY-MM-DD)",
+            FieldKind::Timestamp => "an RFC 3339 timestamp",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum FilterOp {
+    /// Matches any of a comma-separated list of values.
+    In,
+    Min,
+    Max,
+    /// Case-insensitive substring match.
+    Contains,
+    /// Full-text match of a `tsvector` column (`simple` configuration)
+    /// against every word given, each as a prefix.
+    Search,
+    /// Matches rows whose array column shares any of a comma-separated list of values.
+    Overlaps,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Filter {
+    pub param: &'static str,
+    pub column: &'static str,
+    pub kind: FieldKind,
+    pub op: FilterOp,
+}
+
+impl Filter {
+    pub const fn new(param: &'static str, column: &'static str, kind: FieldKind, op: FilterOp) -> Self {
+        Filter { param, column, kind, op }
+    }
+}
+
+/// A sortable field. `column` must never be NULL; wrap nullable columns in `COALESCE`.
+#[derive(Debug, Clone, Copy)]
+pub struct SortKey {
+    pub name: &'static str,
+    pub column: &'static str,
+    pub kind: FieldKind,
+}
+
+impl SortKey {
+    pub const fn new(name: &'static str, column: &'static str, kind: FieldKind) -> Self {
+        SortKey { name, column, kind }
+    }
+}
+
+pub struct ListSpec {
+    /// Selected columns, in the shape the row type's `FromRow` expects.
+    pub columns: &'static str,
+    /// `FROM` clause including joins.
+    pub from: &'static str,
+    /// Column restricting rows to the caller's account.
+    pub owner_column: &'static str,
+    pub id_column: &'static str,
+    pub sort_keys: &'static [SortKey],
+    /// Sort used when none is given, e.g. `-created_at`.
+    pub default_sort: &'static str,
+    pub filters: &'static [Filter],
+    /// Filter values applied when the request doesn't set that parameter,
+    /// e.g. `("status", "active,inactive")` to hide archived rows.
+    pub default_filters: &'static [(&'static str, &'static str)],
+}
+
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Pass back as `cursor` to fetch the next page; absent on the last page.
+    pub next_cursor: Option<String>,
+    /// Rows matching the filters, across all pages.
+    pub total: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Cursor {
+    sort: String,
+    value: String,
+    id: i64,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    fn decode(raw: &str) -> Option<Self> {
+        let bytes = URL_SAFE_NO_PAD.decode(raw).ok()?;
+        serde_json::from_slice(&bytes).ok()
+    }
+}
+
+/// Raw list parameters; checked against a [`ListSpec`] by [`ListQuery::fetch`].
+pub struct ListQuery {
+    params: HashMap<String, String>,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ListQuery {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Query(params) = Query::<HashMap<String, String>>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| ApiError::BadRequest(e.body_text()))?;
+        Ok(ListQuery { params })
+    }
+}
+
+struct Plan<'a> {
+    limit: i64,
+    sort: &'a SortKey,
+    descending: bool,
+    cursor: Option<Cursor>,
+    filters: Vec<(&'a Filter, Vec<String>)>,
+}
+
+fn invalid(errors: &mut Vec<FieldError>, field: &str, message: impl Into<String>) {
+    errors.push(FieldError { field: field.to_string(), message: message.into() });
+}
+
+impl ListQuery {
+    fn plan<'a>(&self, spec: &'a ListSpec) -> Result<Plan<'a>, ApiError> {
+        let mut errors = Vec::new();
+
+        let limit = match self.params.get("limit") {
+            Some(raw) => match raw.parse::<i64>() {
+                Ok(n) if (1..=MAX_LIMIT).contains(&n) => n,
+                _ => {
+                    invalid(&mut errors, "limit", format!("must be between 1 and {}", MAX_LIMIT));
+                    DEFAULT_LIMIT
+                }
+            },
+            None => DEFAULT_LIMIT,
+        };
+
+        let raw_sort = self.params.get("sort").map(String::as_str).unwrap_or(spec.default_sort);
+        let (name, descending) = match raw_sort.strip_prefix('-') {
+            Some(name) => (name, true),
+            None => (raw_sort, false),
+        };
+        let sort = match spec.sort_keys.iter().find(|k| k.name == name) {
+            Some(key) => key,
+            None => {
+                let names: Vec<_> = spec.sort_keys.iter().map(|k| k.name).collect();
+                invalid(&mut errors, "sort", format!("must be one of: {} (prefix with - for descending)", names.join(", ")));
+                &spec.sort_keys[0]
+            }
+        };
+
+        let cursor = match self.params.get("cursor") {
+            Some(raw) => match Cursor::decode(raw) {
+                Some(c) if c.sort == raw_sort && sort.kind.parses(&c.value) => Some(c),
+                _ => {
+                    invalid(&mut errors, "cursor", "is invalid or was issued for a different sort");
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let defaults = spec
+            .default_filters
+            .iter()
+            .filter(|(param, _)| !self.params.contains_key(*param))
+            .copied();
+        let given = self.params.iter().map(|(param, raw)| (param.as_str(), raw.as_str()));
+
+        let mut filters = Vec::new();
+        for (param, raw) in given.chain(defaults) {
+            if RESERVED_PARAMS.contains(&param) {
+                continue;
+            }
+            let Some(filter) = spec.filters.iter().find(|f| f.param == param) else {
+                invalid(&mut errors, param, "is not a supported filter");
+                continue;
+            };
+            let values: Vec<String> = match filter.op {
+                FilterOp::In | FilterOp::Overlaps => raw.split(',').map(|v| v.trim().to_string()).filter(|v| !v.is_empty()).collect(),
+                _ => vec![raw.trim().to_string()],
+            };
+            if values.is_empty() || values.iter().any(|v| v.is_empty() || !filter.kind.parses(v)) {
+                invalid(&mut errors, param, format!("must be {}", filter.kind.expected()));
+                continue;
+            }
+            filters.push((filter, values));
+        }
+
+        if !errors.is_empty() {
+            return Err(ApiError::Validation(errors));
+        }
+        Ok(Plan { limit, sort, descending, cursor, filters })
+    }
+
+    /// Whether the request sorts by the key `name`, in either direction.
+    pub fn sorts_by(&self, name: &str) -> bool {
+        self.params.get("sort").is_some_and(|sort| sort.strip_prefix('-').unwrap_or(sort) == name)
+    }
+
+    /// Whether the request sets the filter `param`.
+    pub fn filters_by(&self, param: &str) -> bool {
+        self.params.contains_key(param)
+    }
+
+    /// Fetches one page of the caller's rows plus the total matching count.
+    pub async fn fetch<T>(&self, db: &PgPool, spec: &ListSpec, user_id: i32) -> Result<Page<T>, ApiError>
+    where
+        T: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+    {
+        let plan = self.plan(spec)?;
+
+        let mut count = QueryBuilder::<Postgres>::new("SELECT COUNT(*) ");
+        count.push(spec.from);
+        push_conditions(&mut count, spec, user_id, &plan.filters);
+        let total: i64 = count.build().fetch_one(db).await?.try_get(0)?;
+
+        let mut query = QueryBuilder::<Postgres>::new("SELECT ");
+        query.push(spec.columns);
+        query.push(format_args!(
+            ", to_jsonb({})#>>'{{}}' AS list_cursor_value, {}::int8 AS list_cursor_id ",
+            plan.sort.column, spec.id_column
+        ));
+        query.push(spec.from);
+        push_conditions(&mut query, spec, user_id, &plan.filters);
+        if let Some(cursor) = &plan.cursor {
+            query.push(format_args!(" AND ({}, {}) {} (", plan.sort.column, spec.id_column, if plan.descending { "<" } else { ">" }));
+            query.push_bind(cursor.value.clone());
+            query.push(format_args!("::{}, ", plan.sort.kind.sql_type()));
+            query.push_bind(cursor.id);
+            query.push(")");
+        }
+        let direction = if plan.descending { "DESC" } else { "ASC" };
+        query.push(format_args!(" ORDER BY {} {}, {} {} LIMIT ", plan.sort.column, direction, spec.id_column, direction));
+        query.push_bind(plan.limit + 1);
+
+        let mut rows = query.build().fetch_all(db).await?;
+        let has_more = rows.len() as i64 > plan.limit;
+        rows.truncate(plan.limit as usize);
+
+        let next_cursor = match rows.last() {
+            Some(last) if has_more => Some(
+                Cursor {
+                    sort: self.params.get("sort").cloned().unwrap_or_else(|| spec.default_sort.to_string()),
+                    value: last.try_get("list_cursor_value")?,
+                    id: last.try_get("list_cursor_id")?,
+                }
+                .encode(),
+            ),
+            _ => None,
+        };
+        let items = rows.iter().map(T::from_row).collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Page { items, next_cursor, total })
+    }
+}
+
+fn push_conditions(qb: &mut QueryBuilder<'_, Postgres>, spec: &ListSpec, user_id: i32, filters: &[(&Filter, Vec<String>)]) {
+    qb.push(format_args!(" WHERE {} = ", spec.owner_column));
+    qb.push_bind(user_id);
+
+    for (filter, values) in filters {
+        let sql_type = filter.kind.sql_type();
+        match filter.op {
+            FilterOp::In => {
+                qb.push(format_args!(" AND {} = ANY(", filter.column));
+                qb.push_bind(values.clone());
+                qb.push(format_args!("::{}[])", sql_type));
+            }
+            FilterOp::Min | FilterOp::Max => {
+                let op = if matches!(filter.op, FilterOp::Min) { ">=" } else { "<=" };
+                qb.push(format_args!(" AND {} {} ", filter.column, op));
+                qb.push_bind(values[0].clone());
+                qb.push(format_args!("::{}", sql_type));
+            }
+            FilterOp::Contains => {
+                let escaped = values[0].replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+                qb.push(format_args!(" AND {} ILIKE ", filter.column));
+                qb.push_bind(format!("%{}%", escaped));
+            }
+            FilterOp::Search => {
+                // Postgres splits the input into words the same way it built the column
+                qb.push(format_args!(
+                    " AND {} @@ (SELECT to_tsquery('simple', string_agg(quote_literal(lexeme) || ':*', ' & ')) FROM unnest(to_tsvector('simple', ",
+                    filter.column
+                ));
+                qb.push_bind(values[0].clone());
+                qb.push(")))");
+            }
+            FilterOp::Overlaps => {
+                qb.push(format_args!(" AND {} && ", filter.column));
+                qb.push_bind(values.clone());
+                qb.push(format_args!("::{}[]", sql_type));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEC: ListSpec = ListSpec {
+        columns: "id, name",
+        from: "FROM things",
+        owner_column: "user_id",
+        id_column: "id",
+        sort_keys: &[
+            SortKey::new("name", "lower(name)", FieldKind::Text),
+            SortKey::new("due_date", "COALESCE(due_date, '0001-01-01')", FieldKind::Date),
+        ],
+        default_sort: "name",
+        filters: &[
+            Filter::new("status", "status", FieldKind::Text, FilterOp::In),
+            Filter::new("client_id", "client_id", FieldKind::Int, FilterOp::In),
+            Filter::new("min_total", "total", FieldKind::Number, FilterOp::Min),
+            Filter::new("due_to", "due_date", FieldKind::Date, FilterOp::Max),
+            Filter::new("name", "name", FieldKind::Text, FilterOp::Contains),
+        ],
+        default_filters: &[("status", "active")],
+    };
+
+    fn query(params: &[(&str, &str)]) -> ListQuery {
+        ListQuery { params: params.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect() }
+    }
+
+    fn invalid_fields(query: &ListQuery) -> Vec<String> {
+        match query.plan(&SPEC) {
+            Err(ApiError::Validation(errors)) => {
+                let mut fields: Vec<String> = errors.into_iter().map(|e| e.field).collect();
+                fields.sort();
+                fields
+            }
+            Err(e) => panic!("unexpected error {:?}", e),
+            Ok(_) => Vec::new(),
+        }
+    }
+
+    fn sql(query: &ListQuery) -> String {
+        let plan = query.plan(&SPEC).unwrap();
+        let mut qb = QueryBuilder::<Postgres>::new("SELECT 1 FROM things");
+        let mut filters = plan.filters;
+        filters.sort_by_key(|(filter, _)| filter.param);
+        push_conditions(&mut qb, &SPEC, 1, &filters);
+        qb.sql().to_string()
+    }
+
+    #[test]
+    fn defaults_the_limit_sort_and_filters() {
+        let plan = query(&[]).plan(&SPEC).unwrap();
+        assert_eq!(plan.limit, DEFAULT_LIMIT);
+        assert_eq!(plan.sort.name, "name");
+        assert!(!plan.descending);
+        assert_eq!(plan.filters.len(), 1);
+        assert_eq!(plan.filters[0].1, ["active"]);
+    }
+
+    #[test]
+    fn a_given_filter_replaces_its_default() {
+        let plan = query(&[("status", "archived, active ,")]).plan(&SPEC).unwrap();
+        assert_eq!(plan.filters.len(), 1);
+        assert_eq!(plan.filters[0].1, ["archived", "active"]);
+    }
+
+    #[test]
+    fn sorts_descending_with_a_minus() {
+        let plan = query(&[("sort", "-due_date"), ("limit", "200")]).plan(&SPEC).unwrap();
+        assert_eq!(plan.sort.name, "due_date");
+        assert!(plan.descending);
+        assert_eq!(plan.limit, MAX_LIMIT);
+    }
+
+    #[test]
+    fn rejects_every_bad_parameter_at_once() {
+        let q = query(&[("limit", "0"), ("sort", "total"), ("cursor", "nope"), ("color", "red"), ("client_id", "1,x"), ("min_total", "NaN"), ("due_to", "2026-13-01")]);
+        assert_eq!(invalid_fields(&q), ["client_id", "color", "cursor", "due_to", "limit", "min_total", "sort"]);
+        assert_eq!(invalid_fields(&query(&[("limit", "201")])), ["limit"]);
+        assert_eq!(invalid_fields(&query(&[("status", ",")])), ["status"]);
+    }
+
+    #[test]
+    fn cursors_round_trip_for_the_sort_they_were_issued_with() {
+        let cursor = Cursor { sort: "-due_date".to_string(), value: "2026-10-19".to_string(), id: 42 }.encode();
+        let plan = query(&[("sort", "-due_date"), ("cursor", &cursor)]).plan(&SPEC).unwrap();
+        let decoded = plan.cursor.unwrap();
+        assert_eq!((decoded.value.as_str(), decoded.id), ("2026-10-19", 42));
+
+        assert_eq!(invalid_fields(&query(&[("sort", "due_date"), ("cursor", &cursor)])), ["cursor"]);
+        assert_eq!(invalid_fields(&query(&[("cursor", &cursor)])), ["cursor"]);
+    }
+
+    #[test]
+    fn rejects_a_cursor_whose_value_does_not_fit_the_sort() {
+        let cursor = Cursor { sort: "due_date".to_string(), value: "soon".to_string(), id: 1 }.encode();
+        assert_eq!(invalid_fields(&query(&[("sort", "due_date"), ("cursor", &cursor)])), ["cursor"]);
+    }
+
+    #[test]
+    fn builds_bound_conditions_for_each_filter() {
+        let sql = sql(&query(&[("client_id", "1,2"), ("min_total", "100"), ("due_to", "2026-10-31"), ("name", "50%_off")]));
+        assert_eq!(
+            sql,
+            "SELECT 1 FROM things WHERE user_id = $1 AND client_id = ANY($2::int8[]) AND due_date <= $3::date \
+             AND total >= $4::numeric AND name ILIKE $5 AND status = ANY($6::text[])"
+        );
+    }
+
+    #[test]
+    fn reports_the_sort_and_filters_asked_for() {
+        let q = query(&[("sort", "-due_date"), ("min_total", "5")]);
+        assert!(q.sorts_by("due_date"));
+        assert!(!q.sorts_by("name"));
+        assert!(q.filters_by("min_total"));
+        assert!(!q.filters_by("status"));
+        assert!(!query(&[]).sorts_by("name"));
+    }
+}
@@ -0,0 +1,92 @@
+//! Prometheus metrics, served on `/metrics` by [`crate::bootstrap::serve`].
+//!
+//! HTTP latency and error counts are recorded for every route by
+//! [`crate::telemetry::observe`], and query latency from sqlx's statement
+//! logs. Services declare their domain counters as [`Counter`] statics.
+
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, TextEncoder};
+use std::sync::OnceLock;
+
+/// Buckets in seconds, from a cached lookup to a slow PDF export.
+const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+pub(crate) struct HttpMetrics {
+    pub duration: HistogramVec,
+    pub errors: IntCounterVec,
+    pub query_duration: HistogramVec,
+}
+
+pub(crate) fn http() -> &'static HttpMetrics {
+    static METRICS: OnceLock<HttpMetrics> = OnceLock::new();
+    METRICS.get_or_init(|| HttpMetrics {
+        duration: histogram_vec(
+            "http_request_duration_seconds",
+            "Time to respond to an HTTP request",
+            &["method", "route", "status"],
+        ),
+        errors: counter_vec(
+            "http_request_errors_total",
+            "HTTP requests answered with a 4xx or 5xx, by error code",
+            &["method", "route", "status", "code"],
+        ),
+        query_duration: histogram_vec(
+            "db_query_duration_seconds",
+            "Time to execute a database statement",
+            &["operation"],
+        ),
+    })
+}
+
+/// A counter registered on first use, so it can be declared as a static:
+///
+/// ```ignore
+/// static EMAILS_SENT: Counter = Counter::new("emails_sent_total", "Emails sent, by template", &["template"]);
+/// EMAILS_SENT.inc(&["invoice"]);
+/// ```
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    registered: OnceLock<IntCounterVec>,
+}
+
+impl Counter {
+    pub const fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        Counter { name, help, labels, registered: OnceLock::new() }
+    }
+
+    /// Adds one for the given label values, in the order they were declared.
+    pub fn inc(&self, label_values: &[&str]) {
+        self.inc_by(label_values, 1);
+    }
+
+    pub fn inc_by(&self, label_values: &[&str], n: u64) {
+        self.registered
+            .get_or_init(|| counter_vec(self.name, self.help, self.labels))
+            .with_label_values(label_values)
+            .inc_by(n);
+    }
+}
+
+/// Panics if the name is invalid or already registered, which is a programming error.
+fn counter_vec(name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    let counter = IntCounterVec::new(Opts::new(name, help), labels).expect("invalid counter");
+    prometheus::register(Box::new(counter.clone())).expect("counter registered twice");
+    counter
+}
+
+fn histogram_vec(name: &str, help: &str, labels: &[&str]) -> HistogramVec {
+    let opts = HistogramOpts::new(name, help).buckets(LATENCY_BUCKETS.to_vec());
+    let histogram = HistogramVec::new(opts, labels).expect("invalid histogram");
+    prometheus::register(Box::new(histogram.clone())).expect("histogram registered twice");
+    histogram
+}
+
+/// Every registered metric in the Prometheus text format.
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        tracing::error!("Failed to encode metrics: {}", e);
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
@@ -0,0 +1,13 @@
+//! Database migrations.
+//!
+//! The schema lives in `backend/migrations` as reversible sqlx migrations
+//! (`<version>_<name>.up.sql` / `.down.sql`) embedded into every binary that
+//! links this crate. Services apply whatever is pending when they connect
+//! (see [`crate::bootstrap`]); the `migrate` binary applies, reverts and lists
+//! them by hand. Applied versions are recorded in `_sqlx_migrations`, and
+//! Postgres advisory locking keeps services that start together from racing
+//! each other.
+
+use sqlx::migrate::Migrator;
+
+pub static MIGRATOR: Migrator = sqlx::migrate!("../migrations");
@@ -0,0 +1,289 @@
+//! Unit price resolution from price lists.
+//!
+//! Price lists are managed by product-service. Each list has prices for one
+//! currency, optionally only for clients tagged with its `client_tag` and
+//! within a validity range, and each price applies from a `min_quantity` up.
+//! product-service serves the result at `GET /api/products/:id/price`, and
+//! invoice-service prices invoice and estimate lines that reference a product
+//! with it.
+
+use chrono::NaiveDate;
+use serde::Serialize;
+use sqlx::{FromRow, PgConnection};
+
+use crate::billing::DEFAULT_CURRENCY;
+use crate::ledger::round_cents;
+
+/// How a list's quantity tiers apply.
+pub const TIER_MODES: &[&str] = &["volume", "graduated"];
+
+pub struct PriceRequest<'a> {
+    pub product_id: i32,
+    /// Client tags select the client-group lists that apply.
+    pub client_id: Option<i32>,
+    pub quantity: f64,
+    pub date: NaiveDate,
+    pub currency: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResolvedPrice {
+    pub product_id: i32,
+    pub currency: String,
+    pub quantity: f64,
+    pub date: NaiveDate,
+    pub unit_price: f64,
+    /// What `quantity` units cost, rounded to cents. Under `graduated` tiers
+    /// this is the exact sum over the tiers, so it can differ from `quantity`
+    /// × `unit_price` by the rounding of the unit price.
+    pub amount: f64,
+    /// The list the price comes from; `None` for the product's own price.
+    pub price_list_id: Option<i32>,
+}
+
+#[derive(FromRow)]
+struct ApplicableList {
+    id: i32,
+    tier_mode: String,
+}
+
+#[derive(FromRow)]
+struct Tier {
+    min_quantity: f64,
+    unit_price: f64,
+}
+
+/// A unit price in cents and the unrounded amount of the quantity priced.
+#[derive(Debug, PartialEq)]
+struct Priced {
+    unit_price: f64,
+    amount: f64,
+}
+
+/// Resolves the unit price of `request.quantity` units of a product, or
+/// `None` when the product is not the user's.
+///
+/// Of the lists in the currency valid on the date with a price for the
+/// quantity, a list for one of the client's tags wins over a list for every
+/// client, then the most recently started one. Without any, the product's own
+/// price applies, whatever the currency; for a bundle priced by its
+/// `components` that is the sum of its components' prices, each resolved the
+/// same way for its quantity in the bundle.
+///
+/// Under `volume` tiers every unit costs the price of the highest tier
+/// reached; under `graduated` tiers each tier's units cost that tier's price,
+/// the amount is their sum and the unit price its average, in cents.
+pub async fn resolve(conn: &mut PgConnection, user_id: i32, request: &PriceRequest<'_>) -> Result<Option<ResolvedPrice>, sqlx::Error> {
+    let Some((own_price, bundle_pricing)) = sqlx::query_as::<_, (f64, Option<String>)>(
+        "SELECT price::float8, bundle_pricing FROM products WHERE id = $1 AND user_id = $2"
+    )
+    .bind(request.product_id)
+    .bind(user_id)
+    .fetch_optional(&mut *conn)
+    .await?
+    else {
+        return Ok(None);
+    };
+
+    let tags: Vec<String> = match request.client_id {
+        Some(client_id) => sqlx::query_scalar("SELECT tags FROM clients WHERE id = $1 AND user_id = $2")
+            .bind(client_id)
+            .bind(user_id)
+            .fetch_optional(&mut *conn)
+            .await?
+            .unwrap_or_default(),
+        None => Vec::new(),
+    };
+
+    let (priced, price_list_id) = match list_price(conn, user_id, request, request.product_id, request.quantity, &tags).await? {
+        Some((priced, list_id)) => (priced, Some(list_id)),
+        None if bundle_pricing.as_deref() == Some("components") => {
+            let components = sqlx::query_as::<_, (i32, f64, f64)>(
+                "SELECT bc.component_id, bc.quantity::float8, p.price::float8 FROM bundle_components bc \
+                 JOIN products p ON p.id = bc.component_id WHERE bc.bundle_id = $1 ORDER BY bc.component_id"
+            )
+            .bind(request.product_id)
+            .fetch_all(&mut *conn)
+            .await?;
+            let mut parts = Vec::with_capacity(components.len());
+            for (component_id, quantity, price) in components {
+                let component_quantity = request.quantity * quantity;
+                let component = match list_price(conn, user_id, request, component_id, component_quantity, &tags).await? {
+                    Some((priced, _)) => priced,
+                    None => Priced { unit_price: price, amount: component_quantity * price },
+                };
+                parts.push((quantity, component));
+            }
+            (bundle_price(request.quantity, &parts), None)
+        }
+        None => (Priced { unit_price: own_price, amount: request.quantity * own_price }, None),
+    };
+
+    Ok(Some(ResolvedPrice {
+        product_id: request.product_id,
+        currency: request.currency.to_string(),
+        quantity: request.quantity,
+        date: request.date,
+        unit_price: priced.unit_price,
+        amount: round_cents(priced.amount),
+        price_list_id,
+    }))
+}
+
+/// The price of `quantity` units of a product from the list that applies to
+/// `request`, with that list's id.
+async fn list_price(
+    conn: &mut PgConnection,
+    user_id: i32,
+    request: &PriceRequest<'_>,
+    product_id: i32,
+    quantity: f64,
+    tags: &[String],
+) -> Result<Option<(Priced, i32)>, sqlx::Error> {
+    let list = sqlx::query_as::<_, ApplicableList>(
+        "SELECT l.id, l.tier_mode FROM price_lists l \
+         WHERE l.user_id = $1 AND l.currency = $2 \
+           AND (l.valid_from IS NULL OR l.valid_from <= $3) AND (l.valid_to IS NULL OR l.valid_to >= $3) \
+           AND (l.client_tag IS NULL OR l.client_tag = ANY($4)) \
+           AND EXISTS (SELECT 1 FROM price_list_prices p WHERE p.price_list_id = l.id AND p.product_id = $5 AND p.min_quantity <= $6) \
+         ORDER BY l.client_tag IS NOT NULL DESC, l.valid_from DESC NULLS LAST, l.id DESC \
+         LIMIT 1"
+    )
+    .bind(user_id)
+    .bind(request.currency)
+    .bind(request.date)
+    .bind(tags)
+    .bind(product_id)
+    .bind(quantity)
+    .fetch_optional(&mut *conn)
+    .await?;
+    let Some(list) = list else { return Ok(None) };
+
+    let tiers = sqlx::query_as::<_, Tier>(
+        "SELECT min_quantity::float8 AS min_quantity, unit_price::float8 AS unit_price FROM price_list_prices \
+         WHERE price_list_id = $1 AND product_id = $2 ORDER BY min_quantity"
+    )
+    .bind(list.id)
+    .bind(product_id)
+    .fetch_all(&mut *conn)
+    .await?;
+    Ok(Some((tier_price(&tiers, quantity, list.tier_mode == "graduated"), list.id)))
+}
+
+/// The currency to price in when none is given: the client's, else the
+/// company's default, else [`DEFAULT_CURRENCY`].
+pub async fn default_currency(conn: &mut PgConnection, user_id: i32, client_id: Option<i32>) -> Result<String, sqlx::Error> {
+    let currency: Option<String> = sqlx::query_scalar(
+        "SELECT COALESCE((SELECT currency FROM clients WHERE id = $2 AND user_id = $1), \
+                         (SELECT default_currency FROM companies WHERE user_id = $1))"
+    )
+    .bind(user_id)
+    .bind(client_id)
+    .fetch_one(&mut *conn)
+    .await?;
+    Ok(currency.unwrap_or_else(|| DEFAULT_CURRENCY.to_string()))
+}
+
+/// The price of `quantity` under `tiers`, ordered by `min_quantity`.
+fn tier_price(tiers: &[Tier], quantity: f64, graduated: bool) -> Priced {
+    let reached: Vec<&Tier> = tiers.iter().take_while(|t| t.min_quantity <= quantity).collect();
+    if !graduated || quantity <= 0.0 {
+        let unit_price = reached.last().map_or(0.0, |t| t.unit_price);
+        return Priced { unit_price, amount: quantity * unit_price };
+    }
+
+    // Each tier covers its own minimum up to the next one's; the first also
+    // covers anything below its minimum
+    let mut total = 0.0;
+    for (i, tier) in reached.iter().enumerate() {
+        let start = if i == 0 { 0.0 } else { tier.min_quantity };
+        let end = reached.get(i + 1).map_or(quantity, |next| next.min_quantity);
+        total += (end - start) * tier.unit_price;
+    }
+    Priced { unit_price: round_cents(total / quantity), amount: total }
+}
+
+/// The price of `quantity` bundles from their components', each with its
+/// quantity per bundle and the price of that many × `quantity`. As under
+/// graduated tiers, the unit price is the amount's average, in cents.
+fn bundle_price(quantity: f64, components: &[(f64, Priced)]) -> Priced {
+    let amount = components.iter().map(|(_, priced)| priced.amount).sum();
+    let unit_price = if quantity > 0.0 {
+        amount / quantity
+    } else {
+        components.iter().map(|(per_bundle, priced)| per_bundle * priced.unit_price).sum()
+    };
+    Priced { unit_price: round_cents(unit_price), amount }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tiers(prices: &[(f64, f64)]) -> Vec<Tier> {
+        prices.iter().map(|&(min_quantity, unit_price)| Tier { min_quantity, unit_price }).collect()
+    }
+
+    #[test]
+    fn volume_tiers_price_every_unit_at_the_highest_tier_reached() {
+        let tiers = tiers(&[(1.0, 10.0), (10.0, 8.0), (100.0, 5.0)]);
+        assert_eq!(tier_price(&tiers, 5.0, false), Priced { unit_price: 10.0, amount: 50.0 });
+        assert_eq!(tier_price(&tiers, 10.0, false), Priced { unit_price: 8.0, amount: 80.0 });
+        assert_eq!(tier_price(&tiers, 250.0, false), Priced { unit_price: 5.0, amount: 1250.0 });
+    }
+
+    #[test]
+    fn graduated_tiers_price_each_tier_at_its_own_price() {
+        let tiers = tiers(&[(0.0, 10.0), (10.0, 8.0), (100.0, 5.0)]);
+        assert_eq!(tier_price(&tiers, 5.0, true), Priced { unit_price: 10.0, amount: 50.0 });
+        // 10 × 10 + 90 × 8 + 50 × 5
+        assert_eq!(tier_price(&tiers, 150.0, true), Priced { unit_price: 7.13, amount: 1070.0 });
+    }
+
+    #[test]
+    fn graduated_amount_is_exact_rather_than_quantity_times_the_rounded_unit_price() {
+        // 1 × 10 + 2 × 5 = 20 over 3 units: 6.67 each, but 20.00 in total
+        let priced = tier_price(&tiers(&[(0.0, 10.0), (1.0, 5.0)]), 3.0, true);
+        assert_eq!(priced.unit_price, 6.67);
+        assert_eq!(round_cents(priced.amount), 20.0);
+        assert_eq!(round_cents(3.0 * priced.unit_price), 20.01);
+    }
+
+    #[test]
+    fn the_first_tier_covers_quantities_below_its_minimum() {
+        let tiers = tiers(&[(5.0, 4.0), (10.0, 2.0)]);
+        // 10 × 4 + 2 × 2
+        assert_eq!(tier_price(&tiers, 12.0, true).amount, 44.0);
+    }
+
+    #[test]
+    fn no_tier_reached_or_no_quantity_costs_nothing() {
+        let tiers = tiers(&[(10.0, 8.0)]);
+        assert_eq!(tier_price(&tiers, 5.0, false), Priced { unit_price: 0.0, amount: 0.0 });
+        assert_eq!(tier_price(&tiers, 0.0, true), Priced { unit_price: 0.0, amount: 0.0 });
+    }
+
+    #[test]
+    fn bundle_costs_its_components_times_their_quantity_per_bundle() {
+        // 2 × 3.50 + 1 × 10 per bundle, 4 bundles
+        let parts = [(2.0, Priced { unit_price: 3.5, amount: 28.0 }), (1.0, Priced { unit_price: 10.0, amount: 40.0 })];
+        assert_eq!(bundle_price(4.0, &parts), Priced { unit_price: 17.0, amount: 68.0 });
+    }
+
+    #[test]
+    fn bundle_unit_price_is_the_average_of_its_exact_amount() {
+        // The component's 3 units cost 20.00 under graduated tiers, not 3 × 6.67
+        let component = tier_price(&tiers(&[(0.0, 10.0), (1.0, 5.0)]), 3.0, true);
+        assert_eq!(bundle_price(1.0, &[(3.0, component)]), Priced { unit_price: 20.0, amount: 20.0 });
+
+        let component = tier_price(&tiers(&[(0.0, 10.0), (1.0, 5.0)]), 3.0, true);
+        assert_eq!(bundle_price(3.0, &[(1.0, component)]), Priced { unit_price: 6.67, amount: 20.0 });
+    }
+
+    #[test]
+    fn no_bundles_cost_nothing_at_the_components_unit_prices() {
+        let parts = [(2.0, Priced { unit_price: 3.5, amount: 0.0 }), (1.0, Priced { unit_price: 10.0, amount: 0.0 })];
+        assert_eq!(bundle_price(0.0, &parts), Priced { unit_price: 17.0, amount: 0.0 });
+        assert_eq!(bundle_price(2.0, &[]), Priced { unit_price: 0.0, amount: 0.0 });
+    }
+}
@@ -0,0 +1,118 @@
+//! Request IDs for correlating error responses with logs.
+//!
+//! The gateway sets `X-Request-Id`; requests that reach a service directly get
+//! a fresh one. The ID is echoed on the response and available to anything
+//! running inside the handler through [`current_request_id`].
+
+use axum::{
+    extract::Request,
+    http::HeaderValue,
+    middleware::Next,
+    response::Response,
+};
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// The ID of the request being handled, if called within [`request_id`].
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Middleware: `.layer(axum::middleware::from_fn(common::request_id))`.
+pub async fn request_id(req: Request, next: Next) -> Response {
+    let id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .filter(|id| is_valid(id))
+        .map(|id| id.to_string())
+        .unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string());
+
+    let mut response = REQUEST_ID.scope(id.clone(), next.run(req)).await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ApiError;
+    use axum::routing::get;
+    use axum::Router;
+
+    async fn spawn() -> String {
+        let app = Router::new()
+            .route("/id", get(|| async { current_request_id().unwrap_or_default() }))
+            .route("/missing", get(|| async { ApiError::NotFound("Client not found".to_string()) }))
+            .layer(axum::middleware::from_fn(request_id));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    async fn get_id(url: &str, incoming: Option<&str>) -> (String, String) {
+        let mut request = reqwest::Client::new().get(url);
+        if let Some(id) = incoming {
+            request = request.header(REQUEST_ID_HEADER, id);
+        }
+        let response = request.send().await.unwrap();
+        let echoed = response.headers()[REQUEST_ID_HEADER].to_str().unwrap().to_string();
+        (echoed, response.text().await.unwrap())
+    }
+
+    #[test]
+    fn accepts_short_opaque_ids_only() {
+        assert!(is_valid("3f2c9a1e-req_7"));
+        assert!(!is_valid(""));
+        assert!(!is_valid("has space"));
+        assert!(!is_valid("line\nbreak"));
+        assert!(!is_valid(&"a".repeat(129)));
+    }
+
+    #[test]
+    fn is_unset_outside_a_request() {
+        assert_eq!(current_request_id(), None);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_gateways_id() {
+        let url = spawn().await;
+        let (echoed, seen) = get_id(&format!("{}/id", url), Some("gateway-42")).await;
+        assert_eq!(echoed, "gateway-42");
+        assert_eq!(seen, "gateway-42");
+    }
+
+    #[tokio::test]
+    async fn replaces_missing_and_invalid_ids() {
+        let url = spawn().await;
+        let (fresh, seen) = get_id(&format!("{}/id", url), None).await;
+        assert_eq!(fresh.len(), 32);
+        assert_eq!(seen, fresh);
+
+        let (replaced, seen) = get_id(&format!("{}/id", url), Some("not valid!")).await;
+        assert!(is_valid(&replaced));
+        assert_ne!(replaced, "not valid!");
+        assert_eq!(seen, replaced);
+    }
+
+    #[tokio::test]
+    async fn puts_the_id_in_error_responses() {
+        let url = spawn().await;
+        let (echoed, body) = get_id(&format!("{}/missing", url), Some("req-1")).await;
+        let problem: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(echoed, "req-1");
+        assert_eq!(problem["request_id"], "req-1");
+        assert_eq!(problem["code"], "not_found");
+    }
+}
@@ -0,0 +1,633 @@
+//! Tax identification numbers: offline format and checksum validation, and an
+//! optional VIES lookup for EU VAT numbers.
+//!
+//! EU VAT numbers (and GB/XI VAT and Swiss UID numbers) carry their country
+//! prefix; one entered without it takes the prefix of the country it was
+//! issued in, when that is known. Australian ABNs, Indian GSTINs and US EINs
+//! have no prefix and are only recognised through that country. Numbers of any
+//! other country are accepted as entered.
+
+use serde::Deserialize;
+use std::time::Duration;
+
+use crate::{ApiError, FieldError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaxIdKind {
+    EuVat,
+    GbVat,
+    ChUid,
+    AuAbn,
+    InGstin,
+    UsEin,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaxId {
+    pub kind: TaxIdKind,
+    /// Compact form, with its prefix for VAT and UID numbers (`DE136695976`,
+    /// `CHE107787577`) and digits only otherwise.
+    pub number: String,
+}
+
+impl TaxId {
+    /// The VIES member-state code and national number of an EU VAT number (and
+    /// of a Northern Ireland `XI` number, which VIES also answers for).
+    pub fn vies_parts(&self) -> Option<(&str, &str)> {
+        match self.kind {
+            TaxIdKind::EuVat => Some(self.number.split_at(2)),
+            TaxIdKind::GbVat if self.number.starts_with("XI") => Some(self.number.split_at(2)),
+            _ => None,
+        }
+    }
+}
+
+/// VAT prefixes of the EU member states; Greece uses `EL`.
+const EU_PREFIXES: &[&str] = &[
+    "AT", "BE", "BG", "CY", "CZ", "DE", "DK", "EE", "EL", "ES", "FI", "FR", "HR", "HU", "IE", "IT", "LT",
+    "LU", "LV", "MT", "NL", "PL", "PT", "RO", "SE", "SI", "SK",
+];
+
+/// Strips the separators people write tax IDs with and upper-cases the rest.
+pub fn compact(value: &str) -> String {
+    value
+        .chars()
+        .filter(|c| !c.is_whitespace() && !matches!(c, '.' | '-' | '/' | ',' | ':'))
+        .flat_map(char::to_uppercase)
+        .collect()
+}
+
+/// Validates `value`, issued in `country` (ISO 3166-1 alpha-2) if known.
+/// `Ok(None)` means the number's scheme isn't one checked here.
+pub fn parse(value: &str, country: Option<&str>) -> Result<Option<TaxId>, &'static str> {
+    let mut number = compact(value);
+    if number.is_empty() {
+        return Err("must not be empty");
+    }
+    if number.starts_with("GR") {
+        number.replace_range(..2, "EL");
+    }
+
+    // Add the prefix of the issuing country when it was left out
+    let prefix = match country {
+        Some("GR") => "EL",
+        Some("CH") => "CHE",
+        Some(code) => code,
+        None => "",
+    };
+    let is_vat_country = EU_PREFIXES.contains(&prefix) || matches!(prefix, "GB" | "XI" | "CHE");
+    if is_vat_country && number.starts_with(|c: char| c.is_ascii_digit()) {
+        number.insert_str(0, prefix);
+    }
+    // Elsewhere a leading country code is part of the national number
+    let prefixed = country.is_none() || is_vat_country;
+
+    let (kind, valid) = if !prefixed {
+        match country {
+            Some("AU") => (TaxIdKind::AuAbn, au_abn(&number)),
+            Some("IN") => (TaxIdKind::InGstin, in_gstin(&number)),
+            Some("US") => (TaxIdKind::UsEin, us_ein(&number)),
+            _ => return Ok(None),
+        }
+    } else if let Some(rest) = number.strip_prefix("CHE") {
+        let rest = rest.trim_end_matches("MWST").trim_end_matches("TVA").trim_end_matches("IVA").to_string();
+        let valid = ch_uid(&rest);
+        number = format!("CHE{}", rest);
+        (TaxIdKind::ChUid, valid)
+    } else if let Some(rest) = number.strip_prefix("GB").or_else(|| number.strip_prefix("XI")) {
+        (TaxIdKind::GbVat, gb_vat(rest))
+    } else if let Some(prefix) = number.get(..2).filter(|p| number.len() > 2 && EU_PREFIXES.contains(p)) {
+        (TaxIdKind::EuVat, eu_vat(prefix, &number[2..]))
+    } else {
+        return Ok(None);
+    };
+
+    if !valid {
+        return Err(match kind {
+            TaxIdKind::EuVat => "is not a valid EU VAT number",
+            TaxIdKind::GbVat => "is not a valid UK VAT number",
+            TaxIdKind::ChUid => "is not a valid Swiss UID number",
+            TaxIdKind::AuAbn => "is not a valid ABN",
+            TaxIdKind::InGstin => "is not a valid GSTIN",
+            TaxIdKind::UsEin => "is not a valid EIN",
+        });
+    }
+    Ok(Some(TaxId { kind, number }))
+}
+
+/// The form a tax ID is stored in: compact when it was recognised, as entered otherwise.
+pub fn canonical(value: &str, country: Option<&str>) -> String {
+    match parse(value, country) {
+        Ok(Some(tax_id)) => tax_id.number,
+        _ => value.trim().to_string(),
+    }
+}
+
+fn digits(number: &str) -> Option<Vec<u32>> {
+    number.chars().map(|c| c.to_digit(10)).collect()
+}
+
+/// Digits of `number` if it is exactly `len` of them.
+fn digits_of_len(number: &str, len: usize) -> Option<Vec<u32>> {
+    digits(number).filter(|d| d.len() == len)
+}
+
+fn weighted_sum(digits: &[u32], weights: &[u32]) -> u32 {
+    digits.iter().zip(weights).map(|(d, w)| d * w).sum()
+}
+
+fn luhn(digits: &[u32]) -> bool {
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &d)| if i % 2 == 1 { if d * 2 > 9 { d * 2 - 9 } else { d * 2 } } else { d })
+        .sum();
+    sum % 10 == 0
+}
+
+fn luhn_check_digit(body: &[u32]) -> u32 {
+    let mut digits = body.to_vec();
+    digits.push(0);
+    (0..10).find(|&c| {
+        *digits.last_mut().unwrap() = c;
+        luhn(&digits)
+    }).unwrap()
+}
+
+/// ISO 7064 MOD 11,10 over all digits, the last being the check digit.
+fn mod_11_10(digits: &[u32]) -> bool {
+    let (check, body) = digits.split_last().unwrap();
+    let mut product = 10;
+    for d in body {
+        let mut sum = (d + product) % 10;
+        if sum == 0 {
+            sum = 10;
+        }
+        product = (2 * sum) % 11;
+    }
+    (11 - product) % 10 == *check
+}
+
+/// Value of a decimal string too long for `u32` modulo `m`.
+fn mod_big(number: &str, m: u64) -> u64 {
+    number.chars().fold(0, |acc, c| (acc * 10 + c.to_digit(10).unwrap_or(0) as u64) % m)
+}
+
+fn eu_vat(country: &str, number: &str) -> bool {
+    // Every scheme below is ASCII, which the byte offsets they slice at rely on
+    if !number.is_ascii() {
+        return false;
+    }
+    match country {
+        "AT" => number.strip_prefix('U').and_then(|n| digits_of_len(n, 8)).is_some_and(|d| {
+            let sum: u32 = d[..7].iter().enumerate().map(|(i, &d)| if i % 2 == 1 { d * 2 / 10 + d * 2 % 10 } else { d }).sum();
+            (10 - (sum + 4) % 10) % 10 == d[7]
+        }),
+        "BE" => {
+            let number = if number.len() == 9 { format!("0{}", number) } else { number.to_string() };
+            digits_of_len(&number, 10).is_some_and(|d| {
+                d[0] <= 1 && 97 - mod_big(&number[..8], 97) == mod_big(&number[8..], 100)
+            })
+        }
+        "BG" => match digits(number) {
+            Some(d) if d.len() == 9 => {
+                let mut check = weighted_sum(&d, &[1, 2, 3, 4, 5, 6, 7, 8]) % 11;
+                if check == 10 {
+                    check = weighted_sum(&d, &[3, 4, 5, 6, 7, 8, 9, 10]) % 11;
+                }
+                check % 10 == d[8]
+            }
+            Some(d) if d.len() == 10 => {
+                // Personal number, foreigner's number, or other
+                weighted_sum(&d, &[2, 4, 8, 5, 10, 9, 7, 3, 6]) % 11 % 10 == d[9]
+                    || weighted_sum(&d, &[21, 19, 17, 13, 11, 9, 7, 3, 1]) % 10 == d[9]
+                    || (11 - weighted_sum(&d, &[4, 3, 2, 7, 6, 5, 4, 3, 2]) % 11) % 11 == d[9]
+            }
+            _ => false,
+        },
+        "CY" => {
+            let (body, check) = number.split_at(number.len().min(8));
+            digits_of_len(body, 8).is_some_and(|d| {
+                const ODD: [u32; 10] = [1, 0, 5, 7, 9, 13, 15, 17, 19, 21];
+                let sum: u32 = d.iter().enumerate().map(|(i, &d)| if i % 2 == 0 { ODD[d as usize] } else { d }).sum();
+                !body.starts_with("12") && check.len() == 1 && check.as_bytes()[0] == b'A' + (sum % 26) as u8
+            })
+        }
+        "CZ" => match digits(number) {
+            Some(d) if d.len() == 8 => {
+                let check = (11 - weighted_sum(&d, &[8, 7, 6, 5, 4, 3, 2]) % 11) % 11;
+                d[0] != 9 && (if check == 0 { 1 } else { check }) % 10 == d[7]
+            }
+            Some(d) if d.len() == 9 && d[0] == 6 => {
+                let check = weighted_sum(&d[1..], &[8, 7, 6, 5, 4, 3, 2]) % 11;
+                9 - (11 - check) % 10 == d[8]
+            }
+            // Birth numbers; nine-digit ones predate their checksum
+            Some(d) if d.len() == 9 => true,
+            Some(d) if d.len() == 10 => mod_big(number, 11) == 0 || (mod_big(&number[..9], 11) == 10 && d[9] == 0),
+            _ => false,
+        },
+        "DE" => digits_of_len(number, 9).is_some_and(|d| d[0] != 0 && mod_11_10(&d)),
+        "DK" => digits_of_len(number, 8).is_some_and(|d| d[0] != 0 && weighted_sum(&d, &[2, 7, 6, 5, 4, 3, 2, 1]) % 11 == 0),
+        "EE" => digits_of_len(number, 9).is_some_and(|d| {
+            number.starts_with("10") && (10 - weighted_sum(&d, &[3, 7, 1, 3, 7, 1, 3, 7]) % 10) % 10 == d[8]
+        }),
+        "EL" => digits_of_len(number, 9).is_some_and(|d| weighted_sum(&d, &[256, 128, 64, 32, 16, 8, 4, 2]) % 11 % 10 == d[8]),
+        "ES" => es_vat(number),
+        "FI" => digits_of_len(number, 8).is_some_and(|d| match weighted_sum(&d, &[7, 9, 10, 5, 8, 4, 2]) % 11 {
+            0 => d[7] == 0,
+            1 => false,
+            r => 11 - r == d[7],
+        }),
+        "FR" => fr_vat(number),
+        "HR" => digits_of_len(number, 11).is_some_and(|d| mod_11_10(&d)),
+        "HU" => digits_of_len(number, 8).is_some_and(|d| (10 - weighted_sum(&d, &[9, 7, 3, 1, 9, 7, 3]) % 10) % 10 == d[7]),
+        "IE" => ie_vat(number),
+        "IT" => digits_of_len(number, 11).is_some_and(|d| {
+            let office: u32 = number[7..10].parse().unwrap_or(0);
+            !number.starts_with("0000000") && ((1..=100).contains(&office) || matches!(office, 120 | 121 | 888 | 999)) && luhn(&d)
+        }),
+        "LT" => digits(number).is_some_and(|d| {
+            let legal = (d.len() == 9 && d[7] == 1) || (d.len() == 12 && d[10] == 1);
+            let body = &d[..d.len() - 1];
+            let weights = |offset: usize| (0..body.len()).map(|i| 1 + (i + offset) as u32 % 9).collect::<Vec<_>>();
+            let mut check = weighted_sum(body, &weights(0)) % 11;
+            if check == 10 {
+                check = weighted_sum(body, &weights(2)) % 11;
+            }
+            legal && check % 10 == d[d.len() - 1]
+        }),
+        "LU" => digits_of_len(number, 8).is_some_and(|_| mod_big(&number[..6], 89) == mod_big(&number[6..], 100)),
+        "LV" => digits_of_len(number, 11).is_some_and(|d| {
+            // Legal entities have a checksum; personal codes are taken as they are
+            d[0] <= 3 || weighted_sum(&d, &[9, 1, 4, 8, 3, 10, 2, 5, 7, 6, 1]) % 11 == 3
+        }),
+        "MT" => digits_of_len(number, 8).is_some_and(|d| {
+            d[0] != 0 && 37 - weighted_sum(&d, &[3, 4, 6, 7, 8, 9]) % 37 == d[6] * 10 + d[7]
+        }),
+        "NL" => nl_vat(number),
+        "PL" => digits_of_len(number, 10).is_some_and(|d| weighted_sum(&d, &[6, 5, 7, 2, 3, 4, 5, 6, 7]) % 11 == d[9]),
+        "PT" => digits_of_len(number, 9).is_some_and(|d| {
+            d[0] != 0 && (11 - weighted_sum(&d, &[9, 8, 7, 6, 5, 4, 3, 2]) % 11) % 11 % 10 == d[8]
+        }),
+        "RO" => digits(number).is_some_and(|d| {
+            if !(2..=10).contains(&d.len()) || d[0] == 0 {
+                return false;
+            }
+            let (check, body) = d.split_last().unwrap();
+            let mut padded = vec![0; 9 - body.len()];
+            padded.extend_from_slice(body);
+            weighted_sum(&padded, &[7, 5, 3, 2, 1, 7, 5, 3, 2]) * 10 % 11 % 10 == *check
+        }),
+        "SE" => digits_of_len(number, 12).is_some_and(|d| number.ends_with("01") && luhn(&d[..10])),
+        "SI" => digits_of_len(number, 8).is_some_and(|d| {
+            let check = 11 - weighted_sum(&d, &[8, 7, 6, 5, 4, 3, 2]) % 11;
+            d[0] != 0 && check != 11 && check % 10 == d[7]
+        }),
+        "SK" => digits_of_len(number, 10).is_some_and(|d| d[0] != 0 && [2, 3, 4, 7, 8, 9].contains(&d[2]) && mod_big(number, 11) == 0),
+        _ => false,
+    }
+}
+
+fn es_vat(number: &str) -> bool {
+    const DNI_LETTERS: &[u8] = b"TRWAGMYFPDXBNJZSQVHLCKE";
+    if number.len() != 9 {
+        return false;
+    }
+    let (first, rest) = number.split_at(1);
+    let (middle, last) = rest.split_at(7);
+    let Some(middle_digits) = digits(middle) else { return false };
+    let last = last.as_bytes()[0];
+
+    match first.as_bytes()[0] {
+        // DNI and NIE: the letter is the number modulo 23
+        b'0'..=b'9' | b'X' | b'Y' | b'Z' | b'K' | b'L' | b'M' => {
+            let lead = match first {
+                "X" => "0",
+                "Y" => "1",
+                "Z" => "2",
+                "K" | "L" | "M" => "",
+                digit => digit,
+            };
+            DNI_LETTERS[mod_big(&format!("{}{}", lead, middle), 23) as usize] == last
+        }
+        // CIF of a legal entity: a Luhn digit, or the letter standing for it
+        b'A'..=b'H' | b'J' | b'N' | b'P'..=b'S' | b'U'..=b'W' => {
+            let check = luhn_check_digit(&middle_digits);
+            last == b'0' + check as u8 || last == b"JABCDEFGHI"[check as usize]
+        }
+        _ => false,
+    }
+}
+
+fn fr_vat(number: &str) -> bool {
+    if number.len() != 11 {
+        return false;
+    }
+    let (key, siren) = number.split_at(2);
+    if digits(siren).is_none() {
+        return false;
+    }
+    let siren_mod = mod_big(siren, 97) as u32;
+    if let Some(key) = digits(key) {
+        return key[0] * 10 + key[1] == (12 + 3 * siren_mod) % 97;
+    }
+    // Newer alphanumeric keys
+    const ALPHABET: &str = "0123456789ABCDEFGHJKLMNPQRSTUVWXYZ";
+    let mut chars = key.chars().map(|c| ALPHABET.find(c));
+    let (Some(Some(a)), Some(Some(b))) = (chars.next(), chars.next()) else { return false };
+    let check = if a < 10 { a * 24 + b - 10 } else { a * 34 + b - 100 };
+    (mod_big(siren, 11) as usize + 1 + check / 11) % 11 == check % 11
+}
+
+fn ie_vat(number: &str) -> bool {
+    const ALPHABET: &str = "WABCDEFGHIJKLMNOPQRSTUV";
+    // Old format: digit, letter or + or *, five digits, check letter
+    let number = match number.as_bytes() {
+        [d, c, ..] if number.len() == 8 && d.is_ascii_digit() && (c.is_ascii_uppercase() || *c == b'+' || *c == b'*') => {
+            format!("0{}{}{}", &number[2..7], &number[..1], &number[7..])
+        }
+        _ => number.to_string(),
+    };
+    if !(8..=9).contains(&number.len()) {
+        return false;
+    }
+    let Some(d) = digits(&number[..7]) else { return false };
+    let extra = match number.get(8..) {
+        Some("") | None => 0,
+        Some(c) => match ALPHABET.find(c) {
+            Some(i) => i as u32,
+            None => return false,
+        },
+    };
+    let sum = weighted_sum(&d, &[8, 7, 6, 5, 4, 3, 2]) + 9 * extra;
+    ALPHABET.as_bytes()[(sum % 23) as usize] == number.as_bytes()[7]
+}
+
+fn nl_vat(number: &str) -> bool {
+    let Some((body, branch)) = number.split_once('B') else { return false };
+    if digits_of_len(branch, 2).is_none() || body.is_empty() || body.len() > 9 {
+        return false;
+    }
+    let body = format!("{:0>9}", body);
+    let Some(d) = digits(&body) else { return false };
+    let bsn = (weighted_sum(&d, &[9, 8, 7, 6, 5, 4, 3, 2]) + 11 - d[8]) % 11 == 0;
+    // Sole proprietors' numbers since 2020: ISO 7064 MOD 97-10 over "NL" + number
+    let expanded: String = format!("NL{}B{}", body, branch).chars().map(|c| c.to_digit(36).unwrap().to_string()).collect();
+    bsn || mod_big(&expanded, 97) == 1
+}
+
+fn gb_vat(number: &str) -> bool {
+    // Government departments and health authorities
+    if let Some(n) = number.strip_prefix("GD") {
+        return digits_of_len(n, 3).is_some() && n < "500";
+    }
+    if let Some(n) = number.strip_prefix("HA") {
+        return digits_of_len(n, 3).is_some() && n >= "500";
+    }
+    // Nine digits, optionally followed by a three-digit branch
+    digits(number).filter(|d| d.len() == 9 || d.len() == 12).is_some_and(|d| {
+        matches!(weighted_sum(&d[..9], &[8, 7, 6, 5, 4, 3, 2, 10, 1]) % 97, 0 | 42)
+    })
+}
+
+fn ch_uid(number: &str) -> bool {
+    digits_of_len(number, 9).is_some_and(|d| match 11 - weighted_sum(&d, &[5, 4, 3, 2, 7, 6, 5, 4]) % 11 {
+        10 => false,
+        11 => d[8] == 0,
+        check => check == d[8],
+    })
+}
+
+fn au_abn(number: &str) -> bool {
+    digits_of_len(number, 11).is_some_and(|mut d| {
+        if d[0] == 0 {
+            return false;
+        }
+        d[0] -= 1;
+        weighted_sum(&d, &[10, 1, 3, 5, 7, 9, 11, 13, 15, 17, 19]) % 89 == 0
+    })
+}
+
+fn in_gstin(number: &str) -> bool {
+    const ALPHABET: &str = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    let b = number.as_bytes();
+    if b.len() != 15 || !number.is_ascii() {
+        return false;
+    }
+    let state: u32 = number[..2].parse().unwrap_or(0);
+    let pan = b[2..7].iter().all(u8::is_ascii_uppercase) && b[7..11].iter().all(u8::is_ascii_digit) && b[11].is_ascii_uppercase();
+    if !((1..=38).contains(&state) || state == 97 || state == 99) || !pan || b[12] == b'0' {
+        return false;
+    }
+    let Some(values) = number.chars().map(|c| ALPHABET.find(c)).collect::<Option<Vec<_>>>() else { return false };
+    let sum: usize = values[..14].iter().enumerate().map(|(i, v)| {
+        let product = v * if i % 2 == 1 { 2 } else { 1 };
+        product / 36 + product % 36
+    }).sum();
+    (36 - sum % 36) % 36 == values[14]
+}
+
+fn us_ein(number: &str) -> bool {
+    // Prefixes the IRS has never assigned
+    const UNASSIGNED: &[&str] = &["00", "07", "08", "09", "17", "18", "19", "28", "29", "49", "69", "70", "78", "79", "89", "96", "97"];
+    digits_of_len(number, 9).is_some() && !UNASSIGNED.contains(&&number[..2])
+}
+
+/// Result of a VIES lookup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViesStatus {
+    Valid,
+    Invalid,
+    /// VIES or the member state's service couldn't answer.
+    Unavailable,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ViesResponse {
+    is_valid: bool,
+    user_error: Option<String>,
+}
+
+/// Client for the VIES REST API (`GET {url}/ms/{country}/vat/{number}`),
+/// enabled by setting `VIES_URL`, e.g. to
+/// `https://ec.europa.eu/taxation_customs/vies/rest-api` or a local stub.
+#[derive(Clone)]
+pub struct Vies {
+    http: reqwest::Client,
+    url: String,
+}
+
+impl Vies {
+    /// `None` when `VIES_URL` is unset or empty.
+    pub fn from_env() -> Option<Self> {
+        let url = std::env::var("VIES_URL").ok().filter(|url| !url.is_empty())?;
+        let http = reqwest::Client::builder().timeout(Duration::from_secs(5)).build().ok()?;
+        Some(Vies { http, url: url.trim_end_matches('/').to_string() })
+    }
+
+    /// Rejects `value` with a 422 on `field` if it is an EU VAT number VIES
+    /// reports as unregistered.
+    pub async fn ensure_registered(&self, field: &str, value: &str, country: Option<&str>) -> Result<(), ApiError> {
+        let Ok(Some(tax_id)) = parse(value, country) else { return Ok(()) };
+        if self.check(&tax_id).await == ViesStatus::Invalid {
+            return Err(ApiError::Validation(vec![FieldError {
+                field: field.to_string(),
+                message: "is not a registered VAT number according to VIES".to_string(),
+            }]));
+        }
+        Ok(())
+    }
+
+    /// Looks the number up; anything but a definite answer is `Unavailable`,
+    /// so an outage never blocks saving.
+    pub async fn check(&self, tax_id: &TaxId) -> ViesStatus {
+        let Some((country, number)) = tax_id.vies_parts() else { return ViesStatus::Unavailable };
+        let url = format!("{}/ms/{}/vat/{}", self.url, country, number);
+        let response = match self.http.get(url).send().await.and_then(|r| r.error_for_status()) {
+            Ok(response) => response.json::<ViesResponse>().await,
+            Err(e) => Err(e),
+        };
+        match response {
+            Ok(r) if r.is_valid => ViesStatus::Valid,
+            Ok(r) if matches!(r.user_error.as_deref(), None | Some("VALID") | Some("INVALID")) => ViesStatus::Invalid,
+            Ok(r) => {
+                tracing::warn!("VIES could not check {}: {}", tax_id.number, r.user_error.unwrap_or_default());
+                ViesStatus::Unavailable
+            }
+            Err(e) => {
+                tracing::warn!("VIES lookup of {} failed: {}", tax_id.number, e);
+                ViesStatus::Unavailable
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kind_of(value: &str, country: Option<&str>) -> Option<TaxIdKind> {
+        parse(value, country).expect(value).map(|t| t.kind)
+    }
+
+    #[test]
+    fn accepts_valid_eu_vat_numbers() {
+        for number in [
+            "ATU13585627", "BE0403019261", "BG175074752", "CY10259033P", "CZ25123891", "DE136695976", "DK13585628",
+            "EE100931558", "EL094259216", "ESA13585625", "ES54362315K", "FI20774740", "FR40303265045", "HR33392005961",
+            "HU12892312", "IE6433435F", "IE8D79739I", "IT00743110157", "LT119511515", "LT100001919017", "LU15027442",
+            "LV40003521600", "MT11679112", "NL004495445B01", "PL8567346215", "PT501964843", "RO18547290",
+            "SE123456789701", "SI50223054", "SK2022749619",
+        ] {
+            assert_eq!(kind_of(number, None), Some(TaxIdKind::EuVat), "{}", number);
+        }
+    }
+
+    #[test]
+    fn rejects_eu_vat_numbers_with_a_wrong_check_digit() {
+        for number in [
+            "ATU13585626", "BE0403019262", "BG175074751", "CY10259033Z", "CZ25123890", "DE136695978", "DK13585627",
+            "EE100931559", "EL094259217", "ESA13585626", "ES54362315Z", "FI20774741", "FR84323140391", "HR33392005962",
+            "HU12892313", "IE6433435E", "IT00743110158", "LT100001919018", "LU15027443", "LV40003521601", "MT11679113",
+            "NL123456789B90", "PL8567346216", "PT501964842", "RO18547291", "SE123456789101", "SI50223055", "SK2022749618",
+        ] {
+            assert_eq!(parse(number, None), Err("is not a valid EU VAT number"), "{}", number);
+        }
+    }
+
+    #[test]
+    fn compacts_and_prefixes_by_country() {
+        let tax_id = parse("136 695.976", Some("DE")).unwrap().unwrap();
+        assert_eq!(tax_id.number, "DE136695976");
+        assert_eq!(tax_id.vies_parts(), Some(("DE", "136695976")));
+        assert_eq!(parse("GR094259216", None).unwrap().unwrap().number, "EL094259216");
+        assert_eq!(canonical(" de 136-695-976 ", None), "DE136695976");
+        assert_eq!(canonical("ABC 123", Some("BR")), "ABC 123");
+    }
+
+    #[test]
+    fn checks_other_schemes() {
+        let valid = [
+            ("GB980780684", None, TaxIdKind::GbVat),
+            ("GBGD001", None, TaxIdKind::GbVat),
+            ("GBHA500", None, TaxIdKind::GbVat),
+            ("CHE-100.155.212 MWST", None, TaxIdKind::ChUid),
+            ("83 914 571 673", Some("AU"), TaxIdKind::AuAbn),
+            ("27AAPFU0939F1ZV", Some("IN"), TaxIdKind::InGstin),
+            ("04-2103594", Some("US"), TaxIdKind::UsEin),
+        ];
+        for (number, country, kind) in valid {
+            assert_eq!(kind_of(number, country), Some(kind), "{}", number);
+        }
+        let invalid = [
+            ("GB802311781", None),
+            ("GBGD500", None),
+            ("CHE-100.155.213", None),
+            ("99 999 999 999", Some("AU")),
+            ("27AAPFU0939F1Z1", Some("IN")),
+            ("07-2103594", Some("US")),
+        ];
+        for (number, country) in invalid {
+            assert!(parse(number, country).is_err(), "{}", number);
+        }
+        assert_eq!(parse("12345", Some("BR")), Ok(None));
+        assert_eq!(parse(" ", None), Err("must not be empty"));
+    }
+
+    #[test]
+    fn rejects_non_ascii_input_without_panicking() {
+        assert_eq!(parse("aä", None), Ok(None));
+        assert!(parse("CY1234567é", None).is_err());
+        assert!(parse("ESÉ1234567", None).is_err());
+        assert!(parse("FRAÉ23456789", None).is_err());
+        assert!(parse("IE1é34567A", None).is_err());
+        assert!(parse("2éAAPFU0939F1Z", Some("IN")).is_err());
+        assert!(parse("0é-2103594", Some("US")).is_err());
+        assert!(parse("DE13669597é", None).is_err());
+    }
+
+    /// A local stand-in for VIES answering for one registered number.
+    async fn vies_stub() -> Vies {
+        use axum::{extract::Path, routing::get, Json, Router};
+        let app = Router::new().route(
+            "/ms/:country/vat/:number",
+            get(|Path((country, number)): Path<(String, String)>| async move {
+                Json(match (country.as_str(), number.as_str()) {
+                    ("DE", "136695976") => serde_json::json!({ "isValid": true }),
+                    ("FR", _) => serde_json::json!({ "isValid": false, "userError": "MS_UNAVAILABLE" }),
+                    _ => serde_json::json!({ "isValid": false, "userError": "INVALID" }),
+                })
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        Vies { http: reqwest::Client::new(), url }
+    }
+
+    #[tokio::test]
+    async fn checks_registration_with_vies() {
+        let vies = vies_stub().await;
+        let registered = parse("DE136695976", None).unwrap().unwrap();
+        assert_eq!(vies.check(&registered).await, ViesStatus::Valid);
+        assert_eq!(vies.check(&parse("DK13585628", None).unwrap().unwrap()).await, ViesStatus::Invalid);
+        assert_eq!(vies.check(&parse("FR40303265045", None).unwrap().unwrap()).await, ViesStatus::Unavailable);
+        // Not an EU number, so never looked up
+        assert_eq!(vies.check(&parse("GB980780684", None).unwrap().unwrap()).await, ViesStatus::Unavailable);
+
+        assert!(vies.ensure_registered("tax_id", "DE136695976", None).await.is_ok());
+        assert!(matches!(vies.ensure_registered("tax_id", "DK13585628", None).await, Err(ApiError::Validation(_))));
+        assert!(vies.ensure_registered("tax_id", "FR40303265045", None).await.is_ok());
+        // Numbers failing the offline check are left to the validator
+        assert!(vies.ensure_registered("tax_id", "DE136695978", None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn treats_an_unreachable_vies_as_unavailable() {
+        let vies = Vies { http: reqwest::Client::new(), url: "http://127.0.0.1:9".to_string() };
+        assert_eq!(vies.check(&parse("DE136695976", None).unwrap().unwrap()).await, ViesStatus::Unavailable);
+    }
+}
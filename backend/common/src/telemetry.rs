@@ -0,0 +1,218 @@
+//! Logging and distributed tracing.
+//!
+//! Spans are exported over OTLP/HTTP when `OTEL_EXPORTER_OTLP_ENDPOINT` is
+//! set, e.g. `http://localhost:4318` for a local collector; otherwise the
+//! service only logs. Every request gets a server span named after its route
+//! that continues the caller's trace from a W3C `traceparent` header, and
+//! every sqlx statement becomes a client span beneath it. Calls to other
+//! services carry the trace on through [`propagate`].
+
+use axum::{
+    extract::{MatchedPath, Request},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::{Span as _, SpanKind, Tracer as _, TracerProvider as _};
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace, Resource};
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Instrument, Level, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+use crate::error::ErrorCode;
+use crate::metrics;
+use crate::request_id::current_request_id;
+
+/// Sets up the log output (`RUST_LOG`, default `info`) and, when an OTLP
+/// endpoint is configured, the span exporter.
+pub(crate) fn init(service: &'static str) {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let log_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| "info,sqlx::postgres::notice=warn".into());
+    let (exporter, export_error) = match otlp_endpoint() {
+        Some(endpoint) => match install_exporter(service, &endpoint) {
+            Ok(tracer) => (Some(tracer), None),
+            Err(e) => (None, Some(e)),
+        },
+        None => (None, None),
+    };
+    let otel_layer = exporter.map(|tracer| {
+        tracing_opentelemetry::layer()
+            .with_tracer(tracer)
+            .with_filter(Targets::new().with_default(Level::INFO))
+    });
+
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(log_filter))
+        .with(otel_layer)
+        // sqlx logs each statement at DEBUG; only this layer listens at that level.
+        .with(QuerySpans.with_filter(Targets::new().with_target("sqlx::query", Level::DEBUG)))
+        .init();
+
+    if let Some(e) = export_error {
+        tracing::warn!("Tracing export disabled: {}", e);
+    }
+}
+
+fn otlp_endpoint() -> Option<String> {
+    ["OTEL_EXPORTER_OTLP_TRACES_ENDPOINT", "OTEL_EXPORTER_OTLP_ENDPOINT"]
+        .iter()
+        .find_map(|key| std::env::var(key).ok().filter(|v| !v.trim().is_empty()))
+}
+
+fn install_exporter(service: &'static str, endpoint: &str) -> Result<trace::Tracer, opentelemetry::trace::TraceError> {
+    let service_name = std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| service.to_string());
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().http().with_endpoint(endpoint))
+        .with_trace_config(trace::config().with_resource(Resource::new([KeyValue::new("service.name", service_name)])))
+        .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+    tracing::info!("Exporting traces to {}", endpoint);
+    Ok(tracer)
+}
+
+/// Flushes spans that have not been exported yet.
+pub async fn shutdown() {
+    tokio::task::spawn_blocking(global::shutdown_tracer_provider).await.ok();
+}
+
+/// Middleware giving each request a span and recording its latency and,
+/// for 4xx and 5xx responses, its error code.
+pub async fn observe(request: Request, next: Next) -> Response {
+    let method = request.method().clone();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let span = tracing::info_span!(
+        "request",
+        otel.name = %format_args!("{} {}", method, route),
+        otel.kind = "server",
+        otel.status_code = tracing::field::Empty,
+        http.request.method = %method,
+        http.route = %route,
+        http.response.status_code = tracing::field::Empty,
+        request_id = current_request_id().as_deref(),
+    );
+    span.set_parent(global::get_text_map_propagator(|p| p.extract(&HeaderExtractor(request.headers()))));
+
+    let start = Instant::now();
+    let response = next.run(request).instrument(span.clone()).await;
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let status = response.status();
+    span.record("http.response.status_code", status.as_u16());
+    if status.is_server_error() {
+        span.record("otel.status_code", "ERROR");
+    }
+
+    let metrics = metrics::http();
+    metrics.duration.with_label_values(&[method.as_str(), &route, status.as_str()]).observe(elapsed);
+    if status.is_client_error() || status.is_server_error() {
+        let code = response.extensions().get::<ErrorCode>().map(|c| c.0).unwrap_or("none");
+        metrics.errors.with_label_values(&[method.as_str(), &route, status.as_str(), code]).inc();
+    }
+
+    response
+}
+
+/// Adds the current trace context (`traceparent`) to a call to another service.
+pub fn propagate(request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    let cx = tracing::Span::current().context();
+    let mut headers = HashMap::new();
+    global::get_text_map_propagator(|p| p.inject_context(&cx, &mut headers));
+    headers.into_iter().fold(request, |request, (name, value)| request.header(name, value))
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+/// Turns sqlx's per-statement log events into client spans and query
+/// latency samples. sqlx 0.7 has no spans of its own, but the event carries
+/// the elapsed time, so the span is back-dated to when the statement began.
+struct QuerySpans;
+
+#[derive(Default)]
+struct QueryFields {
+    summary: String,
+    statement: String,
+    rows_affected: u64,
+    elapsed_secs: Option<f64>,
+}
+
+impl Visit for QueryFields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "summary" => self.summary = value.to_string(),
+            "db.statement" => self.statement = value.trim().to_string(),
+            _ => {}
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name() == "rows_affected" {
+            self.rows_affected = value;
+        }
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        if field.name() == "elapsed_secs" {
+            self.elapsed_secs = Some(value);
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+}
+
+impl<S> Layer<S> for QuerySpans
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut fields = QueryFields::default();
+        event.record(&mut fields);
+        let Some(elapsed) = fields.elapsed_secs else { return };
+
+        let operation = fields.summary.split_whitespace().next().unwrap_or("query").to_uppercase();
+        metrics::http().query_duration.with_label_values(&[&operation]).observe(elapsed);
+
+        let end = SystemTime::now();
+        let start = end.checked_sub(Duration::from_secs_f64(elapsed)).unwrap_or(end);
+        let statement = if fields.statement.is_empty() { fields.summary.clone() } else { fields.statement };
+        let parent = tracing::Span::current().context();
+        let tracer = global::tracer_provider().tracer("sqlx");
+        let mut span = tracer
+            .span_builder(fields.summary)
+            .with_kind(SpanKind::Client)
+            .with_start_time(start)
+            .with_attributes(vec![
+                KeyValue::new("db.system", "postgresql"),
+                KeyValue::new("db.operation", operation),
+                KeyValue::new("db.statement", statement),
+                KeyValue::new("db.rows_affected", fields.rows_affected as i64),
+            ])
+            .start_with_context(&tracer, &parent);
+        span.end_with_timestamp(end);
+    }
+}
@@ -0,0 +1,395 @@
+//! Request validation.
+//!
+//! Request types implement [`Validate`] by listing their rules against a
+//! [`Validator`]; handlers take [`Valid<T>`] instead of `Json<T>`, which
+//! rejects the request with a 422 listing every invalid field.
+
+use axum::{
+    async_trait,
+    extract::{FromRequest, Request},
+    Json,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt::Display;
+
+use crate::ApiError;
+
+/// Officially assigned ISO 3166-1 alpha-2 codes.
+const COUNTRY_CODES: &str = "\
+    AD AE AF AG AI AL AM AO AQ AR AS AT AU AW AX AZ BA BB BD BE BF BG BH BI BJ \
+    BL BM BN BO BQ BR BS BT BV BW BY BZ CA CC CD CF CG CH CI CK CL CM CN CO CR \
+    CU CV CW CX CY CZ DE DJ DK DM DO DZ EC EE EG EH ER ES ET FI FJ FK FM FO FR \
+    GA GB GD GE GF GG GH GI GL GM GN GP GQ GR GS GT GU GW GY HK HM HN HR HT HU \
+    ID IE IL IM IN IO IQ IR IS IT JE JM JO JP KE KG KH KI KM KN KP KR KW KY KZ \
+    LA LB LC LI LK LR LS LT LU LV LY MA MC MD ME MF MG MH MK ML MM MN MO MP MQ \
+    MR MS MT MU MV MW MX MY MZ NA NC NE NF NG NI NL NO NP NR NU NZ OM PA PE PF \
+    PG PH PK PL PM PN PR PS PT PW PY QA RE RO RS RU RW SA SB SC SD SE SG SH SI \
+    SJ SK SL SM SN SO SR SS ST SV SX SY SZ TC TD TF TG TH TJ TK TL TM TN TO TR \
+    TT TV TW TZ UA UG UM US UY UZ VA VC VE VG VI VN VU WF WS YE YT ZA ZM ZW";
+
+/// Active ISO 4217 currency codes.
+const CURRENCY_CODES: &str = "\
+    AED AFN ALL AMD ANG AOA ARS AUD AWG AZN BAM BBD BDT BGN BHD BIF BMD BND BOB \
+    BRL BSD BTN BWP BYN BZD CAD CDF CHF CLP CNY COP CRC CUP CVE CZK DJF DKK DOP \
+    DZD EGP ERN ETB EUR FJD FKP GBP GEL GHS GIP GMD GNF GTQ GYD HKD HNL HTG HUF \
+    IDR ILS INR IQD IRR ISK JMD JOD JPY KES KGS KHR KMF KPW KRW KWD KYD KZT LAK \
+    LBP LKR LRD LSL LYD MAD MDL MGA MKD MMK MNT MOP MRU MUR MVR MWK MXN MYR MZN \
+    NAD NGN NIO NOK NPR NZD OMR PAB PEN PGK PHP PKR PLN PYG QAR RON RSD RUB RWF \
+    SAR SBD SCR SDG SEK SGD SHP SLE SOS SRD SSP STN SVC SYP SZL THB TJS TMT TND \
+    TOP TRY TTD TWD TZS UAH UGX USD UYU UZS VES VND VUV WST XAF XCD XOF XPF YER \
+    ZAR ZMW ZWL";
+
+pub trait Validate {
+    fn validate(&self, v: &mut Validator);
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    /// Path of the field in the request body, e.g. `items[2].amount`.
+    pub field: String,
+    pub message: String,
+}
+
+#[derive(Default)]
+pub struct Validator {
+    prefix: String,
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub fn check<T: Validate + ?Sized>(value: &T) -> Result<(), ApiError> {
+        let errors = Validator::collect(value);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ApiError::Validation(errors))
+        }
+    }
+
+    /// The rule violations of `value`, for callers that report them other than as a 422.
+    pub fn collect<T: Validate + ?Sized>(value: &T) -> Vec<FieldError> {
+        let mut v = Validator::default();
+        value.validate(&mut v);
+        v.errors
+    }
+
+    pub fn error(&mut self, field: &str, message: impl Into<String>) {
+        self.errors.push(FieldError { field: format!("{}{}", self.prefix, field), message: message.into() });
+    }
+
+    pub fn required(&mut self, field: &str, value: &str) {
+        if value.trim().is_empty() {
+            self.error(field, "must not be empty");
+        }
+    }
+
+    pub fn min_len(&mut self, field: &str, value: &str, min: usize) {
+        if value.chars().count() < min {
+            self.error(field, format!("must be at least {} characters", min));
+        }
+    }
+
+    pub fn max_len<'a>(&mut self, field: &str, value: impl Into<Option<&'a str>>, max: usize) {
+        if let Some(value) = value.into() {
+            if value.chars().count() > max {
+                self.error(field, format!("must be at most {} characters", max));
+            }
+        }
+    }
+
+    /// A pragmatic shape check, not RFC 5322: one `@`, a dotted domain, no spaces.
+    pub fn email<'a>(&mut self, field: &str, value: impl Into<Option<&'a str>>) {
+        let Some(value) = value.into() else { return };
+        let valid = match value.split_once('@') {
+            Some((local, domain)) => {
+                !local.is_empty()
+                    && !domain.contains('@')
+                    && domain.contains('.')
+                    && !domain.starts_with('.')
+                    && !domain.ends_with('.')
+                    && !value.chars().any(char::is_whitespace)
+                    && value.len() <= 254
+            }
+            None => false,
+        };
+        if !valid {
+            self.error(field, "must be a valid email address");
+        }
+    }
+
+    pub fn url<'a>(&mut self, field: &str, value: impl Into<Option<&'a str>>) {
+        let Some(value) = value.into() else { return };
+        let rest = value.strip_prefix("https://").or_else(|| value.strip_prefix("http://"));
+        if !matches!(rest, Some(host) if !host.is_empty() && !host.chars().any(char::is_whitespace)) {
+            self.error(field, "must be an http(s) URL");
+        }
+    }
+
+    pub fn min<T: PartialOrd + Display>(&mut self, field: &str, value: impl Into<Option<T>>, min: T) {
+        if let Some(value) = value.into() {
+            if value < min {
+                self.error(field, format!("must be at least {}", min));
+            }
+        }
+    }
+
+    pub fn range<T: PartialOrd + Display>(&mut self, field: &str, value: impl Into<Option<T>>, min: T, max: T) {
+        if let Some(value) = value.into() {
+            if value < min || value > max {
+                self.error(field, format!("must be between {} and {}", min, max));
+            }
+        }
+    }
+
+    pub fn one_of<'a>(&mut self, field: &str, value: impl Into<Option<&'a str>>, allowed: &[&str]) {
+        if let Some(value) = value.into() {
+            if !allowed.contains(&value) {
+                self.error(field, format!("must be one of: {}", allowed.join(", ")));
+            }
+        }
+    }
+
+    /// An ISO 3166-1 alpha-2 country code, upper case (`DE`, `US`).
+    pub fn country<'a>(&mut self, field: &str, value: impl Into<Option<&'a str>>) {
+        let Some(value) = value.into() else { return };
+        if value.len() != 2 || !COUNTRY_CODES.split(' ').any(|code| code == value) {
+            self.error(field, "must be an ISO 3166-1 alpha-2 country code");
+        }
+    }
+
+    /// An ISO 4217 currency code, upper case (`EUR`, `USD`).
+    pub fn currency<'a>(&mut self, field: &str, value: impl Into<Option<&'a str>>) {
+        let Some(value) = value.into() else { return };
+        if value.len() != 3 || !CURRENCY_CODES.split(' ').any(|code| code == value) {
+            self.error(field, "must be an ISO 4217 currency code");
+        }
+    }
+
+    /// A product tax category: up to 50 lower-case letters, digits and
+    /// underscores (`standard`, `reduced`, `zero_rated`).
+    pub fn tax_category<'a>(&mut self, field: &str, value: impl Into<Option<&'a str>>) {
+        let Some(value) = value.into() else { return };
+        let valid_char = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_';
+        if value.is_empty() || value.len() > 50 || !value.chars().all(valid_char) {
+            self.error(field, "must be 1-50 lower-case letters, digits or underscores");
+        }
+    }
+
+    /// A tax ID issued in `country`, checked where its scheme is known (see [`crate::tax_id`]).
+    pub fn tax_id<'a>(&mut self, field: &str, value: impl Into<Option<&'a str>>, country: Option<&str>) {
+        let Some(value) = value.into() else { return };
+        if let Err(message) = crate::tax_id::parse(value, country) {
+            self.error(field, message);
+        }
+    }
+
+    /// Validates a single nested object with its fields reported as `field.…`.
+    pub fn object<T: Validate>(&mut self, field: &str, value: Option<&T>) {
+        let Some(value) = value else { return };
+        let inner = format!("{}{}.", self.prefix, field);
+        let outer = std::mem::replace(&mut self.prefix, inner);
+        value.validate(self);
+        self.prefix = outer;
+    }
+
+    /// Validates each element with its fields reported as `field[i].…`.
+    pub fn nested<T: Validate>(&mut self, field: &str, items: &[T]) {
+        for (i, item) in items.iter().enumerate() {
+            let inner = format!("{}{}[{}].", self.prefix, field, i);
+            let outer = std::mem::replace(&mut self.prefix, inner);
+            item.validate(self);
+            self.prefix = outer;
+        }
+    }
+}
+
+/// JSON body extractor that runs the type's [`Validate`] rules.
+pub struct Valid<T>(pub T);
+
+#[async_trait]
+impl<S, T> FromRequest<S> for Valid<T>
+where
+    S: Send + Sync,
+    T: DeserializeOwned + Validate,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(req, state).await?;
+        Validator::check(&value)?;
+        Ok(Valid(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{header, StatusCode};
+    use axum::response::IntoResponse;
+    use serde::Deserialize;
+
+    fn fields(run: impl FnOnce(&mut Validator)) -> Vec<String> {
+        let mut v = Validator::default();
+        run(&mut v);
+        v.errors.into_iter().map(|e| e.field).collect()
+    }
+
+    fn rejects(run: impl FnOnce(&mut Validator)) -> bool {
+        !fields(run).is_empty()
+    }
+
+    #[test]
+    fn emails_need_a_local_part_and_a_dotted_domain() {
+        assert!(!rejects(|v| v.email("email", "ada@example.com")));
+        assert!(!rejects(|v| v.email("email", None)));
+        for email in ["ada", "@example.com", "ada@example", "ada@.com", "ada@example.", "a@b@example.com", "ada @example.com"] {
+            assert!(rejects(|v| v.email("email", email)), "{} was accepted", email);
+        }
+        assert!(rejects(|v| v.email("email", format!("{}@example.com", "a".repeat(250)).as_str())));
+    }
+
+    #[test]
+    fn urls_must_be_http_with_a_host() {
+        assert!(!rejects(|v| v.url("website", "https://example.com/about")));
+        assert!(!rejects(|v| v.url("website", "http://localhost:8080")));
+        for url in ["example.com", "ftp://example.com", "https://", "https://exa mple.com", "javascript:alert(1)"] {
+            assert!(rejects(|v| v.url("website", url)), "{} was accepted", url);
+        }
+    }
+
+    #[test]
+    fn countries_are_assigned_upper_case_codes() {
+        assert!(!rejects(|v| v.country("country", "DE")));
+        assert!(!rejects(|v| v.country("country", None)));
+        for country in ["de", "XX", "DEU", "", "D"] {
+            assert!(rejects(|v| v.country("country", country)), "{} was accepted", country);
+        }
+    }
+
+    #[test]
+    fn currencies_are_active_upper_case_codes() {
+        assert!(!rejects(|v| v.currency("currency", "EUR")));
+        for currency in ["eur", "EU", "EURO", "DEM", "ABC"] {
+            assert!(rejects(|v| v.currency("currency", currency)), "{} was accepted", currency);
+        }
+    }
+
+    #[test]
+    fn tax_categories_are_lower_case_slugs() {
+        assert!(!rejects(|v| v.tax_category("tax_category", "zero_rated")));
+        assert!(!rejects(|v| v.tax_category("tax_category", "vat7")));
+        let too_long = "a".repeat(51);
+        for category in ["", "Standard", "zero-rated", "reduced rate", too_long.as_str()] {
+            assert!(rejects(|v| v.tax_category("tax_category", category)), "{} was accepted", category);
+        }
+    }
+
+    #[test]
+    fn ranges_include_both_ends() {
+        assert!(!rejects(|v| v.range("tax_rate", 0.0, 0.0, 100.0)));
+        assert!(!rejects(|v| v.range("tax_rate", 100.0, 0.0, 100.0)));
+        assert!(!rejects(|v| v.range::<f64>("tax_rate", None, 0.0, 100.0)));
+        assert!(rejects(|v| v.range("tax_rate", -0.01, 0.0, 100.0)));
+        assert!(rejects(|v| v.range("tax_rate", 100.01, 0.0, 100.0)));
+        assert!(rejects(|v| v.min("quantity", 0, 1)));
+    }
+
+    #[test]
+    fn text_rules_count_characters_not_bytes() {
+        assert!(!rejects(|v| v.max_len("name", "Müller", 6)));
+        assert!(rejects(|v| v.min_len("password", "short", 8)));
+        assert!(rejects(|v| v.required("name", "  ")));
+        assert!(rejects(|v| v.one_of("status", "archived", &["draft", "sent"])));
+    }
+
+    #[derive(Deserialize)]
+    struct Item {
+        quantity: f64,
+    }
+
+    impl Validate for Item {
+        fn validate(&self, v: &mut Validator) {
+            v.min("quantity", self.quantity, 0.01);
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct Address {
+        country: String,
+    }
+
+    impl Validate for Address {
+        fn validate(&self, v: &mut Validator) {
+            v.country("country", self.country.as_str());
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct Order {
+        email: String,
+        address: Option<Address>,
+        items: Vec<Item>,
+    }
+
+    impl Validate for Order {
+        fn validate(&self, v: &mut Validator) {
+            v.email("email", self.email.as_str());
+            v.object("address", self.address.as_ref());
+            v.nested("items", &self.items);
+        }
+    }
+
+    fn order(json: &str) -> Order {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn reports_nested_fields_by_path() {
+        let order = order(r#"{"email":"nope","address":{"country":"Germany"},"items":[{"quantity":1},{"quantity":0}]}"#);
+        let errors: Vec<String> = Validator::collect(&order).into_iter().map(|e| e.field).collect();
+        assert_eq!(errors, ["email", "address.country", "items[1].quantity"]);
+    }
+
+    #[test]
+    fn passes_a_valid_value() {
+        let order = order(r#"{"email":"ada@example.com","address":null,"items":[{"quantity":2}]}"#);
+        assert!(Validator::check(&order).is_ok());
+    }
+
+    async fn extract(body: &'static str) -> Result<Valid<Order>, ApiError> {
+        let request = Request::post("/orders")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(axum::body::Body::from(body))
+            .unwrap();
+        Valid::<Order>::from_request(request, &()).await
+    }
+
+    #[tokio::test]
+    async fn the_extractor_answers_422_with_every_invalid_field() {
+        let Err(error) = extract(r#"{"email":"nope","items":[{"quantity":0}]}"#).await else {
+            panic!("an invalid order was accepted");
+        };
+        let response = error.into_response();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let problem: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(problem["code"], "validation_failed");
+        assert_eq!(
+            problem["errors"],
+            serde_json::json!([
+                { "field": "email", "message": "must be a valid email address" },
+                { "field": "items[0].quantity", "message": "must be at least 0.01" },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn the_extractor_passes_valid_bodies_through() {
+        let Ok(Valid(order)) = extract(r#"{"email":"ada@example.com","items":[]}"#).await else {
+            panic!("a valid order was rejected");
+        };
+        assert_eq!(order.email, "ada@example.com");
+        assert!(matches!(extract(r#"{"email":1,"items":[]}"#).await, Err(ApiError::Unprocessable(_))));
+    }
+}
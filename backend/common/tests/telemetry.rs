@@ -0,0 +1,52 @@
+//! Spans reach an OTLP/HTTP collector, stood in for by a local server that
+//! keeps every export it receives.
+
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::routing::{get, post};
+use axum::Router;
+use common::{bootstrap, telemetry};
+use std::sync::{Arc, Mutex};
+
+type Exports = Arc<Mutex<Vec<Bytes>>>;
+
+async fn collect(State(exports): State<Exports>, body: Bytes) {
+    exports.lock().unwrap().push(body);
+}
+
+async fn spawn(app: Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    format!("http://{}", addr)
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|window| window == needle)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn exports_request_spans_continuing_the_callers_trace() {
+    let exports = Exports::default();
+    let collector = spawn(Router::new().route("/v1/traces", post(collect)).with_state(exports.clone())).await;
+    std::env::set_var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT", format!("{}/v1/traces", collector));
+    bootstrap::init("telemetry-test", 0);
+
+    let service = spawn(Router::new().route("/ping/:id", get(|| async { "pong" })).layer(axum::middleware::from_fn(telemetry::observe))).await;
+    let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+    let response = reqwest::Client::new()
+        .get(format!("{}/ping/1", service))
+        .header("traceparent", format!("00-{}-00f067aa0ba902b7-01", trace_id))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+
+    telemetry::shutdown().await;
+    let exports = exports.lock().unwrap();
+    let body: Vec<u8> = exports.iter().flat_map(|b| b.iter().copied()).collect();
+    assert!(contains(&body, b"GET /ping/:id"), "no span named after the route was exported");
+    assert!(contains(&body, b"telemetry-test"), "the service name is missing");
+    let trace_id: Vec<u8> = (0..16).map(|i| u8::from_str_radix(&trace_id[i * 2..i * 2 + 2], 16).unwrap()).collect();
+    assert!(contains(&body, &trace_id), "the span is not in the caller's trace");
+}
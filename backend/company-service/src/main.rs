@@ -1,16 +1,17 @@
 use axum::{
-    routing::{get, put},
+    routing::get,
     Router,
     Json,
-    http::StatusCode,
     extract::State,
 };
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
-use tower_http::cors::CorsLayer;
-use common::AuthContext;
+use common::bootstrap;
+use common::billing::STANDARD_TAX_CATEGORY;
+use common::tax_id::{self, Vies};
+use common::{ApiError, AuthContext, AuthState, JwtVerifier, Valid, Validate, Validator};
+use common::account_data::{AccountDataPart, ArchiveFile, ErasureReport};
+use std::collections::BTreeMap;
 use std::sync::Arc;
-use sqlx::postgres::PgPoolOptions;
 use sqlx::{Pool, Postgres, FromRow};
 use chrono::{DateTime, Utc};
 
@@ -18,12 +19,17 @@ type DbPool = Pool<Postgres>;
 
 struct AppState {
     db: DbPool,
-    jwt_secret: Arc<String>,
+    jwt_verifier: JwtVerifier,
+    vies: Option<Vies>,
 }
 
-impl axum::extract::FromRef<Arc<AppState>> for Arc<String> {
-    fn from_ref(state: &Arc<AppState>) -> Self {
-        state.jwt_secret.clone()
+impl AuthState for AppState {
+    fn jwt_verifier(&self) -> &JwtVerifier {
+        &self.jwt_verifier
+    }
+
+    fn db(&self) -> Option<&DbPool> {
+        Some(&self.db)
     }
 }
 
@@ -36,6 +42,8 @@ struct CompanySettings {
     pub company_phone: Option<String>,
     pub company_address: Option<String>,
     pub company_website: Option<String>,
+    /// ISO 3166-1 alpha-2.
+    pub country: Option<String>,
     pub tax_id: Option<String>,
     pub logo_url: Option<String>,
     pub invoice_prefix: Option<String>,
@@ -47,10 +55,19 @@ struct CompanySettings {
     pub default_currency: Option<String>,
     pub default_notes: Option<String>,
     pub default_terms: Option<String>,
+    /// What invoice-service does when an invoice would take a client past its
+    /// credit limit: `warn` or `block`.
+    pub credit_limit_policy: String,
+    /// Rate (percent) per product tax category; `standard` uses `default_tax_rate`.
+    pub tax_rates: sqlx::types::Json<BTreeMap<String, f64>>,
     pub created_at: Option<DateTime<Utc>>,
     pub updated_at: Option<DateTime<Utc>>,
 }
 
+const COMPANY_COLUMNS: &str = "id, user_id, company_name, company_email, company_phone, company_address, company_website, country, tax_id, logo_url, \
+    invoice_prefix, invoice_starting_number, estimate_prefix, estimate_starting_number, default_payment_terms, \
+    default_tax_rate::float8 as default_tax_rate, default_currency, default_notes, default_terms, credit_limit_policy, tax_rates, created_at, updated_at";
+
 #[derive(Deserialize)]
 struct UpdateCompanyRequest {
     pub company_name: String,
@@ -58,6 +75,8 @@ struct UpdateCompanyRequest {
     pub company_phone: Option<String>,
     pub company_address: Option<String>,
     pub company_website: Option<String>,
+    /// ISO 3166-1 alpha-2.
+    pub country: Option<String>,
     pub tax_id: Option<String>,
     pub logo_url: Option<String>,
     pub invoice_prefix: Option<String>,
@@ -69,63 +88,87 @@ struct UpdateCompanyRequest {
     pub default_currency: Option<String>,
     pub default_notes: Option<String>,
     pub default_terms: Option<String>,
+    /// `warn` (the default) or `block`.
+    pub credit_limit_policy: Option<String>,
+    #[serde(default)]
+    pub tax_rates: BTreeMap<String, f64>,
+}
+
+impl Validate for UpdateCompanyRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("company_name", &self.company_name);
+        v.max_len("company_name", self.company_name.as_str(), 255);
+        v.email("company_email", self.company_email.as_deref());
+        v.url("company_website", self.company_website.as_deref());
+        v.country("country", self.country.as_deref());
+        v.tax_id("tax_id", self.tax_id.as_deref(), self.country.as_deref());
+        v.url("logo_url", self.logo_url.as_deref());
+        v.max_len("invoice_prefix", self.invoice_prefix.as_deref(), 20);
+        v.max_len("estimate_prefix", self.estimate_prefix.as_deref(), 20);
+        v.min("invoice_starting_number", self.invoice_starting_number, 1);
+        v.min("estimate_starting_number", self.estimate_starting_number, 1);
+        v.range("default_payment_terms", self.default_payment_terms, 0, 365);
+        v.range("default_tax_rate", self.default_tax_rate, 0.0, 100.0);
+        if let Some(currency) = &self.default_currency {
+            if currency.len() != 3 || !currency.chars().all(|c| c.is_ascii_uppercase()) {
+                v.error("default_currency", "must be a three-letter ISO 4217 code");
+            }
+        }
+        v.one_of("credit_limit_policy", self.credit_limit_policy.as_deref(), &["warn", "block"]);
+        for (category, rate) in &self.tax_rates {
+            let field = format!("tax_rates.{}", category);
+            if category == STANDARD_TAX_CATEGORY {
+                v.error(&field, "is set with default_tax_rate");
+            }
+            v.tax_category(&field, category.as_str());
+            v.range(&field, Some(*rate), 0.0, 100.0);
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() {
-    dotenvy::dotenv().ok();
-    tracing_subscriber::fmt::init();
-
-    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&database_url)
-        .await
-        .expect("Failed to create pool");
+    let config = bootstrap::init("company-service", 5005);
+    let pool = config.connect_database().await;
 
-    let jwt_secret = Arc::new(std::env::var("JWT_SECRET").unwrap_or_else(|_| "supersecret".to_string()));
+    let jwt_verifier = config.jwt_verifier();
     
     let state = Arc::new(AppState {
-        db: pool,
-        jwt_secret,
+        db: pool.clone(),
+        jwt_verifier,
+        vies: Vies::from_env(),
     });
 
     let app = Router::new()
         .route("/api/company", get(get_company).put(update_company))
-        .layer(CorsLayer::permissive())
+        .route("/internal/account-data/company", get(export_account_data).delete(erase_account_data))
         .with_state(state);
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 5005));
-    tracing::info!("Company service listening on {}", addr);
-    
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    bootstrap::serve(config, app, Some(pool)).await;
 }
 
 async fn get_company(
     auth: AuthContext,
     State(state): State<Arc<AppState>>,
-) -> Result<Json<CompanySettings>, (StatusCode, String)> {
+) -> Result<Json<CompanySettings>, ApiError> {
     let company = sqlx::query_as::<_, CompanySettings>(
-        "SELECT * FROM companies WHERE user_id = $1"
+        &format!("SELECT {} FROM companies WHERE user_id = $1", COMPANY_COLUMNS)
     )
     .bind(auth.user_id)
     .fetch_optional(&state.db)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    .await?;
 
     match company {
         Some(c) => Ok(Json(c)),
         None => {
             // Create default settings if not exists
             let c = sqlx::query_as::<_, CompanySettings>(
-                "INSERT INTO companies (user_id, company_name) VALUES ($1, $2) RETURNING *"
+                &format!("INSERT INTO companies (user_id, company_name) VALUES ($1, $2) RETURNING {}", COMPANY_COLUMNS)
             )
             .bind(auth.user_id)
             .bind("My Company")
             .fetch_one(&state.db)
-            .await
-            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+            .await?;
             Ok(Json(c))
         }
     }
@@ -134,11 +177,16 @@ async fn get_company(
 async fn update_company(
     auth: AuthContext,
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<UpdateCompanyRequest>,
-) -> Result<Json<CompanySettings>, (StatusCode, String)> {
-    let company = sqlx::query_as::<_, CompanySettings>(
-        "INSERT INTO companies (user_id, company_name, company_email, company_phone, company_address, company_website, tax_id, logo_url, invoice_prefix, invoice_starting_number, estimate_prefix, estimate_starting_number, default_payment_terms, default_tax_rate, default_currency, default_notes, default_terms) \
-         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17) \
+    Valid(payload): Valid<UpdateCompanyRequest>,
+) -> Result<Json<CompanySettings>, ApiError> {
+    if let (Some(vies), Some(tax_id)) = (&state.vies, &payload.tax_id) {
+        vies.ensure_registered("tax_id", tax_id, payload.country.as_deref()).await?;
+    }
+    let tax_id = payload.tax_id.as_deref().map(|t| tax_id::canonical(t, payload.country.as_deref()));
+
+    let company = sqlx::query_as::<_, CompanySettings>(&format!(
+        "INSERT INTO companies (user_id, company_name, company_email, company_phone, company_address, company_website, tax_id, logo_url, invoice_prefix, invoice_starting_number, estimate_prefix, estimate_starting_number, default_payment_terms, default_tax_rate, default_currency, default_notes, default_terms, country, credit_limit_policy, tax_rates) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, COALESCE($19, 'warn'), $20) \
          ON CONFLICT (user_id) DO UPDATE SET \
          company_name = EXCLUDED.company_name, \
          company_email = EXCLUDED.company_email, \
@@ -156,16 +204,20 @@ async fn update_company(
          default_currency = EXCLUDED.default_currency, \
          default_notes = EXCLUDED.default_notes, \
          default_terms = EXCLUDED.default_terms, \
+         country = EXCLUDED.country, \
+         credit_limit_policy = EXCLUDED.credit_limit_policy, \
+         tax_rates = EXCLUDED.tax_rates, \
          updated_at = NOW() \
-         RETURNING *"
-    )
+         RETURNING {}",
+        COMPANY_COLUMNS
+    ))
     .bind(auth.user_id)
     .bind(payload.company_name)
     .bind(payload.company_email)
     .bind(payload.company_phone)
     .bind(payload.company_address)
     .bind(payload.company_website)
-    .bind(payload.tax_id)
+    .bind(tax_id)
     .bind(payload.logo_url)
     .bind(payload.invoice_prefix)
     .bind(payload.invoice_starting_number)
@@ -176,9 +228,40 @@ async fn update_company(
     .bind(payload.default_currency)
     .bind(payload.default_notes)
     .bind(payload.default_terms)
+    .bind(payload.country)
+    .bind(payload.credit_limit_policy)
+    .bind(sqlx::types::Json(payload.tax_rates))
     .fetch_one(&state.db)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    .await?;
 
     Ok(Json(company))
 }
+
+async fn export_account_data(
+    auth: AuthContext,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<AccountDataPart>, ApiError> {
+    let companies = sqlx::query_as::<_, CompanySettings>(
+        &format!("SELECT {} FROM companies WHERE user_id = $1", COMPANY_COLUMNS)
+    )
+    .bind(auth.user_id)
+    .fetch_all(&state.db)
+    .await?;
+
+    let file = ArchiveFile::json("company.json", &companies).map_err(ApiError::internal)?;
+
+    Ok(Json(AccountDataPart { files: vec![file] }))
+}
+
+async fn erase_account_data(
+    auth: AuthContext,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<ErasureReport>, ApiError> {
+    let result = sqlx::query("DELETE FROM companies WHERE user_id = $1")
+        .bind(auth.user_id)
+        .execute(&state.db)
+        .await?;
+
+    tracing::info!("Erased {} company profiles for user {}", result.rows_affected(), auth.user_id);
+    Ok(Json(ErasureReport { deleted: result.rows_affected(), anonymized: 0 }))
+}
@@ -0,0 +1,215 @@
+//! The invoices part of GDPR export and erasure, served over
+//! `/internal/account-data/invoices` to auth-service (see `common::account_data`).
+
+use axum::{extract::State, Json};
+use common::account_data::{AccountDataPart, ArchiveFile, ErasureReport};
+use common::{ApiError, AuthContext};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::{
+    bundle_components, payments, render_invoice_pdf, subscriptions, usage, AppState, BillTo, Estimate, EstimateItem,
+    EstimateWithItems, Invoice, InvoiceItem, InvoiceWithItems, RecurringInvoice, BILL_TO_QUERY, INVOICE_LIST,
+};
+
+/// Invoices, estimates, recurring profiles, plans, payments, credit notes and usage as JSON, plus a PDF of every invoice.
+pub(crate) async fn export_account_data(auth: AuthContext, State(state): State<Arc<AppState>>) -> Result<Json<AccountDataPart>, ApiError> {
+    let invoices = sqlx::query_as::<_, Invoice>(&format!("SELECT {}, c.email as client_email {} WHERE i.user_id = $1 ORDER BY i.created_at", INVOICE_LIST.columns, INVOICE_LIST.from)).bind(auth.user_id).fetch_all(&state.db).await?;
+    let mut items = sqlx::query_as::<_, InvoiceItem>("SELECT ii.id, ii.invoice_id, ii.product_id, ii.description, ii.quantity::float8 as quantity, ii.price::float8 as price, ii.amount::float8 as amount FROM invoice_items ii JOIN invoices i ON i.id = ii.invoice_id WHERE i.user_id = $1 ORDER BY ii.id").bind(auth.user_id).fetch_all(&state.db).await?;
+    let estimates = sqlx::query_as::<_, Estimate>("SELECT e.id, e.user_id, e.client_id, c.name as client_name, e.estimate_number, e.status, e.total::float8 as total, e.issue_date, e.expiry_date, e.created_at FROM estimates e LEFT JOIN clients c ON e.client_id = c.id WHERE e.user_id = $1").bind(auth.user_id).fetch_all(&state.db).await?;
+    let mut estimate_items = sqlx::query_as::<_, EstimateItem>("SELECT ei.id, ei.estimate_id, ei.product_id, ei.description, ei.quantity::float8 as quantity, ei.price::float8 as price, ei.amount::float8 as amount FROM estimate_items ei JOIN estimates e ON e.id = ei.estimate_id WHERE e.user_id = $1 ORDER BY ei.id").bind(auth.user_id).fetch_all(&state.db).await?;
+    let recurring = sqlx::query_as::<_, RecurringInvoice>("SELECT r.id, r.user_id, r.client_id, c.name as client_name, r.interval, r.interval_count, r.start_date, r.next_run, r.last_run, r.status, r.total::float8 as total, r.bill_usage, r.plan_id, r.created_at FROM recurring_invoices r LEFT JOIN clients c ON r.client_id = c.id WHERE r.user_id = $1").bind(auth.user_id).fetch_all(&state.db).await?;
+    let plans = sqlx::query_as::<_, subscriptions::Plan>(&format!("SELECT {} {} WHERE p.user_id = $1 ORDER BY p.id", subscriptions::PLAN_LIST.columns, subscriptions::PLAN_LIST.from)).bind(auth.user_id).fetch_all(&state.db).await?;
+    let payments = sqlx::query_as::<_, payments::Payment>("SELECT p.id, p.invoice_id, p.amount::float8 as amount, p.paid_on, p.method, p.reference, p.created_at FROM payments p JOIN invoices i ON i.id = p.invoice_id WHERE i.user_id = $1 ORDER BY p.id").bind(auth.user_id).fetch_all(&state.db).await?;
+    let credit_notes = sqlx::query_as::<_, payments::CreditNote>(&format!("SELECT {} {} WHERE cn.user_id = $1 ORDER BY cn.id", payments::CREDIT_NOTE_LIST.columns, payments::CREDIT_NOTE_LIST.from)).bind(auth.user_id).fetch_all(&state.db).await?;
+    let usage_events = sqlx::query_as::<_, usage::UsageEvent>(&format!("SELECT {} {} WHERE e.user_id = $1 ORDER BY e.id", usage::USAGE_LIST.columns, usage::USAGE_LIST.from)).bind(auth.user_id).fetch_all(&state.db).await?;
+
+    let bill_to: HashMap<i32, BillTo> = sqlx::query_as::<_, BillTo>(BILL_TO_QUERY).bind(auth.user_id).fetch_all(&state.db).await?.into_iter().map(|b| (b.client_id, b)).collect();
+    let product_ids: Vec<i32> = items.iter().filter_map(|i| i.product_id).collect();
+    let components = bundle_components(&state.db, &product_ids).await?;
+
+    let mut files = Vec::new();
+    let mut with_items = Vec::new();
+    for invoice in invoices {
+        let (own, rest): (Vec<_>, Vec<_>) = items.into_iter().partition(|i| i.invoice_id == invoice.id);
+        items = rest;
+        let pdf = render_invoice_pdf(&invoice, &own, invoice.client_id.and_then(|id| bill_to.get(&id)), &components).map_err(ApiError::Internal)?;
+        files.push(ArchiveFile::bytes(format!("invoices/{}-{}.pdf", invoice.id, invoice.invoice_number), &pdf));
+        with_items.push(InvoiceWithItems { invoice, items: own, warnings: Vec::new() });
+    }
+
+    files.push(ArchiveFile::json("invoices.json", &with_items).map_err(ApiError::internal)?);
+    let mut estimates_with_items = Vec::new();
+    for estimate in estimates {
+        let (own, rest): (Vec<_>, Vec<_>) = estimate_items.into_iter().partition(|i| i.estimate_id == estimate.id);
+        estimate_items = rest;
+        estimates_with_items.push(EstimateWithItems { estimate, items: own });
+    }
+    files.push(ArchiveFile::json("estimates.json", &estimates_with_items).map_err(ApiError::internal)?);
+    files.push(ArchiveFile::json("recurring_invoices.json", &recurring).map_err(ApiError::internal)?);
+    files.push(ArchiveFile::json("plans.json", &plans).map_err(ApiError::internal)?);
+    files.push(ArchiveFile::json("payments.json", &payments).map_err(ApiError::internal)?);
+    files.push(ArchiveFile::json("credit_notes.json", &credit_notes).map_err(ApiError::internal)?);
+    files.push(ArchiveFile::json("usage_events.json", &usage_events).map_err(ApiError::internal)?);
+
+    Ok(Json(AccountDataPart { files }))
+}
+
+/// Invoices must be retained, so they are detached from the account and
+/// anonymized: the seller and buyer names and tax IDs printed on them are
+/// snapshotted, while notes and the link to the client record are dropped.
+/// Credit notes are kept the same way. Estimates, recurring profiles, plans and usage are deleted.
+pub(crate) async fn erase_account_data(auth: AuthContext, State(state): State<Arc<AppState>>) -> Result<Json<ErasureReport>, ApiError> {
+    let mut tx = state.db.begin().await?;
+
+    sqlx::query("UPDATE invoices i SET buyer_name = c.name, buyer_tax_id = c.tax_id FROM clients c WHERE c.id = i.client_id AND i.user_id = $1").bind(auth.user_id).execute(&mut *tx).await?;
+    sqlx::query("UPDATE invoices i SET seller_name = co.company_name, seller_tax_id = co.tax_id FROM companies co WHERE co.user_id = i.user_id AND i.user_id = $1").bind(auth.user_id).execute(&mut *tx).await?;
+    let anonymized = sqlx::query("UPDATE invoices SET user_id = NULL, client_id = NULL, notes = NULL, anonymized_at = NOW() WHERE user_id = $1").bind(auth.user_id).execute(&mut *tx).await?.rows_affected();
+    let anonymized = anonymized + sqlx::query("UPDATE credit_notes SET user_id = NULL, client_id = NULL, reason = NULL, anonymized_at = NOW() WHERE user_id = $1").bind(auth.user_id).execute(&mut *tx).await?.rows_affected();
+
+    let estimates = sqlx::query("DELETE FROM estimates WHERE user_id = $1").bind(auth.user_id).execute(&mut *tx).await?.rows_affected();
+    let recurring = sqlx::query("DELETE FROM recurring_invoices WHERE user_id = $1").bind(auth.user_id).execute(&mut *tx).await?.rows_affected();
+    let plans = sqlx::query("DELETE FROM plans WHERE user_id = $1").bind(auth.user_id).execute(&mut *tx).await?.rows_affected();
+    let usage = sqlx::query("DELETE FROM usage_events WHERE user_id = $1").bind(auth.user_id).execute(&mut *tx).await?.rows_affected();
+
+    tx.commit().await?;
+
+    tracing::info!("Anonymized {} invoices/credit notes and deleted {} estimates/recurring profiles/plans/usage events for user {}", anonymized, estimates + recurring + plans + usage, auth.user_id);
+    Ok(Json(ErasureReport { deleted: estimates + recurring + plans + usage, anonymized }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::JwtVerifier;
+    use sqlx::PgPool;
+
+    /// Tables whose rows auth-service erases along with the user itself.
+    const AUTH_TABLES: [&str; 5] = ["account_unlock_tokens", "api_keys", "login_attempts", "sso_connections", "user_identities"];
+
+    async fn insert(db: &PgPool, sql: &str, user_id: i32) -> i32 {
+        sqlx::query_scalar(sql).bind(user_id).fetch_one(db).await.unwrap()
+    }
+
+    /// An account with a company, a client, a product and a document of every kind.
+    async fn seed(db: &PgPool) -> (i32, i32) {
+        let user_id: i32 = sqlx::query_scalar("INSERT INTO users (email, password_hash) VALUES ('erase@example.com', 'x') RETURNING id")
+            .fetch_one(db)
+            .await
+            .unwrap();
+        insert(db, "INSERT INTO companies (user_id, company_name, tax_id, company_email) VALUES ($1, 'Ada Ltd', 'GB123456789', 'ada@example.com') RETURNING id", user_id).await;
+        let client_id = insert(db, "INSERT INTO clients (user_id, name, email, tax_id) VALUES ($1, 'Grace Hopper', 'grace@example.com', 'DE123456789') RETURNING id", user_id).await;
+        let product_id = insert(db, "INSERT INTO products (user_id, name, price, track_inventory) VALUES ($1, 'Widget', 10, TRUE) RETURNING id", user_id).await;
+
+        let invoice_id: i32 = sqlx::query_scalar(
+            "INSERT INTO invoices (user_id, client_id, invoice_number, status, total, notes) VALUES ($1, $2, 'INV-1', 'sent', 20, 'Call Grace first') RETURNING id"
+        )
+        .bind(user_id)
+        .bind(client_id)
+        .fetch_one(db)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO invoice_items (invoice_id, product_id, description, quantity, price, amount) VALUES ($1, $2, 'Widget', 2, 10, 20)")
+            .bind(invoice_id)
+            .bind(product_id)
+            .execute(db)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO payments (invoice_id, amount, paid_on) VALUES ($1, 5, CURRENT_DATE)").bind(invoice_id).execute(db).await.unwrap();
+        sqlx::query("INSERT INTO stock_movements (user_id, product_id, quantity, kind, invoice_id) VALUES ($1, $2, -2, 'issue', $3)")
+            .bind(user_id)
+            .bind(product_id)
+            .bind(invoice_id)
+            .execute(db)
+            .await
+            .unwrap();
+        sqlx::query(
+            "INSERT INTO credit_notes (user_id, client_id, invoice_id, credit_note_number, issue_date, amount, reason) \
+             VALUES ($1, $2, $3, 'CN-1', CURRENT_DATE, 5, 'Grace returned a widget')"
+        )
+        .bind(user_id)
+        .bind(client_id)
+        .bind(invoice_id)
+        .execute(db)
+        .await
+        .unwrap();
+
+        for sql in [
+            "INSERT INTO estimates (user_id, client_id, estimate_number) VALUES ($1, $2, 'EST-1') RETURNING id",
+            "INSERT INTO recurring_invoices (user_id, client_id, interval, start_date) VALUES ($1, $2, 'month', CURRENT_DATE) RETURNING id",
+            "INSERT INTO usage_events (user_id, idempotency_key, client_id, product_id, quantity, occurred_at) \
+             SELECT $1, 'evt-1', $2, id, 3, NOW() FROM products WHERE user_id = $1 RETURNING id",
+        ] {
+            sqlx::query(sql).bind(user_id).bind(client_id).execute(db).await.unwrap();
+        }
+        insert(db, "INSERT INTO plans (user_id, name, price, interval) VALUES ($1, 'Monthly', 10, 'month') RETURNING id", user_id).await;
+        (user_id, invoice_id)
+    }
+
+    /// Rows still tied to the user, by table, outside of `except`.
+    async fn rows_of(db: &PgPool, user_id: i32, except: &[&str]) -> Vec<(String, i64)> {
+        let tables: Vec<String> = sqlx::query_scalar(
+            "SELECT table_name::text FROM information_schema.columns WHERE table_schema = 'public' AND column_name = 'user_id' ORDER BY table_name"
+        )
+        .fetch_all(db)
+        .await
+        .unwrap();
+        let mut left = Vec::new();
+        for table in tables.into_iter().filter(|t| !except.contains(&t.as_str())) {
+            let count: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {} WHERE user_id = $1", table)).bind(user_id).fetch_one(db).await.unwrap();
+            if count > 0 {
+                left.push((table, count));
+            }
+        }
+        left
+    }
+
+    #[sqlx::test(migrator = "common::MIGRATOR")]
+    async fn erasure_anonymizes_invoices_and_leaves_no_personal_data(db: PgPool) {
+        let (user_id, invoice_id) = seed(&db).await;
+        let state = Arc::new(AppState { db: db.clone(), jwt_verifier: JwtVerifier::from_jwks_url("http://localhost:5001/.well-known/jwks.json".to_string()) });
+        let auth = AuthContext { user_id, scopes: None };
+
+        let Json(report) = erase_account_data(auth, State(state)).await.unwrap();
+        assert_eq!((report.anonymized, report.deleted), (2, 4));
+
+        // What client-, product- and company-service erase on their turn
+        for sql in [
+            "DELETE FROM clients WHERE user_id = $1",
+            "DELETE FROM price_lists WHERE user_id = $1",
+            "DELETE FROM products WHERE user_id = $1",
+            "DELETE FROM companies WHERE user_id = $1",
+        ] {
+            sqlx::query(sql).bind(user_id).execute(&db).await.unwrap();
+        }
+        assert_eq!(rows_of(&db, user_id, &AUTH_TABLES).await, []);
+
+        sqlx::query("DELETE FROM users WHERE id = $1").bind(user_id).execute(&db).await.unwrap();
+        assert_eq!(rows_of(&db, user_id, &[]).await, []);
+
+        let detached: (Option<i32>, Option<i32>, Option<String>, bool) =
+            sqlx::query_as("SELECT user_id, client_id, notes, anonymized_at IS NOT NULL FROM invoices WHERE id = $1").bind(invoice_id).fetch_one(&db).await.unwrap();
+        assert_eq!(detached, (None, None, None, true));
+        let snapshot: (String, String, String, String) =
+            sqlx::query_as("SELECT seller_name, seller_tax_id, buyer_name, buyer_tax_id FROM invoices WHERE id = $1").bind(invoice_id).fetch_one(&db).await.unwrap();
+        assert_eq!(snapshot, ("Ada Ltd".to_string(), "GB123456789".to_string(), "Grace Hopper".to_string(), "DE123456789".to_string()));
+
+        let lines: Vec<(String, Option<i32>)> = sqlx::query_as("SELECT description, product_id FROM invoice_items WHERE invoice_id = $1").bind(invoice_id).fetch_all(&db).await.unwrap();
+        assert_eq!(lines, [("Widget".to_string(), None)]);
+        let payments: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM payments WHERE invoice_id = $1").bind(invoice_id).fetch_one(&db).await.unwrap();
+        assert_eq!(payments, 1);
+
+        let credit_note: (Option<i32>, Option<String>, Option<i32>) = sqlx::query_as("SELECT client_id, reason, invoice_id FROM credit_notes").fetch_one(&db).await.unwrap();
+        assert_eq!(credit_note, (None, None, Some(invoice_id)));
+
+        // Nothing names the client anywhere
+        let mentions: i64 = sqlx::query_scalar(
+            "SELECT (SELECT COUNT(*) FROM invoices WHERE notes LIKE '%Grace%') + (SELECT COUNT(*) FROM credit_notes WHERE reason LIKE '%Grace%') \
+             + (SELECT COUNT(*) FROM clients) + (SELECT COUNT(*) FROM companies)"
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        assert_eq!(mentions, 0);
+    }
+}
@@ -0,0 +1,109 @@
+//! Applies the client's billing defaults (see client-service) to new and
+//! updated invoices: whatever the request leaves out is taken from the client,
+//! then the company settings, then the built-in defaults.
+
+use chrono::{Days, NaiveDate};
+use common::billing::{DEFAULT_CURRENCY, DEFAULT_LANGUAGE, DEFAULT_PAYMENT_TERMS};
+use common::ledger::round_cents;
+use common::{ApiError, FieldError};
+use sqlx::FromRow;
+
+use crate::CreateInvoiceRequest;
+
+#[derive(FromRow)]
+struct ClientDefaults {
+    currency: Option<String>,
+    language: Option<String>,
+    tax_treatment: String,
+    tax_exemption_reason: Option<String>,
+    discount_percent: f64,
+    payment_terms: Option<i32>,
+    tax_id: Option<String>,
+}
+
+#[derive(FromRow)]
+struct CompanyDefaults {
+    default_currency: Option<String>,
+    default_payment_terms: Option<i32>,
+}
+
+/// The settings an invoice is stored with.
+pub(crate) struct Billing {
+    pub currency: String,
+    pub language: String,
+    pub tax_treatment: String,
+    pub tax_exemption_reason: Option<String>,
+    pub discount_percent: f64,
+    pub discount_amount: f64,
+    /// After the discount.
+    pub total: f64,
+    pub due_date: Option<NaiveDate>,
+}
+
+/// Resolves the invoice's settings; `issued` is the date payment terms run
+/// from and `subtotal` the total before the discount.
+pub(crate) async fn resolve(conn: &mut sqlx::PgConnection, user_id: i32, payload: &CreateInvoiceRequest, issued: NaiveDate, subtotal: f64) -> Result<Billing, ApiError> {
+    let client = match payload.client_id {
+        Some(client_id) => Some(
+            sqlx::query_as::<_, ClientDefaults>(
+                "SELECT currency, language, tax_treatment, tax_exemption_reason, discount_percent::float8 AS discount_percent, payment_terms, tax_id \
+                 FROM clients WHERE id = $1 AND user_id = $2"
+            )
+            .bind(client_id)
+            .bind(user_id)
+            .fetch_optional(&mut *conn)
+            .await?
+            .ok_or_else(|| invalid("client_id", "does not exist"))?,
+        ),
+        None => None,
+    };
+    let company = sqlx::query_as::<_, CompanyDefaults>("SELECT default_currency, default_payment_terms FROM companies WHERE user_id = $1")
+        .bind(user_id)
+        .fetch_optional(&mut *conn)
+        .await?;
+
+    let tax_treatment = payload.tax_treatment.clone()
+        .or_else(|| client.as_ref().map(|c| c.tax_treatment.clone()))
+        .unwrap_or_else(|| "standard".to_string());
+    let tax_exemption_reason = match tax_treatment.as_str() {
+        "exempt" => Some(
+            payload.tax_exemption_reason.clone()
+                .or_else(|| client.as_ref().filter(|c| c.tax_treatment == "exempt").and_then(|c| c.tax_exemption_reason.clone()))
+                .ok_or_else(|| invalid("tax_exemption_reason", "is required for tax-exempt invoices"))?,
+        ),
+        "reverse_charge" if client.as_ref().and_then(|c| c.tax_id.as_ref()).is_none() => {
+            return Err(invalid("tax_treatment", "reverse charge requires a client with a tax ID"));
+        }
+        _ => None,
+    };
+
+    let discount_percent = payload.discount_percent.or(client.as_ref().map(|c| c.discount_percent)).unwrap_or(0.0);
+    let discount_amount = round_cents(subtotal * discount_percent / 100.0);
+
+    let due_date = payload.due_date.or_else(|| {
+        let terms = client.as_ref().and_then(|c| c.payment_terms)
+            .or(company.as_ref().and_then(|c| c.default_payment_terms))
+            .unwrap_or(DEFAULT_PAYMENT_TERMS);
+        issued.checked_add_days(Days::new(terms.max(0) as u64))
+    });
+
+    Ok(Billing {
+        currency: payload.currency.clone()
+            .or_else(|| client.as_ref().and_then(|c| c.currency.clone()))
+            .or_else(|| company.and_then(|c| c.default_currency))
+            .unwrap_or_else(|| DEFAULT_CURRENCY.to_string()),
+        language: payload.language.clone()
+            .or_else(|| client.and_then(|c| c.language))
+            .unwrap_or_else(|| DEFAULT_LANGUAGE.to_string()),
+        tax_treatment,
+        tax_exemption_reason,
+        discount_percent,
+        discount_amount,
+        total: round_cents(subtotal - discount_amount),
+        due_date,
+    })
+}
+
+fn invalid(field: &str, message: &str) -> ApiError {
+    ApiError::Validation(vec![FieldError { field: field.to_string(), message: message.to_string() }])
+}
@@ -0,0 +1,105 @@
+//! Credit control: whether issuing an amount to a client would take it past
+//! its credit limit (see client-service), or bill a client on hold. The
+//! company's `credit_limit_policy` decides whether that blocks the invoice or
+//! only warns.
+
+use common::ApiError;
+use sqlx::FromRow;
+
+#[derive(FromRow)]
+struct ClientCredit {
+    name: String,
+    credit_limit: Option<f64>,
+    risk_status: String,
+    outstanding_balance: f64,
+    policy: String,
+}
+
+/// Checks issuing `amount` more to `client_id`. `excluding` is an invoice
+/// being re-issued, whose current total is left out of the balance.
+///
+/// Returns the warning to pass on when the policy is `warn`, and
+/// `ApiError::Conflict` when it is `block`.
+pub(crate) async fn check(conn: &mut sqlx::PgConnection, user_id: i32, client_id: Option<i32>, amount: f64, excluding: Option<i32>) -> Result<Option<String>, ApiError> {
+    let Some(client_id) = client_id else { return Ok(None) };
+    if amount <= 0.0 {
+        return Ok(None);
+    }
+
+    // The same balance as the client list and statement: issued invoices less
+    // payments and credit notes
+    let client = sqlx::query_as::<_, ClientCredit>(
+        "SELECT c.name, c.credit_limit::float8 AS credit_limit, c.risk_status, \
+           (COALESCE((SELECT SUM(i.total) FROM invoices i WHERE i.client_id = c.id AND i.status NOT IN ('draft', 'void') AND i.id IS DISTINCT FROM $3), 0) \
+            - COALESCE((SELECT SUM(p.amount) FROM payments p JOIN invoices i ON i.id = p.invoice_id WHERE i.client_id = c.id AND i.status NOT IN ('draft', 'void')), 0) \
+            - COALESCE((SELECT SUM(cn.amount) FROM credit_notes cn WHERE cn.client_id = c.id), 0))::float8 AS outstanding_balance, \
+           COALESCE((SELECT credit_limit_policy FROM companies WHERE user_id = $2), 'warn') AS policy \
+         FROM clients c WHERE c.id = $1 AND c.user_id = $2"
+    )
+    .bind(client_id)
+    .bind(user_id)
+    .bind(excluding)
+    .fetch_optional(&mut *conn)
+    .await?;
+    let Some(client) = client else { return Ok(None) };
+    client.assess(amount)
+}
+
+impl ClientCredit {
+    /// The problem with issuing `amount` more, as a warning or, under the
+    /// `block` policy, a refusal.
+    fn assess(&self, amount: f64) -> Result<Option<String>, ApiError> {
+        let problem = if self.risk_status == "hold" {
+            format!("Client {} is on credit hold", self.name)
+        } else {
+            match self.credit_limit {
+                Some(limit) if self.outstanding_balance + amount > limit + 0.005 => format!(
+                    "This takes client {}'s outstanding balance to {:.2}, over its credit limit of {:.2}",
+                    self.name,
+                    self.outstanding_balance + amount,
+                    limit
+                ),
+                _ => return Ok(None),
+            }
+        };
+
+        if self.policy == "block" {
+            Err(ApiError::Conflict(problem))
+        } else {
+            Ok(Some(problem))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(credit_limit: Option<f64>, risk_status: &str, outstanding_balance: f64, policy: &str) -> ClientCredit {
+        ClientCredit { name: "Acme".to_string(), credit_limit, risk_status: risk_status.to_string(), outstanding_balance, policy: policy.to_string() }
+    }
+
+    #[test]
+    fn allows_up_to_the_credit_limit() {
+        assert_eq!(client(Some(1000.0), "normal", 400.0, "block").assess(600.0).unwrap(), None);
+        assert_eq!(client(Some(1000.0), "normal", 400.0, "block").assess(600.004).unwrap(), None);
+        assert_eq!(client(None, "watch", 1e9, "block").assess(1e9).unwrap(), None);
+    }
+
+    #[test]
+    fn warns_past_the_limit_under_the_warn_policy() {
+        let warning = client(Some(1000.0), "normal", 400.0, "warn").assess(600.01).unwrap().unwrap();
+        assert_eq!(warning, "This takes client Acme's outstanding balance to 1000.01, over its credit limit of 1000.00");
+    }
+
+    #[test]
+    fn blocks_past_the_limit_under_the_block_policy() {
+        assert!(matches!(client(Some(0.0), "normal", 0.0, "block").assess(0.01), Err(ApiError::Conflict(_))));
+    }
+
+    #[test]
+    fn a_client_on_hold_is_refused_credit_whatever_its_limit() {
+        assert_eq!(client(None, "hold", 0.0, "warn").assess(1.0).unwrap().as_deref(), Some("Client Acme is on credit hold"));
+        assert!(matches!(client(Some(1e6), "hold", 0.0, "block").assess(1.0), Err(ApiError::Conflict(_))));
+    }
+}
@@ -0,0 +1,75 @@
+//! Invoice and estimate lines. A line that references a product and leaves
+//! out its price is priced from the company's price lists (see
+//! `common::pricing`) for the document's client, currency and date.
+
+use chrono::NaiveDate;
+use common::ledger::round_cents;
+use common::pricing::{self, PriceRequest};
+use common::{ApiError, FieldError};
+use serde::Serialize;
+
+use crate::CreateInvoiceItemRequest;
+
+/// A line as stored.
+#[derive(Serialize)]
+pub(crate) struct PricedLine {
+    pub product_id: Option<i32>,
+    pub description: String,
+    pub quantity: f64,
+    pub price: f64,
+    pub amount: f64,
+}
+
+/// Prices `items`; `currency` defaults to the client's, then the company's.
+pub(crate) async fn price_lines(
+    conn: &mut sqlx::PgConnection,
+    user_id: i32,
+    client_id: Option<i32>,
+    currency: Option<&str>,
+    date: NaiveDate,
+    items: Vec<CreateInvoiceItemRequest>,
+) -> Result<Vec<PricedLine>, ApiError> {
+    let product_ids: Vec<i32> = items.iter().filter_map(|i| i.product_id).collect();
+    let owned: Vec<i32> = sqlx::query_scalar("SELECT id FROM products WHERE id = ANY($1) AND user_id = $2")
+        .bind(&product_ids)
+        .bind(user_id)
+        .fetch_all(&mut *conn)
+        .await?;
+    let unknown: Vec<FieldError> = items
+        .iter()
+        .enumerate()
+        .filter(|(_, i)| i.product_id.is_some_and(|id| !owned.contains(&id)))
+        .map(|(n, _)| FieldError { field: format!("items[{}].product_id", n), message: "does not exist".to_string() })
+        .collect();
+    if !unknown.is_empty() {
+        return Err(ApiError::Validation(unknown));
+    }
+
+    let currency = match currency {
+        Some(currency) => currency.to_string(),
+        None if items.iter().any(|i| i.price.is_none()) => pricing::default_currency(&mut *conn, user_id, client_id).await?,
+        None => String::new(),
+    };
+
+    let mut lines = Vec::with_capacity(items.len());
+    for item in items {
+        let (price, amount) = match (item.price, item.product_id) {
+            (Some(price), _) => (price, item.amount.unwrap_or_else(|| round_cents(item.quantity * price))),
+            (None, Some(product_id)) => {
+                let request = PriceRequest { product_id, client_id, quantity: item.quantity, date, currency: &currency };
+                let resolved = pricing::resolve(&mut *conn, user_id, &request)
+                    .await?
+                    .ok_or_else(|| ApiError::NotFound("Product not found".to_string()))?;
+                (resolved.unit_price, resolved.amount)
+            }
+            // Rejected by validation
+            (None, None) => (0.0, 0.0),
+        };
+        lines.push(PricedLine { product_id: item.product_id, description: item.description, quantity: item.quantity, price, amount });
+    }
+    Ok(lines)
+}
+
+pub(crate) fn subtotal(lines: &[PricedLine]) -> f64 {
+    round_cents(lines.iter().map(|l| l.amount).sum())
+}
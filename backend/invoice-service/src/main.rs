@@ -1,32 +1,49 @@
 use axum::{
-    routing::{get, post, put, delete},
+    routing::{delete, get, post},
     Router,
     Json,
     http::{StatusCode, header, Response},
-    extract::{State, Path, Query},
+    extract::{rejection::QueryRejection, State, Path, Query},
     body::Body,
 };
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
-use tower_http::cors::CorsLayer;
-use common::AuthContext;
+use common::billing::{DocumentText, DEFAULT_CURRENCY, DOCUMENT_LANGUAGES, TAX_TREATMENTS};
+use common::bootstrap;
+use common::{ApiError, AuthContext, AuthState, JwtVerifier, ListQuery, ListSpec, Page, Valid, Validate, Validator};
+use common::{FieldKind, Filter, FilterOp, SortKey};
+use common::inventory::{self, StockLevel};
+use common::metrics::Counter;
+use std::collections::HashMap;
 use std::sync::Arc;
-use sqlx::postgres::PgPoolOptions;
 use sqlx::{Pool, Postgres, FromRow};
 use chrono::{NaiveDate, DateTime, Utc};
 use genpdf::elements;
+use genpdf::Element as _;
 use genpdf::fonts;
 
+mod account_data;
+mod billing;
+mod credit;
+mod lines;
+mod payments;
+mod recurring;
+mod subscriptions;
+mod usage;
+
 type DbPool = Pool<Postgres>;
 
 struct AppState {
     db: DbPool,
-    jwt_secret: Arc<String>,
+    jwt_verifier: JwtVerifier,
 }
 
-impl axum::extract::FromRef<Arc<AppState>> for Arc<String> {
-    fn from_ref(state: &Arc<AppState>) -> Self {
-        state.jwt_secret.clone()
+impl AuthState for AppState {
+    fn jwt_verifier(&self) -> &JwtVerifier {
+        &self.jwt_verifier
+    }
+
+    fn db(&self) -> Option<&DbPool> {
+        Some(&self.db)
     }
 }
 
@@ -41,9 +58,21 @@ struct Invoice {
     client_email: Option<String>,
     invoice_number: String,
     status: String,
+    /// Before the discount.
+    subtotal: f64,
+    discount_percent: f64,
+    discount_amount: f64,
     total: f64,
+    currency: Option<String>,
+    language: Option<String>,
+    tax_treatment: String,
+    tax_exemption_reason: Option<String>,
     due_date: Option<NaiveDate>,
     notes: Option<String>,
+    /// Whether the PDF lists the components under each bundle line.
+    print_bundle_components: bool,
+    /// The recurring profile whose run created the invoice.
+    recurring_invoice_id: Option<i32>,
     created_at: Option<DateTime<Utc>>,
 }
 
@@ -51,6 +80,7 @@ struct Invoice {
 struct InvoiceItem {
     id: i32,
     invoice_id: i32,
+    product_id: Option<i32>,
     description: String,
     quantity: f64,
     price: f64,
@@ -62,18 +92,32 @@ struct CreateInvoiceRequest {
     client_id: Option<i32>,
     invoice_number: String,
     status: String,
-    total: f64,
+    /// Before the discount; defaults to the sum of the lines.
+    total: Option<f64>,
+    /// Defaults to the issue date plus the client's payment terms.
     due_date: Option<NaiveDate>,
     notes: Option<String>,
     items: Vec<CreateInvoiceItemRequest>,
+    // Default to the client's settings (see `billing`)
+    currency: Option<String>,
+    language: Option<String>,
+    tax_treatment: Option<String>,
+    tax_exemption_reason: Option<String>,
+    discount_percent: Option<f64>,
+    #[serde(default)]
+    print_bundle_components: bool,
 }
 
 #[derive(Deserialize)]
 struct CreateInvoiceItemRequest {
+    /// The catalog product the line was billed from.
+    product_id: Option<i32>,
     description: String,
     quantity: f64,
-    price: f64,
-    amount: f64,
+    /// Resolved from price lists when left out of a line with a product.
+    price: Option<f64>,
+    /// Defaults to quantity × price.
+    amount: Option<f64>,
 }
 
 #[derive(Serialize)]
@@ -81,6 +125,9 @@ struct InvoiceWithItems {
     #[serde(flatten)]
     invoice: Invoice,
     items: Vec<InvoiceItem>,
+    /// Credit-limit and stock warnings (see `credit` and `common::inventory`).
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<String>,
 }
 
 #[derive(Debug, FromRow, Serialize, Deserialize)]
@@ -98,6 +145,37 @@ struct Estimate {
     created_at: Option<DateTime<Utc>>,
 }
 
+#[derive(Deserialize)]
+struct CreateEstimateRequest {
+    client_id: Option<i32>,
+    estimate_number: String,
+    status: String,
+    /// Defaults to the sum of the lines.
+    total: Option<f64>,
+    issue_date: Option<NaiveDate>,
+    expiry_date: Option<NaiveDate>,
+    #[serde(default)]
+    items: Vec<CreateInvoiceItemRequest>,
+}
+
+#[derive(Debug, FromRow, Serialize)]
+struct EstimateItem {
+    id: i32,
+    estimate_id: i32,
+    product_id: Option<i32>,
+    description: String,
+    quantity: f64,
+    price: f64,
+    amount: f64,
+}
+
+#[derive(Serialize)]
+struct EstimateWithItems {
+    #[serde(flatten)]
+    estimate: Estimate,
+    items: Vec<EstimateItem>,
+}
+
 #[derive(Debug, FromRow, Serialize, Deserialize)]
 struct RecurringInvoice {
     id: i32,
@@ -112,26 +190,240 @@ struct RecurringInvoice {
     last_run: Option<NaiveDate>,
     status: String,
     total: f64,
+    /// Whether runs also invoice the client's usage of metered products (see `usage`).
+    #[serde(default)]
+    bill_usage: bool,
+    /// Set on a subscription's profile, which is changed through `/api/subscriptions` (see `subscriptions`).
+    #[serde(default, skip_deserializing)]
+    plan_id: Option<i32>,
     created_at: Option<DateTime<Utc>>,
+    /// Credit-limit warnings (see `credit`).
+    #[sqlx(skip)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<String>,
+}
+
+/// The buyer block printed on an invoice: the client, its first invoice
+/// recipient and its billing address, or the free-text address without one.
+#[derive(Debug, FromRow)]
+struct BillTo {
+    client_id: i32,
+    name: String,
+    tax_id: Option<String>,
+    attention: Option<String>,
+    street: Option<String>,
+    city: Option<String>,
+    postal_code: Option<String>,
+    region: Option<String>,
+    country: Option<String>,
+    address: Option<String>,
+}
+
+impl BillTo {
+    fn lines(&self) -> Vec<String> {
+        let mut lines = vec![self.name.clone()];
+        lines.extend(self.attention.as_ref().map(|name| format!("Attn: {}", name)));
+        match &self.street {
+            Some(street) => {
+                lines.extend(street.lines().map(str::to_string));
+                let mut locality = [self.postal_code.as_deref(), self.city.as_deref()].into_iter().flatten().collect::<Vec<_>>().join(" ");
+                if let Some(region) = &self.region {
+                    locality = format!("{}, {}", locality, region);
+                }
+                lines.push(locality);
+                lines.extend(self.country.clone());
+            }
+            None => lines.extend(self.address.iter().flat_map(|a| a.lines()).map(str::to_string)),
+        }
+        lines.extend(self.tax_id.as_ref().map(|id| format!("Tax ID: {}", id)));
+        lines
+    }
 }
 
+const BILL_TO_QUERY: &str = "SELECT c.id AS client_id, c.name, c.tax_id, ct.name AS attention, a.street, a.city, a.postal_code, a.region, a.country, c.address \
+     FROM clients c \
+     LEFT JOIN client_addresses a ON a.client_id = c.id AND a.kind = 'billing' \
+     LEFT JOIN LATERAL (SELECT name FROM client_contacts WHERE client_id = c.id AND invoice_recipient ORDER BY id LIMIT 1) ct ON TRUE \
+     WHERE c.user_id = $1";
+
+const INVOICE_STATUSES: &[&str] = &["draft", "sent", "paid", "overdue", "void"];
+const ESTIMATE_STATUSES: &[&str] = &["draft", "sent", "accepted", "declined", "expired", "converted"];
+const RECURRING_STATUSES: &[&str] = &["active", "paused", "completed", "canceled"];
+const RECURRING_INTERVALS: &[&str] = &["day", "week", "month", "year"];
+/// Longest interval between runs, in units of the `interval`.
+const MAX_INTERVAL_COUNT: i32 = 120;
+
+/// Whether an invoice in `status` is owed: drafts and void invoices are not,
+/// and take no stock.
+fn is_issued(status: &str) -> bool {
+    !matches!(status, "draft" | "void")
+}
+
+/// Amounts are compared in whole cents so float noise doesn't fail a valid line.
+fn cents(value: f64) -> i64 {
+    (value * 100.0).round() as i64
+}
+
+impl Validate for CreateInvoiceRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("invoice_number", &self.invoice_number);
+        v.max_len("invoice_number", self.invoice_number.as_str(), 50);
+        v.one_of("status", self.status.as_str(), INVOICE_STATUSES);
+        v.min("total", self.total, 0.0);
+        v.nested("items", &self.items);
+        v.currency("currency", self.currency.as_deref());
+        v.one_of("language", self.language.as_deref(), DOCUMENT_LANGUAGES);
+        v.one_of("tax_treatment", self.tax_treatment.as_deref(), TAX_TREATMENTS);
+        v.max_len("tax_exemption_reason", self.tax_exemption_reason.as_deref(), 500);
+        v.range("discount_percent", self.discount_percent, 0.0, 100.0);
+    }
+}
+
+impl Validate for CreateInvoiceItemRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("description", &self.description);
+        if self.quantity <= 0.0 {
+            v.error("quantity", "must be greater than 0");
+        }
+        v.min("price", self.price, 0.0);
+        match (self.price, self.amount) {
+            // Only rounding to cents may separate them. Lines priced from the
+            // price lists leave out price and amount and get the list's exact
+            // amount, which under graduated tiers need not be quantity × price.
+            (Some(price), Some(amount)) if (amount - self.quantity * price).abs() > 0.005 => {
+                v.error("amount", "must equal quantity × price");
+            }
+            (None, _) if self.product_id.is_none() => v.error("price", "is required for a line without a product_id"),
+            (None, Some(_)) => v.error("amount", "cannot be given without a price"),
+            _ => {}
+        }
+    }
+}
+
+impl Validate for CreateEstimateRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("estimate_number", &self.estimate_number);
+        v.max_len("estimate_number", self.estimate_number.as_str(), 50);
+        v.one_of("status", self.status.as_str(), ESTIMATE_STATUSES);
+        v.min("total", self.total, 0.0);
+        v.nested("items", &self.items);
+        if let (Some(issue), Some(expiry)) = (self.issue_date, self.expiry_date) {
+            if expiry < issue {
+                v.error("expiry_date", "must not be before issue_date");
+            }
+        }
+    }
+}
+
+impl Validate for RecurringInvoice {
+    fn validate(&self, v: &mut Validator) {
+        v.one_of("interval", self.interval.as_str(), RECURRING_INTERVALS);
+        v.range("interval_count", self.interval_count, 1, MAX_INTERVAL_COUNT);
+        v.one_of("status", self.status.as_str(), RECURRING_STATUSES);
+        v.min("total", self.total, 0.0);
+        if let Some(next_run) = self.next_run {
+            if next_run < self.start_date {
+                v.error("next_run", "must not be before start_date");
+            }
+        }
+    }
+}
+
+const INVOICE_LIST: ListSpec = ListSpec {
+    columns: "i.id, i.user_id, i.client_id, c.name as client_name, i.invoice_number, i.status, (i.total + i.discount_amount)::float8 as subtotal, \
+              i.discount_percent::float8 as discount_percent, i.discount_amount::float8 as discount_amount, i.total::float8 as total, \
+              i.currency, i.language, i.tax_treatment, i.tax_exemption_reason, i.due_date, i.notes, i.print_bundle_components, i.recurring_invoice_id, i.created_at",
+    from: "FROM invoices i LEFT JOIN clients c ON i.client_id = c.id",
+    owner_column: "i.user_id",
+    id_column: "i.id",
+    sort_keys: &[
+        SortKey::new("created_at", "COALESCE(i.created_at, 'epoch'::timestamptz)", FieldKind::Timestamp),
+        SortKey::new("due_date", "COALESCE(i.due_date, DATE '9999-12-31')", FieldKind::Date),
+        SortKey::new("total", "i.total", FieldKind::Number),
+        SortKey::new("invoice_number", "i.invoice_number", FieldKind::Text),
+        SortKey::new("client_name", "COALESCE(c.name, '')", FieldKind::Text),
+    ],
+    default_sort: "-created_at",
+    filters: &[
+        Filter::new("status", "i.status", FieldKind::Text, FilterOp::In),
+        Filter::new("client_id", "i.client_id", FieldKind::Int, FilterOp::In),
+        Filter::new("currency", "i.currency", FieldKind::Text, FilterOp::In),
+        Filter::new("due_from", "i.due_date", FieldKind::Date, FilterOp::Min),
+        Filter::new("due_to", "i.due_date", FieldKind::Date, FilterOp::Max),
+        Filter::new("created_from", "i.created_at::date", FieldKind::Date, FilterOp::Min),
+        Filter::new("created_to", "i.created_at::date", FieldKind::Date, FilterOp::Max),
+        Filter::new("min_total", "i.total", FieldKind::Number, FilterOp::Min),
+        Filter::new("max_total", "i.total", FieldKind::Number, FilterOp::Max),
+        Filter::new("q", "i.invoice_number", FieldKind::Text, FilterOp::Contains),
+        Filter::new("recurring_invoice_id", "i.recurring_invoice_id", FieldKind::Int, FilterOp::In),
+    ],
+    default_filters: &[],
+};
+
+const ESTIMATE_LIST: ListSpec = ListSpec {
+    columns: "e.id, e.user_id, e.client_id, c.name as client_name, e.estimate_number, e.status, e.total::float8 as total, e.issue_date, e.expiry_date, e.created_at",
+    from: "FROM estimates e LEFT JOIN clients c ON e.client_id = c.id",
+    owner_column: "e.user_id",
+    id_column: "e.id",
+    sort_keys: &[
+        SortKey::new("created_at", "COALESCE(e.created_at, 'epoch'::timestamptz)", FieldKind::Timestamp),
+        SortKey::new("issue_date", "COALESCE(e.issue_date, DATE '0001-01-01')", FieldKind::Date),
+        SortKey::new("expiry_date", "COALESCE(e.expiry_date, DATE '9999-12-31')", FieldKind::Date),
+        SortKey::new("total", "e.total", FieldKind::Number),
+        SortKey::new("estimate_number", "e.estimate_number", FieldKind::Text),
+    ],
+    default_sort: "-created_at",
+    filters: &[
+        Filter::new("status", "e.status", FieldKind::Text, FilterOp::In),
+        Filter::new("client_id", "e.client_id", FieldKind::Int, FilterOp::In),
+        Filter::new("issue_from", "e.issue_date", FieldKind::Date, FilterOp::Min),
+        Filter::new("issue_to", "e.issue_date", FieldKind::Date, FilterOp::Max),
+        Filter::new("min_total", "e.total", FieldKind::Number, FilterOp::Min),
+        Filter::new("max_total", "e.total", FieldKind::Number, FilterOp::Max),
+        Filter::new("q", "e.estimate_number", FieldKind::Text, FilterOp::Contains),
+    ],
+    default_filters: &[],
+};
+
+const RECURRING_LIST: ListSpec = ListSpec {
+    columns: "r.id, r.user_id, r.client_id, c.name as client_name, r.interval, r.interval_count, r.start_date, r.next_run, r.last_run, r.status, r.total::float8 as total, r.bill_usage, r.plan_id, r.created_at",
+    from: "FROM recurring_invoices r LEFT JOIN clients c ON r.client_id = c.id",
+    owner_column: "r.user_id",
+    id_column: "r.id",
+    sort_keys: &[
+        SortKey::new("created_at", "COALESCE(r.created_at, 'epoch'::timestamptz)", FieldKind::Timestamp),
+        SortKey::new("next_run", "COALESCE(r.next_run, DATE '9999-12-31')", FieldKind::Date),
+        SortKey::new("total", "r.total", FieldKind::Number),
+    ],
+    default_sort: "-created_at",
+    filters: &[
+        Filter::new("status", "r.status", FieldKind::Text, FilterOp::In),
+        Filter::new("client_id", "r.client_id", FieldKind::Int, FilterOp::In),
+        Filter::new("interval", "r.interval", FieldKind::Text, FilterOp::In),
+        Filter::new("plan_id", "r.plan_id", FieldKind::Int, FilterOp::In),
+        Filter::new("next_run_from", "r.next_run", FieldKind::Date, FilterOp::Min),
+        Filter::new("next_run_to", "r.next_run", FieldKind::Date, FilterOp::Max),
+        Filter::new("min_total", "r.total", FieldKind::Number, FilterOp::Min),
+        Filter::new("max_total", "r.total", FieldKind::Number, FilterOp::Max),
+    ],
+    default_filters: &[],
+};
+
+static INVOICES_CREATED: Counter = Counter::new("invoices_created_total", "Invoices created", &[]);
+static ESTIMATES_CREATED: Counter = Counter::new("estimates_created_total", "Estimates created", &[]);
+static PDFS_RENDERED: Counter = Counter::new("pdfs_rendered_total", "PDF documents rendered, by document type", &["document"]);
+static EMAILS_SENT: Counter = Counter::new("emails_sent_total", "Emails sent, by template", &["template"]);
+
 #[tokio::main]
 async fn main() {
-    dotenvy::dotenv().ok();
-    tracing_subscriber::fmt::init();
-
-    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&database_url)
-        .await
-        .expect("Failed to create pool");
+    let config = bootstrap::init("invoice-service", 5002);
+    let pool = config.connect_database().await;
 
-    let jwt_secret = Arc::new(std::env::var("JWT_SECRET").unwrap_or_else(|_| "supersecret".to_string()));
+    let jwt_verifier = config.jwt_verifier();
     
     let state = Arc::new(AppState {
-        db: pool,
-        jwt_secret,
+        db: pool.clone(),
+        jwt_verifier,
     });
 
     let app = Router::new()
@@ -141,97 +433,197 @@ async fn main() {
         .route("/api/estimates", get(list_estimates).post(create_estimate))
         .route("/api/estimates/:id", get(get_estimate).put(update_estimate).delete(delete_estimate))
         .route("/api/recurring", get(list_recurring).post(create_recurring))
+        .route("/api/recurring/run", post(recurring::run_due_recurring))
         .route("/api/recurring/:id", get(get_recurring).put(update_recurring).delete(delete_recurring))
+        .route("/api/recurring/:id/preview", get(recurring::preview_recurring))
+        .route("/api/recurring/:id/run", post(recurring::run_recurring))
+        .route("/api/plans", get(subscriptions::list_plans).post(subscriptions::create_plan))
+        .route("/api/plans/:id", get(subscriptions::get_plan).put(subscriptions::update_plan).delete(subscriptions::delete_plan))
+        .route("/api/subscriptions", get(subscriptions::list_subscriptions).post(subscriptions::create_subscription))
+        .route("/api/subscriptions/:id", get(subscriptions::get_subscription))
+        .route("/api/subscriptions/:id/change", post(subscriptions::change_subscription))
+        .route("/api/subscriptions/:id/pause", post(subscriptions::pause_subscription))
+        .route("/api/subscriptions/:id/resume", post(subscriptions::resume_subscription))
+        .route("/api/subscriptions/:id/cancel", post(subscriptions::cancel_subscription))
+        .route("/api/usage", get(usage::list_usage).post(usage::record_usage))
+        .route("/api/usage/summary", get(usage::usage_summary))
         .route("/api/reports/dashboard-stats", get(get_dashboard_stats))
         .route("/api/reports/revenue", get(get_revenue_stats))
+        .route("/api/reports/products", get(get_product_revenue))
         .route("/api/reports/export", get(export_reports))
         .route("/api/invoices/:id/send", post(send_invoice_email))
-        .layer(CorsLayer::permissive())
+        .route("/api/invoices/:id/payments", get(payments::list_payments).post(payments::create_payment))
+        .route("/api/invoices/:id/payments/:payment_id", delete(payments::delete_payment))
+        .route("/api/credit-notes", get(payments::list_credit_notes).post(payments::create_credit_note))
+        .route("/api/credit-notes/:id", get(payments::get_credit_note).delete(payments::delete_credit_note))
+        .route("/internal/clients/:id/ledger", get(payments::client_ledger))
+        .route("/internal/account-data/invoices", get(account_data::export_account_data).delete(account_data::erase_account_data))
         .with_state(state);
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 5002));
-    tracing::info!("Invoice service listening on {}", addr);
-    
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    bootstrap::serve(config, app, Some(pool)).await;
+}
+
+async fn list_invoices(auth: AuthContext, State(state): State<Arc<AppState>>, query: ListQuery) -> Result<Json<Page<Invoice>>, ApiError> {
+    let page = query.fetch(&state.db, &INVOICE_LIST, auth.user_id).await?;
+    Ok(Json(page))
 }
 
-async fn list_invoices(auth: AuthContext, State(state): State<Arc<AppState>>) -> Result<Json<Vec<Invoice>>, (StatusCode, String)> {
-    let invoices = sqlx::query_as::<_, Invoice>(
-        "SELECT i.id, i.user_id, i.client_id, c.name as client_name, i.invoice_number, i.status, i.total::float8 as total, i.due_date, i.notes, i.created_at \
-         FROM invoices i \
-         LEFT JOIN clients c ON i.client_id = c.id \
-         WHERE i.user_id = $1 ORDER BY i.created_at DESC"
+async fn create_invoice(auth: AuthContext, State(state): State<Arc<AppState>>, Valid(payload): Valid<CreateInvoiceRequest>) -> Result<Json<InvoiceWithItems>, ApiError> {
+    let mut tx = state.db.begin().await?;
+    let invoice = insert_invoice(&mut tx, auth.user_id, payload, None).await?;
+    tx.commit().await?;
+    INVOICES_CREATED.inc(&[]);
+    Ok(Json(invoice))
+}
+
+/// Creates an invoice, `recurring_invoice_id` being the profile a run created it from.
+async fn insert_invoice(tx: &mut sqlx::PgConnection, user_id: i32, mut payload: CreateInvoiceRequest, recurring_invoice_id: Option<i32>) -> Result<InvoiceWithItems, ApiError> {
+    let issued = Utc::now().date_naive();
+    let lines = lines::price_lines(&mut *tx, user_id, payload.client_id, payload.currency.as_deref(), issued, std::mem::take(&mut payload.items)).await?;
+    let subtotal = payload.total.unwrap_or_else(|| lines::subtotal(&lines));
+    let billing = billing::resolve(&mut *tx, user_id, &payload, issued, subtotal).await?;
+    let issued_amount = if is_issued(&payload.status) { billing.total } else { 0.0 };
+    let mut warnings: Vec<String> = credit::check(&mut *tx, user_id, payload.client_id, issued_amount, None).await?.into_iter().collect();
+
+    let id: i32 = sqlx::query_scalar(
+        "INSERT INTO invoices (user_id, client_id, invoice_number, status, total, due_date, notes, currency, language, tax_treatment, tax_exemption_reason, discount_percent, discount_amount, print_bundle_components, recurring_invoice_id) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15) RETURNING id"
     )
-    .bind(auth.user_id)
-    .fetch_all(&state.db)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    .bind(user_id)
+    .bind(payload.client_id)
+    .bind(&payload.invoice_number)
+    .bind(&payload.status)
+    .bind(billing.total)
+    .bind(billing.due_date)
+    .bind(&payload.notes)
+    .bind(&billing.currency)
+    .bind(&billing.language)
+    .bind(&billing.tax_treatment)
+    .bind(&billing.tax_exemption_reason)
+    .bind(billing.discount_percent)
+    .bind(billing.discount_amount)
+    .bind(payload.print_bundle_components)
+    .bind(recurring_invoice_id)
+    .fetch_one(&mut *tx)
+    .await?;
 
-    Ok(Json(invoices))
+    let items = insert_items(&mut *tx, id, lines).await?;
+    let stock = inventory::sync_invoice(&mut *tx, user_id, id, is_issued(&payload.status)).await?;
+    warnings.extend(stock.iter().filter_map(StockLevel::warning));
+    let invoice = fetch_invoice(&mut *tx, id, user_id).await?;
+    Ok(InvoiceWithItems { invoice, items, warnings })
 }
 
-async fn create_invoice(auth: AuthContext, State(state): State<Arc<AppState>>, Json(payload): Json<CreateInvoiceRequest>) -> Result<Json<InvoiceWithItems>, (StatusCode, String)> {
-    let mut tx = state.db.begin().await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-    
-    let invoice = sqlx::query_as::<_, Invoice>(
-        "INSERT INTO invoices (user_id, client_id, invoice_number, status, total, due_date, notes) \
-         VALUES ($1, $2, $3, $4, $5, $6, $7) \
-         RETURNING id, user_id, client_id, invoice_number, status, total::float8 as total, due_date, notes, created_at"
+async fn get_invoice(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>) -> Result<Json<InvoiceWithItems>, ApiError> {
+    let invoice = fetch_invoice(&state.db, id, auth.user_id).await?;
+    let items = sqlx::query_as::<_, InvoiceItem>("SELECT id, invoice_id, product_id, description, quantity::float8 as quantity, price::float8 as price, amount::float8 as amount FROM invoice_items WHERE invoice_id = $1").bind(id).fetch_all(&state.db).await?;
+    Ok(Json(InvoiceWithItems { invoice, items, warnings: Vec::new() }))
+}
+
+async fn update_invoice(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>, Valid(mut payload): Valid<CreateInvoiceRequest>) -> Result<Json<InvoiceWithItems>, ApiError> {
+    let mut tx = state.db.begin().await?;
+    let (issued, previous_status, previous_client_id, previous_total) = sqlx::query_as::<_, (NaiveDate, String, Option<i32>, f64)>(
+        "SELECT COALESCE(created_at, NOW())::date, status, client_id, total::float8 FROM invoices WHERE id = $1 AND user_id = $2 FOR UPDATE"
     )
+    .bind(id)
     .bind(auth.user_id)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(ApiError::NotFound("Invoice not found".to_string()))?;
+    let lines = lines::price_lines(&mut tx, auth.user_id, payload.client_id, payload.currency.as_deref(), issued, std::mem::take(&mut payload.items)).await?;
+    let subtotal = payload.total.unwrap_or_else(|| lines::subtotal(&lines));
+    let billing = billing::resolve(&mut tx, auth.user_id, &payload, issued, subtotal).await?;
+
+    // Only updates that add to the client's balance are checked, so an invoice
+    // can still be reduced or corrected while the client is over its limit
+    let adds_to_balance = match (is_issued(&payload.status), is_issued(&previous_status)) {
+        (false, _) => false,
+        (true, false) => true,
+        (true, true) => previous_client_id != payload.client_id || billing.total > previous_total,
+    };
+    let issued_amount = if adds_to_balance { billing.total } else { 0.0 };
+    let mut warnings: Vec<String> = credit::check(&mut tx, auth.user_id, payload.client_id, issued_amount, Some(id)).await?.into_iter().collect();
+
+    if payload.status == "void" && previous_status != "void" {
+        check_unsettled(&mut tx, id, "voided").await?;
+    }
+
+    sqlx::query(
+        "UPDATE invoices SET client_id = $1, invoice_number = $2, status = $3, total = $4, due_date = $5, notes = $6, \
+         currency = $7, language = $8, tax_treatment = $9, tax_exemption_reason = $10, discount_percent = $11, discount_amount = $12, \
+         print_bundle_components = $13 \
+         WHERE id = $14 AND user_id = $15"
+    )
     .bind(payload.client_id)
-    .bind(payload.invoice_number)
-    .bind(payload.status)
-    .bind(payload.total)
-    .bind(payload.due_date)
-    .bind(payload.notes)
-    .fetch_one(&mut *tx)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-    
-    let mut items = Vec::new();
-    for i in payload.items {
-        let item = sqlx::query_as::<_, InvoiceItem>("INSERT INTO invoice_items (invoice_id, description, quantity, price, amount) VALUES ($1, $2, $3, $4, $5) RETURNING id, invoice_id, description, quantity::float8 as quantity, price::float8 as price, amount::float8 as amount").bind(invoice.id).bind(i.description).bind(i.quantity).bind(i.price).bind(i.amount).fetch_one(&mut *tx).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-        items.push(item);
+    .bind(&payload.invoice_number)
+    .bind(&payload.status)
+    .bind(billing.total)
+    .bind(billing.due_date)
+    .bind(&payload.notes)
+    .bind(&billing.currency)
+    .bind(&billing.language)
+    .bind(&billing.tax_treatment)
+    .bind(&billing.tax_exemption_reason)
+    .bind(billing.discount_percent)
+    .bind(billing.discount_amount)
+    .bind(payload.print_bundle_components)
+    .bind(id)
+    .bind(auth.user_id)
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query("DELETE FROM invoice_items WHERE invoice_id = $1").bind(id).execute(&mut *tx).await?;
+    let items = insert_items(&mut tx, id, lines).await?;
+    let stock = inventory::sync_invoice(&mut tx, auth.user_id, id, is_issued(&payload.status)).await?;
+    warnings.extend(stock.iter().filter_map(StockLevel::warning));
+    let invoice = fetch_invoice(&mut *tx, id, auth.user_id).await?;
+    tx.commit().await?;
+    Ok(Json(InvoiceWithItems { invoice, items, warnings }))
+}
+
+async fn fetch_invoice<'e>(db: impl sqlx::PgExecutor<'e>, id: i32, user_id: i32) -> Result<Invoice, ApiError> {
+    sqlx::query_as::<_, Invoice>(&format!("SELECT {}, c.email as client_email {} WHERE i.id = $1 AND i.user_id = $2", INVOICE_LIST.columns, INVOICE_LIST.from))
+        .bind(id)
+        .bind(user_id)
+        .fetch_optional(db)
+        .await?
+        .ok_or(ApiError::NotFound("Invoice not found".to_string()))
+}
+
+async fn insert_items(tx: &mut sqlx::PgConnection, invoice_id: i32, lines: Vec<lines::PricedLine>) -> Result<Vec<InvoiceItem>, ApiError> {
+    let mut inserted = Vec::new();
+    for i in lines {
+        let item = sqlx::query_as::<_, InvoiceItem>("INSERT INTO invoice_items (invoice_id, product_id, description, quantity, price, amount) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id, invoice_id, product_id, description, quantity::float8 as quantity, price::float8 as price, amount::float8 as amount").bind(invoice_id).bind(i.product_id).bind(i.description).bind(i.quantity).bind(i.price).bind(i.amount).fetch_one(&mut *tx).await?;
+        inserted.push(item);
     }
-    tx.commit().await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-    Ok(Json(InvoiceWithItems { invoice, items }))
+    Ok(inserted)
 }
 
-async fn get_invoice(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>) -> Result<Json<InvoiceWithItems>, (StatusCode, String)> {
-    let invoice = sqlx::query_as::<_, Invoice>(
-        "SELECT i.id, i.user_id, i.client_id, c.name as client_name, c.email as client_email, i.invoice_number, i.status, i.total::float8 as total, i.due_date, i.notes, i.created_at \
-         FROM invoices i \
-         LEFT JOIN clients c ON i.client_id = c.id \
-         WHERE i.id = $1 AND i.user_id = $2"
+/// Payments and credit notes must be removed before an invoice is voided or
+/// deleted, so they are not left against an invoice nobody owes.
+async fn check_unsettled(tx: &mut sqlx::PgConnection, id: i32, action: &str) -> Result<(), ApiError> {
+    let settled: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM payments WHERE invoice_id = $1) OR EXISTS (SELECT 1 FROM credit_notes WHERE invoice_id = $1)"
     )
     .bind(id)
-    .bind(auth.user_id)
-    .fetch_optional(&state.db)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
-    .ok_or((StatusCode::NOT_FOUND, "Not found".to_string()))?;
-
-    let items = sqlx::query_as::<_, InvoiceItem>("SELECT id, invoice_id, description, quantity::float8 as quantity, price::float8 as price, amount::float8 as amount FROM invoice_items WHERE invoice_id = $1").bind(id).fetch_all(&state.db).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-    Ok(Json(InvoiceWithItems { invoice, items }))
-}
-
-async fn update_invoice(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>, Json(payload): Json<CreateInvoiceRequest>) -> Result<Json<InvoiceWithItems>, (StatusCode, String)> {
-    let mut tx = state.db.begin().await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-    let invoice = sqlx::query_as::<_, Invoice>("UPDATE invoices SET client_id = $1, invoice_number = $2, status = $3, total = $4, due_date = $5, notes = $6 WHERE id = $7 AND user_id = $8 RETURNING id, user_id, client_id, invoice_number, status, total::float8 as total, due_date, notes, created_at").bind(payload.client_id).bind(payload.invoice_number).bind(payload.status).bind(payload.total).bind(payload.due_date).bind(payload.notes).bind(id).bind(auth.user_id).fetch_one(&mut *tx).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-    sqlx::query("DELETE FROM invoice_items WHERE invoice_id = $1").bind(id).execute(&mut *tx).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-    let mut items = Vec::new();
-    for i in payload.items {
-        let item = sqlx::query_as::<_, InvoiceItem>("INSERT INTO invoice_items (invoice_id, description, quantity, price, amount) VALUES ($1, $2, $3, $4, $5) RETURNING id, invoice_id, description, quantity::float8 as quantity, price::float8 as price, amount::float8 as amount").bind(invoice.id).bind(i.description).bind(i.quantity).bind(i.price).bind(i.amount).fetch_one(&mut *tx).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-        items.push(item);
+    .fetch_one(&mut *tx)
+    .await?;
+    if settled {
+        return Err(ApiError::Conflict(format!("Invoice has payments or credit notes and cannot be {}; remove them first", action)));
     }
-    tx.commit().await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-    Ok(Json(InvoiceWithItems { invoice, items }))
+    Ok(())
 }
 
-async fn delete_invoice(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>) -> Result<StatusCode, (StatusCode, String)> {
-    sqlx::query("DELETE FROM invoices WHERE id = $1 AND user_id = $2").bind(id).bind(auth.user_id).execute(&state.db).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+async fn delete_invoice(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>) -> Result<StatusCode, ApiError> {
+    let mut tx = state.db.begin().await?;
+    let exists: Option<i32> = sqlx::query_scalar("SELECT id FROM invoices WHERE id = $1 AND user_id = $2 FOR UPDATE").bind(id).bind(auth.user_id).fetch_optional(&mut *tx).await?;
+    if exists.is_some() {
+        check_unsettled(&mut tx, id, "deleted").await?;
+        // Stock taken by the invoice goes back
+        inventory::sync_invoice(&mut tx, auth.user_id, id, false).await?;
+        sqlx::query("DELETE FROM invoices WHERE id = $1").bind(id).execute(&mut *tx).await?;
+    }
+    tx.commit().await?;
     Ok(StatusCode::NO_CONTENT)
 }
 
@@ -239,96 +631,240 @@ async fn generate_invoice_pdf(
     auth: AuthContext,
     Path(id): Path<i32>,
     State(state): State<Arc<AppState>>,
-) -> Result<Response<Body>, (StatusCode, String)> {
-    let invoice = sqlx::query_as::<_, Invoice>("SELECT i.id, i.user_id, i.client_id, c.name as client_name, i.invoice_number, i.status, i.total::float8 as total, i.due_date, i.notes, i.created_at FROM invoices i LEFT JOIN clients c ON i.client_id = c.id WHERE i.id = $1 AND i.user_id = $2").bind(id).bind(auth.user_id).fetch_optional(&state.db).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?.ok_or((StatusCode::NOT_FOUND, "Not found".to_string()))?;
-    let items = sqlx::query_as::<_, InvoiceItem>("SELECT id, invoice_id, description, quantity::float8 as quantity, price::float8 as price, amount::float8 as amount FROM invoice_items WHERE invoice_id = $1").bind(id).fetch_all(&state.db).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+) -> Result<Response<Body>, ApiError> {
+    let invoice = fetch_invoice(&state.db, id, auth.user_id).await?;
+    let items = sqlx::query_as::<_, InvoiceItem>("SELECT id, invoice_id, product_id, description, quantity::float8 as quantity, price::float8 as price, amount::float8 as amount FROM invoice_items WHERE invoice_id = $1").bind(id).fetch_all(&state.db).await?;
+    let bill_to = match invoice.client_id {
+        Some(client_id) => sqlx::query_as::<_, BillTo>(&format!("{} AND c.id = $2", BILL_TO_QUERY)).bind(auth.user_id).bind(client_id).fetch_optional(&state.db).await?,
+        None => None,
+    };
+
+    let product_ids: Vec<i32> = items.iter().filter_map(|i| i.product_id).collect();
+    let components = if invoice.print_bundle_components { bundle_components(&state.db, &product_ids).await? } else { BundleComponents::new() };
+
+    let buffer = render_invoice_pdf(&invoice, &items, bill_to.as_ref(), &components).map_err(ApiError::Internal)?;
+
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "application/pdf")
+        .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"invoice_{}.pdf\"", invoice.invoice_number))
+        .body(Body::from(buffer))
+        .unwrap())
+}
+
+/// Component names and quantities per bundle, by bundle product id.
+type BundleComponents = HashMap<i32, Vec<(String, f64)>>;
+
+async fn bundle_components(db: &DbPool, product_ids: &[i32]) -> Result<BundleComponents, ApiError> {
+    let rows = sqlx::query_as::<_, (i32, String, f64)>(
+        "SELECT bc.bundle_id, p.name, bc.quantity::float8 FROM bundle_components bc JOIN products p ON p.id = bc.component_id \
+         WHERE bc.bundle_id = ANY($1) ORDER BY bc.bundle_id, lower(p.name), p.id"
+    )
+    .bind(product_ids)
+    .fetch_all(db)
+    .await?;
+    let mut components = BundleComponents::new();
+    for (bundle_id, name, quantity) in rows {
+        components.entry(bundle_id).or_default().push((name, quantity));
+    }
+    Ok(components)
+}
+
+/// `components` are listed under their bundle's lines when the invoice prints them.
+fn render_invoice_pdf(invoice: &Invoice, items: &[InvoiceItem], bill_to: Option<&BillTo>, components: &BundleComponents) -> Result<Vec<u8>, String> {
+    let buyer = match bill_to {
+        Some(bill_to) => bill_to.lines(),
+        None => vec![invoice.client_name.clone().unwrap_or_default()],
+    };
+    let text = DocumentText::for_language(invoice.language.as_deref().unwrap_or_default());
+    let currency = invoice.currency.as_deref().unwrap_or(DEFAULT_CURRENCY);
+    let money = |amount: f64| format!("{:.2} {}", amount, currency);
+    let issued = invoice.created_at.unwrap_or_else(Utc::now).format("%Y-%m-%d");
+
+    let mut totals = Vec::new();
+    if invoice.discount_amount > 0.0 {
+        totals.push(format!("{}: {}", text.subtotal, money(invoice.subtotal)));
+        totals.push(format!("{} ({}%): -{}", text.discount, invoice.discount_percent, money(invoice.discount_amount)));
+    }
+    let total = format!("{}: {}", text.total, money(invoice.total));
+    let tax_notice = text.tax_notice(&invoice.tax_treatment, invoice.tax_exemption_reason.as_deref());
 
     let font_family = fonts::from_files("/usr/share/fonts", "LiberationSans", None).ok();
     
     let mut buffer = Vec::new();
     if let Some(font_family) = font_family {
         let mut doc = genpdf::Document::new(font_family);
-        doc.set_title(format!("Invoice {}", invoice.invoice_number));
+        doc.set_title(format!("{} {}", text.invoice, invoice.invoice_number));
         let mut decorator = genpdf::SimplePageDecorator::new();
         decorator.set_margins(10);
         doc.set_page_decorator(decorator);
 
-        doc.push(elements::Text::new(format!("INVOICE #{}", invoice.invoice_number)).styled(genpdf::style::Effect::Bold));
-        doc.push(elements::Text::new(format!("Client: {}", invoice.client_name.unwrap_or_default())));
-        doc.push(elements::Text::new(format!("Date: {}", Utc::now().format("%Y-%m-%d"))));
+        doc.push(elements::Text::new(format!("{} #{}", text.invoice, invoice.invoice_number)).styled(genpdf::style::Effect::Bold));
+        doc.push(elements::Text::new(format!("{}: {}", text.date, issued)));
+        if let Some(due_date) = invoice.due_date {
+            doc.push(elements::Text::new(format!("{}: {}", text.due_date, due_date)));
+        }
+        doc.push(elements::Break::new(1));
+        doc.push(elements::Text::new(format!("{}:", text.bill_to)).styled(genpdf::style::Effect::Bold));
+        for line in &buyer {
+            doc.push(elements::Text::new(line.as_str()));
+        }
         doc.push(elements::Break::new(1));
         
         for item in items {
-            doc.push(elements::Text::new(format!("{} - {} x ${} = ${}", item.description, item.quantity, item.price, item.amount)));
+            doc.push(elements::Text::new(format!("{} - {} x {} = {}", item.description, item.quantity, money(item.price), money(item.amount))));
+            let bundled = item.product_id.filter(|_| invoice.print_bundle_components).and_then(|id| components.get(&id));
+            for (name, quantity) in bundled.into_iter().flatten() {
+                doc.push(elements::Text::new(format!("    {} x {}", quantity * item.quantity, name)));
+            }
         }
         
         doc.push(elements::Break::new(1));
-        doc.push(elements::Text::new(format!("TOTAL: ${}", invoice.total)).styled(genpdf::style::Effect::Bold));
+        for line in &totals {
+            doc.push(elements::Text::new(line.as_str()));
+        }
+        doc.push(elements::Text::new(total).styled(genpdf::style::Effect::Bold));
+        if let Some(notice) = tax_notice {
+            doc.push(elements::Break::new(1));
+            doc.push(elements::Text::new(notice));
+        }
 
-        doc.render_to_write(&mut buffer).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        doc.render(&mut buffer).map_err(|e| e.to_string())?;
     } else {
         buffer.extend_from_slice(b"Invoice PDF Content (Simulated as fonts missing in build environment)\n\n");
-        buffer.extend_from_slice(format!("Invoice: {}\n", invoice.invoice_number).as_bytes());
-        buffer.extend_from_slice(format!("Client: {}\n", invoice.client_name.unwrap_or_default()).as_bytes());
-        buffer.extend_from_slice(format!("Total: ${}\n", invoice.total).as_bytes());
+        buffer.extend_from_slice(format!("{}: {}\n", text.invoice, invoice.invoice_number).as_bytes());
+        buffer.extend_from_slice(format!("{}: {}\n", text.bill_to, buyer.join(", ")).as_bytes());
+        for line in &totals {
+            buffer.extend_from_slice(format!("{}\n", line).as_bytes());
+        }
+        buffer.extend_from_slice(format!("{}\n", total).as_bytes());
+        if let Some(notice) = tax_notice {
+            buffer.extend_from_slice(format!("{}\n", notice).as_bytes());
+        }
     }
 
-    Ok(Response::builder()
-        .header(header::CONTENT_TYPE, "application/pdf")
-        .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"invoice_{}.pdf\"", invoice.invoice_number))
-        .body(Body::from(buffer))
-        .unwrap())
+    PDFS_RENDERED.inc(&["invoice"]);
+    Ok(buffer)
 }
 
 // Estimates CRUD
-async fn list_estimates(auth: AuthContext, State(state): State<Arc<AppState>>) -> Result<Json<Vec<Estimate>>, (StatusCode, String)> {
-    let estimates = sqlx::query_as::<_, Estimate>("SELECT e.id, e.user_id, e.client_id, c.name as client_name, e.estimate_number, e.status, e.total::float8 as total, e.issue_date, e.expiry_date, e.created_at FROM estimates e LEFT JOIN clients c ON e.client_id = c.id WHERE e.user_id = $1").bind(auth.user_id).fetch_all(&state.db).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-    Ok(Json(estimates))
+async fn list_estimates(auth: AuthContext, State(state): State<Arc<AppState>>, query: ListQuery) -> Result<Json<Page<Estimate>>, ApiError> {
+    let page = query.fetch(&state.db, &ESTIMATE_LIST, auth.user_id).await?;
+    Ok(Json(page))
 }
 
-async fn create_estimate(auth: AuthContext, State(state): State<Arc<AppState>>, Json(payload): Json<Estimate>) -> Result<Json<Estimate>, (StatusCode, String)> {
-    let estimate = sqlx::query_as::<_, Estimate>("INSERT INTO estimates (user_id, client_id, estimate_number, status, total, issue_date, expiry_date) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id, user_id, client_id, estimate_number, status, total::float8 as total, issue_date, expiry_date, created_at").bind(auth.user_id).bind(payload.client_id).bind(payload.estimate_number).bind(payload.status).bind(payload.total).bind(payload.issue_date).bind(payload.expiry_date).fetch_one(&state.db).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-    Ok(Json(estimate))
+async fn create_estimate(auth: AuthContext, State(state): State<Arc<AppState>>, Valid(mut payload): Valid<CreateEstimateRequest>) -> Result<Json<EstimateWithItems>, ApiError> {
+    let mut tx = state.db.begin().await?;
+    let priced_on = payload.issue_date.unwrap_or_else(|| Utc::now().date_naive());
+    let lines = lines::price_lines(&mut tx, auth.user_id, payload.client_id, None, priced_on, std::mem::take(&mut payload.items)).await?;
+    let total = payload.total.unwrap_or_else(|| lines::subtotal(&lines));
+
+    let id: i32 = sqlx::query_scalar("INSERT INTO estimates (user_id, client_id, estimate_number, status, total, issue_date, expiry_date) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id").bind(auth.user_id).bind(payload.client_id).bind(payload.estimate_number).bind(payload.status).bind(total).bind(payload.issue_date).bind(payload.expiry_date).fetch_one(&mut *tx).await?;
+    let items = insert_estimate_items(&mut tx, id, lines).await?;
+    let estimate = fetch_estimate(&mut *tx, id, auth.user_id).await?;
+    tx.commit().await?;
+    ESTIMATES_CREATED.inc(&[]);
+    Ok(Json(EstimateWithItems { estimate, items }))
+}
+
+async fn get_estimate(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>) -> Result<Json<EstimateWithItems>, ApiError> {
+    let estimate = fetch_estimate(&state.db, id, auth.user_id).await?;
+    let items = sqlx::query_as::<_, EstimateItem>(&format!("SELECT {} FROM estimate_items WHERE estimate_id = $1 ORDER BY id", ESTIMATE_ITEM_COLUMNS)).bind(id).fetch_all(&state.db).await?;
+    Ok(Json(EstimateWithItems { estimate, items }))
 }
 
-async fn get_estimate(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>) -> Result<Json<Estimate>, (StatusCode, String)> {
-    let e = sqlx::query_as::<_, Estimate>("SELECT e.id, e.user_id, e.client_id, c.name as client_name, e.estimate_number, e.status, e.total::float8 as total, e.issue_date, e.expiry_date, e.created_at FROM estimates e LEFT JOIN clients c ON e.client_id = c.id WHERE e.id = $1 AND e.user_id = $2").bind(id).bind(auth.user_id).fetch_optional(&state.db).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?.ok_or((StatusCode::NOT_FOUND, "Not found".to_string()))?;
-    Ok(Json(e))
+async fn update_estimate(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>, Valid(mut payload): Valid<CreateEstimateRequest>) -> Result<Json<EstimateWithItems>, ApiError> {
+    let mut tx = state.db.begin().await?;
+    let priced_on = payload.issue_date.unwrap_or_else(|| Utc::now().date_naive());
+    let lines = lines::price_lines(&mut tx, auth.user_id, payload.client_id, None, priced_on, std::mem::take(&mut payload.items)).await?;
+    let total = payload.total.unwrap_or_else(|| lines::subtotal(&lines));
+
+    sqlx::query_scalar::<_, i32>("UPDATE estimates SET client_id = $1, estimate_number = $2, status = $3, total = $4, issue_date = $5, expiry_date = $6 WHERE id = $7 AND user_id = $8 RETURNING id").bind(payload.client_id).bind(payload.estimate_number).bind(payload.status).bind(total).bind(payload.issue_date).bind(payload.expiry_date).bind(id).bind(auth.user_id).fetch_optional(&mut *tx).await?.ok_or(ApiError::NotFound("Estimate not found".to_string()))?;
+    sqlx::query("DELETE FROM estimate_items WHERE estimate_id = $1").bind(id).execute(&mut *tx).await?;
+    let items = insert_estimate_items(&mut tx, id, lines).await?;
+    let estimate = fetch_estimate(&mut *tx, id, auth.user_id).await?;
+    tx.commit().await?;
+    Ok(Json(EstimateWithItems { estimate, items }))
 }
 
-async fn update_estimate(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>, Json(payload): Json<Estimate>) -> Result<Json<Estimate>, (StatusCode, String)> {
-    let e = sqlx::query_as::<_, Estimate>("UPDATE estimates SET client_id = $1, estimate_number = $2, status = $3, total = $4, issue_date = $5, expiry_date = $6 WHERE id = $7 AND user_id = $8 RETURNING id, user_id, client_id, estimate_number, status, total::float8 as total, issue_date, expiry_date, created_at").bind(payload.client_id).bind(payload.estimate_number).bind(payload.status).bind(payload.total).bind(payload.issue_date).bind(payload.expiry_date).bind(id).bind(auth.user_id).fetch_one(&state.db).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-    Ok(Json(e))
+const ESTIMATE_ITEM_COLUMNS: &str = "id, estimate_id, product_id, description, quantity::float8 as quantity, price::float8 as price, amount::float8 as amount";
+
+async fn fetch_estimate<'e>(db: impl sqlx::PgExecutor<'e>, id: i32, user_id: i32) -> Result<Estimate, ApiError> {
+    sqlx::query_as::<_, Estimate>(&format!("SELECT {} {} WHERE e.id = $1 AND e.user_id = $2", ESTIMATE_LIST.columns, ESTIMATE_LIST.from))
+        .bind(id)
+        .bind(user_id)
+        .fetch_optional(db)
+        .await?
+        .ok_or(ApiError::NotFound("Estimate not found".to_string()))
 }
 
-async fn delete_estimate(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>) -> Result<StatusCode, (StatusCode, String)> {
-    sqlx::query("DELETE FROM estimates WHERE id = $1 AND user_id = $2").bind(id).bind(auth.user_id).execute(&state.db).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+async fn insert_estimate_items(tx: &mut sqlx::PgConnection, estimate_id: i32, lines: Vec<lines::PricedLine>) -> Result<Vec<EstimateItem>, ApiError> {
+    let mut inserted = Vec::new();
+    for i in lines {
+        let item = sqlx::query_as::<_, EstimateItem>(&format!("INSERT INTO estimate_items (estimate_id, product_id, description, quantity, price, amount) VALUES ($1, $2, $3, $4, $5, $6) RETURNING {}", ESTIMATE_ITEM_COLUMNS)).bind(estimate_id).bind(i.product_id).bind(i.description).bind(i.quantity).bind(i.price).bind(i.amount).fetch_one(&mut *tx).await?;
+        inserted.push(item);
+    }
+    Ok(inserted)
+}
+
+async fn delete_estimate(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>) -> Result<StatusCode, ApiError> {
+    sqlx::query("DELETE FROM estimates WHERE id = $1 AND user_id = $2").bind(id).bind(auth.user_id).execute(&state.db).await?;
     Ok(StatusCode::NO_CONTENT)
 }
 
 // Recurring CRUD
-async fn list_recurring(auth: AuthContext, State(state): State<Arc<AppState>>) -> Result<Json<Vec<RecurringInvoice>>, (StatusCode, String)> {
-    let r = sqlx::query_as::<_, RecurringInvoice>("SELECT r.id, r.user_id, r.client_id, c.name as client_name, r.interval, r.interval_count, r.start_date, r.next_run, r.last_run, r.status, r.total::float8 as total, r.created_at FROM recurring_invoices r LEFT JOIN clients c ON r.client_id = c.id WHERE r.user_id = $1").bind(auth.user_id).fetch_all(&state.db).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-    Ok(Json(r))
+async fn list_recurring(auth: AuthContext, State(state): State<Arc<AppState>>, query: ListQuery) -> Result<Json<Page<RecurringInvoice>>, ApiError> {
+    let page = query.fetch(&state.db, &RECURRING_LIST, auth.user_id).await?;
+    Ok(Json(page))
 }
 
-async fn create_recurring(auth: AuthContext, State(state): State<Arc<AppState>>, Json(payload): Json<RecurringInvoice>) -> Result<Json<RecurringInvoice>, (StatusCode, String)> {
-    let r = sqlx::query_as::<_, RecurringInvoice>("INSERT INTO recurring_invoices (user_id, client_id, interval, interval_count, start_date, next_run, status, total) VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING id, user_id, client_id, interval, interval_count, start_date, next_run, last_run, status, total::float8 as total, created_at").bind(auth.user_id).bind(payload.client_id).bind(payload.interval).bind(payload.interval_count).bind(payload.start_date).bind(payload.next_run).bind(payload.status).bind(payload.total).fetch_one(&state.db).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+async fn create_recurring(auth: AuthContext, State(state): State<Arc<AppState>>, Valid(payload): Valid<RecurringInvoice>) -> Result<Json<RecurringInvoice>, ApiError> {
+    let warnings = check_recurring_credit(&state, auth.user_id, &payload).await?;
+    let mut r = sqlx::query_as::<_, RecurringInvoice>("INSERT INTO recurring_invoices (user_id, client_id, interval, interval_count, start_date, next_run, status, total, bill_usage) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING id, user_id, client_id, interval, interval_count, start_date, next_run, last_run, status, total::float8 as total, bill_usage, plan_id, created_at").bind(auth.user_id).bind(payload.client_id).bind(payload.interval).bind(payload.interval_count).bind(payload.start_date).bind(payload.next_run).bind(payload.status).bind(payload.total).bind(payload.bill_usage).fetch_one(&state.db).await?;
+    r.warnings = warnings;
     Ok(Json(r))
 }
 
-async fn get_recurring(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>) -> Result<Json<RecurringInvoice>, (StatusCode, String)> {
-    let r = sqlx::query_as::<_, RecurringInvoice>("SELECT r.id, r.user_id, r.client_id, c.name as client_name, r.interval, r.interval_count, r.start_date, r.next_run, r.last_run, r.status, r.total::float8 as total, r.created_at FROM recurring_invoices r LEFT JOIN clients c ON r.client_id = c.id WHERE r.id = $1 AND r.user_id = $2").bind(id).bind(auth.user_id).fetch_optional(&state.db).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?.ok_or((StatusCode::NOT_FOUND, "Not found".to_string()))?;
+async fn get_recurring(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>) -> Result<Json<RecurringInvoice>, ApiError> {
+    let r = sqlx::query_as::<_, RecurringInvoice>("SELECT r.id, r.user_id, r.client_id, c.name as client_name, r.interval, r.interval_count, r.start_date, r.next_run, r.last_run, r.status, r.total::float8 as total, r.bill_usage, r.plan_id, r.created_at FROM recurring_invoices r LEFT JOIN clients c ON r.client_id = c.id WHERE r.id = $1 AND r.user_id = $2").bind(id).bind(auth.user_id).fetch_optional(&state.db).await?.ok_or(ApiError::NotFound("Recurring invoice not found".to_string()))?;
     Ok(Json(r))
 }
 
-async fn update_recurring(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>, Json(payload): Json<RecurringInvoice>) -> Result<Json<RecurringInvoice>, (StatusCode, String)> {
-    let r = sqlx::query_as::<_, RecurringInvoice>("UPDATE recurring_invoices SET client_id = $1, interval = $2, interval_count = $3, start_date = $4, next_run = $5, status = $6, total = $7 WHERE id = $8 AND user_id = $9 RETURNING id, user_id, client_id, interval, interval_count, start_date, next_run, last_run, status, total::float8 as total, created_at").bind(payload.client_id).bind(payload.interval).bind(payload.interval_count).bind(payload.start_date).bind(payload.next_run).bind(payload.status).bind(payload.total).bind(id).bind(auth.user_id).fetch_one(&state.db).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+async fn update_recurring(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>, Valid(payload): Valid<RecurringInvoice>) -> Result<Json<RecurringInvoice>, ApiError> {
+    let mut tx = state.db.begin().await?;
+    lock_plain_profile(&mut tx, id, auth.user_id).await?;
+    let warnings = check_recurring_credit(&state, auth.user_id, &payload).await?;
+    let mut r = sqlx::query_as::<_, RecurringInvoice>("UPDATE recurring_invoices SET client_id = $1, interval = $2, interval_count = $3, start_date = $4, next_run = $5, status = $6, total = $7, bill_usage = $8 WHERE id = $9 AND user_id = $10 AND plan_id IS NULL RETURNING id, user_id, client_id, interval, interval_count, start_date, next_run, last_run, status, total::float8 as total, bill_usage, plan_id, created_at").bind(payload.client_id).bind(payload.interval).bind(payload.interval_count).bind(payload.start_date).bind(payload.next_run).bind(payload.status).bind(payload.total).bind(payload.bill_usage).bind(id).bind(auth.user_id).fetch_optional(&mut *tx).await?.ok_or(ApiError::NotFound("Recurring invoice not found".to_string()))?;
+    tx.commit().await?;
+    r.warnings = warnings;
     Ok(Json(r))
 }
 
-async fn delete_recurring(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>) -> Result<StatusCode, (StatusCode, String)> {
-    sqlx::query("DELETE FROM recurring_invoices WHERE id = $1 AND user_id = $2").bind(id).bind(auth.user_id).execute(&state.db).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+/// Locks a profile for a generic edit or delete, refusing a subscription's,
+/// whose runs and documents only the subscription endpoints keep consistent.
+async fn lock_plain_profile(tx: &mut sqlx::PgConnection, id: i32, user_id: i32) -> Result<(), ApiError> {
+    let plan_id: Option<Option<i32>> = sqlx::query_scalar("SELECT plan_id FROM recurring_invoices WHERE id = $1 AND user_id = $2 FOR UPDATE").bind(id).bind(user_id).fetch_optional(&mut *tx).await?;
+    match plan_id {
+        None => Err(ApiError::NotFound("Recurring invoice not found".to_string())),
+        Some(Some(_)) => Err(ApiError::Conflict(format!("Recurring invoice {} is a subscription; change it through /api/subscriptions/{}/change, /pause, /resume or /cancel", id, id))),
+        Some(None) => Ok(()),
+    }
+}
+
+/// Checks one run of an active profile against the client's credit limit, as
+/// each run issues an invoice for the profile's total.
+async fn check_recurring_credit(state: &AppState, user_id: i32, profile: &RecurringInvoice) -> Result<Vec<String>, ApiError> {
+    if profile.status != "active" {
+        return Ok(Vec::new());
+    }
+    let mut conn = state.db.acquire().await?;
+    Ok(credit::check(&mut conn, user_id, profile.client_id, profile.total, None).await?.into_iter().collect())
+}
+
+async fn delete_recurring(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>) -> Result<StatusCode, ApiError> {
+    let mut tx = state.db.begin().await?;
+    lock_plain_profile(&mut tx, id, auth.user_id).await?;
+    sqlx::query("DELETE FROM recurring_invoices WHERE id = $1 AND user_id = $2 AND plan_id IS NULL").bind(id).bind(auth.user_id).execute(&mut *tx).await?;
+    tx.commit().await?;
     Ok(StatusCode::NO_CONTENT)
 }
 
@@ -349,12 +885,12 @@ struct ClientStats { total_clients: i64, active_clients: i64 }
 #[derive(Serialize, FromRow)]
 struct OverdueStats { overdue_count: i64, overdue_amount: f64 }
 
-async fn get_dashboard_stats(auth: AuthContext, State(state): State<Arc<AppState>>) -> Result<Json<DashboardStats>, (StatusCode, String)> {
-    let invoice_stats = sqlx::query_as::<_, InvoiceStat>("SELECT status, count(*) as count, sum(total)::float8 as total_amount FROM invoices WHERE user_id = $1 GROUP BY status").bind(auth.user_id).fetch_all(&state.db).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-    let overdue_stats = sqlx::query_as::<_, OverdueStats>("SELECT count(*) as overdue_count, COALESCE(sum(total)::float8, 0) as overdue_amount FROM invoices WHERE user_id = $1 AND status = 'overdue'").bind(auth.user_id).fetch_one(&state.db).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-    let total_clients: i64 = sqlx::query_scalar("SELECT count(*) FROM clients WHERE user_id = $1").bind(auth.user_id).fetch_one(&state.db).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+async fn get_dashboard_stats(auth: AuthContext, State(state): State<Arc<AppState>>) -> Result<Json<DashboardStats>, ApiError> {
+    let invoice_stats = sqlx::query_as::<_, InvoiceStat>("SELECT status, count(*) as count, sum(total)::float8 as total_amount FROM invoices WHERE user_id = $1 GROUP BY status").bind(auth.user_id).fetch_all(&state.db).await?;
+    let overdue_stats = sqlx::query_as::<_, OverdueStats>("SELECT count(*) as overdue_count, COALESCE(sum(total)::float8, 0) as overdue_amount FROM invoices WHERE user_id = $1 AND status = 'overdue'").bind(auth.user_id).fetch_one(&state.db).await?;
+    let total_clients: i64 = sqlx::query_scalar("SELECT count(*) FROM clients WHERE user_id = $1").bind(auth.user_id).fetch_one(&state.db).await?;
     
-    let revenue_stats = sqlx::query_as::<_, RevenueStat>("SELECT date_trunc('month', created_at) as period, sum(total)::float8 as revenue, sum(CASE WHEN status = 'paid' THEN total ELSE 0 END)::float8 as collected FROM invoices WHERE user_id = $1 GROUP BY period ORDER BY period DESC LIMIT 6").bind(auth.user_id).fetch_all(&state.db).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let revenue_stats = sqlx::query_as::<_, RevenueStat>("SELECT date_trunc('month', created_at) as period, sum(total)::float8 as revenue, sum(CASE WHEN status = 'paid' THEN total ELSE 0 END)::float8 as collected FROM invoices WHERE user_id = $1 GROUP BY period ORDER BY period DESC LIMIT 6").bind(auth.user_id).fetch_all(&state.db).await?;
 
     Ok(Json(DashboardStats {
         invoice_stats,
@@ -364,40 +900,93 @@ async fn get_dashboard_stats(auth: AuthContext, State(state): State<Arc<AppState
     }))
 }
 
-async fn get_revenue_stats(auth: AuthContext, State(state): State<Arc<AppState>>, Query(params): Query<std::collections::HashMap<String, String>>) -> Result<Json<Vec<RevenueStat>>, (StatusCode, String)> {
-    let stats = sqlx::query_as::<_, RevenueStat>("SELECT date_trunc('month', created_at) as period, sum(total)::float8 as revenue, sum(CASE WHEN status = 'paid' THEN total ELSE 0 END)::float8 as collected FROM invoices WHERE user_id = $1 GROUP BY period ORDER BY period DESC").bind(auth.user_id).fetch_all(&state.db).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+async fn get_revenue_stats(auth: AuthContext, State(state): State<Arc<AppState>>) -> Result<Json<Vec<RevenueStat>>, ApiError> {
+    let stats = sqlx::query_as::<_, RevenueStat>("SELECT date_trunc('month', created_at) as period, sum(total)::float8 as revenue, sum(CASE WHEN status = 'paid' THEN total ELSE 0 END)::float8 as collected FROM invoices WHERE user_id = $1 GROUP BY period ORDER BY period DESC").bind(auth.user_id).fetch_all(&state.db).await?;
     Ok(Json(stats))
 }
 
-async fn export_reports(auth: AuthContext, State(state): State<Arc<AppState>>, Query(params): Query<std::collections::HashMap<String, String>>) -> Result<Response<Body>, (StatusCode, String)> {
+#[derive(Deserialize)]
+struct ProductRevenueQuery {
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+}
+
+/// Invoiced quantity, revenue and margin of one product; lines not billed
+/// from a product are grouped under a `null` product. Bundle lines count
+/// towards their components, with the line amount split in proportion to each
+/// component's price × quantity in the bundle (evenly when those are all 0).
+#[derive(Serialize, FromRow)]
+struct ProductRevenue {
+    product_id: Option<i32>,
+    sku: Option<String>,
+    name: Option<String>,
+    quantity: f64,
+    /// Line amounts, before invoice discounts.
+    revenue: f64,
+    /// At the product's current cost price, when it has one.
+    cost: Option<f64>,
+    margin: Option<f64>,
+}
+
+async fn get_product_revenue(auth: AuthContext, State(state): State<Arc<AppState>>, query: Result<Query<ProductRevenueQuery>, QueryRejection>) -> Result<Json<Vec<ProductRevenue>>, ApiError> {
+    let Query(query) = query?;
+    let stats = sqlx::query_as::<_, ProductRevenue>(
+        "WITH lines AS ( \
+           SELECT COALESCE(bc.component_id, ii.product_id) AS product_id, ii.quantity * COALESCE(bc.quantity, 1) AS quantity, \
+             CASE WHEN bc.component_id IS NULL THEN ii.amount \
+                  WHEN w.total > 0 THEN ii.amount * bc.quantity * cp.price / w.total \
+                  ELSE ii.amount / w.components END AS amount \
+           FROM invoice_items ii JOIN invoices i ON i.id = ii.invoice_id \
+           LEFT JOIN bundle_components bc ON bc.bundle_id = ii.product_id \
+           LEFT JOIN products cp ON cp.id = bc.component_id \
+           LEFT JOIN LATERAL (SELECT SUM(b.quantity * bp.price) AS total, COUNT(*) AS components FROM bundle_components b JOIN products bp ON bp.id = b.component_id \
+                              WHERE b.bundle_id = ii.product_id) w ON bc.component_id IS NOT NULL \
+           WHERE i.user_id = $1 AND i.status NOT IN ('draft', 'void') \
+             AND ($2::date IS NULL OR COALESCE(i.created_at, NOW())::date >= $2) AND ($3::date IS NULL OR COALESCE(i.created_at, NOW())::date <= $3) \
+         ) \
+         SELECT l.product_id, p.sku, p.name, SUM(l.quantity)::float8 AS quantity, ROUND(SUM(l.amount), 2)::float8 AS revenue, \
+           (SUM(l.quantity) * p.cost_price)::float8 AS cost, ROUND(SUM(l.amount) - SUM(l.quantity) * p.cost_price, 2)::float8 AS margin \
+         FROM lines l LEFT JOIN products p ON p.id = l.product_id \
+         GROUP BY l.product_id, p.sku, p.name, p.cost_price \
+         ORDER BY revenue DESC"
+    )
+    .bind(auth.user_id)
+    .bind(query.from)
+    .bind(query.to)
+    .fetch_all(&state.db)
+    .await?;
+    Ok(Json(stats))
+}
+
+async fn export_reports(auth: AuthContext, State(state): State<Arc<AppState>>, Query(params): Query<std::collections::HashMap<String, String>>) -> Result<Response<Body>, ApiError> {
     let export_type = params.get("type").map(|s| s.as_str()).unwrap_or("invoices");
     
-    let csv_content = match export_type {
+    // Names may contain commas and quotes, so fields are quoted as needed
+    let mut w = csv::Writer::from_writer(Vec::new());
+    match export_type {
         "clients" => {
-            let clients = sqlx::query!("SELECT name, email, phone FROM clients WHERE user_id = $1", auth.user_id).fetch_all(&state.db).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-            let mut w = String::from("Name,Email,Phone\n");
+            let clients = sqlx::query!("SELECT name, email, phone FROM clients WHERE user_id = $1", auth.user_id).fetch_all(&state.db).await?;
+            w.write_record(["Name", "Email", "Phone"]).map_err(ApiError::internal)?;
             for c in clients {
-                w.push_str(&format!("{},{},{}\n", c.name, c.email.unwrap_or_default(), c.phone.unwrap_or_default()));
+                w.write_record([c.name, c.email.unwrap_or_default(), c.phone.unwrap_or_default()]).map_err(ApiError::internal)?;
             }
-            w
         },
         "products" => {
-            let products = sqlx::query!("SELECT name, price FROM products WHERE user_id = $1", auth.user_id).fetch_all(&state.db).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-            let mut w = String::from("Name,Price\n");
+            let products = sqlx::query!(r#"SELECT name, price::float8 AS "price!" FROM products WHERE user_id = $1"#, auth.user_id).fetch_all(&state.db).await?;
+            w.write_record(["Name", "Price"]).map_err(ApiError::internal)?;
             for p in products {
-                w.push_str(&format!("{},{}\n", p.name, p.price));
+                w.write_record([p.name, p.price.to_string()]).map_err(ApiError::internal)?;
             }
-            w
         },
         _ => {
-            let invoices = sqlx::query!("SELECT invoice_number, status, total FROM invoices WHERE user_id = $1", auth.user_id).fetch_all(&state.db).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-            let mut w = String::from("Invoice Number,Status,Total\n");
+            let invoices = sqlx::query!(r#"SELECT invoice_number, status, total::float8 AS "total!" FROM invoices WHERE user_id = $1"#, auth.user_id).fetch_all(&state.db).await?;
+            w.write_record(["Invoice Number", "Status", "Total"]).map_err(ApiError::internal)?;
             for i in invoices {
-                w.push_str(&format!("{},{},{}\n", i.invoice_number, i.status, i.total));
+                w.write_record([i.invoice_number, i.status, i.total.to_string()]).map_err(ApiError::internal)?;
             }
-            w
         }
-    };
+    }
+    let csv_content = w.into_inner().map_err(ApiError::internal)?;
 
     Ok(Response::builder()
         .header(header::CONTENT_TYPE, "text/csv")
@@ -406,13 +995,45 @@ async fn export_reports(auth: AuthContext, State(state): State<Arc<AppState>>, Q
         .unwrap())
 }
 
-async fn send_invoice_email(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>) -> Result<StatusCode, (StatusCode, String)> {
-    let invoice = sqlx::query!("SELECT invoice_number FROM invoices WHERE id = $1 AND user_id = $2", id, auth.user_id).fetch_optional(&state.db).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?.ok_or((StatusCode::NOT_FOUND, "Not found".to_string()))?;
-    
+async fn send_invoice_email(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>) -> Result<StatusCode, ApiError> {
+    let (invoice_number, client_id, status, total) = sqlx::query_as::<_, (String, Option<i32>, String, f64)>("SELECT invoice_number, client_id, status, total::float8 FROM invoices WHERE id = $1 AND user_id = $2").bind(id).bind(auth.user_id).fetch_optional(&state.db).await?.ok_or(ApiError::NotFound("Invoice not found".to_string()))?;
+    let client_id = client_id.ok_or(ApiError::Unprocessable("Invoice has no client to send it to".to_string()))?;
+    if status == "void" {
+        return Err(ApiError::Conflict("Void invoices cannot be sent".to_string()));
+    }
+
+    // Sending a draft issues it
+    if status == "draft" {
+        let mut conn = state.db.acquire().await?;
+        if let Some(warning) = credit::check(&mut conn, auth.user_id, Some(client_id), total, Some(id)).await? {
+            tracing::warn!("Sending invoice {}: {}", invoice_number, warning);
+        }
+    }
+
+    // Invoice-recipient contacts, or the client's own email when it has none
+    let recipients: Vec<String> = sqlx::query_scalar(
+        "SELECT ct.email FROM client_contacts ct WHERE ct.client_id = $1 AND ct.invoice_recipient AND ct.email IS NOT NULL \
+         UNION ALL SELECT c.email FROM clients c WHERE c.id = $1 AND c.email IS NOT NULL \
+           AND NOT EXISTS (SELECT 1 FROM client_contacts WHERE client_id = c.id AND invoice_recipient AND email IS NOT NULL)"
+    ).bind(client_id).fetch_all(&state.db).await?;
+    if recipients.is_empty() {
+        return Err(ApiError::Unprocessable("Client has no email address or invoice recipient contact".to_string()));
+    }
+
     // Simulate sending email
-    tracing::info!("Simulating sending email for invoice {}", invoice.invoice_number);
-    
-    sqlx::query("UPDATE invoices SET status = 'sent' WHERE id = $1 AND status = 'draft'").bind(id).execute(&state.db).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    tracing::info!("Simulating sending email for invoice {} to {}", invoice_number, recipients.join(", "));
+    EMAILS_SENT.inc(&["invoice"]);
     
+    let mut tx = state.db.begin().await?;
+    let issued = sqlx::query("UPDATE invoices SET status = 'sent' WHERE id = $1 AND status = 'draft'").bind(id).execute(&mut *tx).await?.rows_affected() > 0;
+    if issued {
+        for level in inventory::sync_invoice(&mut tx, auth.user_id, id, true).await? {
+            if let Some(warning) = level.warning() {
+                tracing::warn!("Sending invoice {}: {}", invoice_number, warning);
+            }
+        }
+    }
+    tx.commit().await?;
+
     Ok(StatusCode::OK)
 }
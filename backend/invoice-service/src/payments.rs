@@ -0,0 +1,611 @@
+//! Payments against invoices, credit notes, and the client ledger built from
+//! them for client-service's account statements (see `common::ledger`).
+//!
+//! An invoice is settled once its payments and credit notes cover its total:
+//! recording or deleting either moves it between `sent`/`overdue` and `paid`.
+//! A credit note may also return goods to stock (see `common::inventory`).
+
+use axum::{
+    extract::{rejection::QueryRejection, Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use common::inventory::{self, Movement};
+use common::ledger::{ClientLedger, EntryKind, LedgerEntry, LedgerQuery, OpenInvoice};
+use common::{ApiError, AuthContext, FieldError, FieldKind, Filter, FilterOp, ListQuery, ListSpec, Page, SortKey, Valid, Validate, Validator};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::{cents, AppState};
+
+/// Slack when comparing summed `float8` quantities.
+const QUANTITY_TOLERANCE: f64 = 1e-9;
+
+#[derive(Debug, FromRow, Serialize, Deserialize)]
+pub(crate) struct Payment {
+    id: i32,
+    invoice_id: i32,
+    amount: f64,
+    paid_on: NaiveDate,
+    method: Option<String>,
+    reference: Option<String>,
+    created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CreatePaymentRequest {
+    amount: f64,
+    paid_on: NaiveDate,
+    method: Option<String>,
+    reference: Option<String>,
+}
+
+impl Validate for CreatePaymentRequest {
+    fn validate(&self, v: &mut Validator) {
+        if cents(self.amount) <= 0 {
+            v.error("amount", "must be greater than 0");
+        }
+        v.max_len("method", self.method.as_deref(), 50);
+        v.max_len("reference", self.reference.as_deref(), 255);
+    }
+}
+
+#[derive(Debug, FromRow, Serialize, Deserialize)]
+pub(crate) struct CreditNote {
+    id: i32,
+    user_id: Option<i32>,
+    client_id: Option<i32>,
+    #[sqlx(default)]
+    client_name: Option<String>,
+    invoice_id: Option<i32>,
+    #[sqlx(default)]
+    invoice_number: Option<String>,
+    credit_note_number: String,
+    issue_date: NaiveDate,
+    amount: f64,
+    reason: Option<String>,
+    created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CreateCreditNoteRequest {
+    client_id: i32,
+    /// The invoice credited; without one the credit is held on the client's account.
+    invoice_id: Option<i32>,
+    credit_note_number: String,
+    issue_date: NaiveDate,
+    amount: f64,
+    reason: Option<String>,
+    /// Goods returned to stock with the credit.
+    #[serde(default)]
+    restock: Vec<RestockLine>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RestockLine {
+    product_id: i32,
+    quantity: f64,
+}
+
+impl Validate for CreateCreditNoteRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("credit_note_number", &self.credit_note_number);
+        v.max_len("credit_note_number", self.credit_note_number.as_str(), 50);
+        if cents(self.amount) <= 0 {
+            v.error("amount", "must be greater than 0");
+        }
+        v.max_len("reason", self.reason.as_deref(), 1000);
+        if !self.restock.is_empty() && self.invoice_id.is_none() {
+            v.error("restock", "needs the invoice_id the goods were invoiced on");
+        }
+        v.nested("restock", &self.restock);
+    }
+}
+
+impl Validate for RestockLine {
+    fn validate(&self, v: &mut Validator) {
+        if self.quantity <= 0.0 {
+            v.error("quantity", "must be greater than 0");
+        }
+    }
+}
+
+pub(crate) const CREDIT_NOTE_LIST: ListSpec = ListSpec {
+    columns: "cn.id, cn.user_id, cn.client_id, c.name as client_name, cn.invoice_id, i.invoice_number, cn.credit_note_number, cn.issue_date, cn.amount::float8 as amount, cn.reason, cn.created_at",
+    from: "FROM credit_notes cn LEFT JOIN clients c ON cn.client_id = c.id LEFT JOIN invoices i ON cn.invoice_id = i.id",
+    owner_column: "cn.user_id",
+    id_column: "cn.id",
+    sort_keys: &[
+        SortKey::new("issue_date", "cn.issue_date", FieldKind::Date),
+        SortKey::new("amount", "cn.amount", FieldKind::Number),
+    ],
+    default_sort: "-issue_date",
+    filters: &[
+        Filter::new("client_id", "cn.client_id", FieldKind::Int, FilterOp::In),
+        Filter::new("invoice_id", "cn.invoice_id", FieldKind::Int, FilterOp::In),
+        Filter::new("issue_from", "cn.issue_date", FieldKind::Date, FilterOp::Min),
+        Filter::new("issue_to", "cn.issue_date", FieldKind::Date, FilterOp::Max),
+    ],
+    default_filters: &[],
+};
+
+/// What is still owed on an invoice: its total less payments and credit notes.
+async fn outstanding(tx: &mut sqlx::PgConnection, invoice_id: i32) -> Result<f64, ApiError> {
+    let outstanding: f64 = sqlx::query_scalar(
+        "SELECT (i.total \
+           - COALESCE((SELECT SUM(p.amount) FROM payments p WHERE p.invoice_id = i.id), 0) \
+           - COALESCE((SELECT SUM(cn.amount) FROM credit_notes cn WHERE cn.invoice_id = i.id), 0))::float8 \
+         FROM invoices i WHERE i.id = $1"
+    )
+    .bind(invoice_id)
+    .fetch_one(&mut *tx)
+    .await?;
+    Ok(outstanding)
+}
+
+/// Marks the invoice paid once nothing is outstanding, and reopens it (as
+/// `overdue` past its due date) when something is again.
+async fn update_settlement(tx: &mut sqlx::PgConnection, invoice_id: i32) -> Result<(), ApiError> {
+    let settled = cents(outstanding(tx, invoice_id).await?) <= 0;
+    let query = if settled {
+        "UPDATE invoices SET status = 'paid' WHERE id = $1 AND status IN ('sent', 'overdue')"
+    } else {
+        "UPDATE invoices SET status = CASE WHEN due_date < CURRENT_DATE THEN 'overdue' ELSE 'sent' END WHERE id = $1 AND status = 'paid'"
+    };
+    sqlx::query(query).bind(invoice_id).execute(&mut *tx).await?;
+    Ok(())
+}
+
+/// Locks the caller's invoice for the rest of the transaction, so concurrent
+/// payments can't both fit under the outstanding amount. Returns its status.
+async fn lock_invoice(tx: &mut sqlx::PgConnection, invoice_id: i32, user_id: i32) -> Result<String, ApiError> {
+    sqlx::query_scalar("SELECT status FROM invoices WHERE id = $1 AND user_id = $2 FOR UPDATE")
+        .bind(invoice_id)
+        .bind(user_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(ApiError::NotFound("Invoice not found".to_string()))
+}
+
+fn check_applicable(amount: f64, outstanding: f64) -> Result<(), ApiError> {
+    if cents(amount) > cents(outstanding) {
+        return Err(ApiError::Unprocessable(format!("Amount exceeds the {:.2} outstanding on the invoice", outstanding)));
+    }
+    Ok(())
+}
+
+pub(crate) async fn list_payments(auth: AuthContext, Path(invoice_id): Path<i32>, State(state): State<Arc<AppState>>) -> Result<Json<Vec<Payment>>, ApiError> {
+    let exists: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM invoices WHERE id = $1 AND user_id = $2)").bind(invoice_id).bind(auth.user_id).fetch_one(&state.db).await?;
+    if !exists {
+        return Err(ApiError::NotFound("Invoice not found".to_string()));
+    }
+    let payments = sqlx::query_as::<_, Payment>("SELECT id, invoice_id, amount::float8 as amount, paid_on, method, reference, created_at FROM payments WHERE invoice_id = $1 ORDER BY paid_on, id").bind(invoice_id).fetch_all(&state.db).await?;
+    Ok(Json(payments))
+}
+
+pub(crate) async fn create_payment(auth: AuthContext, Path(invoice_id): Path<i32>, State(state): State<Arc<AppState>>, Valid(payload): Valid<CreatePaymentRequest>) -> Result<Json<Payment>, ApiError> {
+    let mut tx = state.db.begin().await?;
+    match lock_invoice(&mut tx, invoice_id, auth.user_id).await?.as_str() {
+        "draft" => return Err(ApiError::Conflict("Draft invoices cannot take payments; send the invoice first".to_string())),
+        "void" => return Err(ApiError::Conflict("Void invoices cannot take payments".to_string())),
+        _ => {}
+    }
+    check_applicable(payload.amount, outstanding(&mut tx, invoice_id).await?)?;
+
+    let payment = sqlx::query_as::<_, Payment>("INSERT INTO payments (invoice_id, amount, paid_on, method, reference) VALUES ($1, $2, $3, $4, $5) RETURNING id, invoice_id, amount::float8 as amount, paid_on, method, reference, created_at").bind(invoice_id).bind(payload.amount).bind(payload.paid_on).bind(payload.method).bind(payload.reference).fetch_one(&mut *tx).await?;
+    update_settlement(&mut tx, invoice_id).await?;
+    tx.commit().await?;
+    Ok(Json(payment))
+}
+
+pub(crate) async fn delete_payment(auth: AuthContext, Path((invoice_id, payment_id)): Path<(i32, i32)>, State(state): State<Arc<AppState>>) -> Result<StatusCode, ApiError> {
+    let mut tx = state.db.begin().await?;
+    lock_invoice(&mut tx, invoice_id, auth.user_id).await?;
+    let result = sqlx::query("DELETE FROM payments WHERE id = $1 AND invoice_id = $2").bind(payment_id).bind(invoice_id).execute(&mut *tx).await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound("Payment not found".to_string()));
+    }
+    update_settlement(&mut tx, invoice_id).await?;
+    tx.commit().await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn list_credit_notes(auth: AuthContext, State(state): State<Arc<AppState>>, query: ListQuery) -> Result<Json<Page<CreditNote>>, ApiError> {
+    let page = query.fetch(&state.db, &CREDIT_NOTE_LIST, auth.user_id).await?;
+    Ok(Json(page))
+}
+
+pub(crate) async fn create_credit_note(auth: AuthContext, State(state): State<Arc<AppState>>, Valid(payload): Valid<CreateCreditNoteRequest>) -> Result<Json<CreditNote>, ApiError> {
+    let mut tx = state.db.begin().await?;
+    let client_exists: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM clients WHERE id = $1 AND user_id = $2)").bind(payload.client_id).bind(auth.user_id).fetch_one(&mut *tx).await?;
+    if !client_exists {
+        return Err(ApiError::NotFound("Client not found".to_string()));
+    }
+    if let Some(invoice_id) = payload.invoice_id {
+        let status = lock_invoice(&mut tx, invoice_id, auth.user_id).await?;
+        let invoice_client: Option<i32> = sqlx::query_scalar("SELECT client_id FROM invoices WHERE id = $1").bind(invoice_id).fetch_one(&mut *tx).await?;
+        if invoice_client != Some(payload.client_id) {
+            return Err(ApiError::Unprocessable("The invoice belongs to a different client".to_string()));
+        }
+        match status.as_str() {
+            "draft" => return Err(ApiError::Conflict("Draft invoices cannot be credited; edit or delete the draft instead".to_string())),
+            "void" => return Err(ApiError::Conflict("Void invoices cannot be credited".to_string())),
+            _ => {}
+        }
+        check_applicable(payload.amount, outstanding(&mut tx, invoice_id).await?)?;
+    }
+
+    let id: i32 = sqlx::query_scalar("INSERT INTO credit_notes (user_id, client_id, invoice_id, credit_note_number, issue_date, amount, reason) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id").bind(auth.user_id).bind(payload.client_id).bind(payload.invoice_id).bind(payload.credit_note_number).bind(payload.issue_date).bind(payload.amount).bind(payload.reason).fetch_one(&mut *tx).await?;
+    if let Some(invoice_id) = payload.invoice_id {
+        update_settlement(&mut tx, invoice_id).await?;
+    }
+    if let Some(invoice_id) = payload.invoice_id {
+        restock(&mut tx, auth.user_id, id, invoice_id, &payload.restock).await?;
+    }
+    let credit_note = fetch_credit_note(&mut tx, id, auth.user_id).await?;
+    tx.commit().await?;
+    Ok(Json(credit_note))
+}
+
+pub(crate) async fn get_credit_note(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>) -> Result<Json<CreditNote>, ApiError> {
+    let mut conn = state.db.acquire().await?;
+    let credit_note = fetch_credit_note(&mut conn, id, auth.user_id).await?;
+    Ok(Json(credit_note))
+}
+
+/// Returns goods credited on a credit note against `invoice_id` to stock.
+/// Every product must be the user's and track inventory, or be a bundle,
+/// whose components that track inventory are returned. No product may come
+/// back more often than the invoice took it out, across its credit notes.
+async fn restock(tx: &mut sqlx::PgConnection, user_id: i32, credit_note_id: i32, invoice_id: i32, lines: &[RestockLine]) -> Result<(), ApiError> {
+    if lines.is_empty() {
+        return Ok(());
+    }
+
+    // Each line as the products whose stock it moves, bundles by their components
+    let mut returns = Vec::new();
+    for (n, line) in lines.iter().enumerate() {
+        let components: Vec<(i32, f64)> = sqlx::query_as(
+            "SELECT bc.component_id, bc.quantity::float8 FROM bundle_components bc JOIN products p ON p.id = bc.bundle_id \
+             WHERE bc.bundle_id = $1 AND p.user_id = $2 ORDER BY bc.component_id"
+        )
+        .bind(line.product_id)
+        .bind(user_id)
+        .fetch_all(&mut *tx)
+        .await?;
+        if components.is_empty() {
+            returns.push(Return { line: n, product_id: line.product_id, quantity: line.quantity, component: false });
+        }
+        for (component_id, quantity) in components {
+            returns.push(Return { line: n, product_id: component_id, quantity: line.quantity * quantity, component: true });
+        }
+    }
+
+    // What the invoice took out, counted the same way, less earlier returns
+    let returnable: HashMap<i32, f64> = sqlx::query_as::<_, (i32, f64)>(
+        "WITH invoiced AS ( \
+           SELECT COALESCE(bc.component_id, ii.product_id) AS product_id, SUM(ii.quantity * COALESCE(bc.quantity, 1)) AS quantity \
+           FROM invoice_items ii LEFT JOIN bundle_components bc ON bc.bundle_id = ii.product_id \
+           WHERE ii.invoice_id = $1 AND ii.product_id IS NOT NULL GROUP BY 1 \
+         ), returned AS ( \
+           SELECT m.product_id, SUM(m.quantity) AS quantity FROM stock_movements m JOIN credit_notes cn ON cn.id = m.credit_note_id \
+           WHERE cn.invoice_id = $1 AND m.kind = 'credit_note' GROUP BY m.product_id \
+         ) \
+         SELECT i.product_id, (i.quantity - COALESCE(r.quantity, 0))::float8 FROM invoiced i LEFT JOIN returned r ON r.product_id = i.product_id"
+    )
+    .bind(invoice_id)
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .collect();
+    let errors = over_returned(&returns, &returnable);
+    if !errors.is_empty() {
+        return Err(ApiError::Validation(errors));
+    }
+
+    let mut errors = Vec::new();
+    for r in &returns {
+        let movement = Movement { product_id: r.product_id, quantity: r.quantity, kind: "credit_note", invoice_id: None, credit_note_id: Some(credit_note_id), reason: None };
+        if inventory::record(&mut *tx, user_id, &movement).await?.is_none() && !r.component {
+            errors.push(FieldError { field: format!("restock[{}].product_id", r.line), message: "is not a product that tracks inventory".to_string() });
+        }
+    }
+    if !errors.is_empty() {
+        return Err(ApiError::Validation(errors));
+    }
+    Ok(())
+}
+
+/// A quantity of one product returned by a restock line.
+struct Return {
+    line: usize,
+    product_id: i32,
+    quantity: f64,
+    /// Returned as a component of the line's bundle.
+    component: bool,
+}
+
+/// Flags every line returning a product beyond what is `returnable` of it.
+fn over_returned(returns: &[Return], returnable: &HashMap<i32, f64>) -> Vec<FieldError> {
+    let mut requested: HashMap<i32, f64> = HashMap::new();
+    for r in returns {
+        *requested.entry(r.product_id).or_default() += r.quantity;
+    }
+    let mut errors: Vec<FieldError> = Vec::new();
+    for r in returns {
+        let left = returnable.get(&r.product_id).copied().unwrap_or(0.0).max(0.0);
+        let field = format!("restock[{}].quantity", r.line);
+        if requested[&r.product_id] > left + QUANTITY_TOLERANCE && !errors.iter().any(|e| e.field == field) {
+            errors.push(FieldError { field, message: format!("returns more of product {} than the {} invoiced and not yet returned", r.product_id, left) });
+        }
+    }
+    errors
+}
+
+/// Deleting a credit note takes the goods it returned back out of stock.
+pub(crate) async fn delete_credit_note(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>) -> Result<StatusCode, ApiError> {
+    let mut tx = state.db.begin().await?;
+    let returned: Vec<(i32, f64)> = sqlx::query_as(
+        "SELECT m.product_id, SUM(m.quantity)::float8 FROM stock_movements m JOIN credit_notes cn ON cn.id = m.credit_note_id \
+         WHERE m.credit_note_id = $1 AND cn.user_id = $2 GROUP BY m.product_id ORDER BY m.product_id"
+    )
+    .bind(id)
+    .bind(auth.user_id)
+    .fetch_all(&mut *tx)
+    .await?;
+    for (product_id, quantity) in returned.into_iter().filter(|(_, q)| *q != 0.0) {
+        let movement = Movement { product_id, quantity: -quantity, kind: "credit_note", invoice_id: None, credit_note_id: Some(id), reason: Some("Credit note deleted") };
+        inventory::record(&mut tx, auth.user_id, &movement).await?;
+    }
+    let invoice_id: Option<i32> = sqlx::query_scalar("DELETE FROM credit_notes WHERE id = $1 AND user_id = $2 RETURNING invoice_id").bind(id).bind(auth.user_id).fetch_optional(&mut *tx).await?.ok_or(ApiError::NotFound("Credit note not found".to_string()))?;
+    if let Some(invoice_id) = invoice_id {
+        update_settlement(&mut tx, invoice_id).await?;
+    }
+    tx.commit().await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn fetch_credit_note(conn: &mut sqlx::PgConnection, id: i32, user_id: i32) -> Result<CreditNote, ApiError> {
+    sqlx::query_as::<_, CreditNote>(&format!("SELECT {} {} WHERE cn.id = $1 AND cn.user_id = $2", CREDIT_NOTE_LIST.columns, CREDIT_NOTE_LIST.from))
+        .bind(id)
+        .bind(user_id)
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or(ApiError::NotFound("Credit note not found".to_string()))
+}
+
+#[derive(FromRow)]
+struct EntryRow {
+    date: NaiveDate,
+    kind: String,
+    reference: Option<String>,
+    invoice_number: Option<String>,
+    amount: f64,
+}
+
+/// Drafts and void invoices are not owed, so they stay off the ledger.
+pub(crate) async fn client_ledger(auth: AuthContext, Path(client_id): Path<i32>, State(state): State<Arc<AppState>>, query: Result<Query<LedgerQuery>, QueryRejection>) -> Result<Json<ClientLedger>, ApiError> {
+    let Query(query) = query?;
+    let rows = sqlx::query_as::<_, EntryRow>(
+        "SELECT date, kind, reference, invoice_number, amount FROM ( \
+           SELECT COALESCE(i.created_at, 'epoch')::date AS date, 'invoice' AS kind, 0 AS kind_order, i.id, i.invoice_number AS reference, NULL::text AS invoice_number, i.total::float8 AS amount \
+           FROM invoices i WHERE i.client_id = $1 AND i.user_id = $2 AND i.status NOT IN ('draft', 'void') \
+           UNION ALL \
+           SELECT p.paid_on, 'payment', 1, p.id, p.reference, i.invoice_number, -p.amount::float8 \
+           FROM payments p JOIN invoices i ON i.id = p.invoice_id WHERE i.client_id = $1 AND i.user_id = $2 AND i.status NOT IN ('draft', 'void') \
+           UNION ALL \
+           SELECT cn.issue_date, 'credit_note', 2, cn.id, cn.credit_note_number, i.invoice_number, -cn.amount::float8 \
+           FROM credit_notes cn LEFT JOIN invoices i ON i.id = cn.invoice_id WHERE cn.client_id = $1 AND cn.user_id = $2 \
+         ) e WHERE date <= $3 ORDER BY date, kind_order, id"
+    )
+    .bind(client_id)
+    .bind(auth.user_id)
+    .bind(query.to)
+    .fetch_all(&state.db)
+    .await?;
+
+    let mut ledger = ClientLedger::default();
+    for row in rows {
+        if query.from.is_some_and(|from| row.date < from) {
+            ledger.opening_balance += row.amount;
+            continue;
+        }
+        let kind = match row.kind.as_str() {
+            "invoice" => EntryKind::Invoice,
+            "payment" => EntryKind::Payment,
+            _ => EntryKind::CreditNote,
+        };
+        ledger.entries.push(LedgerEntry { date: row.date, kind, reference: row.reference, invoice_number: row.invoice_number, amount: row.amount });
+    }
+
+    ledger.open_invoices = sqlx::query_as::<_, OpenInvoice>(
+        "SELECT invoice_id, invoice_number, issue_date, due_date, outstanding FROM ( \
+           SELECT i.id AS invoice_id, i.invoice_number, COALESCE(i.created_at, 'epoch')::date AS issue_date, i.due_date, \
+             (i.total \
+               - COALESCE((SELECT SUM(p.amount) FROM payments p WHERE p.invoice_id = i.id AND p.paid_on <= $3), 0) \
+               - COALESCE((SELECT SUM(cn.amount) FROM credit_notes cn WHERE cn.invoice_id = i.id AND cn.issue_date <= $3), 0))::float8 AS outstanding \
+           FROM invoices i WHERE i.client_id = $1 AND i.user_id = $2 AND i.status NOT IN ('draft', 'void') AND COALESCE(i.created_at, 'epoch')::date <= $3 \
+         ) o WHERE outstanding > 0 ORDER BY issue_date, invoice_id"
+    )
+    .bind(client_id)
+    .bind(auth.user_id)
+    .bind(query.to)
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(ledger))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payment(amount: f64) -> CreatePaymentRequest {
+        CreatePaymentRequest { amount, paid_on: "2026-10-19".parse().unwrap(), method: None, reference: None }
+    }
+
+    fn credit_note(amount: f64, restock: Vec<RestockLine>) -> CreateCreditNoteRequest {
+        CreateCreditNoteRequest {
+            client_id: 1,
+            invoice_id: Some(1),
+            credit_note_number: "CN-1".to_string(),
+            issue_date: "2026-10-19".parse().unwrap(),
+            amount,
+            reason: None,
+            restock,
+        }
+    }
+
+    fn fields(errors: Vec<FieldError>) -> Vec<String> {
+        errors.into_iter().map(|e| e.field).collect()
+    }
+
+    #[test]
+    fn applies_up_to_the_outstanding_amount_to_the_cent() {
+        assert!(check_applicable(50.0, 50.0).is_ok());
+        assert!(check_applicable(0.1 + 0.2, 0.3).is_ok());
+        assert!(check_applicable(50.004, 50.0).is_ok());
+        assert!(check_applicable(50.01, 50.0).is_err());
+        assert!(check_applicable(1.0, 0.0).is_err());
+    }
+
+    #[test]
+    fn requires_a_positive_payment() {
+        assert!(Validator::collect(&payment(0.01)).is_empty());
+        assert_eq!(fields(Validator::collect(&payment(0.0))), ["amount"]);
+        assert_eq!(fields(Validator::collect(&payment(0.004))), ["amount"]);
+        assert_eq!(fields(Validator::collect(&payment(-5.0))), ["amount"]);
+    }
+
+    #[test]
+    fn requires_a_positive_credit_note_and_restock_quantities() {
+        assert!(Validator::collect(&credit_note(10.0, vec![RestockLine { product_id: 1, quantity: 2.0 }])).is_empty());
+        assert_eq!(fields(Validator::collect(&credit_note(-1.0, Vec::new()))), ["amount"]);
+        let errors = fields(Validator::collect(&credit_note(10.0, vec![RestockLine { product_id: 1, quantity: 1.0 }, RestockLine { product_id: 2, quantity: 0.0 }])));
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("restock"), "{:?}", errors);
+    }
+
+    #[test]
+    fn returns_goods_only_against_an_invoice() {
+        let mut on_account = credit_note(10.0, vec![RestockLine { product_id: 1, quantity: 1.0 }]);
+        on_account.invoice_id = None;
+        assert_eq!(fields(Validator::collect(&on_account)), ["restock"]);
+        on_account.restock.clear();
+        assert!(Validator::collect(&on_account).is_empty());
+    }
+
+    fn returned(line: usize, product_id: i32, quantity: f64) -> Return {
+        Return { line, product_id, quantity, component: false }
+    }
+
+    #[test]
+    fn returns_up_to_what_the_invoice_has_left_to_return() {
+        let returnable = HashMap::from([(1, 5.0), (2, 0.5)]);
+        assert!(over_returned(&[returned(0, 1, 5.0), returned(1, 2, 0.5)], &returnable).is_empty());
+        assert_eq!(fields(over_returned(&[returned(0, 1, 5.01)], &returnable)), ["restock[0].quantity"]);
+        assert_eq!(fields(over_returned(&[returned(0, 3, 1.0)], &returnable)), ["restock[0].quantity"]);
+    }
+
+    #[test]
+    fn sums_a_product_over_lines_and_bundle_components() {
+        let returnable = HashMap::from([(1, 4.0), (2, 10.0)]);
+        // Line 1 is a bundle of 2 × product 1 and 1 × product 2
+        let returns = [returned(0, 1, 1.0), returned(1, 1, 4.0), returned(1, 2, 2.0), returned(2, 2, 1.0)];
+        assert_eq!(fields(over_returned(&returns, &returnable)), ["restock[0].quantity", "restock[1].quantity"]);
+    }
+
+    #[test]
+    fn more_returned_than_invoiced_leaves_nothing_to_return() {
+        let returnable = HashMap::from([(1, -2.0)]);
+        assert_eq!(fields(over_returned(&[returned(0, 1, 0.5)], &returnable)), ["restock[0].quantity"]);
+    }
+
+    async fn seed(db: &sqlx::PgPool) -> (i32, i32, i32, i32) {
+        let user_id: i32 = sqlx::query_scalar("INSERT INTO users (email, password_hash) VALUES ('returns@example.com', 'x') RETURNING id").fetch_one(db).await.unwrap();
+        let product = |name: &'static str, tracked: bool| async move {
+            sqlx::query_scalar::<_, i32>("INSERT INTO products (user_id, name, track_inventory, stock_on_hand) VALUES ($1, $2, $3, 0) RETURNING id")
+                .bind(user_id)
+                .bind(name)
+                .bind(tracked)
+                .fetch_one(db)
+                .await
+                .unwrap()
+        };
+        let widget = product("Widget", true).await;
+        let setup = product("Setup", false).await;
+        let kit = product("Kit", false).await;
+        sqlx::query("INSERT INTO bundle_components (bundle_id, component_id, quantity) VALUES ($1, $2, 2), ($1, $3, 1)").bind(kit).bind(widget).bind(setup).execute(db).await.unwrap();
+
+        let invoice_id: i32 = sqlx::query_scalar("INSERT INTO invoices (user_id, invoice_number, status) VALUES ($1, 'INV-1', 'sent') RETURNING id").bind(user_id).fetch_one(db).await.unwrap();
+        // 3 widgets on their own and 2 more in a kit
+        for (product_id, quantity) in [(widget, 3.0), (setup, 1.0), (kit, 1.0)] {
+            sqlx::query("INSERT INTO invoice_items (invoice_id, product_id, description, quantity) VALUES ($1, $2, 'Line', $3)").bind(invoice_id).bind(product_id).bind(quantity).execute(db).await.unwrap();
+        }
+        (user_id, invoice_id, widget, kit)
+    }
+
+    /// Restocks on a new credit note, keeping its movements only if it succeeds.
+    async fn credit(db: &sqlx::PgPool, user_id: i32, invoice_id: i32, lines: Vec<RestockLine>) -> Result<(), ApiError> {
+        let mut tx = db.begin().await.unwrap();
+        let id: i32 = sqlx::query_scalar("INSERT INTO credit_notes (user_id, invoice_id, credit_note_number, issue_date, amount) VALUES ($1, $2, 'CN', CURRENT_DATE, 1) RETURNING id")
+            .bind(user_id)
+            .bind(invoice_id)
+            .fetch_one(&mut *tx)
+            .await
+            .unwrap();
+        restock(&mut tx, user_id, id, invoice_id, &lines).await?;
+        tx.commit().await.unwrap();
+        Ok(())
+    }
+
+    async fn stock(db: &sqlx::PgPool, product_id: i32) -> f64 {
+        sqlx::query_scalar("SELECT stock_on_hand::float8 FROM products WHERE id = $1").bind(product_id).fetch_one(db).await.unwrap()
+    }
+
+    fn invalid_fields(result: Result<(), ApiError>) -> Vec<String> {
+        match result {
+            Err(ApiError::Validation(errors)) => fields(errors),
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+    }
+
+    #[sqlx::test(migrator = "common::MIGRATOR")]
+    async fn returns_tracked_goods_up_to_what_the_invoice_took(db: sqlx::PgPool) {
+        let (user_id, invoice_id, widget, kit) = seed(&db).await;
+
+        credit(&db, user_id, invoice_id, vec![RestockLine { product_id: widget, quantity: 2.0 }]).await.unwrap();
+        assert_eq!(stock(&db, widget).await, 2.0);
+
+        // The kit's 2 widgets count towards the same 5
+        credit(&db, user_id, invoice_id, vec![RestockLine { product_id: kit, quantity: 1.0 }]).await.unwrap();
+        assert_eq!(stock(&db, widget).await, 4.0);
+
+        let over = credit(&db, user_id, invoice_id, vec![RestockLine { product_id: widget, quantity: 1.5 }]).await;
+        assert_eq!(invalid_fields(over), ["restock[0].quantity"]);
+        assert_eq!(stock(&db, widget).await, 4.0);
+
+        credit(&db, user_id, invoice_id, vec![RestockLine { product_id: widget, quantity: 1.0 }]).await.unwrap();
+        assert_eq!(stock(&db, widget).await, 5.0);
+    }
+
+    #[sqlx::test(migrator = "common::MIGRATOR")]
+    async fn refuses_to_restock_untracked_or_uninvoiced_products(db: sqlx::PgPool) {
+        let (user_id, invoice_id, widget, _) = seed(&db).await;
+        let setup: i32 = sqlx::query_scalar("SELECT id FROM products WHERE name = 'Setup'").fetch_one(&db).await.unwrap();
+
+        let untracked = credit(&db, user_id, invoice_id, vec![RestockLine { product_id: widget, quantity: 1.0 }, RestockLine { product_id: setup, quantity: 1.0 }]).await;
+        assert_eq!(invalid_fields(untracked), ["restock[1].product_id"]);
+
+        let other: i32 = sqlx::query_scalar("INSERT INTO products (user_id, name, track_inventory) VALUES ($1, 'Other', TRUE) RETURNING id").bind(user_id).fetch_one(&db).await.unwrap();
+        let uninvoiced = credit(&db, user_id, invoice_id, vec![RestockLine { product_id: other, quantity: 1.0 }]).await;
+        assert_eq!(invalid_fields(uninvoiced), ["restock[0].quantity"]);
+
+        assert_eq!(stock(&db, widget).await, 0.0);
+        let movements: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM stock_movements").fetch_one(&db).await.unwrap();
+        assert_eq!(movements, 0);
+    }
+}
@@ -0,0 +1,462 @@
+//! Runs of recurring profiles. Nothing runs profiles on a timer: a profile is
+//! run once due with `POST /api/recurring/:id/run`, or every due profile with
+//! `POST /api/recurring/run`, e.g. daily from a scheduled job with an API key.
+//!
+//! A run creates a draft invoice, numbered from the company's
+//! `invoice_prefix`, the run date and the profile, with the profile's `total`
+//! for the period starting on the run date. With `bill_usage` it also bills,
+//! one line per metered product, the client's usage (see `usage`) before the
+//! run date not yet invoiced, priced from the price lists for the total
+//! quantity so quantity tiers apply. The profile then moves on to its next run
+//! date. The draft goes out when it is sent; until then it, like
+//! `GET /api/recurring/:id/preview`, shows the client what it will be billed.
+//!
+//! A subscription's profile (see `subscriptions`) bills its plan's price per
+//! seat instead of `total`, and its run on or after `cancel_at` bills only the
+//! remaining usage and ends the subscription.
+
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use chrono::{Days, Months, NaiveDate, Utc};
+use common::pricing;
+use common::{ApiError, AuthContext};
+use serde::Serialize;
+use sqlx::FromRow;
+use std::sync::Arc;
+
+use crate::lines::{self, PricedLine};
+use crate::{insert_invoice, AppState, CreateInvoiceItemRequest, CreateInvoiceRequest, InvoiceWithItems, INVOICES_CREATED};
+
+/// Runs a profile catches up on in one `POST /api/recurring/run`.
+const MAX_CATCH_UP_RUNS: usize = 366;
+
+#[derive(FromRow)]
+pub(crate) struct Profile {
+    pub id: i32,
+    pub client_id: Option<i32>,
+    pub interval: String,
+    pub interval_count: i32,
+    start_date: NaiveDate,
+    /// Set when runs count from another date than `start_date`, see `anchor`.
+    billing_anchor: Option<NaiveDate>,
+    pub next_run: Option<NaiveDate>,
+    pub last_run: Option<NaiveDate>,
+    pub status: String,
+    total: f64,
+    bill_usage: bool,
+    pub quantity: i32,
+    pub trial_end: Option<NaiveDate>,
+    pub cancel_at: Option<NaiveDate>,
+    pub plan_id: Option<i32>,
+    pub plan_name: Option<String>,
+    pub plan_price: Option<f64>,
+    plan_product_id: Option<i32>,
+}
+
+impl Profile {
+    /// The date runs are counted from: the `start_date`, or for a
+    /// subscription the first day billed after a trial or a pause.
+    fn anchor(&self) -> NaiveDate {
+        self.billing_anchor.unwrap_or(self.start_date)
+    }
+
+    /// The date the next run is for; a profile that hasn't run starts on its anchor.
+    fn run_date(&self) -> NaiveDate {
+        self.next_run.unwrap_or(self.anchor())
+    }
+
+    /// The first of the profile's dates (its anchor plus whole intervals)
+    /// after `date`; `None` once they run past the end of the calendar.
+    fn next_date_after(&self, date: NaiveDate) -> Option<NaiveDate> {
+        let count = self.interval_count.max(1) as u32;
+        let anchor = self.anchor();
+        let nth = |n: u32| {
+            let steps = n.checked_mul(count)?;
+            match self.interval.as_str() {
+                "day" => anchor.checked_add_days(Days::new(steps.into())),
+                "week" => anchor.checked_add_days(Days::new(u64::from(steps) * 7)),
+                "year" => anchor.checked_add_months(Months::new(steps.checked_mul(12)?)),
+                // Counted from the anchor so a profile started on the 31st
+                // keeps billing on the last day of shorter months
+                _ => anchor.checked_add_months(Months::new(steps)),
+            }
+        };
+        (1..).map(nth).find(|d| !matches!(d, Some(d) if *d <= date)).flatten()
+    }
+}
+
+/// What a run bills, before pricing.
+struct Plan {
+    run_date: NaiveDate,
+    /// The day after the period the run bills; `None` for a subscription's last run.
+    period_end: Option<NaiveDate>,
+    items: Vec<CreateInvoiceItemRequest>,
+    /// The usage the items bill.
+    usage_event_ids: Vec<i64>,
+}
+
+#[derive(FromRow)]
+struct UsageLine {
+    product_id: i32,
+    name: String,
+    quantity: f64,
+    first: NaiveDate,
+    event_ids: Vec<i64>,
+}
+
+pub(crate) async fn load_profile(conn: &mut sqlx::PgConnection, id: i32, user_id: i32, lock: bool) -> Result<Profile, ApiError> {
+    sqlx::query_as::<_, Profile>(&format!(
+        "SELECT r.id, r.client_id, r.interval, r.interval_count, r.start_date, r.billing_anchor, r.next_run, r.last_run, r.status, r.total::float8 AS total, r.bill_usage, \
+           r.quantity, r.trial_end, r.cancel_at, r.plan_id, p.name AS plan_name, p.price::float8 AS plan_price, p.product_id AS plan_product_id \
+         FROM recurring_invoices r LEFT JOIN plans p ON p.id = r.plan_id WHERE r.id = $1 AND r.user_id = $2{}",
+        if lock { " FOR UPDATE OF r" } else { "" }
+    ))
+    .bind(id)
+    .bind(user_id)
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or(ApiError::NotFound("Recurring invoice not found".to_string()))
+}
+
+async fn plan(conn: &mut sqlx::PgConnection, user_id: i32, profile: &Profile) -> Result<Plan, ApiError> {
+    let run_date = profile.run_date();
+    let period_end = profile
+        .next_date_after(run_date)
+        .ok_or_else(|| ApiError::Unprocessable(format!("Recurring invoice has no run date after {}", run_date)))?;
+    let ending = profile.cancel_at.is_some_and(|cancel_at| cancel_at <= run_date);
+    let period = format!("{} to {}", run_date, period_end.pred_opt().unwrap_or(period_end));
+    let mut items = Vec::new();
+    match (&profile.plan_name, profile.plan_price) {
+        _ if ending => {}
+        (Some(plan_name), Some(price)) if price > 0.0 => items.push(CreateInvoiceItemRequest {
+            product_id: profile.plan_product_id,
+            description: format!("{} plan, {}", plan_name, period),
+            quantity: profile.quantity.into(),
+            price: Some(price),
+            amount: None,
+        }),
+        (None, _) if profile.total > 0.0 => items.push(CreateInvoiceItemRequest {
+            product_id: None,
+            description: format!("Recurring charge, {}", period),
+            quantity: 1.0,
+            price: Some(profile.total),
+            amount: None,
+        }),
+        _ => {}
+    }
+
+    let (usage, usage_event_ids) = usage_items(&mut *conn, user_id, profile, run_date).await?;
+    items.extend(usage);
+    Ok(Plan { run_date, period_end: (!ending).then_some(period_end), items, usage_event_ids })
+}
+
+/// With `bill_usage`, one line per metered product for the client's usage
+/// before `until` not yet invoiced, and the events it bills.
+async fn usage_items(
+    conn: &mut sqlx::PgConnection,
+    user_id: i32,
+    profile: &Profile,
+    until: NaiveDate,
+) -> Result<(Vec<CreateInvoiceItemRequest>, Vec<i64>), ApiError> {
+    let mut items = Vec::new();
+    let mut usage_event_ids = Vec::new();
+    if let (true, Some(client_id)) = (profile.bill_usage, profile.client_id) {
+        // Invoice lines hold quantities in hundredths
+        let usage = sqlx::query_as::<_, UsageLine>(
+            "SELECT e.product_id, p.name, ROUND(SUM(e.quantity), 2)::float8 AS quantity, MIN(e.occurred_at)::date AS first, array_agg(e.id) AS event_ids \
+             FROM usage_events e JOIN products p ON p.id = e.product_id \
+             WHERE e.user_id = $1 AND e.client_id = $2 AND e.invoice_id IS NULL AND e.occurred_at < $3::date \
+             GROUP BY e.product_id, p.name ORDER BY lower(p.name), e.product_id"
+        )
+        .bind(user_id)
+        .bind(client_id)
+        .bind(until)
+        .fetch_all(&mut *conn)
+        .await?;
+        for line in usage {
+            // Usage that rounds to nothing waits for the next run
+            if line.quantity <= 0.0 {
+                continue;
+            }
+            let last = until.pred_opt().unwrap_or(until);
+            items.push(CreateInvoiceItemRequest {
+                product_id: Some(line.product_id),
+                description: format!("{} usage, {} to {}", line.name, line.first.min(last), last),
+                quantity: line.quantity,
+                price: None,
+                amount: None,
+            });
+            usage_event_ids.extend(line.event_ids);
+        }
+    }
+    Ok((items, usage_event_ids))
+}
+
+#[derive(Serialize)]
+pub(crate) struct RunPreview {
+    recurring_invoice_id: i32,
+    run_date: NaiveDate,
+    period_end: Option<NaiveDate>,
+    currency: String,
+    items: Vec<PricedLine>,
+    /// Before any client discount.
+    subtotal: f64,
+}
+
+/// The lines the profile's next run would bill if it ran now.
+pub(crate) async fn preview_recurring(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>) -> Result<Json<RunPreview>, ApiError> {
+    let mut conn = state.db.acquire().await?;
+    let profile = load_profile(&mut conn, id, auth.user_id, false).await?;
+    let plan = plan(&mut conn, auth.user_id, &profile).await?;
+    let currency = pricing::default_currency(&mut conn, auth.user_id, profile.client_id).await?;
+    let items = lines::price_lines(&mut conn, auth.user_id, profile.client_id, Some(&currency), Utc::now().date_naive(), plan.items).await?;
+    let subtotal = lines::subtotal(&items);
+    Ok(Json(RunPreview { recurring_invoice_id: profile.id, run_date: plan.run_date, period_end: plan.period_end, currency, items, subtotal }))
+}
+
+#[derive(Serialize)]
+pub(crate) struct RunReport {
+    recurring_invoice_id: i32,
+    run_date: NaiveDate,
+    /// `None` once a subscription has ended.
+    next_run: Option<NaiveDate>,
+    /// `None` when there was nothing to bill.
+    invoice: Option<InvoiceWithItems>,
+    /// Why the run failed, in `POST /api/recurring/run`; the profile stays due.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Runs the profile's due run.
+pub(crate) async fn run_recurring(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>) -> Result<Json<RunReport>, ApiError> {
+    let mut tx = state.db.begin().await?;
+    let profile = load_profile(&mut tx, id, auth.user_id, true).await?;
+    if profile.status != "active" {
+        return Err(ApiError::Conflict(format!("Recurring invoice is {} and cannot run", profile.status)));
+    }
+    if profile.run_date() > Utc::now().date_naive() {
+        return Err(ApiError::Conflict(format!("Recurring invoice is not due until {}", profile.run_date())));
+    }
+    let report = run(&mut tx, auth.user_id, &profile).await?;
+    tx.commit().await?;
+    if report.invoice.is_some() {
+        INVOICES_CREATED.inc(&[]);
+    }
+    Ok(Json(report))
+}
+
+/// Runs every active profile that is due, each as many times as it has
+/// fallen behind, and each run in its own transaction. A failed run is
+/// reported and its profile skipped; the other profiles still run.
+pub(crate) async fn run_due_recurring(auth: AuthContext, State(state): State<Arc<AppState>>) -> Result<Json<Vec<RunReport>>, ApiError> {
+    let today = Utc::now().date_naive();
+    let due: Vec<i32> = sqlx::query_scalar(
+        "SELECT id FROM recurring_invoices WHERE user_id = $1 AND status = 'active' AND COALESCE(next_run, billing_anchor, start_date) <= $2 ORDER BY id"
+    )
+    .bind(auth.user_id)
+    .bind(today)
+    .fetch_all(&state.db)
+    .await?;
+
+    let mut reports = Vec::new();
+    for id in due {
+        for _ in 0..MAX_CATCH_UP_RUNS {
+            let mut tx = state.db.begin().await?;
+            // Rechecked under the lock, in case another run got there first
+            let profile = load_profile(&mut tx, id, auth.user_id, true).await?;
+            if profile.status != "active" || profile.run_date() > today {
+                break;
+            }
+            let report = match run(&mut tx, auth.user_id, &profile).await {
+                Ok(report) => report,
+                Err(e) => {
+                    tracing::warn!("Recurring invoice {} failed to run for {}: {}", id, profile.run_date(), e);
+                    reports.push(RunReport {
+                        recurring_invoice_id: id,
+                        run_date: profile.run_date(),
+                        next_run: profile.next_run,
+                        invoice: None,
+                        error: Some(e.detail()),
+                    });
+                    break;
+                }
+            };
+            tx.commit().await?;
+            if report.invoice.is_some() {
+                INVOICES_CREATED.inc(&[]);
+            }
+            reports.push(report);
+        }
+    }
+    tracing::info!("Ran {} recurring invoice run(s) for user {}", reports.len(), auth.user_id);
+    Ok(Json(reports))
+}
+
+async fn run(tx: &mut sqlx::PgConnection, user_id: i32, profile: &Profile) -> Result<RunReport, ApiError> {
+    let plan = plan(&mut *tx, user_id, profile).await?;
+
+    let invoice = if plan.items.is_empty() {
+        None
+    } else {
+        Some(draft_invoice(&mut *tx, user_id, profile, plan.run_date, "", plan.items).await?)
+    };
+
+    link_usage(&mut *tx, invoice.as_ref(), &plan.usage_event_ids).await?;
+
+    sqlx::query(
+        "UPDATE recurring_invoices SET last_run = $1, next_run = $2, \
+           status = CASE WHEN $2 IS NULL THEN 'canceled' ELSE status END, canceled_at = CASE WHEN $2 IS NULL THEN cancel_at ELSE canceled_at END \
+         WHERE id = $3"
+    )
+    .bind(plan.run_date)
+    .bind(plan.period_end)
+    .bind(profile.id)
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(RunReport { recurring_invoice_id: profile.id, run_date: plan.run_date, next_run: plan.period_end, invoice, error: None })
+}
+
+/// Bills the client's usage up to and including `date` on a draft of its
+/// own, for a subscription that ends before its next run. The profile is
+/// locked by the caller.
+pub(crate) async fn bill_final_usage(tx: &mut sqlx::PgConnection, user_id: i32, profile: &Profile, date: NaiveDate) -> Result<Option<InvoiceWithItems>, ApiError> {
+    let until = date.succ_opt().unwrap_or(date);
+    let (items, usage_event_ids) = usage_items(&mut *tx, user_id, profile, until).await?;
+    if items.is_empty() {
+        return Ok(None);
+    }
+    let suffix = format!("-{}", next_document_seq(&mut *tx, profile.id).await?);
+    let invoice = draft_invoice(&mut *tx, user_id, profile, date, &suffix, items).await?;
+    link_usage(&mut *tx, Some(&invoice), &usage_event_ids).await?;
+    Ok(Some(invoice))
+}
+
+/// The next number in the subscription's own sequence of prorated and final
+/// documents, so two on the same day don't share a number. The profile is
+/// locked by the caller.
+pub(crate) async fn next_document_seq(tx: &mut sqlx::PgConnection, profile_id: i32) -> Result<i32, ApiError> {
+    let seq = sqlx::query_scalar("UPDATE recurring_invoices SET document_seq = document_seq + 1 WHERE id = $1 RETURNING document_seq")
+        .bind(profile_id)
+        .fetch_one(&mut *tx)
+        .await?;
+    Ok(seq)
+}
+
+/// Marks the usage events as billed on `invoice`.
+async fn link_usage(tx: &mut sqlx::PgConnection, invoice: Option<&InvoiceWithItems>, usage_event_ids: &[i64]) -> Result<(), ApiError> {
+    // Usage billed by another profile of the same client in the meantime
+    // would otherwise be billed twice
+    let billed = sqlx::query("UPDATE usage_events SET invoice_id = $1 WHERE id = ANY($2) AND invoice_id IS NULL")
+        .bind(invoice.map(|i| i.invoice.id))
+        .bind(usage_event_ids)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    if billed as usize != usage_event_ids.len() {
+        return Err(ApiError::Conflict("Usage was billed by another run in the meantime; try again".to_string()));
+    }
+    Ok(())
+}
+
+/// Creates a draft invoice for the profile's client, numbered from the
+/// company's `invoice_prefix`, `date`, the profile and `suffix`.
+pub(crate) async fn draft_invoice(
+    tx: &mut sqlx::PgConnection,
+    user_id: i32,
+    profile: &Profile,
+    date: NaiveDate,
+    suffix: &str,
+    items: Vec<CreateInvoiceItemRequest>,
+) -> Result<InvoiceWithItems, ApiError> {
+    let prefix: String = sqlx::query_scalar("SELECT COALESCE((SELECT invoice_prefix FROM companies WHERE user_id = $1), 'INV')")
+        .bind(user_id)
+        .fetch_one(&mut *tx)
+        .await?;
+    let payload = CreateInvoiceRequest {
+        client_id: profile.client_id,
+        invoice_number: format!("{}-{}-{}{}", prefix, date.format("%Y%m%d"), profile.id, suffix),
+        status: "draft".to_string(),
+        total: None,
+        due_date: None,
+        notes: None,
+        items,
+        currency: None,
+        language: None,
+        tax_treatment: None,
+        tax_exemption_reason: None,
+        discount_percent: None,
+        print_bundle_components: false,
+    };
+    insert_invoice(&mut *tx, user_id, payload, Some(profile.id)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(value: &str) -> NaiveDate {
+        value.parse().unwrap()
+    }
+
+    fn profile(interval: &str, interval_count: i32, start_date: NaiveDate) -> Profile {
+        Profile {
+            id: 1,
+            client_id: None,
+            interval: interval.to_string(),
+            interval_count,
+            start_date,
+            billing_anchor: None,
+            next_run: None,
+            last_run: None,
+            status: "active".to_string(),
+            total: 0.0,
+            bill_usage: false,
+            quantity: 1,
+            trial_end: None,
+            cancel_at: None,
+            plan_id: None,
+            plan_name: None,
+            plan_price: None,
+            plan_product_id: None,
+        }
+    }
+
+    #[test]
+    fn monthly_dates_stay_anchored_on_the_start_date() {
+        let p = profile("month", 1, date("2026-01-31"));
+        assert_eq!(p.next_date_after(date("2026-01-31")), Some(date("2026-02-28")));
+        assert_eq!(p.next_date_after(date("2026-02-28")), Some(date("2026-03-31")));
+        assert_eq!(p.next_date_after(date("2026-04-15")), Some(date("2026-04-30")));
+        let quarterly = profile("month", 3, date("2026-01-15"));
+        assert_eq!(quarterly.next_date_after(date("2026-01-15")), Some(date("2026-04-15")));
+        assert_eq!(quarterly.next_date_after(date("2026-05-01")), Some(date("2026-07-15")));
+    }
+
+    #[test]
+    fn counts_from_the_billing_anchor_when_set() {
+        let mut p = profile("month", 1, date("2026-10-19"));
+        p.billing_anchor = Some(date("2026-11-02"));
+        assert_eq!(p.run_date(), date("2026-11-02"));
+        assert_eq!(p.next_date_after(date("2026-11-02")), Some(date("2026-12-02")));
+    }
+
+    #[test]
+    fn steps_by_days_weeks_and_years() {
+        assert_eq!(profile("day", 10, date("2026-10-01")).next_date_after(date("2026-10-19")), Some(date("2026-10-21")));
+        assert_eq!(profile("week", 2, date("2026-10-05")).next_date_after(date("2026-10-19")), Some(date("2026-11-02")));
+        let leap = profile("year", 1, date("2024-02-29"));
+        assert_eq!(leap.next_date_after(date("2024-02-29")), Some(date("2025-02-28")));
+        assert_eq!(leap.next_date_after(date("2027-03-01")), Some(date("2028-02-29")));
+    }
+
+    #[test]
+    fn runs_out_at_the_end_of_the_calendar() {
+        let start = NaiveDate::MAX - Days::new(20);
+        assert_eq!(profile("month", 1, start).next_date_after(start), None);
+        assert_eq!(profile("year", crate::MAX_INTERVAL_COUNT, start).next_date_after(start), None);
+        assert_eq!(profile("day", i32::MAX, date("2026-01-01")).next_date_after(date("2026-01-01")), None);
+    }
+}
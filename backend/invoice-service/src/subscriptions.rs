@@ -0,0 +1,677 @@
+//! Subscriptions: recurring profiles (see `recurring`) on a plan, billing the
+//! plan's price for each of the subscription's seats (`quantity`) every
+//! period.
+//!
+//! A subscription may start with a free trial, its first run falling on
+//! `trial_end`. Changing its plan or seats partway through a billed period is
+//! prorated by the days left in the period: the difference is invoiced at once
+//! as a draft, or credited to the client with a credit note. Pausing or
+//! cancelling immediately credits the unused time the same way, and an
+//! immediate cancellation bills the usage so far on a final draft, while
+//! cancelling at period end lets the period run out, the last run billing
+//! only usage. Resuming starts a new period on the day.
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Days, NaiveDate, Utc};
+use common::metrics::Counter;
+use common::{ApiError, AuthContext, FieldError, FieldKind, Filter, FilterOp, ListQuery, ListSpec, Page, SortKey, Valid, Validate, Validator};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::sync::Arc;
+
+use crate::payments::{self, CreditNote};
+use crate::recurring::{self, Profile};
+use crate::{credit, AppState, CreateInvoiceItemRequest, InvoiceWithItems, INVOICES_CREATED, MAX_INTERVAL_COUNT, RECURRING_INTERVALS};
+
+const MAX_TRIAL_DAYS: i32 = 365;
+
+static SUBSCRIPTIONS_CREATED: Counter = Counter::new("subscriptions_created_total", "Subscriptions created", &[]);
+
+#[derive(Debug, FromRow, Serialize)]
+pub(crate) struct Plan {
+    id: i32,
+    name: String,
+    description: Option<String>,
+    /// Per seat and period.
+    price: f64,
+    interval: String,
+    interval_count: i32,
+    /// Trial given to new subscriptions unless they set their own.
+    trial_days: i32,
+    /// The catalog product plan lines are billed as, e.g. for its tax category.
+    product_id: Option<i32>,
+    /// Subscriptions on the plan that are not canceled.
+    subscriptions: i64,
+    created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PlanRequest {
+    name: String,
+    description: Option<String>,
+    price: f64,
+    interval: String,
+    /// Defaults to 1.
+    interval_count: Option<i32>,
+    #[serde(default)]
+    trial_days: i32,
+    product_id: Option<i32>,
+}
+
+impl Validate for PlanRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("name", &self.name);
+        v.max_len("name", self.name.as_str(), 255);
+        v.min("price", self.price, 0.0);
+        v.one_of("interval", self.interval.as_str(), RECURRING_INTERVALS);
+        v.range("interval_count", self.interval_count, 1, MAX_INTERVAL_COUNT);
+        v.range("trial_days", self.trial_days, 0, MAX_TRIAL_DAYS);
+    }
+}
+
+pub(crate) const PLAN_LIST: ListSpec = ListSpec {
+    columns: "p.id, p.name, p.description, p.price::float8 AS price, p.interval, p.interval_count, p.trial_days, p.product_id, \
+              (SELECT COUNT(*) FROM recurring_invoices r WHERE r.plan_id = p.id AND r.status <> 'canceled') AS subscriptions, p.created_at",
+    from: "FROM plans p",
+    owner_column: "p.user_id",
+    id_column: "p.id",
+    sort_keys: &[
+        SortKey::new("name", "lower(p.name)", FieldKind::Text),
+        SortKey::new("price", "p.price", FieldKind::Number),
+        SortKey::new("created_at", "COALESCE(p.created_at, 'epoch'::timestamptz)", FieldKind::Timestamp),
+    ],
+    default_sort: "name",
+    filters: &[
+        Filter::new("name", "p.name", FieldKind::Text, FilterOp::Contains),
+        Filter::new("interval", "p.interval", FieldKind::Text, FilterOp::In),
+        Filter::new("product_id", "p.product_id", FieldKind::Int, FilterOp::In),
+        Filter::new("min_price", "p.price", FieldKind::Number, FilterOp::Min),
+        Filter::new("max_price", "p.price", FieldKind::Number, FilterOp::Max),
+    ],
+    default_filters: &[],
+};
+
+/// A subscription's status is its profile's, or `trialing` while an active
+/// subscription's trial runs.
+#[derive(Debug, FromRow, Serialize)]
+pub(crate) struct Subscription {
+    id: i32,
+    client_id: Option<i32>,
+    client_name: Option<String>,
+    plan_id: i32,
+    plan_name: String,
+    /// The plan's price per seat.
+    price: f64,
+    quantity: i32,
+    /// What each period bills, before usage: `price` × `quantity`.
+    total: f64,
+    interval: String,
+    interval_count: i32,
+    status: String,
+    start_date: NaiveDate,
+    trial_end: Option<NaiveDate>,
+    /// The start of the period billed last.
+    last_run: Option<NaiveDate>,
+    /// When the next period is billed, and the current one ends.
+    next_run: Option<NaiveDate>,
+    /// When a cancellation at period end takes effect.
+    cancel_at: Option<NaiveDate>,
+    canceled_at: Option<NaiveDate>,
+    bill_usage: bool,
+    created_at: Option<DateTime<Utc>>,
+    /// Credit-limit warnings (see `credit`).
+    #[sqlx(skip)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<String>,
+}
+
+const SUBSCRIPTION_LIST: ListSpec = ListSpec {
+    columns: "r.id, r.client_id, c.name AS client_name, r.plan_id, p.name AS plan_name, p.price::float8 AS price, r.quantity, r.total::float8 AS total, \
+              r.interval, r.interval_count, CASE WHEN r.status = 'active' AND r.trial_end > CURRENT_DATE THEN 'trialing' ELSE r.status END AS status, \
+              r.start_date, r.trial_end, r.last_run, r.next_run, r.cancel_at, r.canceled_at, r.bill_usage, r.created_at",
+    from: "FROM recurring_invoices r JOIN plans p ON p.id = r.plan_id LEFT JOIN clients c ON c.id = r.client_id",
+    owner_column: "r.user_id",
+    id_column: "r.id",
+    sort_keys: &[
+        SortKey::new("created_at", "COALESCE(r.created_at, 'epoch'::timestamptz)", FieldKind::Timestamp),
+        SortKey::new("next_run", "COALESCE(r.next_run, DATE '9999-12-31')", FieldKind::Date),
+        SortKey::new("total", "r.total", FieldKind::Number),
+    ],
+    default_sort: "-created_at",
+    filters: &[
+        Filter::new("status", "CASE WHEN r.status = 'active' AND r.trial_end > CURRENT_DATE THEN 'trialing' ELSE r.status END", FieldKind::Text, FilterOp::In),
+        Filter::new("client_id", "r.client_id", FieldKind::Int, FilterOp::In),
+        Filter::new("plan_id", "r.plan_id", FieldKind::Int, FilterOp::In),
+        Filter::new("next_run_from", "r.next_run", FieldKind::Date, FilterOp::Min),
+        Filter::new("next_run_to", "r.next_run", FieldKind::Date, FilterOp::Max),
+    ],
+    default_filters: &[],
+};
+
+#[derive(Deserialize)]
+pub(crate) struct CreateSubscriptionRequest {
+    client_id: i32,
+    plan_id: i32,
+    /// Seats; defaults to 1.
+    quantity: Option<i32>,
+    /// Defaults to today.
+    start_date: Option<NaiveDate>,
+    /// Defaults to the plan's `trial_days`.
+    trial_days: Option<i32>,
+    #[serde(default)]
+    bill_usage: bool,
+}
+
+impl Validate for CreateSubscriptionRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.min("quantity", self.quantity, 1);
+        v.range("trial_days", self.trial_days, 0, MAX_TRIAL_DAYS);
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ChangeSubscriptionRequest {
+    plan_id: Option<i32>,
+    quantity: Option<i32>,
+    /// Whether to invoice or credit the rest of the current period; defaults to true.
+    prorate: Option<bool>,
+}
+
+impl Validate for ChangeSubscriptionRequest {
+    fn validate(&self, v: &mut Validator) {
+        if self.plan_id.is_none() && self.quantity.is_none() {
+            v.error("plan_id", "plan_id or quantity is required");
+        }
+        v.min("quantity", self.quantity, 1);
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PauseSubscriptionRequest {
+    /// Whether to credit the rest of the current period; defaults to true.
+    prorate: Option<bool>,
+}
+
+impl Validate for PauseSubscriptionRequest {
+    fn validate(&self, _v: &mut Validator) {}
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CancelSubscriptionRequest {
+    /// Cancels once the current period has run out instead of today.
+    #[serde(default)]
+    at_period_end: bool,
+    /// Whether an immediate cancellation credits the rest of the current
+    /// period; defaults to true.
+    prorate: Option<bool>,
+}
+
+impl Validate for CancelSubscriptionRequest {
+    fn validate(&self, v: &mut Validator) {
+        if self.at_period_end && self.prorate == Some(true) {
+            v.error("prorate", "does not apply to a cancellation at period end");
+        }
+    }
+}
+
+/// A subscription after a change, with the draft invoice or credit note
+/// that prorated it, if any.
+#[derive(Serialize)]
+pub(crate) struct SubscriptionChange {
+    subscription: Subscription,
+    invoice: Option<InvoiceWithItems>,
+    credit_note: Option<CreditNote>,
+}
+
+// Plans
+pub(crate) async fn list_plans(auth: AuthContext, State(state): State<Arc<AppState>>, query: ListQuery) -> Result<Json<Page<Plan>>, ApiError> {
+    let page = query.fetch(&state.db, &PLAN_LIST, auth.user_id).await?;
+    Ok(Json(page))
+}
+
+pub(crate) async fn create_plan(auth: AuthContext, State(state): State<Arc<AppState>>, Valid(payload): Valid<PlanRequest>) -> Result<Json<Plan>, ApiError> {
+    let mut tx = state.db.begin().await?;
+    check_product(&mut tx, auth.user_id, payload.product_id).await?;
+    let id: i32 = sqlx::query_scalar(
+        "INSERT INTO plans (user_id, name, description, price, interval, interval_count, trial_days, product_id) \
+         VALUES ($1, $2, $3, $4, $5, COALESCE($6, 1), $7, $8) RETURNING id"
+    )
+    .bind(auth.user_id)
+    .bind(&payload.name)
+    .bind(&payload.description)
+    .bind(payload.price)
+    .bind(&payload.interval)
+    .bind(payload.interval_count)
+    .bind(payload.trial_days)
+    .bind(payload.product_id)
+    .fetch_one(&mut *tx)
+    .await?;
+    let plan = fetch_plan(&mut tx, id, auth.user_id).await?;
+    tx.commit().await?;
+    Ok(Json(plan))
+}
+
+pub(crate) async fn get_plan(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>) -> Result<Json<Plan>, ApiError> {
+    let mut conn = state.db.acquire().await?;
+    let plan = fetch_plan(&mut conn, id, auth.user_id).await?;
+    Ok(Json(plan))
+}
+
+/// A new price applies to subscriptions from their next run; the billing
+/// interval can't change while the plan has subscriptions.
+pub(crate) async fn update_plan(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>, Valid(payload): Valid<PlanRequest>) -> Result<Json<Plan>, ApiError> {
+    let mut tx = state.db.begin().await?;
+    let current = sqlx::query_as::<_, (String, i32)>("SELECT interval, interval_count FROM plans WHERE id = $1 AND user_id = $2 FOR UPDATE")
+        .bind(id)
+        .bind(auth.user_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(ApiError::NotFound("Plan not found".to_string()))?;
+    if current != (payload.interval.clone(), payload.interval_count.unwrap_or(1)) {
+        let in_use: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM recurring_invoices WHERE plan_id = $1 AND status <> 'canceled'")
+            .bind(id)
+            .fetch_one(&mut *tx)
+            .await?;
+        if in_use > 0 {
+            return Err(ApiError::Conflict(format!("Plan has {} subscription(s); its billing interval cannot change", in_use)));
+        }
+    }
+    check_product(&mut tx, auth.user_id, payload.product_id).await?;
+
+    sqlx::query(
+        "UPDATE plans SET name = $1, description = $2, price = $3, interval = $4, interval_count = COALESCE($5, 1), trial_days = $6, product_id = $7 WHERE id = $8"
+    )
+    .bind(&payload.name)
+    .bind(&payload.description)
+    .bind(payload.price)
+    .bind(&payload.interval)
+    .bind(payload.interval_count)
+    .bind(payload.trial_days)
+    .bind(payload.product_id)
+    .bind(id)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("UPDATE recurring_invoices SET total = $1 * quantity WHERE plan_id = $2 AND status <> 'canceled'")
+        .bind(payload.price)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+
+    let plan = fetch_plan(&mut tx, id, auth.user_id).await?;
+    tx.commit().await?;
+    Ok(Json(plan))
+}
+
+pub(crate) async fn delete_plan(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>) -> Result<StatusCode, ApiError> {
+    let mut tx = state.db.begin().await?;
+    let used: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM recurring_invoices WHERE plan_id = $1 AND user_id = $2")
+        .bind(id)
+        .bind(auth.user_id)
+        .fetch_one(&mut *tx)
+        .await?;
+    if used > 0 {
+        return Err(ApiError::Conflict(format!("Plan is used by {} subscription(s) and cannot be deleted", used)));
+    }
+    sqlx::query("DELETE FROM plans WHERE id = $1 AND user_id = $2").bind(id).bind(auth.user_id).execute(&mut *tx).await?;
+    tx.commit().await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn fetch_plan(conn: &mut sqlx::PgConnection, id: i32, user_id: i32) -> Result<Plan, ApiError> {
+    sqlx::query_as::<_, Plan>(&format!("SELECT {} {} WHERE p.id = $1 AND p.user_id = $2", PLAN_LIST.columns, PLAN_LIST.from))
+        .bind(id)
+        .bind(user_id)
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or(ApiError::NotFound("Plan not found".to_string()))
+}
+
+async fn check_product(conn: &mut sqlx::PgConnection, user_id: i32, product_id: Option<i32>) -> Result<(), ApiError> {
+    let Some(product_id) = product_id else { return Ok(()) };
+    let exists: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM products WHERE id = $1 AND user_id = $2)")
+        .bind(product_id)
+        .bind(user_id)
+        .fetch_one(&mut *conn)
+        .await?;
+    if !exists {
+        return Err(ApiError::Validation(vec![FieldError { field: "product_id".to_string(), message: "does not exist".to_string() }]));
+    }
+    Ok(())
+}
+
+// Subscriptions
+pub(crate) async fn list_subscriptions(auth: AuthContext, State(state): State<Arc<AppState>>, query: ListQuery) -> Result<Json<Page<Subscription>>, ApiError> {
+    let page = query.fetch(&state.db, &SUBSCRIPTION_LIST, auth.user_id).await?;
+    Ok(Json(page))
+}
+
+pub(crate) async fn get_subscription(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>) -> Result<Json<Subscription>, ApiError> {
+    let mut conn = state.db.acquire().await?;
+    let subscription = fetch_subscription(&mut conn, id, auth.user_id).await?;
+    Ok(Json(subscription))
+}
+
+/// Starts a subscription, billed from `start_date` or, with a trial, from
+/// the day the trial ends.
+pub(crate) async fn create_subscription(
+    auth: AuthContext,
+    State(state): State<Arc<AppState>>,
+    Valid(payload): Valid<CreateSubscriptionRequest>,
+) -> Result<Json<Subscription>, ApiError> {
+    let mut tx = state.db.begin().await?;
+    let client_exists: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM clients WHERE id = $1 AND user_id = $2)")
+        .bind(payload.client_id)
+        .bind(auth.user_id)
+        .fetch_one(&mut *tx)
+        .await?;
+    if !client_exists {
+        return Err(ApiError::Validation(vec![FieldError { field: "client_id".to_string(), message: "does not exist".to_string() }]));
+    }
+    let plan = fetch_plan(&mut tx, payload.plan_id, auth.user_id)
+        .await
+        .map_err(|e| does_not_exist("plan_id", e))?;
+
+    let quantity = payload.quantity.unwrap_or(1);
+    let total = plan.price * f64::from(quantity);
+    let start_date = payload.start_date.unwrap_or_else(|| Utc::now().date_naive());
+    let trial_days = payload.trial_days.unwrap_or(plan.trial_days);
+    let trial_end = (trial_days > 0).then(|| start_date + Days::new(trial_days as u64));
+    let first_run = trial_end.unwrap_or(start_date);
+
+    let id: i32 = sqlx::query_scalar(
+        "INSERT INTO recurring_invoices (user_id, client_id, interval, interval_count, start_date, billing_anchor, next_run, status, total, bill_usage, plan_id, quantity, trial_end) \
+         VALUES ($1, $2, $3, $4, $5, $6, $6, 'active', $7, $8, $9, $10, $11) RETURNING id"
+    )
+    .bind(auth.user_id)
+    .bind(payload.client_id)
+    .bind(&plan.interval)
+    .bind(plan.interval_count)
+    .bind(start_date)
+    .bind(first_run)
+    .bind(total)
+    .bind(payload.bill_usage)
+    .bind(plan.id)
+    .bind(quantity)
+    .bind(trial_end)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    // Each run issues an invoice for the total, as for any recurring profile
+    let warnings = credit::check(&mut tx, auth.user_id, Some(payload.client_id), total, None).await?;
+    let mut subscription = fetch_subscription(&mut tx, id, auth.user_id).await?;
+    tx.commit().await?;
+
+    SUBSCRIPTIONS_CREATED.inc(&[]);
+    subscription.warnings = warnings.into_iter().collect();
+    Ok(Json(subscription))
+}
+
+/// Moves a subscription to another plan with the same billing interval, or
+/// to another number of seats. The new price is billed from the next run;
+/// with `prorate`, the difference for the rest of the current period is
+/// invoiced or credited now.
+pub(crate) async fn change_subscription(
+    auth: AuthContext,
+    Path(id): Path<i32>,
+    State(state): State<Arc<AppState>>,
+    Valid(payload): Valid<ChangeSubscriptionRequest>,
+) -> Result<Json<SubscriptionChange>, ApiError> {
+    let mut tx = state.db.begin().await?;
+    let profile = load_subscription(&mut tx, id, auth.user_id).await?;
+    if profile.status == "canceled" {
+        return Err(ApiError::Conflict("Subscription is canceled".to_string()));
+    }
+    let old_plan = profile.plan_name.clone().unwrap_or_default();
+    let old_price = profile.plan_price.unwrap_or(0.0);
+
+    let (plan_id, plan_name, price) = match payload.plan_id {
+        Some(plan_id) if Some(plan_id) != profile.plan_id => {
+            let plan = fetch_plan(&mut tx, plan_id, auth.user_id)
+                .await
+                .map_err(|e| does_not_exist("plan_id", e))?;
+            if (plan.interval.as_str(), plan.interval_count) != (profile.interval.as_str(), profile.interval_count) {
+                return Err(ApiError::Validation(vec![FieldError {
+                    field: "plan_id".to_string(),
+                    message: format!("must bill every {} {}(s), like the current plan", profile.interval_count, profile.interval),
+                }]));
+            }
+            (plan.id, plan.name, plan.price)
+        }
+        _ => (profile.plan_id.unwrap_or_default(), old_plan.clone(), old_price),
+    };
+    let quantity = payload.quantity.unwrap_or(profile.quantity);
+    let old_total = old_price * f64::from(profile.quantity);
+    let total = price * f64::from(quantity);
+
+    sqlx::query("UPDATE recurring_invoices SET plan_id = $1, quantity = $2, total = $3 WHERE id = $4")
+        .bind(plan_id)
+        .bind(quantity)
+        .bind(total)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+
+    let description = format!("Change from {} plan × {} to {} plan × {}", old_plan, profile.quantity, plan_name, quantity);
+    let (invoice, credit_note) = match payload.prorate.unwrap_or(true) {
+        true => settle(&mut tx, auth.user_id, &profile, total - old_total, &description).await?,
+        false => (None, None),
+    };
+    let subscription = fetch_subscription(&mut tx, id, auth.user_id).await?;
+    tx.commit().await?;
+
+    if invoice.is_some() {
+        INVOICES_CREATED.inc(&[]);
+    }
+    Ok(Json(SubscriptionChange { subscription, invoice, credit_note }))
+}
+
+/// Pauses an active subscription. With `prorate` the rest of the current
+/// period is credited and the period ends today.
+pub(crate) async fn pause_subscription(
+    auth: AuthContext,
+    Path(id): Path<i32>,
+    State(state): State<Arc<AppState>>,
+    Valid(payload): Valid<PauseSubscriptionRequest>,
+) -> Result<Json<SubscriptionChange>, ApiError> {
+    let mut tx = state.db.begin().await?;
+    let profile = load_subscription(&mut tx, id, auth.user_id).await?;
+    if profile.status != "active" {
+        return Err(ApiError::Conflict(format!("Subscription is {} and cannot be paused", profile.status)));
+    }
+
+    let (invoice, credit_note) = match payload.prorate.unwrap_or(true) {
+        true => settle(&mut tx, auth.user_id, &profile, -current_total(&profile), "Unused time after pausing").await?,
+        false => (None, None),
+    };
+    let today = Utc::now().date_naive();
+    let period_end = if credit_note.is_some() { profile.next_run.map(|next_run| next_run.min(today)) } else { profile.next_run };
+    sqlx::query("UPDATE recurring_invoices SET status = 'paused', next_run = $1 WHERE id = $2")
+        .bind(period_end)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    let subscription = fetch_subscription(&mut tx, id, auth.user_id).await?;
+    tx.commit().await?;
+
+    Ok(Json(SubscriptionChange { subscription, invoice, credit_note }))
+}
+
+/// Resumes a paused subscription, its next period starting today or, if a
+/// period paid for or a trial is still running, when that ends. On an
+/// active subscription, takes back a cancellation at period end.
+pub(crate) async fn resume_subscription(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>) -> Result<Json<Subscription>, ApiError> {
+    let mut tx = state.db.begin().await?;
+    let profile = load_subscription(&mut tx, id, auth.user_id).await?;
+    match profile.status.as_str() {
+        "paused" => {
+            let today = Utc::now().date_naive();
+            let first_run = [Some(today), profile.next_run, profile.trial_end].into_iter().flatten().max().unwrap_or(today);
+            sqlx::query("UPDATE recurring_invoices SET status = 'active', billing_anchor = $1, next_run = $1 WHERE id = $2")
+                .bind(first_run)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        "active" if profile.cancel_at.is_some() => {
+            sqlx::query("UPDATE recurring_invoices SET cancel_at = NULL WHERE id = $1").bind(id).execute(&mut *tx).await?;
+        }
+        status => return Err(ApiError::Conflict(format!("Subscription is {} and cannot be resumed", status))),
+    }
+    let subscription = fetch_subscription(&mut tx, id, auth.user_id).await?;
+    tx.commit().await?;
+    Ok(Json(subscription))
+}
+
+/// Cancels a subscription today, crediting the rest of the current period
+/// with `prorate` and billing the usage so far on a final draft, or at the
+/// end of the current period, when its last run bills the remaining usage.
+pub(crate) async fn cancel_subscription(
+    auth: AuthContext,
+    Path(id): Path<i32>,
+    State(state): State<Arc<AppState>>,
+    Valid(payload): Valid<CancelSubscriptionRequest>,
+) -> Result<Json<SubscriptionChange>, ApiError> {
+    let mut tx = state.db.begin().await?;
+    let profile = load_subscription(&mut tx, id, auth.user_id).await?;
+    if profile.status == "canceled" {
+        return Err(ApiError::Conflict("Subscription is already canceled".to_string()));
+    }
+    let today = Utc::now().date_naive();
+
+    let (invoice, credit_note) = if payload.at_period_end {
+        let Some(period_end) = profile.next_run.filter(|_| profile.status == "active") else {
+            return Err(ApiError::Conflict(format!("Subscription is {}; cancel it immediately instead", profile.status)));
+        };
+        sqlx::query("UPDATE recurring_invoices SET cancel_at = $1 WHERE id = $2").bind(period_end).bind(id).execute(&mut *tx).await?;
+        (None, None)
+    } else {
+        // Cancelling only ever credits, so the invoice slot is free for the usage
+        let credit_note = match payload.prorate.unwrap_or(true) {
+            true => settle(&mut tx, auth.user_id, &profile, -current_total(&profile), "Unused time after cancelling").await?.1,
+            false => None,
+        };
+        // No run comes after this to bill the usage so far
+        let invoice = recurring::bill_final_usage(&mut tx, auth.user_id, &profile, today).await?;
+        sqlx::query("UPDATE recurring_invoices SET status = 'canceled', cancel_at = $1, canceled_at = $1, next_run = NULL WHERE id = $2")
+            .bind(today)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        (invoice, credit_note)
+    };
+    let subscription = fetch_subscription(&mut tx, id, auth.user_id).await?;
+    tx.commit().await?;
+
+    if invoice.is_some() {
+        INVOICES_CREATED.inc(&[]);
+    }
+    Ok(Json(SubscriptionChange { subscription, invoice, credit_note }))
+}
+
+async fn fetch_subscription(conn: &mut sqlx::PgConnection, id: i32, user_id: i32) -> Result<Subscription, ApiError> {
+    sqlx::query_as::<_, Subscription>(&format!("SELECT {} {} WHERE r.id = $1 AND r.user_id = $2", SUBSCRIPTION_LIST.columns, SUBSCRIPTION_LIST.from))
+        .bind(id)
+        .bind(user_id)
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or(ApiError::NotFound("Subscription not found".to_string()))
+}
+
+/// Locks the subscription's profile.
+async fn load_subscription(conn: &mut sqlx::PgConnection, id: i32, user_id: i32) -> Result<Profile, ApiError> {
+    match recurring::load_profile(&mut *conn, id, user_id, true).await {
+        Ok(profile) if profile.plan_id.is_some() => Ok(profile),
+        Ok(_) | Err(ApiError::NotFound(_)) => Err(ApiError::NotFound("Subscription not found".to_string())),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reports a missing referenced record as a validation error on `field`.
+fn does_not_exist(field: &str, error: ApiError) -> ApiError {
+    match error {
+        ApiError::NotFound(_) => ApiError::Validation(vec![FieldError { field: field.to_string(), message: "does not exist".to_string() }]),
+        e => e,
+    }
+}
+
+fn current_total(profile: &Profile) -> f64 {
+    profile.plan_price.unwrap_or(0.0) * f64::from(profile.quantity)
+}
+
+/// The share of the period from `period_start` to `period_end` (exclusive)
+/// left on `today`, counting today; `None` outside the period.
+fn unused_share(period_start: NaiveDate, period_end: NaiveDate, today: NaiveDate) -> Option<f64> {
+    if today < period_start || today >= period_end {
+        return None;
+    }
+    Some((period_end - today).num_days() as f64 / (period_end - period_start).num_days() as f64)
+}
+
+/// Invoices or credits `change` per period, prorated to the days left in the
+/// subscription's current period: a draft invoice for a charge, a credit
+/// note against the client for a credit. Nothing is due outside a billed
+/// period, e.g. during a trial or a pause.
+async fn settle(
+    tx: &mut sqlx::PgConnection,
+    user_id: i32,
+    profile: &Profile,
+    change: f64,
+    description: &str,
+) -> Result<(Option<InvoiceWithItems>, Option<CreditNote>), ApiError> {
+    let today = Utc::now().date_naive();
+    let (Some(period_start), Some(period_end)) = (profile.last_run, profile.next_run) else { return Ok((None, None)) };
+    let Some(share) = unused_share(period_start, period_end, today).filter(|_| profile.status == "active") else { return Ok((None, None)) };
+    let amount = (change * share * 100.0).round() / 100.0;
+    let description = format!("{}, {} to {}", description, today, period_end.pred_opt().unwrap_or(period_end));
+
+    if amount > 0.0 {
+        let item = CreateInvoiceItemRequest { product_id: None, description, quantity: 1.0, price: Some(amount), amount: None };
+        let suffix = format!("-{}", recurring::next_document_seq(&mut *tx, profile.id).await?);
+        let invoice = recurring::draft_invoice(&mut *tx, user_id, profile, today, &suffix, vec![item]).await?;
+        Ok((Some(invoice), None))
+    } else if amount < 0.0 {
+        let seq = recurring::next_document_seq(&mut *tx, profile.id).await?;
+        let id: i32 = sqlx::query_scalar(
+            "INSERT INTO credit_notes (user_id, client_id, credit_note_number, issue_date, amount, reason) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id"
+        )
+        .bind(user_id)
+        .bind(profile.client_id)
+        .bind(format!("CN-{}-{}-{}", today.format("%Y%m%d"), profile.id, seq))
+        .bind(today)
+        .bind(-amount)
+        .bind(description)
+        .fetch_one(&mut *tx)
+        .await?;
+        Ok((None, Some(payments::fetch_credit_note(&mut *tx, id, user_id).await?)))
+    } else {
+        Ok((None, None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(value: &str) -> NaiveDate {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn unused_share_counts_the_days_left_including_today() {
+        let (start, end) = (date("2026-10-04"), date("2026-11-04"));
+        assert_eq!(unused_share(start, end, start), Some(1.0));
+        assert_eq!(unused_share(start, end, date("2026-10-19")), Some(16.0 / 31.0));
+        assert_eq!(unused_share(start, end, date("2026-11-03")), Some(1.0 / 31.0));
+    }
+
+    #[test]
+    fn unused_share_is_none_outside_the_period() {
+        let (start, end) = (date("2026-10-04"), date("2026-11-04"));
+        assert_eq!(unused_share(start, end, date("2026-10-03")), None);
+        assert_eq!(unused_share(start, end, end), None);
+    }
+}
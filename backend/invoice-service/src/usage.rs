@@ -0,0 +1,241 @@
+//! Usage of metered products (product `kind` `metered`), recorded per client
+//! as events and invoiced by the runs of recurring profiles with `bill_usage`
+//! (see `recurring`).
+//!
+//! Every event carries an `idempotency_key`: an event whose key is already
+//! recorded is skipped, so a batch can safely be sent again after a timeout.
+
+use axum::{
+    extract::{rejection::QueryRejection, Query, State},
+    Json,
+};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use common::metrics::Counter;
+use common::{ApiError, AuthContext, FieldError, FieldKind, Filter, FilterOp, ListQuery, ListSpec, Page, SortKey, Valid, Validate, Validator};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::sync::Arc;
+
+use crate::AppState;
+
+const MAX_EVENTS: usize = 1000;
+const SUMMARY_PERIODS: &[&str] = &["day", "week", "month"];
+
+static USAGE_EVENTS_RECORDED: Counter = Counter::new("usage_events_recorded_total", "Usage events recorded", &[]);
+
+#[derive(Debug, FromRow, Serialize)]
+pub(crate) struct UsageEvent {
+    id: i64,
+    idempotency_key: String,
+    client_id: i32,
+    #[sqlx(default)]
+    client_name: Option<String>,
+    product_id: i32,
+    #[sqlx(default)]
+    product_name: Option<String>,
+    quantity: f64,
+    occurred_at: DateTime<Utc>,
+    /// The invoice the usage was billed on, once it has been.
+    invoice_id: Option<i32>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct UsageEventRequest {
+    /// Unique per account, e.g. the ID of the API request being metered.
+    idempotency_key: String,
+    client_id: i32,
+    product_id: i32,
+    quantity: f64,
+    occurred_at: DateTime<Utc>,
+}
+
+impl Validate for UsageEventRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("idempotency_key", &self.idempotency_key);
+        v.max_len("idempotency_key", self.idempotency_key.as_str(), 255);
+        v.min("quantity", self.quantity, 0.0);
+        // Allows for some clock skew on the sender's side
+        if self.occurred_at > Utc::now() + Duration::minutes(5) {
+            v.error("occurred_at", "must not be in the future");
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RecordUsageRequest {
+    events: Vec<UsageEventRequest>,
+}
+
+impl Validate for RecordUsageRequest {
+    fn validate(&self, v: &mut Validator) {
+        if self.events.is_empty() {
+            v.error("events", "must not be empty");
+        }
+        if self.events.len() > MAX_EVENTS {
+            v.error("events", format!("must have at most {} events", MAX_EVENTS));
+        }
+        v.nested("events", &self.events);
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct RecordUsageResponse {
+    recorded: usize,
+    /// Events skipped because their `idempotency_key` was already recorded.
+    duplicates: usize,
+    events: Vec<UsageEvent>,
+}
+
+pub(crate) const USAGE_LIST: ListSpec = ListSpec {
+    columns: "e.id, e.idempotency_key, e.client_id, c.name AS client_name, e.product_id, p.name AS product_name, \
+              e.quantity::float8 AS quantity, e.occurred_at, e.invoice_id, e.created_at",
+    from: "FROM usage_events e JOIN clients c ON c.id = e.client_id JOIN products p ON p.id = e.product_id",
+    owner_column: "e.user_id",
+    id_column: "e.id",
+    sort_keys: &[
+        SortKey::new("occurred_at", "e.occurred_at", FieldKind::Timestamp),
+        SortKey::new("quantity", "e.quantity", FieldKind::Number),
+    ],
+    default_sort: "-occurred_at",
+    filters: &[
+        Filter::new("client_id", "e.client_id", FieldKind::Int, FilterOp::In),
+        Filter::new("product_id", "e.product_id", FieldKind::Int, FilterOp::In),
+        Filter::new("invoice_id", "e.invoice_id", FieldKind::Int, FilterOp::In),
+        Filter::new("idempotency_key", "e.idempotency_key", FieldKind::Text, FilterOp::In),
+        Filter::new("from", "e.occurred_at::date", FieldKind::Date, FilterOp::Min),
+        Filter::new("to", "e.occurred_at::date", FieldKind::Date, FilterOp::Max),
+    ],
+    default_filters: &[],
+};
+
+pub(crate) async fn list_usage(auth: AuthContext, State(state): State<Arc<AppState>>, query: ListQuery) -> Result<Json<Page<UsageEvent>>, ApiError> {
+    let page = query.fetch(&state.db, &USAGE_LIST, auth.user_id).await?;
+    Ok(Json(page))
+}
+
+/// Records a batch of events, all or none: an event for another account's
+/// client or a product that isn't metered rejects the batch.
+pub(crate) async fn record_usage(
+    auth: AuthContext,
+    State(state): State<Arc<AppState>>,
+    Valid(payload): Valid<RecordUsageRequest>,
+) -> Result<Json<RecordUsageResponse>, ApiError> {
+    let mut tx = state.db.begin().await?;
+
+    let client_ids: Vec<i32> = payload.events.iter().map(|e| e.client_id).collect();
+    let clients: Vec<i32> = sqlx::query_scalar("SELECT id FROM clients WHERE id = ANY($1) AND user_id = $2")
+        .bind(&client_ids)
+        .bind(auth.user_id)
+        .fetch_all(&mut *tx)
+        .await?;
+    let product_ids: Vec<i32> = payload.events.iter().map(|e| e.product_id).collect();
+    let metered: Vec<i32> = sqlx::query_scalar("SELECT id FROM products WHERE id = ANY($1) AND user_id = $2 AND kind = 'metered'")
+        .bind(&product_ids)
+        .bind(auth.user_id)
+        .fetch_all(&mut *tx)
+        .await?;
+
+    let mut errors = Vec::new();
+    for (n, event) in payload.events.iter().enumerate() {
+        if !clients.contains(&event.client_id) {
+            errors.push(FieldError { field: format!("events[{}].client_id", n), message: "does not exist".to_string() });
+        }
+        if !metered.contains(&event.product_id) {
+            errors.push(FieldError { field: format!("events[{}].product_id", n), message: "is not a metered product".to_string() });
+        }
+    }
+    if !errors.is_empty() {
+        return Err(ApiError::Validation(errors));
+    }
+
+    let mut recorded = Vec::new();
+    for event in &payload.events {
+        let id: Option<i64> = sqlx::query_scalar(
+            "INSERT INTO usage_events (user_id, idempotency_key, client_id, product_id, quantity, occurred_at) \
+             VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT (user_id, idempotency_key) DO NOTHING RETURNING id"
+        )
+        .bind(auth.user_id)
+        .bind(&event.idempotency_key)
+        .bind(event.client_id)
+        .bind(event.product_id)
+        .bind(event.quantity)
+        .bind(event.occurred_at)
+        .fetch_optional(&mut *tx)
+        .await?;
+        recorded.extend(id);
+    }
+
+    let events = sqlx::query_as::<_, UsageEvent>(&format!("SELECT {} {} WHERE e.id = ANY($1) ORDER BY e.id", USAGE_LIST.columns, USAGE_LIST.from))
+        .bind(&recorded)
+        .fetch_all(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    USAGE_EVENTS_RECORDED.inc_by(&[], recorded.len() as u64);
+    Ok(Json(RecordUsageResponse { recorded: recorded.len(), duplicates: payload.events.len() - recorded.len(), events }))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct SummaryQuery {
+    client_id: Option<i32>,
+    product_id: Option<i32>,
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+    /// `day`, `week` or `month` (the default).
+    period: Option<String>,
+}
+
+impl Validate for SummaryQuery {
+    fn validate(&self, v: &mut Validator) {
+        v.one_of("period", self.period.as_deref(), SUMMARY_PERIODS);
+        if let (Some(from), Some(to)) = (self.from, self.to) {
+            if to < from {
+                v.error("to", "must not be before from");
+            }
+        }
+    }
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub(crate) struct UsageTotal {
+    period_start: NaiveDate,
+    client_id: i32,
+    client_name: String,
+    product_id: i32,
+    product_name: String,
+    unit: String,
+    quantity: f64,
+    /// Not yet on an invoice.
+    unbilled_quantity: f64,
+}
+
+/// Usage per client, product and period, e.g. to show a client what its
+/// next invoice will bill (see also `GET /api/recurring/:id/preview`).
+pub(crate) async fn usage_summary(
+    auth: AuthContext,
+    State(state): State<Arc<AppState>>,
+    query: Result<Query<SummaryQuery>, QueryRejection>,
+) -> Result<Json<Vec<UsageTotal>>, ApiError> {
+    let Query(query) = query?;
+    Validator::check(&query)?;
+
+    let totals = sqlx::query_as::<_, UsageTotal>(
+        "SELECT date_trunc($2, e.occurred_at)::date AS period_start, e.client_id, c.name AS client_name, e.product_id, p.name AS product_name, p.unit, \
+           SUM(e.quantity)::float8 AS quantity, COALESCE(SUM(e.quantity) FILTER (WHERE e.invoice_id IS NULL), 0)::float8 AS unbilled_quantity \
+         FROM usage_events e JOIN clients c ON c.id = e.client_id JOIN products p ON p.id = e.product_id \
+         WHERE e.user_id = $1 AND ($3::int IS NULL OR e.client_id = $3) AND ($4::int IS NULL OR e.product_id = $4) \
+           AND ($5::date IS NULL OR e.occurred_at::date >= $5) AND ($6::date IS NULL OR e.occurred_at::date <= $6) \
+         GROUP BY 1, e.client_id, c.name, e.product_id, p.name, p.unit \
+         ORDER BY 1, lower(c.name), lower(p.name)"
+    )
+    .bind(auth.user_id)
+    .bind(query.period.as_deref().unwrap_or("month"))
+    .bind(query.client_id)
+    .bind(query.product_id)
+    .bind(query.from)
+    .bind(query.to)
+    .fetch_all(&state.db)
+    .await?;
+    Ok(Json(totals))
+}
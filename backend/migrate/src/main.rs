@@ -0,0 +1,81 @@
+//! Applies, reverts and lists the database migrations in `backend/migrations`.
+//!
+//! ```text
+//! migrate run                 apply all pending migrations
+//! migrate revert              revert the most recently applied migration
+//! migrate revert --to <ver>   revert every migration newer than <ver> (0 for all)
+//! migrate info                list migrations and whether they are applied
+//! ```
+
+use common::bootstrap::{self, DatabaseConfig};
+use common::MIGRATOR;
+use sqlx::migrate::Migrate;
+use std::collections::HashSet;
+use std::process::ExitCode;
+
+const USAGE: &str = "usage: migrate run | revert [--to <version>] | info";
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    match run(&args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(args: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+    bootstrap::load_env_files()?;
+    let db = DatabaseConfig::from_env()?.ok_or("DATABASE_URL must be set")?;
+    let pool = db.pool_options().max_connections(1).connect(&db.url).await?;
+
+    let mut conn = pool.acquire().await?;
+    conn.ensure_migrations_table().await?;
+    let applied: HashSet<i64> = conn.list_applied_migrations().await?.into_iter().map(|m| m.version).collect();
+    drop(conn);
+
+    match args {
+        ["run"] => {
+            MIGRATOR.run(&pool).await?;
+            let pending = MIGRATOR.iter().filter(|m| m.migration_type.is_up_migration() && !applied.contains(&m.version));
+            for migration in pending {
+                println!("Applied {} {}", migration.version, migration.description);
+            }
+        }
+        ["revert"] => {
+            let Some(&latest) = applied.iter().max() else {
+                println!("No migrations to revert");
+                return Ok(());
+            };
+            let target = applied.iter().copied().filter(|&v| v < latest).max().unwrap_or(0);
+            MIGRATOR.undo(&pool, target).await?;
+            println!("Reverted {}", latest);
+        }
+        ["revert", "--to", version] => {
+            let target: i64 = version.parse().map_err(|_| format!("invalid version: {}", version))?;
+            if target != 0 && !MIGRATOR.version_exists(target) {
+                return Err(format!("unknown migration version: {}", target).into());
+            }
+            MIGRATOR.undo(&pool, target).await?;
+            let mut reverted: Vec<_> = applied.iter().filter(|&&v| v > target).collect();
+            reverted.sort_unstable_by(|a, b| b.cmp(a));
+            for version in reverted {
+                println!("Reverted {}", version);
+            }
+        }
+        ["info"] => {
+            for migration in MIGRATOR.iter().filter(|m| m.migration_type.is_up_migration()) {
+                let status = if applied.contains(&migration.version) { "applied" } else { "pending" };
+                println!("{} {:<8} {}", migration.version, status, migration.description);
+            }
+        }
+        _ => return Err(USAGE.into()),
+    }
+
+    Ok(())
+}
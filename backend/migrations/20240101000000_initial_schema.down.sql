@@ -0,0 +1,8 @@
+DROP TABLE IF EXISTS companies;
+DROP TABLE IF EXISTS recurring_invoices;
+DROP TABLE IF EXISTS estimates;
+DROP TABLE IF EXISTS invoice_items;
+DROP TABLE IF EXISTS invoices;
+DROP TABLE IF EXISTS products;
+DROP TABLE IF EXISTS clients;
+DROP TABLE IF EXISTS users;
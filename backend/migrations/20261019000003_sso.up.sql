@@ -0,0 +1,33 @@
+-- OpenID Connect providers, one per organization
+CREATE TABLE IF NOT EXISTS sso_connections (
+    id SERIAL PRIMARY KEY,
+    organization TEXT UNIQUE NOT NULL,
+    issuer_url TEXT NOT NULL,
+    client_id TEXT NOT NULL,
+    client_secret TEXT,
+    redirect_uri TEXT NOT NULL,
+    allowed_domains TEXT[] NOT NULL DEFAULT '{}',
+    jit_provisioning BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+-- In-flight SSO logins (state, nonce and PKCE verifier)
+CREATE TABLE IF NOT EXISTS sso_login_states (
+    state TEXT PRIMARY KEY,
+    connection_id INTEGER NOT NULL REFERENCES sso_connections(id) ON DELETE CASCADE,
+    nonce TEXT NOT NULL,
+    code_verifier TEXT NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+-- Provider identities linked to Billio users
+CREATE TABLE IF NOT EXISTS user_identities (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    connection_id INTEGER NOT NULL REFERENCES sso_connections(id) ON DELETE CASCADE,
+    subject TEXT NOT NULL,
+    email TEXT NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT NOW(),
+    UNIQUE (connection_id, subject)
+);
@@ -0,0 +1,13 @@
+-- Invoices of erased accounts have no owner left; CASCADE cannot be restored for them.
+DELETE FROM invoices WHERE user_id IS NULL AND anonymized_at IS NOT NULL;
+
+ALTER TABLE invoices DROP CONSTRAINT IF EXISTS invoices_user_id_fkey;
+ALTER TABLE invoices ADD CONSTRAINT invoices_user_id_fkey
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE;
+
+ALTER TABLE invoices
+    DROP COLUMN IF EXISTS seller_name,
+    DROP COLUMN IF EXISTS seller_tax_id,
+    DROP COLUMN IF EXISTS buyer_name,
+    DROP COLUMN IF EXISTS buyer_tax_id,
+    DROP COLUMN IF EXISTS anonymized_at;
@@ -0,0 +1,12 @@
+-- SET NULL rather than CASCADE: invoices outlive an erased account (anonymized)
+ALTER TABLE invoices DROP CONSTRAINT IF EXISTS invoices_user_id_fkey;
+ALTER TABLE invoices ADD CONSTRAINT invoices_user_id_fkey
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE SET NULL;
+
+-- Snapshot kept when the owning account is erased
+ALTER TABLE invoices
+    ADD COLUMN IF NOT EXISTS seller_name TEXT,
+    ADD COLUMN IF NOT EXISTS seller_tax_id TEXT,
+    ADD COLUMN IF NOT EXISTS buyer_name TEXT,
+    ADD COLUMN IF NOT EXISTS buyer_tax_id TEXT,
+    ADD COLUMN IF NOT EXISTS anonymized_at TIMESTAMPTZ;
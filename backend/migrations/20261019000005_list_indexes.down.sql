@@ -0,0 +1,5 @@
+DROP INDEX IF EXISTS products_user_name_idx;
+DROP INDEX IF EXISTS clients_user_name_idx;
+DROP INDEX IF EXISTS recurring_invoices_user_created_idx;
+DROP INDEX IF EXISTS estimates_user_created_idx;
+DROP INDEX IF EXISTS invoices_user_created_idx;
@@ -0,0 +1,7 @@
+-- List endpoints page by (sort key, id); these cover the default sorts.
+-- The expressions must match the sort keys declared in each service.
+CREATE INDEX IF NOT EXISTS invoices_user_created_idx ON invoices (user_id, COALESCE(created_at, 'epoch'::timestamptz), id);
+CREATE INDEX IF NOT EXISTS estimates_user_created_idx ON estimates (user_id, COALESCE(created_at, 'epoch'::timestamptz), id);
+CREATE INDEX IF NOT EXISTS recurring_invoices_user_created_idx ON recurring_invoices (user_id, COALESCE(created_at, 'epoch'::timestamptz), id);
+CREATE INDEX IF NOT EXISTS clients_user_name_idx ON clients (user_id, lower(name), id);
+CREATE INDEX IF NOT EXISTS products_user_name_idx ON products (user_id, lower(name), id);
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS client_addresses;
+DROP TABLE IF EXISTS client_contacts;
@@ -0,0 +1,26 @@
+-- People at a client; invoice recipients get invoice emails and the first
+-- of them is named on the PDF
+CREATE TABLE IF NOT EXISTS client_contacts (
+    id SERIAL PRIMARY KEY,
+    client_id INTEGER NOT NULL REFERENCES clients(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    email TEXT,
+    phone TEXT,
+    role TEXT,
+    invoice_recipient BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMPTZ DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS client_contacts_client_idx ON client_contacts (client_id, id);
+
+-- At most one billing and one shipping address per client; clients.address
+-- remains as a free-text fallback for clients without a billing address
+CREATE TABLE IF NOT EXISTS client_addresses (
+    client_id INTEGER NOT NULL REFERENCES clients(id) ON DELETE CASCADE,
+    kind TEXT NOT NULL CHECK (kind IN ('billing', 'shipping')),
+    street TEXT NOT NULL,
+    city TEXT NOT NULL,
+    postal_code TEXT,
+    region TEXT,
+    country CHAR(2) NOT NULL,
+    PRIMARY KEY (client_id, kind)
+);
@@ -0,0 +1,3 @@
+UPDATE clients SET status = 'inactive' WHERE status = 'archived';
+ALTER TABLE clients DROP COLUMN IF EXISTS search_vector;
+ALTER TABLE clients DROP COLUMN IF EXISTS tags;
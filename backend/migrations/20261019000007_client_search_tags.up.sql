@@ -0,0 +1,13 @@
+-- User-defined labels, filtered with `tag=`
+ALTER TABLE clients ADD COLUMN IF NOT EXISTS tags TEXT[] NOT NULL DEFAULT '{}';
+CREATE INDEX IF NOT EXISTS clients_tags_idx ON clients USING GIN (tags);
+
+-- Full-text search over name, email (whole and split at the @), tax ID and notes
+ALTER TABLE clients ADD COLUMN IF NOT EXISTS search_vector tsvector GENERATED ALWAYS AS (
+    to_tsvector('simple',
+        coalesce(name, '') || ' ' ||
+        coalesce(email, '') || ' ' || coalesce(replace(email, '@', ' '), '') || ' ' ||
+        coalesce(tax_id, '') || ' ' ||
+        coalesce(notes, ''))
+) STORED;
+CREATE INDEX IF NOT EXISTS clients_search_idx ON clients USING GIN (search_vector);
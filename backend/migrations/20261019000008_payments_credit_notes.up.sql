@@ -0,0 +1,35 @@
+-- Money received against an invoice
+CREATE TABLE IF NOT EXISTS payments (
+    id SERIAL PRIMARY KEY,
+    invoice_id INTEGER NOT NULL REFERENCES invoices(id) ON DELETE CASCADE,
+    amount DECIMAL(12, 2) NOT NULL CHECK (amount > 0),
+    paid_on DATE NOT NULL,
+    method TEXT,
+    reference TEXT,
+    created_at TIMESTAMPTZ DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS payments_invoice_idx ON payments (invoice_id);
+
+-- Invoices already marked paid are taken as settled in full on their due date
+INSERT INTO payments (invoice_id, amount, paid_on, reference)
+SELECT i.id, i.total, COALESCE(i.due_date, i.created_at::date, CURRENT_DATE), 'Recorded before payment tracking'
+FROM invoices i
+WHERE i.status = 'paid' AND i.total > 0 AND NOT EXISTS (SELECT 1 FROM payments p WHERE p.invoice_id = i.id);
+
+-- Amounts credited to a client, against one of its invoices or on account.
+-- Like invoices, they are retained and anonymized when the account is erased.
+CREATE TABLE IF NOT EXISTS credit_notes (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    client_id INTEGER REFERENCES clients(id) ON DELETE SET NULL,
+    invoice_id INTEGER REFERENCES invoices(id) ON DELETE SET NULL,
+    credit_note_number TEXT NOT NULL,
+    issue_date DATE NOT NULL,
+    amount DECIMAL(12, 2) NOT NULL CHECK (amount > 0),
+    reason TEXT,
+    anonymized_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS credit_notes_client_idx ON credit_notes (client_id, issue_date);
+CREATE INDEX IF NOT EXISTS credit_notes_invoice_idx ON credit_notes (invoice_id);
+CREATE INDEX IF NOT EXISTS credit_notes_user_issued_idx ON credit_notes (user_id, issue_date, id);
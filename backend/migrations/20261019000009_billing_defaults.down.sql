@@ -0,0 +1,14 @@
+ALTER TABLE invoices
+    DROP COLUMN IF EXISTS discount_amount,
+    DROP COLUMN IF EXISTS discount_percent,
+    DROP COLUMN IF EXISTS tax_exemption_reason,
+    DROP COLUMN IF EXISTS tax_treatment,
+    DROP COLUMN IF EXISTS language,
+    DROP COLUMN IF EXISTS currency;
+
+ALTER TABLE clients
+    DROP COLUMN IF EXISTS discount_percent,
+    DROP COLUMN IF EXISTS tax_exemption_reason,
+    DROP COLUMN IF EXISTS tax_treatment,
+    DROP COLUMN IF EXISTS language,
+    DROP COLUMN IF EXISTS currency;
@@ -0,0 +1,20 @@
+-- Per-client billing defaults; NULL currency/language fall back to the company's
+ALTER TABLE clients
+    ADD COLUMN IF NOT EXISTS currency CHAR(3),
+    ADD COLUMN IF NOT EXISTS language TEXT,
+    ADD COLUMN IF NOT EXISTS tax_treatment TEXT NOT NULL DEFAULT 'standard'
+        CHECK (tax_treatment IN ('standard', 'reverse_charge', 'exempt')),
+    ADD COLUMN IF NOT EXISTS tax_exemption_reason TEXT,
+    ADD COLUMN IF NOT EXISTS discount_percent DECIMAL(5, 2) NOT NULL DEFAULT 0
+        CHECK (discount_percent BETWEEN 0 AND 100);
+
+-- What was applied to each invoice; total is after the discount
+ALTER TABLE invoices
+    ADD COLUMN IF NOT EXISTS currency CHAR(3),
+    ADD COLUMN IF NOT EXISTS language TEXT,
+    ADD COLUMN IF NOT EXISTS tax_treatment TEXT NOT NULL DEFAULT 'standard'
+        CHECK (tax_treatment IN ('standard', 'reverse_charge', 'exempt')),
+    ADD COLUMN IF NOT EXISTS tax_exemption_reason TEXT,
+    ADD COLUMN IF NOT EXISTS discount_percent DECIMAL(5, 2) NOT NULL DEFAULT 0
+        CHECK (discount_percent BETWEEN 0 AND 100),
+    ADD COLUMN IF NOT EXISTS discount_amount DECIMAL(12, 2) NOT NULL DEFAULT 0;
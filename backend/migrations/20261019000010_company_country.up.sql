@@ -0,0 +1,2 @@
+-- Country the company is established in, which its tax ID is checked against
+ALTER TABLE companies ADD COLUMN IF NOT EXISTS country CHAR(2);
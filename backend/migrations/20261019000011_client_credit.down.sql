@@ -0,0 +1,5 @@
+DROP INDEX IF EXISTS invoices_client_idx;
+ALTER TABLE companies DROP COLUMN IF EXISTS credit_limit_policy;
+ALTER TABLE clients
+    DROP COLUMN IF EXISTS risk_status,
+    DROP COLUMN IF EXISTS credit_limit;
@@ -0,0 +1,13 @@
+-- Credit control: a NULL limit means none; clients on hold are treated as over it
+ALTER TABLE clients
+    ADD COLUMN IF NOT EXISTS credit_limit DECIMAL(12, 2) CHECK (credit_limit >= 0),
+    ADD COLUMN IF NOT EXISTS risk_status TEXT NOT NULL DEFAULT 'normal'
+        CHECK (risk_status IN ('normal', 'watch', 'hold'));
+
+-- Whether invoicing past a client's limit is allowed with a warning or refused
+ALTER TABLE companies
+    ADD COLUMN IF NOT EXISTS credit_limit_policy TEXT NOT NULL DEFAULT 'warn'
+        CHECK (credit_limit_policy IN ('warn', 'block'));
+
+-- Per-client balances and payment history on the client list
+CREATE INDEX IF NOT EXISTS invoices_client_idx ON invoices (client_id);
@@ -0,0 +1,10 @@
+DROP INDEX IF EXISTS invoice_items_product_idx;
+ALTER TABLE invoice_items DROP COLUMN IF EXISTS product_id;
+ALTER TABLE companies DROP COLUMN IF EXISTS tax_rates;
+DROP INDEX IF EXISTS products_user_sku_idx;
+ALTER TABLE products
+    DROP COLUMN IF EXISTS cost_price,
+    DROP COLUMN IF EXISTS status,
+    DROP COLUMN IF EXISTS tax_category,
+    DROP COLUMN IF EXISTS unit,
+    DROP COLUMN IF EXISTS sku;
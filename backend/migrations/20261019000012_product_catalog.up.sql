@@ -0,0 +1,16 @@
+-- Catalog fields: SKU (unique per user), unit of measure, tax category,
+-- archived flag and cost price for margins
+ALTER TABLE products
+    ADD COLUMN IF NOT EXISTS sku TEXT,
+    ADD COLUMN IF NOT EXISTS unit TEXT NOT NULL DEFAULT 'piece',
+    ADD COLUMN IF NOT EXISTS tax_category TEXT NOT NULL DEFAULT 'standard',
+    ADD COLUMN IF NOT EXISTS status TEXT NOT NULL DEFAULT 'active' CHECK (status IN ('active', 'archived')),
+    ADD COLUMN IF NOT EXISTS cost_price DECIMAL(12, 2) CHECK (cost_price >= 0);
+CREATE UNIQUE INDEX IF NOT EXISTS products_user_sku_idx ON products (user_id, sku) WHERE sku IS NOT NULL;
+
+-- Tax rate per category other than `standard`, which uses default_tax_rate
+ALTER TABLE companies ADD COLUMN IF NOT EXISTS tax_rates JSONB NOT NULL DEFAULT '{}';
+
+-- The product a line was billed from, for revenue by product
+ALTER TABLE invoice_items ADD COLUMN IF NOT EXISTS product_id INTEGER REFERENCES products(id) ON DELETE SET NULL;
+CREATE INDEX IF NOT EXISTS invoice_items_product_idx ON invoice_items (product_id) WHERE product_id IS NOT NULL;
@@ -0,0 +1,3 @@
+DROP TABLE IF EXISTS estimate_items;
+DROP TABLE IF EXISTS price_list_prices;
+DROP TABLE IF EXISTS price_lists;
@@ -0,0 +1,38 @@
+-- Price lists: prices in one currency, optionally only for clients with
+-- `client_tag` and within a validity range
+CREATE TABLE IF NOT EXISTS price_lists (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    currency TEXT NOT NULL,
+    client_tag TEXT,
+    valid_from DATE,
+    valid_to DATE CHECK (valid_to >= valid_from),
+    tier_mode TEXT NOT NULL DEFAULT 'volume' CHECK (tier_mode IN ('volume', 'graduated')),
+    created_at TIMESTAMPTZ DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS price_lists_user_idx ON price_lists (user_id, currency);
+
+-- A product's unit price from `min_quantity` units up
+CREATE TABLE IF NOT EXISTS price_list_prices (
+    id SERIAL PRIMARY KEY,
+    price_list_id INTEGER NOT NULL REFERENCES price_lists(id) ON DELETE CASCADE,
+    product_id INTEGER NOT NULL REFERENCES products(id) ON DELETE CASCADE,
+    min_quantity DECIMAL(12, 2) NOT NULL DEFAULT 0 CHECK (min_quantity >= 0),
+    unit_price DECIMAL(12, 2) NOT NULL CHECK (unit_price >= 0),
+    UNIQUE (price_list_id, product_id, min_quantity)
+);
+CREATE INDEX IF NOT EXISTS price_list_prices_product_idx ON price_list_prices (product_id);
+
+-- Estimate lines, priced like invoice lines
+CREATE TABLE IF NOT EXISTS estimate_items (
+    id SERIAL PRIMARY KEY,
+    estimate_id INTEGER NOT NULL REFERENCES estimates(id) ON DELETE CASCADE,
+    product_id INTEGER REFERENCES products(id) ON DELETE SET NULL,
+    description TEXT NOT NULL,
+    quantity DECIMAL(12, 2) NOT NULL DEFAULT 1,
+    price DECIMAL(12, 2) NOT NULL DEFAULT 0,
+    amount DECIMAL(12, 2) NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS estimate_items_estimate_idx ON estimate_items (estimate_id);
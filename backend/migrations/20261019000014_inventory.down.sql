@@ -0,0 +1,5 @@
+DROP TABLE IF EXISTS stock_movements;
+ALTER TABLE products
+    DROP COLUMN IF EXISTS low_stock_threshold,
+    DROP COLUMN IF EXISTS stock_on_hand,
+    DROP COLUMN IF EXISTS track_inventory;
@@ -0,0 +1,23 @@
+-- Optional stock tracking per product. stock_on_hand is kept in step with
+-- stock_movements, which it is the sum of
+ALTER TABLE products
+    ADD COLUMN IF NOT EXISTS track_inventory BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN IF NOT EXISTS stock_on_hand DECIMAL(12, 2) NOT NULL DEFAULT 0,
+    ADD COLUMN IF NOT EXISTS low_stock_threshold DECIMAL(12, 2) CHECK (low_stock_threshold >= 0);
+
+-- Every change to a product's stock: invoices issued and voided, goods
+-- returned on credit notes, and manual adjustments
+CREATE TABLE IF NOT EXISTS stock_movements (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    product_id INTEGER NOT NULL REFERENCES products(id) ON DELETE CASCADE,
+    quantity DECIMAL(12, 2) NOT NULL CHECK (quantity <> 0),
+    kind TEXT NOT NULL CHECK (kind IN ('issue', 'void', 'credit_note', 'adjustment')),
+    invoice_id INTEGER REFERENCES invoices(id) ON DELETE SET NULL,
+    credit_note_id INTEGER REFERENCES credit_notes(id) ON DELETE SET NULL,
+    reason TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS stock_movements_product_idx ON stock_movements (product_id, created_at);
+CREATE INDEX IF NOT EXISTS stock_movements_invoice_idx ON stock_movements (invoice_id) WHERE invoice_id IS NOT NULL;
+CREATE INDEX IF NOT EXISTS stock_movements_credit_note_idx ON stock_movements (credit_note_id) WHERE credit_note_id IS NOT NULL;
@@ -0,0 +1,5 @@
+ALTER TABLE invoices DROP COLUMN IF EXISTS print_bundle_components;
+DROP TABLE IF EXISTS bundle_components;
+ALTER TABLE products
+    DROP COLUMN IF EXISTS bundle_pricing,
+    DROP COLUMN IF EXISTS kind;
@@ -0,0 +1,17 @@
+-- Bundles: products sold as one line and made up of other products. A bundle
+-- is priced at its own `price` (`fixed`) or the sum of its components
+ALTER TABLE products
+    ADD COLUMN IF NOT EXISTS kind TEXT NOT NULL DEFAULT 'simple' CHECK (kind IN ('simple', 'bundle')),
+    ADD COLUMN IF NOT EXISTS bundle_pricing TEXT CHECK (bundle_pricing IN ('fixed', 'components'));
+
+CREATE TABLE IF NOT EXISTS bundle_components (
+    bundle_id INTEGER NOT NULL REFERENCES products(id) ON DELETE CASCADE,
+    component_id INTEGER NOT NULL REFERENCES products(id),
+    quantity DECIMAL(12, 2) NOT NULL CHECK (quantity > 0),
+    PRIMARY KEY (bundle_id, component_id),
+    CHECK (bundle_id <> component_id)
+);
+CREATE INDEX IF NOT EXISTS bundle_components_component_idx ON bundle_components (component_id);
+
+-- Whether the invoice PDF lists each bundle line's components
+ALTER TABLE invoices ADD COLUMN IF NOT EXISTS print_bundle_components BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,6 @@
+ALTER TABLE invoices DROP COLUMN IF EXISTS recurring_invoice_id;
+ALTER TABLE recurring_invoices DROP COLUMN IF EXISTS bill_usage;
+DROP TABLE IF EXISTS usage_events;
+UPDATE products SET kind = 'simple' WHERE kind = 'metered';
+ALTER TABLE products DROP CONSTRAINT IF EXISTS products_kind_check;
+ALTER TABLE products ADD CONSTRAINT products_kind_check CHECK (kind IN ('simple', 'bundle'));
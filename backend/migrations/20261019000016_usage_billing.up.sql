@@ -0,0 +1,26 @@
+-- Metered products are billed by the usage recorded against them
+ALTER TABLE products DROP CONSTRAINT IF EXISTS products_kind_check;
+ALTER TABLE products ADD CONSTRAINT products_kind_check CHECK (kind IN ('simple', 'bundle', 'metered'));
+
+-- Usage of a metered product by a client. `idempotency_key` makes resending
+-- an event harmless; `invoice_id` is set once the usage has been invoiced
+CREATE TABLE IF NOT EXISTS usage_events (
+    id BIGSERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    idempotency_key TEXT NOT NULL,
+    client_id INTEGER NOT NULL REFERENCES clients(id) ON DELETE CASCADE,
+    product_id INTEGER NOT NULL REFERENCES products(id),
+    quantity DECIMAL(14, 4) NOT NULL CHECK (quantity >= 0),
+    occurred_at TIMESTAMPTZ NOT NULL,
+    invoice_id INTEGER REFERENCES invoices(id) ON DELETE SET NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE (user_id, idempotency_key)
+);
+CREATE INDEX IF NOT EXISTS usage_events_unbilled_idx ON usage_events (client_id, occurred_at) WHERE invoice_id IS NULL;
+CREATE INDEX IF NOT EXISTS usage_events_product_idx ON usage_events (product_id);
+CREATE INDEX IF NOT EXISTS usage_events_invoice_idx ON usage_events (invoice_id);
+
+-- Whether a recurring profile's runs also invoice the client's usage, and the
+-- profile an invoice was generated from
+ALTER TABLE recurring_invoices ADD COLUMN IF NOT EXISTS bill_usage BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE invoices ADD COLUMN IF NOT EXISTS recurring_invoice_id INTEGER REFERENCES recurring_invoices(id) ON DELETE SET NULL;
@@ -0,0 +1,7 @@
+ALTER TABLE recurring_invoices
+    DROP COLUMN IF EXISTS canceled_at,
+    DROP COLUMN IF EXISTS cancel_at,
+    DROP COLUMN IF EXISTS trial_end,
+    DROP COLUMN IF EXISTS quantity,
+    DROP COLUMN IF EXISTS plan_id;
+DROP TABLE IF EXISTS plans;
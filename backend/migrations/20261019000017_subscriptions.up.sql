@@ -0,0 +1,24 @@
+-- Subscription plans: a price per seat for each billing interval
+CREATE TABLE IF NOT EXISTS plans (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    description TEXT,
+    price DECIMAL(12, 2) NOT NULL CHECK (price >= 0),
+    interval TEXT NOT NULL CHECK (interval IN ('day', 'week', 'month', 'year')),
+    interval_count INTEGER NOT NULL DEFAULT 1 CHECK (interval_count > 0),
+    trial_days INTEGER NOT NULL DEFAULT 0 CHECK (trial_days >= 0),
+    product_id INTEGER REFERENCES products(id) ON DELETE SET NULL,
+    created_at TIMESTAMPTZ DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS plans_user_idx ON plans (user_id);
+
+-- A subscription is a recurring profile on a plan, for `quantity` seats,
+-- free until `trial_end` and ending at `cancel_at` once cancelled
+ALTER TABLE recurring_invoices
+    ADD COLUMN IF NOT EXISTS plan_id INTEGER REFERENCES plans(id),
+    ADD COLUMN IF NOT EXISTS quantity INTEGER NOT NULL DEFAULT 1 CHECK (quantity > 0),
+    ADD COLUMN IF NOT EXISTS trial_end DATE,
+    ADD COLUMN IF NOT EXISTS cancel_at DATE,
+    ADD COLUMN IF NOT EXISTS canceled_at DATE;
+CREATE INDEX IF NOT EXISTS recurring_invoices_plan_idx ON recurring_invoices (plan_id);
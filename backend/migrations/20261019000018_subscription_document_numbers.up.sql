@@ -0,0 +1,2 @@
+-- Numbers a subscription's prorated invoices and credit notes one after another
+ALTER TABLE recurring_invoices ADD COLUMN IF NOT EXISTS document_seq INTEGER NOT NULL DEFAULT 0;
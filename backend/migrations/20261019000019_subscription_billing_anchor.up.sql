@@ -0,0 +1,3 @@
+-- The date a profile's runs are counted from, when not its start_date: a
+-- subscription's first billed day after a trial or a pause
+ALTER TABLE recurring_invoices ADD COLUMN IF NOT EXISTS billing_anchor DATE;
@@ -0,0 +1,6 @@
+ALTER TABLE sso_connections ADD COLUMN IF NOT EXISTS allowed_domains TEXT[] NOT NULL DEFAULT '{}';
+
+UPDATE sso_connections c SET allowed_domains = ARRAY(SELECT d.domain FROM sso_domains d WHERE d.connection_id = c.id ORDER BY d.domain);
+
+DROP TABLE IF EXISTS sso_domains;
+ALTER TABLE sso_connections DROP COLUMN IF EXISTS owner_id;
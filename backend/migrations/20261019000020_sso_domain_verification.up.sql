@@ -0,0 +1,25 @@
+-- The Billio user who administers an SSO connection and verifies its domains
+ALTER TABLE sso_connections ADD COLUMN IF NOT EXISTS owner_id INTEGER REFERENCES users(id) ON DELETE CASCADE;
+
+-- Email domains a connection signs in for, each proven by its owner with a
+-- DNS TXT record
+CREATE TABLE IF NOT EXISTS sso_domains (
+    id SERIAL PRIMARY KEY,
+    connection_id INTEGER NOT NULL REFERENCES sso_connections(id) ON DELETE CASCADE,
+    domain TEXT NOT NULL,
+    verification_token TEXT NOT NULL,
+    verified_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT NOW(),
+    UNIQUE (connection_id, domain)
+);
+
+-- A domain signs in through at most one connection
+CREATE UNIQUE INDEX IF NOT EXISTS idx_sso_domains_verified ON sso_domains(domain) WHERE verified_at IS NOT NULL;
+
+-- Domains listed so far were never proven, so they start out unverified
+INSERT INTO sso_domains (connection_id, domain, verification_token)
+SELECT c.id, lower(d.domain), replace(gen_random_uuid()::text, '-', '')
+FROM sso_connections c, unnest(c.allowed_domains) AS d(domain)
+ON CONFLICT (connection_id, domain) DO NOTHING;
+
+ALTER TABLE sso_connections DROP COLUMN IF EXISTS allowed_domains;
@@ -0,0 +1,199 @@
+//! Bundles: products sold as one invoice line and made up of other products.
+//!
+//! A bundle's components are simple products, each with a quantity per
+//! bundle. Its price is its own `price` (`fixed` pricing) or the sum of its
+//! components' (`components`, see `common::pricing`). Stock and product
+//! revenue reports expand bundle lines into their components.
+
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::collections::HashMap;
+
+use common::{ApiError, FieldError, Validate, Validator};
+
+pub(crate) const PRODUCT_KINDS: &[&str] = &["simple", "bundle", "metered"];
+pub(crate) const BUNDLE_PRICINGS: &[&str] = &["fixed", "components"];
+
+#[derive(Debug, FromRow, Serialize, Deserialize)]
+pub(crate) struct BundleComponent {
+    #[serde(skip)]
+    bundle_id: i32,
+    product_id: i32,
+    sku: Option<String>,
+    name: String,
+    quantity: f64,
+    price: f64,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ComponentRequest {
+    pub product_id: i32,
+    pub quantity: f64,
+}
+
+impl Validate for ComponentRequest {
+    fn validate(&self, v: &mut Validator) {
+        if self.quantity <= 0.0 {
+            v.error("quantity", "must be greater than 0");
+        }
+    }
+}
+
+/// A product's kind and the settings that depend on it.
+pub(crate) struct ProductKind<'a> {
+    pub kind: Option<&'a str>,
+    pub bundle_pricing: Option<&'a str>,
+    pub components: &'a [ComponentRequest],
+    pub track_inventory: Option<bool>,
+}
+
+impl Validate for ProductKind<'_> {
+    fn validate(&self, v: &mut Validator) {
+        v.one_of("kind", self.kind, PRODUCT_KINDS);
+        v.nested("components", self.components);
+        if self.kind == Some("bundle") {
+            v.one_of("bundle_pricing", self.bundle_pricing, BUNDLE_PRICINGS);
+            if self.components.is_empty() {
+                v.error("components", "must not be empty for a bundle");
+            }
+            for (i, component) in self.components.iter().enumerate() {
+                if self.components[..i].iter().any(|c| c.product_id == component.product_id) {
+                    v.error(&format!("components[{}].product_id", i), "is already a component");
+                }
+            }
+            // Bundles move their components' stock instead
+            if self.track_inventory == Some(true) {
+                v.error("track_inventory", "is not available for bundles");
+            }
+        } else {
+            if !self.components.is_empty() {
+                v.error("components", "are only allowed for bundles");
+            }
+            if self.bundle_pricing.is_some() {
+                v.error("bundle_pricing", "is only allowed for bundles");
+            }
+        }
+        if self.kind == Some("metered") && self.track_inventory == Some(true) {
+            v.error("track_inventory", "is not available for metered products");
+        }
+    }
+}
+
+/// Components of the given bundles, by bundle.
+pub(crate) async fn load_components(conn: &mut sqlx::PgConnection, bundle_ids: &[i32]) -> Result<HashMap<i32, Vec<BundleComponent>>, ApiError> {
+    let components = sqlx::query_as::<_, BundleComponent>(
+        "SELECT bc.bundle_id, p.id AS product_id, p.sku, p.name, bc.quantity::float8 AS quantity, p.price::float8 AS price \
+         FROM bundle_components bc JOIN products p ON p.id = bc.component_id \
+         WHERE bc.bundle_id = ANY($1) ORDER BY bc.bundle_id, lower(p.name), p.id"
+    )
+    .bind(bundle_ids)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let mut by_bundle: HashMap<i32, Vec<BundleComponent>> = HashMap::new();
+    for component in components {
+        by_bundle.entry(component.bundle_id).or_default().push(component);
+    }
+    Ok(by_bundle)
+}
+
+/// Replaces a bundle's components, or clears them when `product_id` is not a
+/// bundle. Components must be the user's simple products, and a product that
+/// is itself a component can't become a bundle.
+pub(crate) async fn save_components(
+    tx: &mut sqlx::PgConnection,
+    user_id: i32,
+    product_id: i32,
+    is_bundle: bool,
+    components: &[ComponentRequest],
+) -> Result<(), ApiError> {
+    sqlx::query("DELETE FROM bundle_components WHERE bundle_id = $1")
+        .bind(product_id)
+        .execute(&mut *tx)
+        .await?;
+    if !is_bundle {
+        return Ok(());
+    }
+
+    let in_bundles: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM bundle_components WHERE component_id = $1")
+        .bind(product_id)
+        .fetch_one(&mut *tx)
+        .await?;
+    if in_bundles > 0 {
+        return Err(ApiError::Conflict(format!("Product is a component of {} bundle(s) and cannot be a bundle itself", in_bundles)));
+    }
+
+    let ids: Vec<i32> = components.iter().map(|c| c.product_id).collect();
+    let simple: Vec<i32> = sqlx::query_scalar("SELECT id FROM products WHERE id = ANY($1) AND user_id = $2 AND kind = 'simple'")
+        .bind(&ids)
+        .bind(user_id)
+        .fetch_all(&mut *tx)
+        .await?;
+    let unknown: Vec<FieldError> = components
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| !simple.contains(&c.product_id))
+        .map(|(n, _)| FieldError { field: format!("components[{}].product_id", n), message: "is not a product that can be a component".to_string() })
+        .collect();
+    if !unknown.is_empty() {
+        return Err(ApiError::Validation(unknown));
+    }
+
+    for component in components {
+        sqlx::query("INSERT INTO bundle_components (bundle_id, component_id, quantity) VALUES ($1, $2, $3)")
+            .bind(product_id)
+            .bind(component.product_id)
+            .bind(component.quantity)
+            .execute(&mut *tx)
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn components(parts: &[(i32, f64)]) -> Vec<ComponentRequest> {
+        parts.iter().map(|&(product_id, quantity)| ComponentRequest { product_id, quantity }).collect()
+    }
+
+    fn errors(kind: &str, bundle_pricing: Option<&str>, components: &[ComponentRequest], track_inventory: Option<bool>) -> Vec<String> {
+        let product = ProductKind { kind: Some(kind), bundle_pricing, components, track_inventory };
+        Validator::collect(&product).into_iter().map(|e| e.field).collect()
+    }
+
+    #[test]
+    fn accepts_a_bundle_of_distinct_components() {
+        let parts = components(&[(1, 2.0), (2, 0.5)]);
+        assert!(errors("bundle", Some("components"), &parts, None).is_empty());
+        assert!(errors("bundle", None, &parts, Some(false)).is_empty());
+    }
+
+    #[test]
+    fn rejects_an_empty_bundle_or_a_repeated_component() {
+        assert_eq!(errors("bundle", None, &[], None), ["components"]);
+        let parts = components(&[(1, 1.0), (2, 1.0), (1, 3.0)]);
+        assert_eq!(errors("bundle", None, &parts, None), ["components[2].product_id"]);
+    }
+
+    #[test]
+    fn rejects_component_quantities_of_zero_or_less() {
+        let parts = components(&[(1, 0.0), (2, -1.0)]);
+        assert_eq!(errors("bundle", None, &parts, None), ["components[0].quantity", "components[1].quantity"]);
+    }
+
+    #[test]
+    fn bundles_and_metered_products_do_not_track_inventory() {
+        assert_eq!(errors("bundle", None, &components(&[(1, 1.0)]), Some(true)), ["track_inventory"]);
+        assert_eq!(errors("metered", None, &[], Some(true)), ["track_inventory"]);
+        assert!(errors("simple", None, &[], Some(true)).is_empty());
+    }
+
+    #[test]
+    fn only_bundles_have_components_or_bundle_pricing() {
+        assert_eq!(errors("simple", Some("fixed"), &components(&[(1, 1.0)]), None), ["components", "bundle_pricing"]);
+        assert_eq!(errors("kit", Some("sum"), &[], None), ["kind", "bundle_pricing"]);
+        assert_eq!(errors("bundle", Some("sum"), &components(&[(1, 1.0)]), None), ["bundle_pricing"]);
+    }
+}
@@ -0,0 +1,642 @@
+//! Bulk catalog export and import, as CSV or JSON.
+//!
+//! `GET /api/products/export` writes every product in the format
+//! `POST /api/products/import` reads, so a catalog can be edited in a
+//! spreadsheet and imported back. Rows are matched to existing products by
+//! SKU: a matching row updates the product with its non-empty fields, any
+//! other row creates one. Bundle components are given by SKU
+//! (`SKU-1:1;SKU-2:3` in CSV) and may name products created by the same file;
+//! components without a SKU are left out of exports and kept on import unless
+//! the row lists components.
+//!
+//! As with client imports, `dry_run: true` reports each row's product, action
+//! and errors without writing, and the real run writes nothing if any row is
+//! invalid. Rows are numbered from 1, the first data line or array element.
+
+use axum::{
+    body::Body,
+    extract::{rejection::QueryRejection, Query, State},
+    http::{header, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use common::billing::STANDARD_TAX_CATEGORY;
+use common::{ApiError, AuthContext, FieldError, Valid, Validate, Validator};
+
+use crate::bundles::ComponentRequest;
+use crate::{bundles, insert_product, write_product, AppState, CreateProductRequest, PRODUCTS_CREATED};
+
+const MAX_ROWS: usize = 5000;
+
+/// CSV columns, in export order.
+const COLUMNS: &[&str] = &[
+    "sku", "name", "description", "price", "unit", "tax_category", "status", "cost_price",
+    "track_inventory", "low_stock_threshold", "kind", "bundle_pricing", "components",
+];
+
+const FORMATS: &[&str] = &["csv", "json"];
+
+/// A product as exported and imported. On import an absent or empty field is
+/// left as it is on an existing product.
+#[derive(Debug, Clone, Default, FromRow, Serialize, Deserialize)]
+pub(crate) struct CatalogProduct {
+    #[serde(skip)]
+    id: Option<i32>,
+    sku: Option<String>,
+    name: Option<String>,
+    description: Option<String>,
+    price: Option<f64>,
+    unit: Option<String>,
+    tax_category: Option<String>,
+    status: Option<String>,
+    cost_price: Option<f64>,
+    track_inventory: Option<bool>,
+    low_stock_threshold: Option<f64>,
+    kind: Option<String>,
+    bundle_pricing: Option<String>,
+    #[sqlx(skip)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    components: Vec<CatalogComponent>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct CatalogComponent {
+    sku: Option<String>,
+    quantity: f64,
+    /// Set for existing components, so those without a SKU are kept.
+    #[serde(skip)]
+    product_id: Option<i32>,
+}
+
+impl CatalogProduct {
+    /// `self` with `row`'s non-empty fields written over it.
+    fn overlaid(mut self, row: &CatalogProduct) -> CatalogProduct {
+        fn over<T: Clone>(field: &mut Option<T>, value: &Option<T>) {
+            if value.is_some() {
+                field.clone_from(value);
+            }
+        }
+        over(&mut self.sku, &row.sku);
+        over(&mut self.name, &row.name);
+        over(&mut self.description, &row.description);
+        over(&mut self.price, &row.price);
+        over(&mut self.unit, &row.unit);
+        over(&mut self.tax_category, &row.tax_category);
+        over(&mut self.status, &row.status);
+        over(&mut self.cost_price, &row.cost_price);
+        over(&mut self.track_inventory, &row.track_inventory);
+        over(&mut self.low_stock_threshold, &row.low_stock_threshold);
+        over(&mut self.kind, &row.kind);
+        over(&mut self.bundle_pricing, &row.bundle_pricing);
+        if !row.components.is_empty() {
+            self.components.clone_from(&row.components);
+        }
+        self
+    }
+
+    fn is_bundle(&self) -> bool {
+        self.kind.as_deref() == Some("bundle")
+    }
+
+    /// The product as a create/update request, with components resolved
+    /// through `ids` (SKU → product id).
+    fn to_request(&self, ids: &HashMap<String, i32>) -> (CreateProductRequest, Vec<FieldError>) {
+        let (components, errors) = component_requests(&self.components, ids);
+        let request = CreateProductRequest {
+            sku: self.sku.clone(),
+            name: self.name.clone().unwrap_or_default(),
+            description: self.description.clone(),
+            price: self.price.unwrap_or_default(),
+            unit: self.unit.clone(),
+            tax_category: self.tax_category.clone(),
+            status: self.status.clone(),
+            cost_price: self.cost_price,
+            track_inventory: self.track_inventory,
+            low_stock_threshold: self.low_stock_threshold,
+            kind: self.kind.clone(),
+            bundle_pricing: self.bundle_pricing.clone(),
+            components,
+        };
+        (request, errors)
+    }
+}
+
+fn component_requests(components: &[CatalogComponent], ids: &HashMap<String, i32>) -> (Vec<ComponentRequest>, Vec<FieldError>) {
+    let mut requests = Vec::new();
+    let mut errors = Vec::new();
+    for (i, component) in components.iter().enumerate() {
+        match component.sku.as_ref().and_then(|sku| ids.get(sku)).copied().or(component.product_id) {
+            Some(product_id) => requests.push(ComponentRequest { product_id, quantity: component.quantity }),
+            None => errors.push(FieldError { field: format!("components[{}].sku", i), message: "is not the SKU of a product".to_string() }),
+        }
+    }
+    (requests, errors)
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ExportQuery {
+    /// `csv` (the default) or `json`.
+    format: Option<String>,
+}
+
+impl Validate for ExportQuery {
+    fn validate(&self, v: &mut Validator) {
+        v.one_of("format", self.format.as_deref(), FORMATS);
+    }
+}
+
+/// Every product, archived ones included, ordered by name.
+async fn load_catalog(conn: &mut sqlx::PgConnection, user_id: i32) -> Result<Vec<CatalogProduct>, ApiError> {
+    let mut products = sqlx::query_as::<_, CatalogProduct>(
+        "SELECT id, sku, name, description, price::float8 AS price, unit, tax_category, status, cost_price::float8 AS cost_price, \
+           track_inventory, low_stock_threshold::float8 AS low_stock_threshold, kind, bundle_pricing \
+         FROM products WHERE user_id = $1 ORDER BY lower(name), id"
+    )
+    .bind(user_id)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let components = sqlx::query_as::<_, (i32, i32, Option<String>, f64)>(
+        "SELECT bc.bundle_id, p.id, p.sku, bc.quantity::float8 FROM bundle_components bc JOIN products p ON p.id = bc.component_id \
+         WHERE p.user_id = $1 ORDER BY bc.bundle_id, p.sku, p.id"
+    )
+    .bind(user_id)
+    .fetch_all(&mut *conn)
+    .await?;
+    let mut by_bundle: HashMap<i32, Vec<CatalogComponent>> = HashMap::new();
+    for (bundle_id, product_id, sku, quantity) in components {
+        by_bundle.entry(bundle_id).or_default().push(CatalogComponent { sku, quantity, product_id: Some(product_id) });
+    }
+    for product in &mut products {
+        if let Some(id) = product.id {
+            product.components = by_bundle.remove(&id).unwrap_or_default();
+        }
+    }
+    Ok(products)
+}
+
+pub(crate) async fn export_catalog(
+    auth: AuthContext,
+    State(state): State<Arc<AppState>>,
+    query: Result<Query<ExportQuery>, QueryRejection>,
+) -> Result<Response<Body>, ApiError> {
+    let Query(query) = query?;
+    Validator::check(&query)?;
+
+    let mut conn = state.db.acquire().await?;
+    let mut products = load_catalog(&mut conn, auth.user_id).await?;
+    // Components without a SKU can't be referred to in a file, so they are left out
+    for product in &mut products {
+        product.components.retain(|c| c.sku.is_some());
+    }
+
+    let (content_type, extension, body) = match query.format.as_deref().unwrap_or("csv") {
+        "json" => ("application/json", "json", serde_json::to_vec_pretty(&products).map_err(ApiError::internal)?),
+        _ => ("text/csv", "csv", write_csv(&products).map_err(ApiError::internal)?),
+    };
+
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"products.{}\"", extension))
+        .body(Body::from(body))
+        .unwrap())
+}
+
+fn write_csv(products: &[CatalogProduct]) -> Result<Vec<u8>, csv::Error> {
+    let text = |value: &Option<String>| value.clone().unwrap_or_default();
+    let number = |value: Option<f64>| value.map(|v| v.to_string()).unwrap_or_default();
+
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(COLUMNS)?;
+    for p in products {
+        let components: Vec<String> = p.components.iter().map(|c| format!("{}:{}", c.sku.as_deref().unwrap_or_default(), c.quantity)).collect();
+        writer.write_record([
+            text(&p.sku),
+            text(&p.name),
+            text(&p.description),
+            number(p.price),
+            text(&p.unit),
+            text(&p.tax_category),
+            text(&p.status),
+            number(p.cost_price),
+            p.track_inventory.map(|t| t.to_string()).unwrap_or_default(),
+            number(p.low_stock_threshold),
+            text(&p.kind),
+            text(&p.bundle_pricing),
+            components.join(";"),
+        ])?;
+    }
+    writer.into_inner().map_err(|e| e.into_error().into())
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ImportRequest {
+    /// `csv` or `json`.
+    format: String,
+    /// The file's contents.
+    data: String,
+    /// CSV header → product field; unmapped headers that name a field are used as is.
+    #[serde(default)]
+    mapping: HashMap<String, String>,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+impl Validate for ImportRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.one_of("format", self.format.as_str(), FORMATS);
+        v.required("data", &self.data);
+        for (header, field) in &self.mapping {
+            v.one_of(&format!("mapping.{}", header), field.as_str(), COLUMNS);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum ImportAction {
+    Create,
+    /// The row's SKU is an existing product's.
+    Update,
+}
+
+#[derive(Serialize)]
+struct RowReport {
+    row: usize,
+    action: ImportAction,
+    /// The product as it will be saved.
+    product: CatalogProduct,
+    /// The product updated, or created by a real run.
+    product_id: Option<i32>,
+    errors: Vec<FieldError>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ImportReport {
+    dry_run: bool,
+    created: usize,
+    updated: usize,
+    invalid: usize,
+    rows: Vec<RowReport>,
+}
+
+pub(crate) async fn import_catalog(
+    auth: AuthContext,
+    State(state): State<Arc<AppState>>,
+    Valid(payload): Valid<ImportRequest>,
+) -> Result<Json<ImportReport>, ApiError> {
+    let parsed = match payload.format.as_str() {
+        "csv" => parse_csv(&payload.data, &payload.mapping)?,
+        _ => parse_json(&payload.data)?,
+    };
+    if parsed.len() > MAX_ROWS {
+        return Err(ApiError::Validation(vec![FieldError {
+            field: "data".to_string(),
+            message: format!("must have at most {} rows", MAX_ROWS),
+        }]));
+    }
+
+    let mut tx = state.db.begin().await?;
+    let existing = load_catalog(&mut tx, auth.user_id).await?;
+    let by_sku: HashMap<&str, &CatalogProduct> = existing.iter().filter_map(|p| Some((p.sku.as_deref()?, p))).collect();
+    let tax_categories: Vec<String> = sqlx::query_scalar("SELECT k FROM companies, jsonb_object_keys(tax_rates) AS k WHERE user_id = $1")
+        .bind(auth.user_id)
+        .fetch_all(&mut *tx)
+        .await?;
+    let in_bundles: Vec<i32> = sqlx::query_scalar("SELECT DISTINCT bc.component_id FROM bundle_components bc JOIN products p ON p.id = bc.bundle_id WHERE p.user_id = $1")
+        .bind(auth.user_id)
+        .fetch_all(&mut *tx)
+        .await?;
+
+    let mut rows = Vec::with_capacity(parsed.len());
+    for (i, (row, mut errors)) in parsed.into_iter().enumerate() {
+        let matched = row.sku.as_deref().and_then(|sku| by_sku.get(sku));
+        let (action, product) = match matched {
+            Some(existing) => (ImportAction::Update, (*existing).clone().overlaid(&row)),
+            None => {
+                if row.price.is_none() && errors.is_empty() {
+                    errors.push(FieldError { field: "price".to_string(), message: "is required for a new product".to_string() });
+                }
+                (ImportAction::Create, CatalogProduct { id: None, ..row })
+            }
+        };
+        let repeated = product.sku.is_some() && rows.iter().any(|r: &RowReport| r.product.sku == product.sku);
+        if repeated {
+            errors.push(FieldError { field: "sku".to_string(), message: "is already on an earlier row".to_string() });
+        }
+        rows.push(RowReport { row: i + 1, action, product_id: product.id, product, errors });
+    }
+
+    // Products referred to as components: existing ones, and the file's new
+    // ones under a placeholder id until they are created
+    let mut ids: HashMap<String, i32> = existing.iter().filter_map(|p| Some((p.sku.clone()?, p.id?))).collect();
+    let mut kinds: HashMap<String, bool> = existing.iter().filter_map(|p| Some((p.sku.clone()?, p.is_bundle()))).collect();
+    let mut seen = HashSet::new();
+    for row in &rows {
+        if let Some(sku) = row.product.sku.as_ref().filter(|sku| seen.insert(*sku)) {
+            ids.entry(sku.clone()).or_insert(-(row.row as i32));
+            kinds.insert(sku.clone(), row.product.is_bundle());
+        }
+    }
+
+    for row in &mut rows {
+        let (request, errors) = row.product.to_request(&ids);
+        row.errors.extend(errors);
+        row.errors.extend(Validator::collect(&request));
+        for (i, component) in row.product.components.iter().enumerate() {
+            if component.sku.as_ref().and_then(|sku| kinds.get(sku)) == Some(&true) {
+                row.errors.push(FieldError { field: format!("components[{}].sku", i), message: "is a bundle, which can't be a component".to_string() });
+            }
+        }
+        if row.product.is_bundle() && row.product_id.is_some_and(|id| in_bundles.contains(&id)) {
+            row.errors.push(FieldError { field: "kind".to_string(), message: "can't be bundle for a product that is a component of a bundle".to_string() });
+        }
+        let category = request.tax_category.as_deref().filter(|c| *c != STANDARD_TAX_CATEGORY);
+        if category.is_some_and(|c| !tax_categories.iter().any(|known| known == c)) {
+            row.errors.push(FieldError { field: "tax_category".to_string(), message: "is not one of the company's tax rates".to_string() });
+        }
+    }
+
+    let invalid = rows.iter().filter(|r| !r.errors.is_empty()).count();
+    if !payload.dry_run && invalid > 0 {
+        let errors = rows
+            .iter()
+            .flat_map(|r| r.errors.iter().map(move |e| FieldError { field: format!("rows[{}].{}", r.row, e.field), message: e.message.clone() }))
+            .collect();
+        return Err(ApiError::Validation(errors));
+    }
+
+    if !payload.dry_run {
+        // Products first, then components, which may be products created further down
+        for row in &mut rows {
+            let (request, _) = row.product.to_request(&ids);
+            match row.product_id {
+                Some(id) => write_product(&mut tx, auth.user_id, id, &request).await?,
+                None => {
+                    let id = insert_product(&mut tx, auth.user_id, &request).await?;
+                    if let Some(sku) = &row.product.sku {
+                        ids.insert(sku.clone(), id);
+                    }
+                    row.product_id = Some(id);
+                }
+            }
+        }
+        for row in &rows {
+            let (components, _) = component_requests(&row.product.components, &ids);
+            if let Some(id) = row.product_id {
+                bundles::save_components(&mut tx, auth.user_id, id, row.product.is_bundle(), &components).await?;
+            }
+        }
+        tx.commit().await?;
+    }
+
+    let count = |action| rows.iter().filter(|r| r.action == action && r.errors.is_empty()).count();
+    let (created, updated) = (count(ImportAction::Create), count(ImportAction::Update));
+    if !payload.dry_run {
+        PRODUCTS_CREATED.inc_by(&[], created as u64);
+        tracing::info!("Imported products for user {}: {} created, {} updated", auth.user_id, created, updated);
+    }
+
+    Ok(Json(ImportReport { dry_run: payload.dry_run, created, updated, invalid, rows }))
+}
+
+type ParsedRow = (CatalogProduct, Vec<FieldError>);
+
+fn invalid_data(message: String) -> ApiError {
+    ApiError::Validation(vec![FieldError { field: "data".to_string(), message }])
+}
+
+fn parse_json(data: &str) -> Result<Vec<ParsedRow>, ApiError> {
+    let values: Vec<serde_json::Value> = serde_json::from_str(data).map_err(|e| invalid_data(format!("is not a JSON array: {}", e)))?;
+    Ok(values
+        .into_iter()
+        .map(|value| {
+            // An invalid row still matches its product by SKU, for the report
+            let sku = value.get("sku").and_then(|s| s.as_str()).map(str::to_string);
+            match serde_json::from_value::<CatalogProduct>(value) {
+                Ok(product) => (product, Vec::new()),
+                Err(e) => (CatalogProduct { sku, ..Default::default() }, vec![FieldError { field: "row".to_string(), message: e.to_string() }]),
+            }
+        })
+        .collect())
+}
+
+fn parse_csv(data: &str, mapping: &HashMap<String, String>) -> Result<Vec<ParsedRow>, ApiError> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true).trim(csv::Trim::All).from_reader(data.as_bytes());
+    let headers = reader.headers().map_err(|e| invalid_data(format!("is not valid CSV: {}", e)))?.clone();
+    let columns: Vec<Option<&str>> = headers
+        .iter()
+        .map(|header| match mapping.get(header) {
+            Some(field) => Some(field.as_str()),
+            // "SKU", "Cost price" and the like match without a mapping
+            None => COLUMNS.iter().find(|f| squashed(f) == squashed(header)).copied(),
+        })
+        .collect();
+    if !columns.contains(&Some("name")) && !columns.contains(&Some("sku")) {
+        return Err(ApiError::Validation(vec![FieldError {
+            field: "mapping".to_string(),
+            message: "no column is mapped to name or sku".to_string(),
+        }]));
+    }
+
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(|e| invalid_data(format!("is not valid CSV: {}", e)))?;
+        let fields: HashMap<&str, String> = columns
+            .iter()
+            .zip(record.iter())
+            .filter_map(|(field, value)| Some((field.as_ref().copied()?, value.to_string())))
+            .filter(|(_, value)| !value.is_empty())
+            .collect();
+        rows.push(product_from_fields(fields));
+    }
+    Ok(rows)
+}
+
+fn squashed(name: &str) -> String {
+    name.chars().filter(char::is_ascii_alphanumeric).map(|c| c.to_ascii_lowercase()).collect()
+}
+
+/// Builds a product from field → value pairs, reporting values that don't parse.
+fn product_from_fields(mut fields: HashMap<&str, String>) -> ParsedRow {
+    let mut errors = Vec::new();
+    let mut number = |field: &str, fields: &mut HashMap<&str, String>| {
+        fields.remove(field).and_then(|raw| match raw.parse::<f64>() {
+            Ok(value) if value.is_finite() => Some(value),
+            _ => {
+                errors.push(FieldError { field: field.to_string(), message: "must be a number".to_string() });
+                None
+            }
+        })
+    };
+    let price = number("price", &mut fields);
+    let cost_price = number("cost_price", &mut fields);
+    let low_stock_threshold = number("low_stock_threshold", &mut fields);
+
+    let track_inventory = fields.remove("track_inventory").and_then(|raw| match raw.to_ascii_lowercase().as_str() {
+        "true" | "yes" | "1" => Some(true),
+        "false" | "no" | "0" => Some(false),
+        _ => {
+            errors.push(FieldError { field: "track_inventory".to_string(), message: "must be true or false".to_string() });
+            None
+        }
+    });
+
+    // `SKU:quantity` pairs separated by semicolons; the quantity defaults to 1
+    let mut components = Vec::new();
+    for (i, part) in fields.remove("components").unwrap_or_default().split(';').map(str::trim).filter(|p| !p.is_empty()).enumerate() {
+        let (sku, quantity) = match part.rsplit_once(':') {
+            Some((sku, quantity)) => (sku.trim(), quantity.trim().parse::<f64>().ok()),
+            None => (part, Some(1.0)),
+        };
+        match quantity {
+            Some(quantity) => components.push(CatalogComponent { sku: Some(sku.to_string()), quantity, product_id: None }),
+            None => errors.push(FieldError { field: format!("components[{}].quantity", i), message: "must be a number".to_string() }),
+        }
+    }
+
+    let product = CatalogProduct {
+        id: None,
+        sku: fields.remove("sku"),
+        name: fields.remove("name"),
+        description: fields.remove("description"),
+        price,
+        unit: fields.remove("unit"),
+        tax_category: fields.remove("tax_category"),
+        status: fields.remove("status"),
+        cost_price,
+        track_inventory,
+        low_stock_threshold,
+        kind: fields.remove("kind"),
+        bundle_pricing: fields.remove("bundle_pricing"),
+        components,
+    };
+    (product, errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn catalog() -> Vec<CatalogProduct> {
+        let component = |sku: &str, quantity| CatalogComponent { sku: Some(sku.to_string()), quantity, product_id: None };
+        vec![
+            CatalogProduct {
+                sku: Some("BOLT-10".to_string()),
+                name: Some("Bolt, 10 mm \"hex\"".to_string()),
+                description: Some("Zinc plated;\nsold per piece".to_string()),
+                price: Some(0.35),
+                unit: Some("piece".to_string()),
+                cost_price: Some(0.12),
+                track_inventory: Some(true),
+                low_stock_threshold: Some(500.0),
+                kind: Some("simple".to_string()),
+                ..Default::default()
+            },
+            CatalogProduct {
+                sku: Some("KIT-1".to_string()),
+                name: Some("Fixing kit".to_string()),
+                price: Some(9.9),
+                track_inventory: Some(false),
+                kind: Some("bundle".to_string()),
+                bundle_pricing: Some("components".to_string()),
+                components: vec![component("BOLT-10", 20.0), component("NUT-10", 2.5)],
+                ..Default::default()
+            },
+        ]
+    }
+
+    fn as_json(products: &[CatalogProduct]) -> serde_json::Value {
+        serde_json::to_value(products).unwrap()
+    }
+
+    fn parsed(rows: Vec<ParsedRow>) -> Vec<CatalogProduct> {
+        rows.into_iter()
+            .map(|(product, errors)| {
+                assert!(errors.is_empty(), "{:?}", errors);
+                product
+            })
+            .collect()
+    }
+
+    #[test]
+    fn csv_export_imports_back_unchanged() {
+        let csv = String::from_utf8(write_csv(&catalog()).unwrap()).unwrap();
+        let products = parsed(parse_csv(&csv, &HashMap::new()).unwrap());
+        assert_eq!(as_json(&products), as_json(&catalog()));
+    }
+
+    #[test]
+    fn json_export_imports_back_unchanged() {
+        let json = serde_json::to_string(&catalog()).unwrap();
+        let products = parsed(parse_json(&json).unwrap());
+        assert_eq!(as_json(&products), as_json(&catalog()));
+    }
+
+    #[test]
+    fn reads_csv_with_headers_matched_loosely_or_mapped() {
+        let data = "Article,Title,Cost price,Stock tracked,Parts\nKIT-2,Kit,1.5,yes,BOLT-10:4; NUT-10\n";
+        let mapping = HashMap::from([
+            ("Article".to_string(), "sku".to_string()),
+            ("Title".to_string(), "name".to_string()),
+            ("Stock tracked".to_string(), "track_inventory".to_string()),
+            ("Parts".to_string(), "components".to_string()),
+        ]);
+        let product = parsed(parse_csv(data, &mapping).unwrap()).remove(0);
+        assert_eq!((product.sku.as_deref(), product.name.as_deref()), (Some("KIT-2"), Some("Kit")));
+        assert_eq!((product.cost_price, product.track_inventory, product.price), (Some(1.5), Some(true), None));
+        let components: Vec<_> = product.components.iter().map(|c| (c.sku.as_deref().unwrap(), c.quantity)).collect();
+        assert_eq!(components, [("BOLT-10", 4.0), ("NUT-10", 1.0)]);
+    }
+
+    #[test]
+    fn reports_csv_values_that_do_not_parse() {
+        let data = "sku,price,track_inventory,components\nX,1.2.3,maybe,A:2;B:lots\n";
+        let rows = parse_csv(data, &HashMap::new()).unwrap();
+        let fields: Vec<&str> = rows[0].1.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, ["price", "track_inventory", "components[1].quantity"]);
+    }
+
+    #[test]
+    fn csv_needs_a_name_or_sku_column() {
+        let result = parse_csv("price\n1\n", &HashMap::new());
+        assert!(matches!(result, Err(ApiError::Validation(errors)) if errors[0].field == "mapping"));
+    }
+
+    #[test]
+    fn reports_json_rows_that_do_not_parse_by_sku() {
+        let rows = parse_json(r#"[{"sku": "A", "price": "cheap"}, {"sku": "B", "price": 2}]"#).unwrap();
+        assert_eq!(rows[0].0.sku.as_deref(), Some("A"));
+        assert_eq!(rows[0].1[0].field, "row");
+        assert!(rows[1].1.is_empty());
+        assert!(parse_json(r#"{"sku": "A"}"#).is_err());
+    }
+
+    #[test]
+    fn import_overlays_only_the_fields_given() {
+        let existing = catalog().remove(1);
+        let row = CatalogProduct { price: Some(12.0), description: Some("Now with nuts".to_string()), ..Default::default() };
+        let product = existing.overlaid(&row);
+        assert_eq!(product.price, Some(12.0));
+        assert_eq!(product.description.as_deref(), Some("Now with nuts"));
+        assert_eq!(product.name.as_deref(), Some("Fixing kit"));
+        assert_eq!(product.components.len(), 2);
+    }
+
+    #[test]
+    fn resolves_components_by_sku_or_existing_id() {
+        let ids = HashMap::from([("BOLT-10".to_string(), 7)]);
+        let components = [
+            CatalogComponent { sku: Some("BOLT-10".to_string()), quantity: 2.0, product_id: None },
+            CatalogComponent { sku: None, quantity: 1.0, product_id: Some(9) },
+            CatalogComponent { sku: Some("NOPE".to_string()), quantity: 1.0, product_id: None },
+        ];
+        let (requests, errors) = component_requests(&components, &ids);
+        assert_eq!(requests.iter().map(|r| (r.product_id, r.quantity)).collect::<Vec<_>>(), [(7, 2.0), (9, 1.0)]);
+        assert_eq!(errors.iter().map(|e| e.field.as_str()).collect::<Vec<_>>(), ["components[2].sku"]);
+    }
+}
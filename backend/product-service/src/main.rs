@@ -1,28 +1,39 @@
 use axum::{
-    routing::{get, post, put, delete},
+    routing::{get, post},
     Router,
     Json,
     http::StatusCode,
-    extract::{State, Path},
+    extract::{DefaultBodyLimit, State, Path},
 };
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
-use tower_http::cors::CorsLayer;
-use common::AuthContext;
+use common::billing::STANDARD_TAX_CATEGORY;
+use common::bootstrap;
+use common::{ApiError, AuthContext, AuthState, FieldError, JwtVerifier, ListQuery, ListSpec, Page, Valid, Validate, Validator};
+use common::{FieldKind, Filter, FilterOp, SortKey};
+use common::account_data::{AccountDataPart, ArchiveFile, ErasureReport};
+use common::metrics::Counter;
 use std::sync::Arc;
-use sqlx::postgres::PgPoolOptions;
 use sqlx::{Pool, Postgres, FromRow};
 
+mod bundles;
+mod catalog;
+mod price_lists;
+mod stock;
+
 type DbPool = Pool<Postgres>;
 
 struct AppState {
     db: DbPool,
-    jwt_secret: Arc<String>,
+    jwt_verifier: JwtVerifier,
 }
 
-impl axum::extract::FromRef<Arc<AppState>> for Arc<String> {
-    fn from_ref(state: &Arc<AppState>) -> Self {
-        state.jwt_secret.clone()
+impl AuthState for AppState {
+    fn jwt_verifier(&self) -> &JwtVerifier {
+        &self.jwt_verifier
+    }
+
+    fn db(&self) -> Option<&DbPool> {
+        Some(&self.db)
     }
 }
 
@@ -30,82 +41,200 @@ impl axum::extract::FromRef<Arc<AppState>> for Arc<String> {
 struct Product {
     id: i32,
     user_id: i32,
+    sku: Option<String>,
     name: String,
     description: Option<String>,
     #[sqlx(default)]
     price: f64,
+    unit: String,
+    tax_category: String,
+    /// The company's rate for `tax_category`, if it has one.
+    tax_rate: Option<f64>,
+    status: String,
+    cost_price: Option<f64>,
+    /// `price` less `cost_price`.
+    margin: Option<f64>,
+    track_inventory: bool,
+    stock_on_hand: f64,
+    low_stock_threshold: Option<f64>,
+    /// `in_stock`, `low` (at or below `low_stock_threshold`) or `out`; `None`
+    /// when the product doesn't track inventory.
+    stock_status: Option<String>,
+    /// `simple`, `bundle` or `metered`.
+    kind: String,
+    /// `fixed` or `components` for bundles.
+    bundle_pricing: Option<String>,
+    #[sqlx(skip)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    components: Vec<bundles::BundleComponent>,
 }
 
 #[derive(Deserialize)]
 struct CreateProductRequest {
+    sku: Option<String>,
     name: String,
     description: Option<String>,
     price: f64,
+    /// `hour`, `day`, `piece` (the default) or any other unit.
+    unit: Option<String>,
+    /// `standard` (the default) or one of the company's `tax_rates`.
+    tax_category: Option<String>,
+    status: Option<String>,
+    cost_price: Option<f64>,
+    /// Stock starts at 0; it changes through stock movements only.
+    track_inventory: Option<bool>,
+    low_stock_threshold: Option<f64>,
+    /// `simple` (the default), `bundle` or `metered` (billed by recorded usage).
+    kind: Option<String>,
+    /// `fixed` (the default, at `price`) or `components`, for bundles.
+    bundle_pricing: Option<String>,
+    /// A bundle's products and their quantity per bundle.
+    #[serde(default)]
+    components: Vec<bundles::ComponentRequest>,
+}
+
+impl Validate for CreateProductRequest {
+    fn validate(&self, v: &mut Validator) {
+        if let Some(sku) = &self.sku {
+            v.required("sku", sku);
+            v.max_len("sku", sku.as_str(), 64);
+        }
+        v.required("name", &self.name);
+        v.max_len("name", self.name.as_str(), 255);
+        v.min("price", self.price, 0.0);
+        if let Some(unit) = &self.unit {
+            v.required("unit", unit);
+            v.max_len("unit", unit.as_str(), 20);
+        }
+        v.tax_category("tax_category", self.tax_category.as_deref());
+        v.one_of("status", self.status.as_deref(), PRODUCT_STATUSES);
+        v.min("cost_price", self.cost_price, 0.0);
+        v.min("low_stock_threshold", self.low_stock_threshold, 0.0);
+        bundles::ProductKind {
+            kind: self.kind.as_deref(),
+            bundle_pricing: self.bundle_pricing.as_deref(),
+            components: &self.components,
+            track_inventory: self.track_inventory,
+        }
+        .validate(v);
+    }
+}
+
+impl CreateProductRequest {
+    fn is_bundle(&self) -> bool {
+        self.kind.as_deref() == Some("bundle")
+    }
+
+    fn bundle_pricing(&self) -> Option<&str> {
+        self.is_bundle().then(|| self.bundle_pricing.as_deref().unwrap_or("fixed"))
+    }
 }
 
+const PRODUCT_STATUSES: &[&str] = &["active", "archived"];
+
+const PRODUCT_LIST: ListSpec = ListSpec {
+    columns: "id, user_id, sku, name, description, COALESCE(b.components_price, price)::float8 as price, unit, tax_category, \
+              (SELECT COALESCE((c.tax_rates ->> products.tax_category)::float8, \
+                               CASE WHEN products.tax_category = 'standard' THEN c.default_tax_rate::float8 END) \
+               FROM companies c WHERE c.user_id = products.user_id) AS tax_rate, \
+              status, cost_price::float8 as cost_price, (COALESCE(b.components_price, price) - cost_price)::float8 AS margin, \
+              track_inventory, stock_on_hand::float8 AS stock_on_hand, low_stock_threshold::float8 AS low_stock_threshold, \
+              CASE WHEN NOT track_inventory THEN NULL WHEN stock_on_hand <= 0 THEN 'out' \
+                   WHEN stock_on_hand <= low_stock_threshold THEN 'low' ELSE 'in_stock' END AS stock_status, \
+              kind, bundle_pricing",
+    // A bundle priced by its components costs their sum
+    from: "FROM products LEFT JOIN LATERAL ( \
+             SELECT SUM(bc.quantity * cp.price) AS components_price FROM bundle_components bc JOIN products cp ON cp.id = bc.component_id \
+             WHERE bc.bundle_id = products.id AND products.bundle_pricing = 'components' \
+           ) b ON TRUE",
+    owner_column: "user_id",
+    id_column: "id",
+    sort_keys: &[
+        SortKey::new("name", "lower(name)", FieldKind::Text),
+        SortKey::new("sku", "sku", FieldKind::Text),
+        SortKey::new("price", "price", FieldKind::Number),
+        SortKey::new("stock_on_hand", "stock_on_hand", FieldKind::Number),
+        SortKey::new("created_at", "COALESCE(created_at, 'epoch'::timestamptz)", FieldKind::Timestamp),
+    ],
+    default_sort: "name",
+    filters: &[
+        Filter::new("q", "name", FieldKind::Text, FilterOp::Contains),
+        Filter::new("min_price", "price", FieldKind::Number, FilterOp::Min),
+        Filter::new("max_price", "price", FieldKind::Number, FilterOp::Max),
+        Filter::new("sku", "sku", FieldKind::Text, FilterOp::In),
+        Filter::new("unit", "unit", FieldKind::Text, FilterOp::In),
+        Filter::new("tax_category", "tax_category", FieldKind::Text, FilterOp::In),
+        Filter::new("status", "status", FieldKind::Text, FilterOp::In),
+        Filter::new("kind", "kind", FieldKind::Text, FilterOp::In),
+        Filter::new(
+            "stock_status",
+            "CASE WHEN NOT track_inventory THEN NULL WHEN stock_on_hand <= 0 THEN 'out' WHEN stock_on_hand <= low_stock_threshold THEN 'low' ELSE 'in_stock' END",
+            FieldKind::Text,
+            FilterOp::In,
+        ),
+    ],
+    // Archived products only show up when asked for with `status=archived`
+    default_filters: &[("status", "active")],
+};
+
+/// Catalog files hold up to 5000 rows, more than fits the default body limit.
+const IMPORT_BODY_LIMIT: usize = 20 * 1024 * 1024;
+
+static PRODUCTS_CREATED: Counter = Counter::new("products_created_total", "Products created", &[]);
+
 #[tokio::main]
 async fn main() {
-    dotenvy::dotenv().ok();
-    tracing_subscriber::fmt::init();
+    let config = bootstrap::init("product-service", 5004);
+    let pool = config.connect_database().await;
 
-    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&database_url)
-        .await
-        .expect("Failed to create pool");
-
-    let jwt_secret = Arc::new(std::env::var("JWT_SECRET").unwrap_or_else(|_| "supersecret".to_string()));
+    let jwt_verifier = config.jwt_verifier();
     
     let state = Arc::new(AppState {
-        db: pool,
-        jwt_secret,
+        db: pool.clone(),
+        jwt_verifier,
     });
 
     let app = Router::new()
         .route("/api/products", get(list_products).post(create_product))
+        .route("/api/products/stock-valuation", get(stock::get_stock_valuation))
+        .route("/api/products/export", get(catalog::export_catalog))
+        .route("/api/products/import", post(catalog::import_catalog).layer(DefaultBodyLimit::max(IMPORT_BODY_LIMIT)))
         .route("/api/products/:id", get(get_product).put(update_product).delete(delete_product))
-        .layer(CorsLayer::permissive())
+        .route("/api/products/:id/price", get(price_lists::get_product_price))
+        .route("/api/price-lists", get(price_lists::list_price_lists).post(price_lists::create_price_list))
+        .route("/api/price-lists/:id", get(price_lists::get_price_list).put(price_lists::update_price_list).delete(price_lists::delete_price_list))
+        .route("/api/stock-movements", get(stock::list_stock_movements).post(stock::create_adjustment))
+        .route("/internal/account-data/products", get(export_account_data).delete(erase_account_data))
         .with_state(state);
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 5004));
-    tracing::info!("Product service listening on {}", addr);
-    
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    bootstrap::serve(config, app, Some(pool)).await;
 }
 
 async fn list_products(
     auth: AuthContext,
     State(state): State<Arc<AppState>>,
-) -> Result<Json<Vec<Product>>, (StatusCode, String)> {
-    let products = sqlx::query_as::<_, Product>(
-        "SELECT id, user_id, name, description, price::float8 as price FROM products WHERE user_id = $1"
-    )
-    .bind(auth.user_id)
-    .fetch_all(&state.db)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-
-    Ok(Json(products))
+    query: ListQuery,
+) -> Result<Json<Page<Product>>, ApiError> {
+    let mut page = query.fetch(&state.db, &PRODUCT_LIST, auth.user_id).await?;
+    let mut conn = state.db.acquire().await?;
+    attach_components(&mut conn, &mut page.items).await?;
+    Ok(Json(page))
 }
 
 async fn create_product(
     auth: AuthContext,
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<CreateProductRequest>,
-) -> Result<Json<Product>, (StatusCode, String)> {
-    let product = sqlx::query_as::<_, Product>(
-        "INSERT INTO products (user_id, name, description, price) VALUES ($1, $2, $3, $4) RETURNING id, user_id, name, description, price::float8 as price"
-    )
-    .bind(auth.user_id)
-    .bind(payload.name)
-    .bind(payload.description)
-    .bind(payload.price)
-    .fetch_one(&state.db)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Valid(payload): Valid<CreateProductRequest>,
+) -> Result<Json<Product>, ApiError> {
+    check_tax_category(&state.db, auth.user_id, payload.tax_category.as_deref()).await?;
+
+    let mut tx = state.db.begin().await?;
+    let id = insert_product(&mut tx, auth.user_id, &payload).await?;
+    bundles::save_components(&mut tx, auth.user_id, id, payload.is_bundle(), &payload.components).await?;
+    let product = fetch_product(&mut tx, id, auth.user_id).await?;
+    tx.commit().await?;
 
+    PRODUCTS_CREATED.inc(&[]);
     Ok(Json(product))
 }
 
@@ -113,56 +242,223 @@ async fn get_product(
     auth: AuthContext,
     Path(id): Path<i32>,
     State(state): State<Arc<AppState>>,
-) -> Result<Json<Product>, (StatusCode, String)> {
-    let product = sqlx::query_as::<_, Product>(
-        "SELECT id, user_id, name, description, price::float8 as price FROM products WHERE id = $1 AND user_id = $2"
-    )
-    .bind(id)
-    .bind(auth.user_id)
-    .fetch_optional(&state.db)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
-    .ok_or((StatusCode::NOT_FOUND, "Product not found".to_string()))?;
-
+) -> Result<Json<Product>, ApiError> {
+    let mut conn = state.db.acquire().await?;
+    let product = fetch_product(&mut conn, id, auth.user_id).await?;
     Ok(Json(product))
 }
 
+async fn fetch_product(conn: &mut sqlx::PgConnection, id: i32, user_id: i32) -> Result<Product, ApiError> {
+    let product = sqlx::query_as::<_, Product>(&format!("SELECT {} {} WHERE id = $1 AND user_id = $2", PRODUCT_LIST.columns, PRODUCT_LIST.from))
+        .bind(id)
+        .bind(user_id)
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or(ApiError::NotFound("Product not found".to_string()))?;
+    let mut products = [product];
+    attach_components(conn, &mut products).await?;
+    let [product] = products;
+    Ok(product)
+}
+
+async fn attach_components(conn: &mut sqlx::PgConnection, products: &mut [Product]) -> Result<(), ApiError> {
+    let bundle_ids: Vec<i32> = products.iter().filter(|p| p.kind == "bundle").map(|p| p.id).collect();
+    if bundle_ids.is_empty() {
+        return Ok(());
+    }
+    let mut components = bundles::load_components(conn, &bundle_ids).await?;
+    for product in products {
+        product.components = components.remove(&product.id).unwrap_or_default();
+    }
+    Ok(())
+}
+
+const SKU_TAKEN: &str = "Another product already has this SKU";
+
+/// Categories other than `standard` must be among the company's `tax_rates`.
+async fn check_tax_category(db: &DbPool, user_id: i32, category: Option<&str>) -> Result<(), ApiError> {
+    let Some(category) = category.filter(|c| *c != STANDARD_TAX_CATEGORY) else { return Ok(()) };
+    let known: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM companies WHERE user_id = $1 AND tax_rates ? $2)")
+        .bind(user_id)
+        .bind(category)
+        .fetch_one(db)
+        .await?;
+    if !known {
+        return Err(ApiError::Validation(vec![FieldError {
+            field: "tax_category".to_string(),
+            message: "is not one of the company's tax rates".to_string(),
+        }]));
+    }
+    Ok(())
+}
+
 async fn update_product(
     auth: AuthContext,
     Path(id): Path<i32>,
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<CreateProductRequest>,
-) -> Result<Json<Product>, (StatusCode, String)> {
-    let product = sqlx::query_as::<_, Product>(
-        "UPDATE products SET name = $1, description = $2, price = $3 WHERE id = $4 AND user_id = $5 RETURNING id, user_id, name, description, price::float8 as price"
+    Valid(payload): Valid<CreateProductRequest>,
+) -> Result<Json<Product>, ApiError> {
+    check_tax_category(&state.db, auth.user_id, payload.tax_category.as_deref()).await?;
+
+    let mut tx = state.db.begin().await?;
+    write_product(&mut tx, auth.user_id, id, &payload).await?;
+    bundles::save_components(&mut tx, auth.user_id, id, payload.is_bundle(), &payload.components).await?;
+    let product = fetch_product(&mut tx, id, auth.user_id).await?;
+    tx.commit().await?;
+
+    Ok(Json(product))
+}
+
+/// Inserts a product without its components (see `bundles::save_components`).
+async fn insert_product(tx: &mut sqlx::PgConnection, user_id: i32, payload: &CreateProductRequest) -> Result<i32, ApiError> {
+    sqlx::query_scalar(
+        "INSERT INTO products (user_id, sku, name, description, price, unit, tax_category, status, cost_price, track_inventory, low_stock_threshold, kind, bundle_pricing) \
+         VALUES ($1, $2, $3, $4, $5, COALESCE($6, 'piece'), COALESCE($7, 'standard'), COALESCE($8, 'active'), $9, COALESCE($10, FALSE), $11, COALESCE($12, 'simple'), $13) RETURNING id"
     )
-    .bind(payload.name)
-    .bind(payload.description)
+    .bind(user_id)
+    .bind(&payload.sku)
+    .bind(&payload.name)
+    .bind(&payload.description)
     .bind(payload.price)
-    .bind(id)
-    .bind(auth.user_id)
-    .fetch_one(&state.db)
+    .bind(&payload.unit)
+    .bind(&payload.tax_category)
+    .bind(&payload.status)
+    .bind(payload.cost_price)
+    .bind(payload.track_inventory)
+    .bind(payload.low_stock_threshold)
+    .bind(&payload.kind)
+    .bind(payload.bundle_pricing())
+    .fetch_one(&mut *tx)
     .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    .map_err(|e| ApiError::from(e).on_conflict(SKU_TAKEN))
+}
 
-    Ok(Json(product))
+/// Overwrites a product's fields, leaving its components.
+async fn write_product(tx: &mut sqlx::PgConnection, user_id: i32, id: i32, payload: &CreateProductRequest) -> Result<(), ApiError> {
+    sqlx::query_scalar::<_, i32>(
+        "UPDATE products SET sku = $1, name = $2, description = $3, price = $4, unit = COALESCE($5, 'piece'), \
+         tax_category = COALESCE($6, 'standard'), status = COALESCE($7, 'active'), cost_price = $8, \
+         track_inventory = COALESCE($9, FALSE), low_stock_threshold = $10, kind = COALESCE($11, 'simple'), bundle_pricing = $12 \
+         WHERE id = $13 AND user_id = $14 RETURNING id"
+    )
+    .bind(&payload.sku)
+    .bind(&payload.name)
+    .bind(&payload.description)
+    .bind(payload.price)
+    .bind(&payload.unit)
+    .bind(&payload.tax_category)
+    .bind(&payload.status)
+    .bind(payload.cost_price)
+    .bind(payload.track_inventory)
+    .bind(payload.low_stock_threshold)
+    .bind(&payload.kind)
+    .bind(payload.bundle_pricing())
+    .bind(id)
+    .bind(user_id)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|e| ApiError::from(e).on_conflict(SKU_TAKEN))?
+    .ok_or(ApiError::NotFound("Product not found".to_string()))?;
+    Ok(())
 }
 
 async fn delete_product(
     auth: AuthContext,
     Path(id): Path<i32>,
     State(state): State<Arc<AppState>>,
-) -> Result<StatusCode, (StatusCode, String)> {
-    let result = sqlx::query("DELETE FROM products WHERE id = $1 AND user_id = $2")
+) -> Result<StatusCode, ApiError> {
+    let mut tx = state.db.begin().await?;
+    // Locking the row keeps it from being invoiced in the meantime
+    sqlx::query("SELECT id FROM products WHERE id = $1 AND user_id = $2 FOR UPDATE")
         .bind(id)
         .bind(auth.user_id)
-        .execute(&state.db)
-        .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(ApiError::NotFound("Product not found".to_string()))?;
 
-    if result.rows_affected() == 0 {
-        return Err((StatusCode::NOT_FOUND, "Product not found".to_string()));
+    // Invoice lines keep pointing at their product for revenue reports
+    let lines: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM invoice_items WHERE product_id = $1")
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await?;
+    if lines > 0 {
+        return Err(ApiError::Conflict(format!(
+            "Product is on {} invoice line(s) and cannot be deleted; archive it instead",
+            lines
+        )));
     }
 
+    let bundles: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM bundle_components WHERE component_id = $1")
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await?;
+    if bundles > 0 {
+        return Err(ApiError::Conflict(format!(
+            "Product is a component of {} bundle(s) and cannot be deleted; archive it instead",
+            bundles
+        )));
+    }
+
+    let usage: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM usage_events WHERE product_id = $1")
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await?;
+    if usage > 0 {
+        return Err(ApiError::Conflict(format!(
+            "Product has {} usage event(s) and cannot be deleted; archive it instead",
+            usage
+        )));
+    }
+
+    sqlx::query("DELETE FROM products WHERE id = $1")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
     Ok(StatusCode::NO_CONTENT)
 }
+
+async fn export_account_data(
+    auth: AuthContext,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<AccountDataPart>, ApiError> {
+    let mut products = sqlx::query_as::<_, Product>(&format!("SELECT {} {} WHERE user_id = $1", PRODUCT_LIST.columns, PRODUCT_LIST.from))
+    .bind(auth.user_id)
+    .fetch_all(&state.db)
+    .await?;
+    let mut conn = state.db.acquire().await?;
+    attach_components(&mut conn, &mut products).await?;
+
+    let price_lists = price_lists::export_price_lists(&state.db, auth.user_id).await?;
+    let stock_movements = stock::export_stock_movements(&state.db, auth.user_id).await?;
+
+    let files = vec![
+        ArchiveFile::json("products.json", &products).map_err(ApiError::internal)?,
+        ArchiveFile::json("price_lists.json", &price_lists).map_err(ApiError::internal)?,
+        ArchiveFile::json("stock_movements.json", &stock_movements).map_err(ApiError::internal)?,
+    ];
+
+    Ok(Json(AccountDataPart { files }))
+}
+
+async fn erase_account_data(
+    auth: AuthContext,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<ErasureReport>, ApiError> {
+    let mut tx = state.db.begin().await?;
+    let price_lists = sqlx::query("DELETE FROM price_lists WHERE user_id = $1")
+        .bind(auth.user_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    let products = sqlx::query("DELETE FROM products WHERE user_id = $1")
+        .bind(auth.user_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    tx.commit().await?;
+
+    tracing::info!("Erased {} products and {} price lists for user {}", products, price_lists, auth.user_id);
+    Ok(Json(ErasureReport { deleted: products + price_lists, anonymized: 0 }))
+}
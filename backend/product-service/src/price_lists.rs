@@ -0,0 +1,376 @@
+//! Price lists and price resolution (see `common::pricing`).
+//!
+//! A list is saved whole, with its `prices`: one per product and
+//! `min_quantity` tier.
+
+use axum::{
+    extract::{rejection::QueryRejection, Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::sync::Arc;
+
+use common::pricing::{self, PriceRequest, ResolvedPrice, TIER_MODES};
+use common::{ApiError, AuthContext, FieldError, ListQuery, ListSpec, Page, Valid, Validate, Validator};
+use common::{FieldKind, Filter, FilterOp, SortKey};
+
+use crate::AppState;
+
+#[derive(Debug, FromRow, Serialize)]
+pub(crate) struct PriceList {
+    id: i32,
+    user_id: i32,
+    name: String,
+    currency: String,
+    /// Only clients with this tag get the list's prices; every client without one.
+    client_tag: Option<String>,
+    valid_from: Option<NaiveDate>,
+    valid_to: Option<NaiveDate>,
+    tier_mode: String,
+    created_at: Option<chrono::DateTime<Utc>>,
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub(crate) struct ListPrice {
+    id: i32,
+    price_list_id: i32,
+    product_id: i32,
+    min_quantity: f64,
+    unit_price: f64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct PriceListWithPrices {
+    #[serde(flatten)]
+    price_list: PriceList,
+    prices: Vec<ListPrice>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CreatePriceListRequest {
+    name: String,
+    currency: String,
+    client_tag: Option<String>,
+    valid_from: Option<NaiveDate>,
+    valid_to: Option<NaiveDate>,
+    /// `volume` (the default) or `graduated`.
+    tier_mode: Option<String>,
+    #[serde(default)]
+    prices: Vec<ListPriceRequest>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ListPriceRequest {
+    product_id: i32,
+    #[serde(default)]
+    min_quantity: f64,
+    unit_price: f64,
+}
+
+impl Validate for CreatePriceListRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("name", &self.name);
+        v.max_len("name", self.name.as_str(), 255);
+        v.currency("currency", self.currency.as_str());
+        if let Some(tag) = &self.client_tag {
+            v.required("client_tag", tag);
+            v.max_len("client_tag", tag.as_str(), 50);
+        }
+        if let (Some(from), Some(to)) = (self.valid_from, self.valid_to) {
+            if to < from {
+                v.error("valid_to", "must not be before valid_from");
+            }
+        }
+        v.one_of("tier_mode", self.tier_mode.as_deref(), TIER_MODES);
+        v.nested("prices", &self.prices);
+        for (i, price) in self.prices.iter().enumerate() {
+            let repeated = self.prices[..i].iter().any(|p| p.product_id == price.product_id && p.min_quantity == price.min_quantity);
+            if repeated {
+                v.error(&format!("prices[{}].min_quantity", i), "is already a tier of this product");
+            }
+        }
+    }
+}
+
+impl Validate for ListPriceRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.min("min_quantity", self.min_quantity, 0.0);
+        v.min("unit_price", self.unit_price, 0.0);
+    }
+}
+
+pub(crate) const PRICE_LIST_LIST: ListSpec = ListSpec {
+    columns: "id, user_id, name, currency, client_tag, valid_from, valid_to, tier_mode, created_at",
+    from: "FROM price_lists",
+    owner_column: "user_id",
+    id_column: "id",
+    sort_keys: &[
+        SortKey::new("name", "lower(name)", FieldKind::Text),
+        SortKey::new("valid_from", "COALESCE(valid_from, DATE '0001-01-01')", FieldKind::Date),
+        SortKey::new("created_at", "COALESCE(created_at, 'epoch'::timestamptz)", FieldKind::Timestamp),
+    ],
+    default_sort: "name",
+    filters: &[
+        Filter::new("q", "name", FieldKind::Text, FilterOp::Contains),
+        Filter::new("currency", "currency", FieldKind::Text, FilterOp::In),
+        Filter::new("client_tag", "client_tag", FieldKind::Text, FilterOp::In),
+    ],
+    default_filters: &[],
+};
+
+const PRICE_COLUMNS: &str = "id, price_list_id, product_id, min_quantity::float8 AS min_quantity, unit_price::float8 AS unit_price";
+
+pub(crate) async fn list_price_lists(auth: AuthContext, State(state): State<Arc<AppState>>, query: ListQuery) -> Result<Json<Page<PriceList>>, ApiError> {
+    let page = query.fetch(&state.db, &PRICE_LIST_LIST, auth.user_id).await?;
+    Ok(Json(page))
+}
+
+pub(crate) async fn create_price_list(
+    auth: AuthContext,
+    State(state): State<Arc<AppState>>,
+    Valid(payload): Valid<CreatePriceListRequest>,
+) -> Result<Json<PriceListWithPrices>, ApiError> {
+    let mut tx = state.db.begin().await?;
+    let id: i32 = sqlx::query_scalar(
+        "INSERT INTO price_lists (user_id, name, currency, client_tag, valid_from, valid_to, tier_mode) \
+         VALUES ($1, $2, $3, $4, $5, $6, COALESCE($7, 'volume')) RETURNING id"
+    )
+    .bind(auth.user_id)
+    .bind(&payload.name)
+    .bind(&payload.currency)
+    .bind(&payload.client_tag)
+    .bind(payload.valid_from)
+    .bind(payload.valid_to)
+    .bind(&payload.tier_mode)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    insert_prices(&mut tx, auth.user_id, id, &payload.prices).await?;
+    let price_list = fetch_price_list(&mut tx, id, auth.user_id).await?;
+    tx.commit().await?;
+    Ok(Json(price_list))
+}
+
+pub(crate) async fn get_price_list(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>) -> Result<Json<PriceListWithPrices>, ApiError> {
+    let mut conn = state.db.acquire().await?;
+    Ok(Json(fetch_price_list(&mut conn, id, auth.user_id).await?))
+}
+
+pub(crate) async fn update_price_list(
+    auth: AuthContext,
+    Path(id): Path<i32>,
+    State(state): State<Arc<AppState>>,
+    Valid(payload): Valid<CreatePriceListRequest>,
+) -> Result<Json<PriceListWithPrices>, ApiError> {
+    let mut tx = state.db.begin().await?;
+    sqlx::query_scalar::<_, i32>(
+        "UPDATE price_lists SET name = $1, currency = $2, client_tag = $3, valid_from = $4, valid_to = $5, tier_mode = COALESCE($6, 'volume') \
+         WHERE id = $7 AND user_id = $8 RETURNING id"
+    )
+    .bind(&payload.name)
+    .bind(&payload.currency)
+    .bind(&payload.client_tag)
+    .bind(payload.valid_from)
+    .bind(payload.valid_to)
+    .bind(&payload.tier_mode)
+    .bind(id)
+    .bind(auth.user_id)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(ApiError::NotFound("Price list not found".to_string()))?;
+
+    sqlx::query("DELETE FROM price_list_prices WHERE price_list_id = $1").bind(id).execute(&mut *tx).await?;
+    insert_prices(&mut tx, auth.user_id, id, &payload.prices).await?;
+    let price_list = fetch_price_list(&mut tx, id, auth.user_id).await?;
+    tx.commit().await?;
+    Ok(Json(price_list))
+}
+
+pub(crate) async fn delete_price_list(auth: AuthContext, Path(id): Path<i32>, State(state): State<Arc<AppState>>) -> Result<StatusCode, ApiError> {
+    let result = sqlx::query("DELETE FROM price_lists WHERE id = $1 AND user_id = $2")
+        .bind(id)
+        .bind(auth.user_id)
+        .execute(&state.db)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound("Price list not found".to_string()));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn fetch_price_list(conn: &mut sqlx::PgConnection, id: i32, user_id: i32) -> Result<PriceListWithPrices, ApiError> {
+    let price_list = sqlx::query_as::<_, PriceList>(&format!("SELECT {} {} WHERE id = $1 AND user_id = $2", PRICE_LIST_LIST.columns, PRICE_LIST_LIST.from))
+        .bind(id)
+        .bind(user_id)
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or(ApiError::NotFound("Price list not found".to_string()))?;
+    let prices = sqlx::query_as::<_, ListPrice>(&format!("SELECT {} FROM price_list_prices WHERE price_list_id = $1 ORDER BY product_id, min_quantity", PRICE_COLUMNS))
+        .bind(id)
+        .fetch_all(&mut *conn)
+        .await?;
+    Ok(PriceListWithPrices { price_list, prices })
+}
+
+/// Every price list of the user with its prices, for the account export.
+pub(crate) async fn export_price_lists(db: &sqlx::PgPool, user_id: i32) -> Result<Vec<PriceListWithPrices>, ApiError> {
+    let lists = sqlx::query_as::<_, PriceList>(&format!("SELECT {} {} WHERE user_id = $1 ORDER BY id", PRICE_LIST_LIST.columns, PRICE_LIST_LIST.from))
+        .bind(user_id)
+        .fetch_all(db)
+        .await?;
+    let mut prices = sqlx::query_as::<_, ListPrice>(&format!(
+        "SELECT {} FROM price_list_prices WHERE price_list_id IN (SELECT id FROM price_lists WHERE user_id = $1) ORDER BY product_id, min_quantity",
+        PRICE_COLUMNS
+    ))
+    .bind(user_id)
+    .fetch_all(db)
+    .await?;
+
+    let mut exported = Vec::new();
+    for price_list in lists {
+        let (own, rest): (Vec<_>, Vec<_>) = prices.into_iter().partition(|p| p.price_list_id == price_list.id);
+        prices = rest;
+        exported.push(PriceListWithPrices { price_list, prices: own });
+    }
+    Ok(exported)
+}
+
+async fn insert_prices(tx: &mut sqlx::PgConnection, user_id: i32, price_list_id: i32, prices: &[ListPriceRequest]) -> Result<(), ApiError> {
+    let product_ids: Vec<i32> = prices.iter().map(|p| p.product_id).collect();
+    let owned: Vec<i32> = sqlx::query_scalar("SELECT id FROM products WHERE id = ANY($1) AND user_id = $2")
+        .bind(&product_ids)
+        .bind(user_id)
+        .fetch_all(&mut *tx)
+        .await?;
+    let unknown: Vec<FieldError> = prices
+        .iter()
+        .enumerate()
+        .filter(|(_, p)| !owned.contains(&p.product_id))
+        .map(|(i, _)| FieldError { field: format!("prices[{}].product_id", i), message: "does not exist".to_string() })
+        .collect();
+    if !unknown.is_empty() {
+        return Err(ApiError::Validation(unknown));
+    }
+
+    for price in prices {
+        sqlx::query("INSERT INTO price_list_prices (price_list_id, product_id, min_quantity, unit_price) VALUES ($1, $2, $3, $4)")
+            .bind(price_list_id)
+            .bind(price.product_id)
+            .bind(price.min_quantity)
+            .bind(price.unit_price)
+            .execute(&mut *tx)
+            .await?;
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PriceQuery {
+    client_id: Option<i32>,
+    /// Defaults to 1.
+    quantity: Option<f64>,
+    /// Defaults to today.
+    date: Option<NaiveDate>,
+    /// Defaults to the client's currency, then the company's.
+    currency: Option<String>,
+}
+
+impl Validate for PriceQuery {
+    fn validate(&self, v: &mut Validator) {
+        if self.quantity.is_some_and(|q| q <= 0.0) {
+            v.error("quantity", "must be greater than 0");
+        }
+        v.currency("currency", self.currency.as_deref());
+    }
+}
+
+pub(crate) async fn get_product_price(
+    auth: AuthContext,
+    Path(id): Path<i32>,
+    State(state): State<Arc<AppState>>,
+    query: Result<Query<PriceQuery>, QueryRejection>,
+) -> Result<Json<ResolvedPrice>, ApiError> {
+    let Query(query) = query?;
+    Validator::check(&query)?;
+
+    let mut conn = state.db.acquire().await?;
+    let currency = match query.currency {
+        Some(currency) => currency,
+        None => pricing::default_currency(&mut conn, auth.user_id, query.client_id).await?,
+    };
+    let request = PriceRequest {
+        product_id: id,
+        client_id: query.client_id,
+        quantity: query.quantity.unwrap_or(1.0),
+        date: query.date.unwrap_or_else(|| Utc::now().date_naive()),
+        currency: &currency,
+    };
+    let price = pricing::resolve(&mut conn, auth.user_id, &request)
+        .await?
+        .ok_or(ApiError::NotFound("Product not found".to_string()))?;
+    Ok(Json(price))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn errors(body: serde_json::Value) -> Vec<String> {
+        let request: CreatePriceListRequest = serde_json::from_value(body).unwrap();
+        Validator::collect(&request).into_iter().map(|e| e.field).collect()
+    }
+
+    #[test]
+    fn accepts_a_tiered_list() {
+        let body = json!({
+            "name": "Wholesale", "currency": "EUR", "client_tag": "wholesale", "tier_mode": "graduated",
+            "valid_from": "2026-01-01", "valid_to": "2026-12-31",
+            "prices": [
+                { "product_id": 1, "unit_price": 10 },
+                { "product_id": 1, "min_quantity": 10, "unit_price": 8 },
+                { "product_id": 2, "unit_price": 5 }
+            ]
+        });
+        assert!(errors(body).is_empty());
+    }
+
+    #[test]
+    fn rejects_a_repeated_tier_of_a_product() {
+        let body = json!({
+            "name": "List", "currency": "EUR",
+            "prices": [
+                { "product_id": 1, "min_quantity": 10, "unit_price": 8 },
+                { "product_id": 2, "min_quantity": 10, "unit_price": 8 },
+                { "product_id": 1, "min_quantity": 10, "unit_price": 7 }
+            ]
+        });
+        assert_eq!(errors(body), ["prices[2].min_quantity"]);
+    }
+
+    #[test]
+    fn rejects_bad_settings_and_prices() {
+        let body = json!({
+            "name": "", "currency": "EURO", "client_tag": "", "tier_mode": "stepped",
+            "valid_from": "2026-12-31", "valid_to": "2026-01-01",
+            "prices": [{ "product_id": 1, "min_quantity": -1, "unit_price": -0.5 }]
+        });
+        let fields = errors(body);
+        for field in ["name", "currency", "client_tag", "tier_mode", "valid_to", "prices[0].min_quantity", "prices[0].unit_price"] {
+            assert!(fields.iter().any(|f| f == field), "{} not in {:?}", field, fields);
+        }
+    }
+
+    #[test]
+    fn prices_only_positive_quantities() {
+        let query = |quantity: Option<f64>| PriceQuery { client_id: None, quantity, date: None, currency: None };
+        assert!(Validator::collect(&query(None)).is_empty());
+        assert!(Validator::collect(&query(Some(0.5))).is_empty());
+        assert_eq!(Validator::collect(&query(Some(0.0)))[0].field, "quantity");
+    }
+}
@@ -0,0 +1,190 @@
+//! Stock movements and valuation of products that track inventory (see
+//! `common::inventory`). Invoices and credit notes move stock from
+//! invoice-service; manual adjustments are recorded here.
+
+use axum::{
+    extract::{rejection::QueryRejection, Query, State},
+    Json,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::sync::Arc;
+
+use common::inventory::{self, Movement};
+use common::ledger::round_cents;
+use common::{ApiError, AuthContext, FieldError, ListQuery, ListSpec, Page, Valid, Validate, Validator};
+use common::{FieldKind, Filter, FilterOp, SortKey};
+
+use crate::{AppState, DbPool};
+
+#[derive(Debug, FromRow, Serialize)]
+pub(crate) struct StockMovement {
+    id: i32,
+    product_id: i32,
+    #[sqlx(default)]
+    sku: Option<String>,
+    #[sqlx(default)]
+    product_name: Option<String>,
+    /// Positive into stock, negative out of it.
+    quantity: f64,
+    /// `issue`, `void`, `credit_note` or `adjustment`.
+    kind: String,
+    invoice_id: Option<i32>,
+    #[sqlx(default)]
+    invoice_number: Option<String>,
+    credit_note_id: Option<i32>,
+    reason: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CreateAdjustmentRequest {
+    product_id: i32,
+    quantity: f64,
+    reason: String,
+}
+
+impl Validate for CreateAdjustmentRequest {
+    fn validate(&self, v: &mut Validator) {
+        if self.quantity == 0.0 {
+            v.error("quantity", "must not be 0");
+        }
+        v.required("reason", &self.reason);
+        v.max_len("reason", self.reason.as_str(), 500);
+    }
+}
+
+pub(crate) const STOCK_MOVEMENT_LIST: ListSpec = ListSpec {
+    columns: "m.id, m.product_id, p.sku, p.name AS product_name, m.quantity::float8 AS quantity, m.kind, \
+              m.invoice_id, i.invoice_number, m.credit_note_id, m.reason, m.created_at",
+    from: "FROM stock_movements m JOIN products p ON p.id = m.product_id LEFT JOIN invoices i ON i.id = m.invoice_id",
+    owner_column: "m.user_id",
+    id_column: "m.id",
+    sort_keys: &[
+        SortKey::new("created_at", "m.created_at", FieldKind::Timestamp),
+        SortKey::new("quantity", "m.quantity", FieldKind::Number),
+    ],
+    default_sort: "-created_at",
+    filters: &[
+        Filter::new("product_id", "m.product_id", FieldKind::Int, FilterOp::In),
+        Filter::new("kind", "m.kind", FieldKind::Text, FilterOp::In),
+        Filter::new("invoice_id", "m.invoice_id", FieldKind::Int, FilterOp::In),
+        Filter::new("credit_note_id", "m.credit_note_id", FieldKind::Int, FilterOp::In),
+        Filter::new("from", "m.created_at::date", FieldKind::Date, FilterOp::Min),
+        Filter::new("to", "m.created_at::date", FieldKind::Date, FilterOp::Max),
+    ],
+    default_filters: &[],
+};
+
+pub(crate) async fn list_stock_movements(auth: AuthContext, State(state): State<Arc<AppState>>, query: ListQuery) -> Result<Json<Page<StockMovement>>, ApiError> {
+    let page = query.fetch(&state.db, &STOCK_MOVEMENT_LIST, auth.user_id).await?;
+    Ok(Json(page))
+}
+
+/// Records a manual adjustment, e.g. after a stock count or for damaged goods.
+pub(crate) async fn create_adjustment(
+    auth: AuthContext,
+    State(state): State<Arc<AppState>>,
+    Valid(payload): Valid<CreateAdjustmentRequest>,
+) -> Result<Json<StockMovement>, ApiError> {
+    let mut tx = state.db.begin().await?;
+    let movement = Movement {
+        product_id: payload.product_id,
+        quantity: payload.quantity,
+        kind: "adjustment",
+        invoice_id: None,
+        credit_note_id: None,
+        reason: Some(&payload.reason),
+    };
+    if inventory::record(&mut tx, auth.user_id, &movement).await?.is_none() {
+        let exists: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM products WHERE id = $1 AND user_id = $2)")
+            .bind(payload.product_id)
+            .bind(auth.user_id)
+            .fetch_one(&mut *tx)
+            .await?;
+        if !exists {
+            return Err(ApiError::NotFound("Product not found".to_string()));
+        }
+        return Err(ApiError::Validation(vec![FieldError {
+            field: "product_id".to_string(),
+            message: "does not track inventory".to_string(),
+        }]));
+    }
+
+    let adjustment = sqlx::query_as::<_, StockMovement>(&format!(
+        "SELECT {} {} WHERE m.user_id = $1 AND m.product_id = $2 ORDER BY m.id DESC LIMIT 1",
+        STOCK_MOVEMENT_LIST.columns, STOCK_MOVEMENT_LIST.from
+    ))
+    .bind(auth.user_id)
+    .bind(payload.product_id)
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(Json(adjustment))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ValuationQuery {
+    /// Defaults to today.
+    as_of: Option<NaiveDate>,
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub(crate) struct StockValue {
+    product_id: i32,
+    sku: Option<String>,
+    name: String,
+    unit: String,
+    quantity: f64,
+    cost_price: Option<f64>,
+    /// `quantity` × `cost_price`; `None` without a cost price.
+    value: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct StockValuation {
+    as_of: NaiveDate,
+    items: Vec<StockValue>,
+    /// Of the products with a cost price.
+    total_value: f64,
+}
+
+/// Stock of every product that tracks inventory at the end of `as_of`, valued
+/// at its current cost price.
+pub(crate) async fn get_stock_valuation(
+    auth: AuthContext,
+    State(state): State<Arc<AppState>>,
+    query: Result<Query<ValuationQuery>, QueryRejection>,
+) -> Result<Json<StockValuation>, ApiError> {
+    let Query(query) = query?;
+    let as_of = query.as_of.unwrap_or_else(|| Utc::now().date_naive());
+
+    // Today's stock less whatever moved after the day
+    let items = sqlx::query_as::<_, StockValue>(
+        "SELECT id AS product_id, sku, name, unit, quantity, cost_price, ROUND((quantity * cost_price)::numeric, 2)::float8 AS value FROM ( \
+           SELECT p.id, p.sku, p.name, p.unit, p.cost_price::float8 AS cost_price, \
+             (p.stock_on_hand - COALESCE((SELECT SUM(m.quantity) FROM stock_movements m \
+                WHERE m.product_id = p.id AND m.created_at >= ($2::date + 1)::timestamptz), 0))::float8 AS quantity \
+           FROM products p WHERE p.user_id = $1 AND p.track_inventory \
+         ) s ORDER BY lower(name), id"
+    )
+    .bind(auth.user_id)
+    .bind(as_of)
+    .fetch_all(&state.db)
+    .await?;
+
+    let total_value = round_cents(items.iter().filter_map(|i| i.value).sum());
+    Ok(Json(StockValuation { as_of, items, total_value }))
+}
+
+pub(crate) async fn export_stock_movements(db: &DbPool, user_id: i32) -> Result<Vec<StockMovement>, ApiError> {
+    let movements = sqlx::query_as::<_, StockMovement>(&format!(
+        "SELECT {} {} WHERE m.user_id = $1 ORDER BY m.id",
+        STOCK_MOVEMENT_LIST.columns, STOCK_MOVEMENT_LIST.from
+    ))
+    .bind(user_id)
+    .fetch_all(db)
+    .await?;
+    Ok(movements)
+}
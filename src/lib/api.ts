@@ -1,5 +1,23 @@
 const API_URL = import.meta.env.VITE_API_URL || 'http://localhost:5000/api';
 
+/** Response of every list endpoint. */
+export interface Page<T> {
+  items: T[];
+  next_cursor: string | null;
+  total: number;
+}
+
+type ListParams = Record<string, string | number | undefined>;
+
+const withParams = (path: string, params: ListParams) => {
+  const query = new URLSearchParams();
+  Object.entries(params).forEach(([key, value]) => {
+    if (value !== undefined && value !== '') query.set(key, String(value));
+  });
+  const qs = query.toString();
+  return qs ? `${path}?${qs}` : path;
+};
+
 const getHeaders = () => {
   const token = localStorage.getItem('token');
   return {
@@ -17,7 +35,17 @@ const handleResponse = async (res: Response) => {
   }
   if (!res.ok) {
     const errorText = await res.text();
-    throw new Error(errorText || `Request failed with status ${res.status}`);
+    let message = errorText;
+    try {
+      // Services answer with application/problem+json: { status, code, title, detail, request_id }
+      const problem = JSON.parse(errorText);
+      message = Array.isArray(problem.errors) && problem.errors.length
+        ? problem.errors.map((e: { field: string; message: string }) => `${e.field} ${e.message}`).join('; ')
+        : problem.detail || problem.title || errorText;
+    } catch {
+      // Not JSON (e.g. a gateway error page); show it as is
+    }
+    throw new Error(message || `Request failed with status ${res.status}`);
   }
   return res.json();
 };
@@ -30,6 +58,23 @@ export const api = {
     return handleResponse(res);
   },
 
+  /** One page of a list endpoint; pass `cursor` from the previous page to continue. */
+  list: async <T = any>(path: string, params: ListParams = {}): Promise<Page<T>> => {
+    return api.get(withParams(path, params));
+  },
+
+  /** Every row of a list endpoint, for pickers and other small collections. */
+  listAll: async <T = any>(path: string, params: ListParams = {}): Promise<T[]> => {
+    const items: T[] = [];
+    let cursor: string | undefined;
+    do {
+      const page: Page<T> = await api.list<T>(path, { ...params, limit: 200, cursor });
+      items.push(...page.items);
+      cursor = page.next_cursor ?? undefined;
+    } while (cursor);
+    return items;
+  },
+
   post: async (path: string, data: any) => {
     const res = await fetch(`${API_URL}${path}`, {
       method: 'POST',
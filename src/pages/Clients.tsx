@@ -72,7 +72,7 @@ export default function Clients() {
     }
 
     try {
-      const data = await api.get('/clients');
+      const data = await api.listAll('/clients');
       setClients(data);
     } catch (err) {
       console.error(err);
@@ -80,16 +80,17 @@ export default function Dashboard() {
       }
 
       try {
-        const invoices: Invoice[] = await api.get('/invoices');
-        const clients = await api.get('/clients');
+        const invoices = await api.list<Invoice>('/invoices', { limit: 5 });
+        const clients = await api.list('/clients', { limit: 1 });
+        const { invoice_stats } = await api.get('/reports/dashboard-stats');
         
-        const total = invoices.reduce((acc: number, inv: Invoice) => acc + parseFloat(inv.total.toString()), 0);
+        const total = invoice_stats.reduce((acc: number, s: { total_amount: number }) => acc + s.total_amount, 0);
         
         setStats({
-          totalInvoices: invoices.length,
-          totalClients: clients.length,
+          totalInvoices: invoices.total,
+          totalClients: clients.total,
           revenue: total,
-          recentInvoices: invoices.slice(0, 5),
+          recentInvoices: invoices.items,
           revenueData: [
             { name: 'Week 1', value: total * 0.2 },
             { name: 'Week 2', value: total * 0.4 },
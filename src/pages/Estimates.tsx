@@ -53,7 +53,7 @@ export default function Estimates() {
     }
 
     try {
-      const data = await api.get('/estimates');
+      const data = await api.listAll('/estimates');
       setEstimates(data);
     } catch (err) {
       console.error('Failed to fetch estimates', err);
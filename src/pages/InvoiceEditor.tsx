@@ -87,7 +87,7 @@ export default function InvoiceEditor({ isEstimate = false }: { isEstimate?: boo
     }
 
     try {
-      const data = await api.get('/clients');
+      const data = await api.listAll('/clients');
       setClients(data);
     } catch (err) {
       console.error('Failed to fetch clients');
@@ -106,7 +106,7 @@ export default function InvoiceEditor({ isEstimate = false }: { isEstimate?: boo
       return;
     }
     try {
-      const data = await api.get('/products');
+      const data = await api.listAll('/products');
       setProducts(data);
     } catch (err) {
       console.error('Failed to fetch products');
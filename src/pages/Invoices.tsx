@@ -15,6 +15,8 @@ interface Invoice {
 
 export default function Invoices() {
   const [invoices, setInvoices] = useState<Invoice[]>([]);
+  const [total, setTotal] = useState(0);
+  const [nextCursor, setNextCursor] = useState<string | null>(null);
   const [filter, setFilter] = useState('all');
   const [search, setSearch] = useState('');
   const navigate = useNavigate();
@@ -31,9 +33,9 @@ export default function Invoices() {
 
   useEffect(() => {
     fetchInvoices();
-  }, []);
+  }, [filter]);
 
-  const fetchInvoices = async () => {
+  const fetchInvoices = async (cursor?: string) => {
     const token = localStorage.getItem('token');
     const isDemo = token?.startsWith('demo-token-');
 
@@ -46,12 +48,18 @@ export default function Invoices() {
         { id: 105, client_name: 'Oscorp', created_at: new Date().toISOString(), due_date: new Date(Date.now() - 86400000 * 15).toISOString(), total: 560.00, status: 'paid' },
       ];
       setInvoices(mockInvoices);
+      setTotal(mockInvoices.length);
       return;
     }
 
     try {
-      const data = await api.get('/invoices');
-      setInvoices(data);
+      const page = await api.list<Invoice>('/invoices', {
+        status: filter === 'all' ? undefined : filter,
+        cursor,
+      });
+      setInvoices(prev => cursor ? [...prev, ...page.items] : page.items);
+      setTotal(page.total);
+      setNextCursor(page.next_cursor);
     } catch (err) {
       console.error(err);
     }
@@ -82,6 +90,7 @@ export default function Invoices() {
   };
 
   const filteredInvoices = invoices.filter(inv => {
+    // Status is filtered server-side; this keeps demo data consistent
     const matchesFilter = filter === 'all' || inv.status === filter;
     const matchesSearch = inv.client_name.toLowerCase().includes(search.toLowerCase()) || 
                           inv.id.toString().includes(search);
@@ -102,7 +111,7 @@ export default function Invoices() {
           </h1>
           <p className="text-muted-foreground text-sm font-medium flex items-center gap-2">
             <Activity size={14} className="text-blue-500" />
-            Managing <span className="text-white font-bold">{filteredInvoices.length}</span> documents in the current registry view.
+            Managing <span className="text-white font-bold">{total}</span> documents in the current registry view.
           </p>
         </div>
         <Link to="/invoices/new" className="px-6 py-3 rounded-xl bg-blue-600 hover:bg-blue-500 text-sm font-black uppercase tracking-widest transition-all no-underline text-white flex items-center gap-2 shadow-xl shadow-blue-900/20">
@@ -258,6 +267,16 @@ export default function Invoices() {
             </tbody>
           </table>
         </div>
+        {nextCursor && (
+          <div className="flex justify-center p-6 border-t border-white/5">
+            <button
+              onClick={() => fetchInvoices(nextCursor)}
+              className="px-6 py-2 rounded-xl bg-white/5 border border-white/10 text-xs font-black uppercase tracking-widest text-blue-400 hover:bg-white/10 transition-all"
+            >
+              Load more ({invoices.length} of {total})
+            </button>
+          </div>
+        )}
       </motion.div>
     </motion.div>
   );
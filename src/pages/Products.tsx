@@ -39,7 +39,7 @@ export default function Products() {
     }
 
     try {
-      const data = await api.get('/products');
+      const data = await api.listAll('/products');
       setProducts(data);
     } catch (err) {
       console.error(err);
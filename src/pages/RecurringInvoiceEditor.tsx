@@ -42,7 +42,7 @@ export default function RecurringInvoiceEditor() {
 
   const fetchClients = async () => {
     try {
-      const data = await api.get('/clients');
+      const data = await api.listAll('/clients');
       setClients(data);
     } catch (err) {
       console.error('Failed to fetch clients');
@@ -40,7 +40,7 @@ export default function RecurringInvoices() {
     }
 
     try {
-      const data = await api.get('/recurring');
+      const data = await api.listAll('/recurring');
       setInvoices(data);
     } catch (err) {
       console.error('Failed to fetch recurring invoices');